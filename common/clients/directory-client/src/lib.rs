@@ -1,3 +1,4 @@
+use crate::presence::SignaturePolicy;
 use crate::requests::health_check_get::{HealthCheckRequester, Request as HealthCheckRequest};
 use crate::requests::metrics_mixes_get::{MetricsMixRequester, Request as MetricsMixRequest};
 use crate::requests::metrics_mixes_post::{MetricsMixPoster, Request as MetricsMixPost};
@@ -20,11 +21,20 @@ pub mod requests;
 
 pub struct Config {
     pub base_url: String,
+    pub signature_policy: SignaturePolicy,
 }
 
 impl Config {
     pub fn new(base_url: String) -> Self {
-        Config { base_url }
+        Config {
+            base_url,
+            signature_policy: Default::default(),
+        }
+    }
+
+    pub fn with_signature_policy(mut self, signature_policy: SignaturePolicy) -> Self {
+        self.signature_policy = signature_policy;
+        self
     }
 }
 
@@ -48,7 +58,8 @@ impl DirectoryClient for Client {
         let metrics_mixes: MetricsMixRequest = MetricsMixRequest::new(config.base_url.clone());
         let metrics_post: MetricsMixPost = MetricsMixPost::new(config.base_url.clone());
         let presence_topology: PresenceTopologyRequest =
-            PresenceTopologyRequest::new(config.base_url.clone());
+            PresenceTopologyRequest::new(config.base_url.clone())
+                .with_signature_policy(config.signature_policy);
         let presence_coconodes_post: PresenceCocoNodesPost =
             PresenceCocoNodesPost::new(config.base_url.clone());
         let presence_mix_nodes_post: PresenceMixNodesPost =
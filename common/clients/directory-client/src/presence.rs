@@ -1,5 +1,6 @@
 use crate::requests::presence_topology_get::PresenceTopologyGetRequester;
 use crate::{Client, Config, DirectoryClient};
+use crypto::identity::{ed25519, MixnetIdentityPrivateKey, MixnetIdentityPublicKey};
 use log::*;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
@@ -7,6 +8,106 @@ use std::io;
 use std::net::ToSocketAddrs;
 use topology::{CocoNode, MixNode, MixProviderNode, NymTopology};
 
+// Presences of mix nodes and providers are signed by the identity key of the node announcing
+// them, so that neither the directory nor anyone in between could modify them or announce
+// nodes without signatures. Do note the identity key is included in the presence itself, so
+// the signature doesn't tell whether the node is legitimate - telling that requires knowing
+// the identity keys of the nodes in advance.
+pub trait SignedPresence {
+    // canonical serialization of everything announced by the node itself, i.e. excluding
+    // the signature and `last_seen` which is set by the directory
+    fn signed_content(&self) -> Vec<u8>;
+    // base64 encoded ed25519 identity key the presence is signed with
+    fn identity_key(&self) -> &str;
+    fn signature(&self) -> &str;
+    fn set_signature(&mut self, signature: String);
+
+    fn is_signed(&self) -> bool {
+        !self.signature().is_empty()
+    }
+
+    fn sign(&mut self, identity_key: &ed25519::PrivateKey) {
+        let signature = identity_key.sign(&self.signed_content());
+        self.set_signature(base64::encode_config(&signature, base64::URL_SAFE));
+    }
+
+    fn verify_signature(&self) -> bool {
+        let identity_key = match base64::decode_config(self.identity_key(), base64::URL_SAFE)
+            .ok()
+            .and_then(|bytes| ed25519::PublicKey::from_bytes(&bytes).ok())
+        {
+            Some(identity_key) => identity_key,
+            None => return false,
+        };
+        let signature = match base64::decode_config(self.signature(), base64::URL_SAFE) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        identity_key.verify(&self.signed_content(), &signature)
+    }
+}
+
+// Fields are length-prefixed so that no two different presences could share the same encoding.
+// The domain separates signatures of different presence kinds.
+fn canonical_encoding(domain: &[u8], fields: &[&[u8]]) -> Vec<u8> {
+    std::iter::once(domain)
+        .chain(fields.iter().cloned())
+        .flat_map(|field| {
+            (field.len() as u32)
+                .to_be_bytes()
+                .to_vec()
+                .into_iter()
+                .chain(field.iter().cloned())
+        })
+        .collect()
+}
+
+// What to do with presences whose signatures can't be verified.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignaturePolicy {
+    // only presences with valid signatures are accepted
+    Strict,
+    // presences without any signature (i.e. announced by older nodes) are accepted,
+    // but ones with invalid signatures are still dropped
+    AllowUnsigned,
+    // signatures are not checked at all
+    Disabled,
+}
+
+// nodes that have not been upgraded yet can only be used by explicitly allowing unsigned presences
+impl Default for SignaturePolicy {
+    fn default() -> Self {
+        SignaturePolicy::Strict
+    }
+}
+
+impl SignaturePolicy {
+    fn accepts<P: SignedPresence>(self, presence: &P) -> bool {
+        match self {
+            SignaturePolicy::Strict => presence.verify_signature(),
+            SignaturePolicy::AllowUnsigned => !presence.is_signed() || presence.verify_signature(),
+            SignaturePolicy::Disabled => true,
+        }
+    }
+
+    fn retain_accepted<P: SignedPresence>(self, presences: &mut Vec<P>) {
+        presences.retain(|presence| {
+            let accepted = self.accepts(presence);
+            if !accepted {
+                warn!(
+                    "dropping presence of {} as its signature could not be verified",
+                    presence.identity_key()
+                );
+            }
+            accepted
+        })
+    }
+}
+
+// Coco nodes don't sign their presences, so they are exempt from signature verification.
+// They are never used for routing our packets, so they can't be used to deanonymise us.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CocoPresence {
@@ -42,10 +143,42 @@ impl From<topology::CocoNode> for CocoPresence {
 #[serde(rename_all = "camelCase")]
 pub struct MixNodePresence {
     pub host: String,
+    // sphinx key of the node; the presence is signed with its separate identity key
     pub pub_key: String,
+    #[serde(default)]
+    pub identity_key: String,
     pub layer: u64,
     pub last_seen: u64,
     pub version: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl SignedPresence for MixNodePresence {
+    fn signed_content(&self) -> Vec<u8> {
+        canonical_encoding(
+            b"MIX_NODE_PRESENCE",
+            &[
+                self.host.as_bytes(),
+                self.pub_key.as_bytes(),
+                self.identity_key.as_bytes(),
+                &self.layer.to_be_bytes()[..],
+                self.version.as_bytes(),
+            ],
+        )
+    }
+
+    fn identity_key(&self) -> &str {
+        &self.identity_key
+    }
+
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature
+    }
 }
 
 impl TryInto<topology::MixNode> for MixNodePresence {
@@ -75,9 +208,11 @@ impl From<topology::MixNode> for MixNodePresence {
         MixNodePresence {
             host: mn.host.to_string(),
             pub_key: mn.pub_key,
+            identity_key: String::new(),
             layer: mn.layer,
             last_seen: mn.last_seen,
             version: mn.version,
+            signature: String::new(),
         }
     }
 }
@@ -87,10 +222,49 @@ impl From<topology::MixNode> for MixNodePresence {
 pub struct MixProviderPresence {
     pub client_listener: String,
     pub mixnet_listener: String,
+    // sphinx key of the provider; the presence is signed with its separate identity key
     pub pub_key: String,
+    #[serde(default)]
+    pub identity_key: String,
     pub registered_clients: Vec<MixProviderClient>,
     pub last_seen: u64,
     pub version: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl SignedPresence for MixProviderPresence {
+    fn signed_content(&self) -> Vec<u8> {
+        let registered_clients: Vec<_> = self
+            .registered_clients
+            .iter()
+            .map(|client| client.pub_key.as_bytes())
+            .collect();
+
+        canonical_encoding(
+            b"MIX_PROVIDER_PRESENCE",
+            &[
+                self.client_listener.as_bytes(),
+                self.mixnet_listener.as_bytes(),
+                self.pub_key.as_bytes(),
+                self.identity_key.as_bytes(),
+                canonical_encoding(b"REGISTERED_CLIENTS", &registered_clients).as_slice(),
+                self.version.as_bytes(),
+            ],
+        )
+    }
+
+    fn identity_key(&self) -> &str {
+        &self.identity_key
+    }
+
+    fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    fn set_signature(&mut self, signature: String) {
+        self.signature = signature
+    }
 }
 
 impl Into<topology::MixProviderNode> for MixProviderPresence {
@@ -116,6 +290,7 @@ impl From<topology::MixProviderNode> for MixProviderPresence {
             client_listener: mpn.client_listener.to_string(),
             mixnet_listener: mpn.mixnet_listener.to_string(),
             pub_key: mpn.pub_key,
+            identity_key: String::new(),
             registered_clients: mpn
                 .registered_clients
                 .into_iter()
@@ -123,6 +298,7 @@ impl From<topology::MixProviderNode> for MixProviderPresence {
                 .collect(),
             last_seen: mpn.last_seen,
             version: mpn.version,
+            signature: String::new(),
         }
     }
 }
@@ -158,12 +334,19 @@ pub struct Topology {
     pub mix_provider_nodes: Vec<MixProviderPresence>,
 }
 
+impl Topology {
+    // drops all mix node and provider presences that are not accepted by the signature policy.
+    // coco nodes are left as they are, as they can't be verified
+    pub fn verify_presences(&mut self, policy: SignaturePolicy) {
+        policy.retain_accepted(&mut self.mix_nodes);
+        policy.retain_accepted(&mut self.mix_provider_nodes);
+    }
+}
+
 impl NymTopology for Topology {
     fn new(directory_server: String) -> Self {
         debug!("Using directory server: {:?}", directory_server);
-        let directory_config = Config {
-            base_url: directory_server,
-        };
+        let directory_config = Config::new(directory_server);
         let directory = Client::new(directory_config);
 
         let topology = directory
@@ -218,9 +401,11 @@ mod converting_mixnode_presence_into_topology_mixnode {
         let mix_presence = MixNodePresence {
             host: unresolvable_hostname.to_string(),
             pub_key: "".to_string(),
+            identity_key: "".to_string(),
             layer: 0,
             last_seen: 0,
             version: "".to_string(),
+            signature: "".to_string(),
         };
 
         let result: Result<topology::MixNode, io::Error> = mix_presence.try_into();
@@ -234,12 +419,104 @@ mod converting_mixnode_presence_into_topology_mixnode {
         let mix_presence = MixNodePresence {
             host: resolvable_hostname.to_string(),
             pub_key: "".to_string(),
+            identity_key: "".to_string(),
             layer: 0,
             last_seen: 0,
             version: "".to_string(),
+            signature: "".to_string(),
         };
 
         let result: Result<topology::MixNode, io::Error> = mix_presence.try_into();
         assert!(result.is_ok())
     }
 }
+
+#[cfg(test)]
+mod verifying_presence_signatures {
+    use super::*;
+    use crypto::identity::MixnetIdentityKeyPair;
+
+    fn signed_mix_presence(keypair: &ed25519::KeyPair) -> MixNodePresence {
+        let mut presence = MixNodePresence {
+            host: "1.2.3.4:1789".to_string(),
+            pub_key: "sphinx-key".to_string(),
+            identity_key: keypair.public_key().to_b64_string(),
+            layer: 1,
+            last_seen: 0,
+            version: "0.3.3".to_string(),
+            signature: "".to_string(),
+        };
+        presence.sign(keypair.private_key());
+        presence
+    }
+
+    #[test]
+    fn accepts_presence_signed_by_its_identity() {
+        let keypair = ed25519::KeyPair::new();
+        let presence = signed_mix_presence(&keypair);
+
+        assert!(presence.verify_signature());
+        assert!(SignaturePolicy::Strict.accepts(&presence));
+    }
+
+    #[test]
+    fn ignores_last_seen_set_by_the_directory() {
+        let keypair = ed25519::KeyPair::new();
+        let mut presence = signed_mix_presence(&keypair);
+        presence.last_seen = 1575915097085539300;
+
+        assert!(presence.verify_signature());
+    }
+
+    #[test]
+    fn rejects_modified_presence() {
+        let keypair = ed25519::KeyPair::new();
+        let mut presence = signed_mix_presence(&keypair);
+        presence.host = "5.6.7.8:1789".to_string();
+
+        assert!(!presence.verify_signature());
+        assert!(!SignaturePolicy::AllowUnsigned.accepts(&presence));
+        assert!(SignaturePolicy::Disabled.accepts(&presence));
+    }
+
+    #[test]
+    fn rejects_presence_signed_by_different_identity() {
+        let keypair = ed25519::KeyPair::new();
+        let mut presence = signed_mix_presence(&keypair);
+        presence.identity_key = ed25519::KeyPair::new().public_key().to_b64_string();
+
+        assert!(!presence.verify_signature());
+    }
+
+    #[test]
+    fn accepts_unsigned_presence_only_if_allowed() {
+        let keypair = ed25519::KeyPair::new();
+        let mut presence = signed_mix_presence(&keypair);
+        presence.signature = "".to_string();
+
+        assert!(!SignaturePolicy::Strict.accepts(&presence));
+        assert!(SignaturePolicy::AllowUnsigned.accepts(&presence));
+    }
+
+    #[test]
+    fn covers_registered_clients_of_providers() {
+        let keypair = ed25519::KeyPair::new();
+        let mut presence = MixProviderPresence {
+            client_listener: "1.2.3.4:9000".to_string(),
+            mixnet_listener: "1.2.3.4:1789".to_string(),
+            pub_key: "sphinx-key".to_string(),
+            identity_key: keypair.public_key().to_b64_string(),
+            registered_clients: vec![],
+            last_seen: 0,
+            version: "0.3.3".to_string(),
+            signature: "".to_string(),
+        };
+        presence.sign(keypair.private_key());
+        assert!(presence.verify_signature());
+
+        presence.registered_clients.push(MixProviderClient {
+            pub_key: "foomp".to_string(),
+        });
+        assert!(!presence.verify_signature());
+    }
+}
@@ -79,9 +79,11 @@ mod metrics_get_request {
             MixNodePresence {
                 host: "foo.com".to_string(),
                 pub_key: "abc".to_string(),
+                identity_key: "def".to_string(),
                 layer: 1,
                 last_seen: 0,
                 version: "0.1.0".to_string(),
+                signature: "".to_string(),
             }
         }
     }
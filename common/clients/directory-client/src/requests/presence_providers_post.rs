@@ -79,9 +79,11 @@ mod metrics_get_request {
                 client_listener: "foo.com".to_string(),
                 mixnet_listener: "foo.com".to_string(),
                 pub_key: "abc".to_string(),
+                identity_key: "def".to_string(),
                 registered_clients: vec![],
                 last_seen: 0,
                 version: "0.1.0".to_string(),
+                signature: "".to_string(),
             }
         }
     }
@@ -1,8 +1,9 @@
-use crate::presence::Topology;
+use crate::presence::{SignaturePolicy, Topology};
 
 pub struct Request {
     base_url: String,
     path: String,
+    signature_policy: SignaturePolicy,
 }
 
 pub trait PresenceTopologyGetRequester {
@@ -15,16 +16,26 @@ impl PresenceTopologyGetRequester for Request {
         Request {
             base_url,
             path: "/api/presence/topology".to_string(),
+            signature_policy: Default::default(),
         }
     }
 
+    // presences not accepted by the signature policy are not included in the returned topology
     fn get(&self) -> Result<Topology, reqwest::Error> {
         let url = format!("{}{}", self.base_url, self.path);
-        let topology: Topology = reqwest::get(&url)?.json()?;
+        let mut topology: Topology = reqwest::get(&url)?.json()?;
+        topology.verify_presences(self.signature_policy);
         Ok(topology)
     }
 }
 
+impl Request {
+    pub fn with_signature_policy(mut self, signature_policy: SignaturePolicy) -> Self {
+        self.signature_policy = signature_policy;
+        self
+    }
+}
+
 #[cfg(test)]
 mod topology_requests {
     use super::*;
@@ -64,6 +75,38 @@ mod topology_requests {
             );
             _m.assert();
         }
+
+        #[test]
+        fn it_keeps_unsigned_presences_if_allowed() {
+            let json = fixtures::topology_response_json();
+            let _m = mock("GET", "/api/presence/topology")
+                .with_status(200)
+                .with_body(json)
+                .create();
+            let req = Request::new(mockito::server_url())
+                .with_signature_policy(SignaturePolicy::AllowUnsigned);
+            let topology = req.get().unwrap();
+            assert!(!topology.coco_nodes.is_empty());
+            assert!(!topology.mix_nodes.is_empty());
+            assert!(!topology.mix_provider_nodes.is_empty());
+            _m.assert();
+        }
+
+        #[test]
+        fn it_drops_unsigned_presences_by_default() {
+            let json = fixtures::topology_response_json();
+            let _m = mock("GET", "/api/presence/topology")
+                .with_status(200)
+                .with_body(json)
+                .create();
+            let req = Request::new(mockito::server_url());
+            let topology = req.get().unwrap();
+            // coco nodes never sign their presences
+            assert!(!topology.coco_nodes.is_empty());
+            assert!(topology.mix_nodes.is_empty());
+            assert!(topology.mix_provider_nodes.is_empty());
+            _m.assert();
+        }
     }
     #[cfg(test)]
     pub mod fixtures {
@@ -0,0 +1,268 @@
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use log::*;
+use sphinx_framing::codec::SphinxCodec;
+use sphinx_framing::packet::FramedSphinxPacket;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::WriteHalf;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::codec::FramedWrite;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectionConfig {
+    pub(crate) initial_reconnection_backoff: Duration,
+    pub(crate) maximum_reconnection_backoff: Duration,
+    pub(crate) maximum_reconnection_attempts: u32,
+    pub(crate) connection_timeout: Duration,
+    pub(crate) maximum_idle_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConnectionStatus {
+    // the first connection attempt is still in progress
+    Connecting,
+    Connected,
+    // the connection (or the last attempt to establish it) failed and we're trying again
+    Reconnecting(io::ErrorKind),
+    // the handler gave up on reconnecting or the connection was idle for too long
+    Closed,
+}
+
+enum ConnectionEnd {
+    Failed(io::ErrorKind),
+    Idle,
+    NoLongerRequired,
+}
+
+// ManagedConnection owns a single, persistent, tcp connection to a remote node and writes
+// all packets it receives on its channel into it. If the connection breaks, it is re-established
+// with an exponential backoff. If no packets are sent for a while, the connection is closed
+// and the handler stops. The current state of the connection is published on `status_tx`.
+pub(crate) struct ManagedConnection {
+    address: SocketAddr,
+    config: ConnectionConfig,
+    packets_rx: mpsc::Receiver<FramedSphinxPacket>,
+    status_tx: watch::Sender<ConnectionStatus>,
+}
+
+impl ManagedConnection {
+    pub(crate) fn new(
+        address: SocketAddr,
+        config: ConnectionConfig,
+        packets_rx: mpsc::Receiver<FramedSphinxPacket>,
+        status_tx: watch::Sender<ConnectionStatus>,
+    ) -> Self {
+        ManagedConnection {
+            address,
+            config,
+            packets_rx,
+            status_tx,
+        }
+    }
+
+    fn update_status(&self, status: ConnectionStatus) {
+        // it's fine if nobody is interested in the status anymore
+        let _ = self.status_tx.broadcast(status);
+    }
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        match tokio::time::timeout(
+            self.config.connection_timeout,
+            TcpStream::connect(self.address),
+        )
+        .await
+        {
+            Ok(connection_result) => connection_result,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn connect_with_backoff(&self) -> Option<TcpStream> {
+        let mut backoff = self.config.initial_reconnection_backoff;
+        for attempt in 1..=self.config.maximum_reconnection_attempts {
+            match self.connect().await {
+                Ok(stream) => return Some(stream),
+                Err(err) => {
+                    warn!(
+                        "failed to connect to {} ({}/{}) - {:?}. Going to retry in {:?}",
+                        self.address,
+                        attempt,
+                        self.config.maximum_reconnection_attempts,
+                        err,
+                        backoff
+                    );
+                    self.update_status(ConnectionStatus::Reconnecting(err.kind()));
+                }
+            }
+
+            tokio::time::delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, self.config.maximum_reconnection_backoff);
+        }
+
+        None
+    }
+
+    pub(crate) async fn run(mut self) {
+        loop {
+            let stream = match self.connect_with_backoff().await {
+                Some(stream) => stream,
+                None => {
+                    // any packets still waiting in the channel are dropped together with it.
+                    // next time somebody wants to send something to this address, a fresh
+                    // connection is going to be started.
+                    error!(
+                        "failed to establish connection to {}. Giving up",
+                        self.address
+                    );
+                    self.update_status(ConnectionStatus::Closed);
+                    return;
+                }
+            };
+            debug!("established connection to {}", self.address);
+            self.update_status(ConnectionStatus::Connected);
+
+            let (mut reader, writer) = tokio::io::split(stream);
+            let mut writer = FramedWrite::new(writer, SphinxCodec);
+            // we do not expect any meaningful data to be sent back to us, however, we need to keep
+            // reading whatever the remote sends so that it would not fill up the socket buffers
+            // (and to know as soon as possible if the connection got closed)
+            let mut discard_buf = [0u8; 64];
+            let mut last_sent = Instant::now();
+
+            let end = loop {
+                tokio::select! {
+                    packet = self.packets_rx.next() => match packet {
+                        // all senders were dropped, nobody is ever going to send anything here
+                        None => break ConnectionEnd::NoLongerRequired,
+                        Some(packet) => {
+                            if let Err(err) = writer.send(packet).await {
+                                warn!("failed to write packet to {} - {:?}", self.address, err);
+                                break ConnectionEnd::Failed(err.kind());
+                            }
+                            last_sent = Instant::now();
+                        }
+                    },
+                    read = reader.read(&mut discard_buf) => match read {
+                        Ok(n) if n == 0 => {
+                            debug!("connection to {} was closed by the remote", self.address);
+                            break ConnectionEnd::Failed(io::ErrorKind::ConnectionReset);
+                        }
+                        Ok(_) => trace!("discarding data received from {}", self.address),
+                        Err(err) => {
+                            warn!("connection to {} failed - {:?}", self.address, err);
+                            break ConnectionEnd::Failed(err.kind());
+                        }
+                    },
+                    _ = tokio::time::delay_until(last_sent + self.config.maximum_idle_time) => {
+                        break ConnectionEnd::Idle
+                    }
+                }
+            };
+
+            match end {
+                ConnectionEnd::Failed(kind) => {
+                    self.update_status(ConnectionStatus::Reconnecting(kind))
+                }
+                ConnectionEnd::Idle => {
+                    debug!("connection to {} has been idle for too long", self.address);
+                    self.close(&mut writer).await;
+                    return;
+                }
+                ConnectionEnd::NoLongerRequired => {
+                    debug!("connection to {} is no longer required", self.address);
+                    self.update_status(ConnectionStatus::Closed);
+                    return;
+                }
+            }
+        }
+    }
+
+    // stops accepting new packets, but still writes the ones that were already queued
+    async fn close(&mut self, writer: &mut FramedWrite<WriteHalf<TcpStream>, SphinxCodec>) {
+        self.update_status(ConnectionStatus::Closed);
+        self.packets_rx.close();
+        while let Some(packet) = self.packets_rx.next().await {
+            if let Err(err) = writer.send(packet).await {
+                warn!("failed to write packet to {} - {:?}", self.address, err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod managed_connection {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn test_config(maximum_idle_time: Duration) -> ConnectionConfig {
+        ConnectionConfig {
+            initial_reconnection_backoff: Duration::from_millis(10),
+            maximum_reconnection_backoff: Duration::from_millis(10),
+            maximum_reconnection_attempts: 2,
+            connection_timeout: Duration::from_secs(1),
+            maximum_idle_time,
+        }
+    }
+
+    fn start(
+        address: SocketAddr,
+        config: ConnectionConfig,
+    ) -> (
+        mpsc::Sender<FramedSphinxPacket>,
+        watch::Receiver<ConnectionStatus>,
+    ) {
+        let (packets_tx, packets_rx) = mpsc::channel(1);
+        let (status_tx, status) = watch::channel(ConnectionStatus::Connecting);
+        tokio::spawn(ManagedConnection::new(address, config, packets_rx, status_tx).run());
+        (packets_tx, status)
+    }
+
+    async fn next_status(status: &mut watch::Receiver<ConnectionStatus>) -> ConnectionStatus {
+        loop {
+            match status.recv().await {
+                Some(ConnectionStatus::Connecting) => continue,
+                Some(status) => return status,
+                None => return ConnectionStatus::Closed,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_connection_attempts_are_reported() {
+        // nothing is listening on the address once the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (packets_tx, mut status) = start(address, test_config(Duration::from_secs(60)));
+
+        assert_eq!(
+            ConnectionStatus::Reconnecting(io::ErrorKind::ConnectionRefused),
+            next_status(&mut status).await
+        );
+        while next_status(&mut status).await != ConnectionStatus::Closed {}
+        assert!(packets_tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (packets_tx, mut status) = start(
+            listener.local_addr().unwrap(),
+            test_config(Duration::from_millis(50)),
+        );
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        while next_status(&mut status).await != ConnectionStatus::Closed {}
+        assert!(packets_tx.is_closed());
+        let mut buf = [0u8; 1];
+        assert_eq!(0, remote.read(&mut buf).await.unwrap());
+    }
+}
@@ -0,0 +1,325 @@
+// Messages that do not fit into a single sphinx packet are split into fragments, each sent in
+// its own packet (and hence over its own route). Every message is sent this way, even if it
+// fits into a single fragment, so that the recipient would not need to guess the format.
+//
+// Fragment layout: set_id || total_fragments || index || data
+
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+// TODO: wait until 0.4.0 release to replace those constants with newly exposed
+// sphinx::constants::MAXIMUM_PLAINTEXT_LENGTH
+// we can't do it now for compatibility reasons as most recent sphinx revision
+// has breaking changes due to packet format changes
+pub const MAXIMUM_PLAINTEXT_LENGTH: usize = sphinx::constants::PAYLOAD_SIZE
+    - sphinx::constants::SECURITY_PARAMETER
+    - sphinx::constants::DESTINATION_ADDRESS_LENGTH
+    - 1;
+
+const FRAGMENT_HEADER_LENGTH: usize = 8 + 1 + 1;
+pub const MAXIMUM_FRAGMENT_DATA_LENGTH: usize = MAXIMUM_PLAINTEXT_LENGTH - FRAGMENT_HEADER_LENGTH;
+pub const MAXIMUM_FRAGMENTS_PER_MESSAGE: usize = std::u8::MAX as usize;
+pub const MAXIMUM_MESSAGE_LENGTH: usize =
+    MAXIMUM_FRAGMENT_DATA_LENGTH * MAXIMUM_FRAGMENTS_PER_MESSAGE;
+
+pub type FragmentSetId = u64;
+
+#[derive(Debug, PartialEq)]
+pub enum FragmentationError {
+    TooLongMessageError(usize),
+    MalformedFragmentError,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub set_id: FragmentSetId,
+    pub total_fragments: u8,
+    pub index: u8,
+    pub data: Vec<u8>,
+}
+
+impl Fragment {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.set_id
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(std::iter::once(self.total_fragments))
+            .chain(std::iter::once(self.index))
+            .chain(self.data.iter().cloned())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FragmentationError> {
+        if bytes.len() < FRAGMENT_HEADER_LENGTH {
+            return Err(FragmentationError::MalformedFragmentError);
+        }
+
+        // this can't fail as we've just checked the length
+        let set_id = FragmentSetId::from_be_bytes(bytes[..8].try_into().unwrap());
+        let total_fragments = bytes[8];
+        let index = bytes[9];
+        if total_fragments == 0 || index >= total_fragments {
+            return Err(FragmentationError::MalformedFragmentError);
+        }
+        // together with the limit on the number of fragments it bounds the size of a single set
+        if bytes.len() - FRAGMENT_HEADER_LENGTH > MAXIMUM_FRAGMENT_DATA_LENGTH {
+            return Err(FragmentationError::MalformedFragmentError);
+        }
+
+        Ok(Fragment {
+            set_id,
+            total_fragments,
+            index,
+            data: bytes[FRAGMENT_HEADER_LENGTH..].to_vec(),
+        })
+    }
+}
+
+// splits the message into fragments of a random set id, each of which fits into a single
+// sphinx packet
+pub fn split_into_fragments(message: &[u8]) -> Result<Vec<Fragment>, FragmentationError> {
+    if message.len() > MAXIMUM_MESSAGE_LENGTH {
+        return Err(FragmentationError::TooLongMessageError(message.len()));
+    }
+
+    let set_id: FragmentSetId = rand::random();
+    // empty message still needs a single (empty) fragment
+    let chunks: Vec<_> = if message.is_empty() {
+        vec![&message[..]]
+    } else {
+        message.chunks(MAXIMUM_FRAGMENT_DATA_LENGTH).collect()
+    };
+    let total_fragments = chunks.len() as u8;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| Fragment {
+            set_id,
+            total_fragments,
+            index: index as u8,
+            data: chunk.to_vec(),
+        })
+        .collect())
+}
+
+struct FragmentSet {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    last_received: Instant,
+}
+
+impl FragmentSet {
+    fn new(total_fragments: u8) -> Self {
+        FragmentSet {
+            fragments: vec![None; total_fragments as usize],
+            received: 0,
+            last_received: Instant::now(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.fragments.len()
+    }
+
+    fn into_message(self) -> Vec<u8> {
+        self.fragments.into_iter().flatten().flatten().collect()
+    }
+}
+
+// Collects fragments until all of them of the same set are received. Fragments can arrive in any
+// order as each of them travels through the mixnet on its own. Sets that have not received any
+// new fragment for longer than the timeout are assumed to be never completed. At most
+// `max_incomplete_sets` sets are kept at once - once the limit is reached, starting a new set
+// drops the one that has been waiting for its next fragment the longest.
+pub struct MessageReassembler {
+    incomplete_sets: HashMap<FragmentSetId, FragmentSet>,
+    timeout: Duration,
+    max_incomplete_sets: usize,
+}
+
+impl MessageReassembler {
+    pub fn new(timeout: Duration, max_incomplete_sets: usize) -> Self {
+        MessageReassembler {
+            incomplete_sets: HashMap::new(),
+            timeout,
+            max_incomplete_sets,
+        }
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .incomplete_sets
+            .iter()
+            .min_by_key(|(_, set)| set.last_received)
+            .map(|(set_id, _)| *set_id);
+        if let Some(set_id) = oldest {
+            warn!(
+                "Too many partially received messages - dropping fragments of {}",
+                set_id
+            );
+            self.incomplete_sets.remove(&set_id);
+        }
+    }
+
+    // returns the full message if the fragment was the last missing one
+    pub fn insert_fragment(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        if fragment.total_fragments == 1 {
+            return Some(fragment.data);
+        }
+
+        if !self.incomplete_sets.contains_key(&fragment.set_id) {
+            while self.incomplete_sets.len() >= self.max_incomplete_sets.max(1) {
+                self.remove_oldest();
+            }
+        }
+        let set = self
+            .incomplete_sets
+            .entry(fragment.set_id)
+            .or_insert_with(|| FragmentSet::new(fragment.total_fragments));
+        // it's either a collision of set ids or someone is messing with us
+        if set.fragments.len() != fragment.total_fragments as usize {
+            return None;
+        }
+
+        let slot = &mut set.fragments[fragment.index as usize];
+        if slot.is_none() {
+            *slot = Some(fragment.data);
+            set.received += 1;
+            set.last_received = Instant::now();
+        }
+
+        if set.is_complete() {
+            self.incomplete_sets
+                .remove(&fragment.set_id)
+                .map(FragmentSet::into_message)
+        } else {
+            None
+        }
+    }
+
+    // removes the sets that timed out and returns how many of them there were
+    pub fn remove_expired(&mut self) -> usize {
+        let timeout = self.timeout;
+        let before = self.incomplete_sets.len();
+        self.incomplete_sets
+            .retain(|_, set| set.last_received.elapsed() < timeout);
+        before - self.incomplete_sets.len()
+    }
+
+    pub fn incomplete_count(&self) -> usize {
+        self.incomplete_sets.len()
+    }
+}
+
+#[cfg(test)]
+mod fragmenting_messages {
+    use super::*;
+
+    fn reassemble(fragments: Vec<Fragment>) -> Option<Vec<u8>> {
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(60), 10);
+        let mut message = None;
+        for fragment in fragments {
+            let recovered = Fragment::from_bytes(&fragment.to_bytes()).unwrap();
+            assert!(recovered.to_bytes().len() <= MAXIMUM_PLAINTEXT_LENGTH);
+            message = reassembler.insert_fragment(recovered);
+        }
+        message
+    }
+
+    #[test]
+    fn short_message_fits_into_single_fragment() {
+        let fragments = split_into_fragments(b"foomp").unwrap();
+        assert_eq!(1, fragments.len());
+        assert_eq!(Some(b"foomp".to_vec()), reassemble(fragments));
+
+        let fragments = split_into_fragments(&[]).unwrap();
+        assert_eq!(1, fragments.len());
+        assert_eq!(Some(Vec::new()), reassemble(fragments));
+    }
+
+    #[test]
+    fn long_message_is_recovered_regardless_of_fragment_order() {
+        let message: Vec<_> = (0..MAXIMUM_FRAGMENT_DATA_LENGTH * 3 + 42)
+            .map(|i| i as u8)
+            .collect();
+        let mut fragments = split_into_fragments(&message).unwrap();
+        assert_eq!(4, fragments.len());
+
+        fragments.reverse();
+        assert_eq!(Some(message), reassemble(fragments));
+    }
+
+    #[test]
+    fn message_is_not_recovered_until_all_fragments_are_received() {
+        let message = vec![42u8; MAXIMUM_FRAGMENT_DATA_LENGTH * 2];
+        let mut fragments = split_into_fragments(&message).unwrap();
+        let last = fragments.pop().unwrap();
+
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(60), 10);
+        assert_eq!(None, reassembler.insert_fragment(fragments[0].clone()));
+        // duplicates are ignored
+        assert_eq!(None, reassembler.insert_fragment(fragments[0].clone()));
+        assert_eq!(1, reassembler.incomplete_count());
+        assert_eq!(Some(message), reassembler.insert_fragment(last));
+        assert_eq!(0, reassembler.incomplete_count());
+    }
+
+    #[test]
+    fn incomplete_sets_expire() {
+        let message = vec![42u8; MAXIMUM_FRAGMENT_DATA_LENGTH * 2];
+        let fragments = split_into_fragments(&message).unwrap();
+
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(0), 10);
+        assert_eq!(None, reassembler.insert_fragment(fragments[0].clone()));
+        assert_eq!(1, reassembler.remove_expired());
+        assert_eq!(0, reassembler.incomplete_count());
+    }
+
+    #[test]
+    fn oldest_incomplete_set_is_dropped_when_limit_is_reached() {
+        let message = vec![42u8; MAXIMUM_FRAGMENT_DATA_LENGTH * 2];
+        let first = split_into_fragments(&message).unwrap();
+        let second = split_into_fragments(&message).unwrap();
+        let third = split_into_fragments(&message).unwrap();
+
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(60), 2);
+        assert_eq!(None, reassembler.insert_fragment(first[0].clone()));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(None, reassembler.insert_fragment(second[0].clone()));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(None, reassembler.insert_fragment(third[0].clone()));
+        assert_eq!(2, reassembler.incomplete_count());
+
+        // the first set is gone, so its last fragment starts a new one
+        assert_eq!(None, reassembler.insert_fragment(first[1].clone()));
+        assert_eq!(2, reassembler.incomplete_count());
+        assert_eq!(Some(message), reassembler.insert_fragment(third[1].clone()));
+    }
+
+    #[test]
+    fn too_long_message_is_rejected() {
+        let message = vec![42u8; MAXIMUM_MESSAGE_LENGTH + 1];
+        assert_eq!(
+            Err(FragmentationError::TooLongMessageError(message.len())),
+            split_into_fragments(&message)
+        );
+        assert!(split_into_fragments(&message[1..]).is_ok());
+    }
+
+    #[test]
+    fn malformed_fragments_are_rejected() {
+        assert!(Fragment::from_bytes(&[0u8; FRAGMENT_HEADER_LENGTH - 1]).is_err());
+
+        let mut fragment = split_into_fragments(b"foomp").unwrap().pop().unwrap();
+        fragment.index = 1;
+        assert!(Fragment::from_bytes(&fragment.to_bytes()).is_err());
+
+        let mut fragment = split_into_fragments(b"foomp").unwrap().pop().unwrap();
+        fragment.data = vec![42u8; MAXIMUM_FRAGMENT_DATA_LENGTH + 1];
+        assert!(Fragment::from_bytes(&fragment.to_bytes()).is_err());
+    }
+}
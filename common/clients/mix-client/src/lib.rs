@@ -1,30 +1,161 @@
+use crate::connection::{ConnectionConfig, ConnectionStatus, ManagedConnection};
+use futures::channel::mpsc;
+use futures::lock::Mutex as FMutex;
 use log::*;
 use sphinx::SphinxPacket;
+use sphinx_framing::packet::FramedSphinxPacket;
+use std::collections::HashMap;
+use std::fmt::{Error, Formatter};
+use std::io;
 use std::net::SocketAddr;
-use tokio::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
+mod connection;
+pub mod fragmentation;
 pub mod packet;
 pub mod poisson;
 
-pub struct MixClient {}
+const DEFAULT_INITIAL_RECONNECTION_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAXIMUM_RECONNECTION_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_MAXIMUM_RECONNECTION_ATTEMPTS: u32 = 10;
+const DEFAULT_MAXIMUM_IN_FLIGHT_PACKETS: usize = 128;
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAXIMUM_IDLE_TIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub enum MixClientError {
+    TooManyInFlightPacketsError,
+    // the connection is down (or could not be established) and is being re-established
+    ConnectionError(io::ErrorKind),
+    ConnectionClosedError,
+}
+
+// required by std::error::Error
+impl std::fmt::Display for MixClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        // just have implementation equivalent to derived debug
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for MixClientError {}
+
+// channel to the handler of a single connection together with the current state of it
+#[derive(Clone)]
+struct ConnectionHandle {
+    packets_tx: mpsc::Sender<FramedSphinxPacket>,
+    status: watch::Receiver<ConnectionStatus>,
+}
+
+impl ConnectionHandle {
+    // whether the handler has stopped, i.e. it gave up on reconnecting or was idle for too long
+    fn is_closed(&self) -> bool {
+        self.packets_tx.is_closed()
+    }
+
+    // the first connection attempt is always waited for, so that unreachable nodes could be
+    // reported rather than silently swallowing everything that is sent to them
+    async fn current_status(&mut self) -> ConnectionStatus {
+        let mut status = *self.status.borrow();
+        while status == ConnectionStatus::Connecting {
+            status = self.status.recv().await.unwrap_or(ConnectionStatus::Closed);
+        }
+        status
+    }
+
+    async fn send(mut self, packet: FramedSphinxPacket) -> Result<(), MixClientError> {
+        match self.current_status().await {
+            ConnectionStatus::Reconnecting(kind) => Err(MixClientError::ConnectionError(kind)),
+            ConnectionStatus::Closed => Err(MixClientError::ConnectionClosedError),
+            _ => self.packets_tx.try_send(packet).map_err(|err| {
+                if err.is_full() {
+                    MixClientError::TooManyInFlightPacketsError
+                } else {
+                    MixClientError::ConnectionClosedError
+                }
+            }),
+        }
+    }
+}
+
+// MixClient is cheap to clone - all clones share the same pool of connections
+#[derive(Clone)]
+pub struct MixClient {
+    connections: Arc<FMutex<HashMap<SocketAddr, ConnectionHandle>>>,
+    connection_config: ConnectionConfig,
+    maximum_in_flight_packets: usize,
+}
 
 impl MixClient {
     pub fn new() -> MixClient {
-        MixClient {}
+        MixClient::new_with_config(
+            DEFAULT_INITIAL_RECONNECTION_BACKOFF,
+            DEFAULT_MAXIMUM_RECONNECTION_BACKOFF,
+            DEFAULT_MAXIMUM_RECONNECTION_ATTEMPTS,
+            DEFAULT_MAXIMUM_IN_FLIGHT_PACKETS,
+            DEFAULT_CONNECTION_TIMEOUT,
+            DEFAULT_MAXIMUM_IDLE_TIME,
+        )
+    }
+
+    pub fn new_with_config(
+        initial_reconnection_backoff: Duration,
+        maximum_reconnection_backoff: Duration,
+        maximum_reconnection_attempts: u32,
+        maximum_in_flight_packets: usize,
+        connection_timeout: Duration,
+        maximum_idle_time: Duration,
+    ) -> MixClient {
+        MixClient {
+            connections: Arc::new(FMutex::new(HashMap::new())),
+            connection_config: ConnectionConfig {
+                initial_reconnection_backoff,
+                maximum_reconnection_backoff,
+                maximum_reconnection_attempts,
+                connection_timeout,
+                maximum_idle_time,
+            },
+            maximum_in_flight_packets,
+        }
+    }
+
+    fn start_connection(&self, address: SocketAddr) -> ConnectionHandle {
+        debug!("Starting new connection handler for {:?}", address);
+        let (packets_tx, packets_rx) = mpsc::channel(self.maximum_in_flight_packets);
+        let (status_tx, status) = watch::channel(ConnectionStatus::Connecting);
+        tokio::spawn(
+            ManagedConnection::new(address, self.connection_config, packets_rx, status_tx).run(),
+        );
+        ConnectionHandle { packets_tx, status }
     }
 
     // Sends a Sphinx packet to a mixnode.
+    // An error is returned if the connection to the node could not be established or is
+    // currently broken. Note that successful return only means the packet was queued to be
+    // written to the connection - if the write itself fails, the following sends are going to
+    // report it.
     pub async fn send(
         &self,
         packet: SphinxPacket,
         mix_addr: SocketAddr,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let bytes = packet.to_bytes();
+    ) -> Result<(), MixClientError> {
+        let framed_packet = FramedSphinxPacket::new(packet);
         debug!("Sending to the following address: {:?}", mix_addr);
 
-        let mut stream = tokio::net::TcpStream::connect(mix_addr).await?;
-        stream.write_all(&bytes[..]).await?;
-        Ok(())
+        // the lock is not held while waiting for the connection so that sending to other
+        // nodes would not be blocked by it
+        let connection = {
+            let mut connections = self.connections.lock().await;
+            // handlers that have stopped are replaced with fresh ones when they're needed again
+            connections.retain(|_, connection| !connection.is_closed());
+            connections
+                .entry(mix_addr)
+                .or_insert_with(|| self.start_connection(mix_addr))
+                .clone()
+        };
+        connection.send(framed_packet).await
     }
 }
 
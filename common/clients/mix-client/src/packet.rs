@@ -1,36 +1,289 @@
 use addressing;
-use sphinx::route::{Destination, DestinationAddressBytes, SURBIdentifier};
+use sfw_provider_requests::REPLY_DESTINATION_ADDRESS;
+use sphinx::route::{Destination, DestinationAddressBytes, Node as SphinxNode, SURBIdentifier};
 use sphinx::SphinxPacket;
+use std::convert::TryInto;
 use std::net::SocketAddr;
-use topology::NymTopology;
+use topology::{MixProviderNode, NymTopology};
 
 pub const LOOP_COVER_MESSAGE_PAYLOAD: &[u8] = b"The cake is a lie!";
 pub const LOOP_COVER_MESSAGE_AVERAGE_DELAY: f64 = 2.0;
 
+// provider key || reply token id
+pub const REPLY_TOKEN_LENGTH: usize = 32 + 16;
+
+const PLAIN_MESSAGE_PREFIX: u8 = 0;
+const MESSAGE_WITH_REPLY_TOKEN_PREFIX: u8 = 1;
+const REPLY_MESSAGE_PREFIX: u8 = 2;
+const RELIABLE_MESSAGE_PREFIX: u8 = 3;
+const ACK_MESSAGE_PREFIX: u8 = 4;
+
+const RELIABLE_MESSAGE_HEADER_LENGTH: usize = 1 + 8 + REPLY_TOKEN_LENGTH;
+
+// the most bytes `MessageEnvelope` can add on top of the actual message, i.e. for a reliable
+// message with attached reply token
+pub const MAXIMUM_ENVELOPE_OVERHEAD: usize =
+    RELIABLE_MESSAGE_HEADER_LENGTH + 1 + REPLY_TOKEN_LENGTH;
+pub const REPLY_ENVELOPE_OVERHEAD: usize = 1 + 16;
+
+// chosen by the sender of a reliable message; it stays the same for all retransmissions
+// so that the recipient could recognise duplicates
+pub type ReliableMessageId = u64;
+
+#[derive(Debug)]
+pub enum ReplyTokenError {
+    MalformedReplyTokenError,
+    // the provider that issued the reply token is not part of the current network topology
+    UnknownProviderError,
+}
+
+#[derive(Debug)]
+pub enum MessageDecodingError {
+    EmptyMessageError,
+    UnknownMessageTypeError(u8),
+    MalformedReplyError,
+    MalformedReplyTokenError,
+    MalformedReliableMessageError,
+    MalformedAckError,
+}
+
+impl From<ReplyTokenError> for MessageDecodingError {
+    fn from(_: ReplyTokenError) -> Self {
+        use MessageDecodingError::*;
+
+        MalformedReplyTokenError
+    }
+}
+
+// Provider-routed reply token that can be attached to a message so that its recipient could
+// reply (once) without learning the address of the sender. Note that it is NOT a sphinx SURB:
+// the reply is an ordinary forward packet sent to the provider of the sender, which then
+// delivers it based on the reply token id the sender has registered there. So while the
+// address of the sender stays hidden, the token reveals which provider the sender is using
+// to anyone holding it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplyToken {
+    pub provider_key: [u8; 32],
+    pub id: SURBIdentifier,
+}
+
+impl ReplyToken {
+    pub fn new(provider_key: [u8; 32], id: SURBIdentifier) -> Self {
+        ReplyToken { provider_key, id }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.provider_key
+            .iter()
+            .chain(self.id.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ReplyTokenError> {
+        if bytes.len() != REPLY_TOKEN_LENGTH {
+            return Err(ReplyTokenError::MalformedReplyTokenError);
+        }
+
+        let mut provider_key = [0u8; 32];
+        provider_key.copy_from_slice(&bytes[..32]);
+
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&bytes[32..]);
+
+        Ok(ReplyToken { provider_key, id })
+    }
+
+    pub fn to_b64_string(&self) -> String {
+        base64::encode_config(&self.to_bytes(), base64::URL_SAFE)
+    }
+
+    pub fn from_b64_string(encoded: &str) -> Result<Self, ReplyTokenError> {
+        let bytes = base64::decode_config(encoded, base64::URL_SAFE)
+            .map_err(|_| ReplyTokenError::MalformedReplyTokenError)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+// Format of the data carried by sphinx packets of real messages.
+// Replies carry the id of the reply token they were sent with, so that the original sender
+// could tell which of its messages they are replying to.
+// Reliable messages wrap any other non-reliable message and carry a reply token over which
+// the recipient sends back `Ack` with the id of the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageEnvelope {
+    Plain(Vec<u8>),
+    WithReplyToken(ReplyToken, Vec<u8>),
+    Reply(SURBIdentifier, Vec<u8>),
+    Reliable(ReliableMessageId, ReplyToken, Box<MessageEnvelope>),
+    Ack(ReliableMessageId),
+}
+
+impl MessageEnvelope {
+    // prefix || [reply token | reply token id | message id || ack token] || message
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MessageEnvelope::Plain(message) => std::iter::once(PLAIN_MESSAGE_PREFIX)
+                .chain(message.iter().cloned())
+                .collect(),
+            MessageEnvelope::WithReplyToken(reply_token, message) => {
+                std::iter::once(MESSAGE_WITH_REPLY_TOKEN_PREFIX)
+                    .chain(reply_token.to_bytes().into_iter())
+                    .chain(message.iter().cloned())
+                    .collect()
+            }
+            MessageEnvelope::Reply(token_id, message) => std::iter::once(REPLY_MESSAGE_PREFIX)
+                .chain(token_id.iter().cloned())
+                .chain(message.iter().cloned())
+                .collect(),
+            MessageEnvelope::Reliable(message_id, ack_token, inner) => {
+                std::iter::once(RELIABLE_MESSAGE_PREFIX)
+                    .chain(message_id.to_be_bytes().iter().cloned())
+                    .chain(ack_token.to_bytes().into_iter())
+                    .chain(inner.to_bytes().into_iter())
+                    .collect()
+            }
+            MessageEnvelope::Ack(message_id) => std::iter::once(ACK_MESSAGE_PREFIX)
+                .chain(message_id.to_be_bytes().iter().cloned())
+                .collect(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageDecodingError> {
+        if bytes.is_empty() {
+            return Err(MessageDecodingError::EmptyMessageError);
+        }
+
+        match bytes[0] {
+            PLAIN_MESSAGE_PREFIX => Ok(MessageEnvelope::Plain(bytes[1..].to_vec())),
+            MESSAGE_WITH_REPLY_TOKEN_PREFIX => {
+                if bytes.len() < 1 + REPLY_TOKEN_LENGTH {
+                    return Err(MessageDecodingError::MalformedReplyTokenError);
+                }
+                let reply_token = ReplyToken::from_bytes(&bytes[1..1 + REPLY_TOKEN_LENGTH])?;
+                Ok(MessageEnvelope::WithReplyToken(
+                    reply_token,
+                    bytes[1 + REPLY_TOKEN_LENGTH..].to_vec(),
+                ))
+            }
+            REPLY_MESSAGE_PREFIX => {
+                if bytes.len() < 1 + 16 {
+                    return Err(MessageDecodingError::MalformedReplyError);
+                }
+                let mut token_id = [0u8; 16];
+                token_id.copy_from_slice(&bytes[1..17]);
+                Ok(MessageEnvelope::Reply(token_id, bytes[17..].to_vec()))
+            }
+            RELIABLE_MESSAGE_PREFIX => {
+                if bytes.len() < RELIABLE_MESSAGE_HEADER_LENGTH {
+                    return Err(MessageDecodingError::MalformedReliableMessageError);
+                }
+                // this can't fail as we've just checked the length
+                let message_id = ReliableMessageId::from_be_bytes(bytes[1..9].try_into().unwrap());
+                let ack_token = ReplyToken::from_bytes(&bytes[9..RELIABLE_MESSAGE_HEADER_LENGTH])?;
+                let inner = MessageEnvelope::from_bytes(&bytes[RELIABLE_MESSAGE_HEADER_LENGTH..])?;
+                match inner {
+                    // reliability only makes sense for messages sent directly to the recipient
+                    MessageEnvelope::Plain(_) | MessageEnvelope::WithReplyToken(..) => Ok(
+                        MessageEnvelope::Reliable(message_id, ack_token, Box::new(inner)),
+                    ),
+                    _ => Err(MessageDecodingError::MalformedReliableMessageError),
+                }
+            }
+            ACK_MESSAGE_PREFIX => {
+                if bytes.len() != 1 + 8 {
+                    return Err(MessageDecodingError::MalformedAckError);
+                }
+                // this can't fail as we've just checked the length
+                let message_id = ReliableMessageId::from_be_bytes(bytes[1..].try_into().unwrap());
+                Ok(MessageEnvelope::Ack(message_id))
+            }
+            prefix => Err(MessageDecodingError::UnknownMessageTypeError(prefix)),
+        }
+    }
+}
+
 pub fn loop_cover_message<T: NymTopology>(
     our_address: DestinationAddressBytes,
     surb_id: SURBIdentifier,
+    our_provider: MixProviderNode,
     topology: &T,
 ) -> (SocketAddr, SphinxPacket) {
     let destination = Destination::new(our_address, surb_id);
 
     encapsulate_message(
         destination,
+        our_provider,
         LOOP_COVER_MESSAGE_PAYLOAD.to_vec(),
         topology,
         LOOP_COVER_MESSAGE_AVERAGE_DELAY,
     )
 }
 
+// the provider the client has registered with, as announced in the presence of the provider
+pub fn recipient_provider<T: NymTopology>(
+    recipient: &DestinationAddressBytes,
+    topology: &T,
+) -> Option<MixProviderNode> {
+    let encoded_recipient = base64::encode_config(recipient, base64::URL_SAFE);
+    topology
+        .get_mix_provider_nodes()
+        .into_iter()
+        .find(|provider| {
+            provider
+                .registered_clients
+                .iter()
+                .any(|client| client.pub_key == encoded_recipient)
+        })
+}
+
+// the message is only going to be delivered if the recipient is registered with the provider
 pub fn encapsulate_message<T: NymTopology>(
     recipient: Destination,
+    recipient_provider: MixProviderNode,
     message: Vec<u8>,
     topology: &T,
     average_delay: f64,
 ) -> (SocketAddr, SphinxPacket) {
-    let mut providers = topology.get_mix_provider_nodes();
-    let provider = providers.pop().unwrap().into();
+    encapsulate_message_to_provider(
+        recipient_provider.into(),
+        recipient,
+        message,
+        topology,
+        average_delay,
+    )
+}
+
+// unlike normal messages, replies have to go through the provider that issued the reply token
+pub fn encapsulate_reply<T: NymTopology>(
+    reply_token: &ReplyToken,
+    message: Vec<u8>,
+    topology: &T,
+    average_delay: f64,
+) -> Result<(SocketAddr, SphinxPacket), ReplyTokenError> {
+    let provider = topology
+        .get_mix_provider_nodes()
+        .into_iter()
+        .find(|provider| provider.get_pub_key_bytes() == reply_token.provider_key)
+        .ok_or(ReplyTokenError::UnknownProviderError)?;
+    let recipient = Destination::new(REPLY_DESTINATION_ADDRESS, reply_token.id);
 
+    Ok(encapsulate_message_to_provider(
+        provider.into(),
+        recipient,
+        message,
+        topology,
+        average_delay,
+    ))
+}
+
+fn encapsulate_message_to_provider<T: NymTopology>(
+    provider: SphinxNode,
+    recipient: Destination,
+    message: Vec<u8>,
+    topology: &T,
+    average_delay: f64,
+) -> (SocketAddr, SphinxPacket) {
     let route = topology.route_to(provider).unwrap();
 
     let delays = sphinx::header::delays::generate(route.len(), average_delay);
@@ -43,3 +296,143 @@ pub fn encapsulate_message<T: NymTopology>(
 
     (first_node_address, packet)
 }
+
+#[cfg(test)]
+mod message_envelope {
+    use super::*;
+
+    #[test]
+    fn all_kinds_are_recovered_from_bytes() {
+        let reply_token = ReplyToken::new([1u8; 32], [2u8; 16]);
+        let envelopes = vec![
+            MessageEnvelope::Plain(b"foomp".to_vec()),
+            MessageEnvelope::Plain(Vec::new()),
+            MessageEnvelope::WithReplyToken(reply_token, b"foomp".to_vec()),
+            MessageEnvelope::Reply([2u8; 16], b"foomp".to_vec()),
+            MessageEnvelope::Reliable(
+                42,
+                reply_token,
+                Box::new(MessageEnvelope::WithReplyToken(
+                    reply_token,
+                    b"foomp".to_vec(),
+                )),
+            ),
+            MessageEnvelope::Ack(42),
+        ];
+
+        for envelope in envelopes {
+            let bytes = envelope.to_bytes();
+            assert!(bytes.len() <= 5 + MAXIMUM_ENVELOPE_OVERHEAD);
+            assert_eq!(envelope, MessageEnvelope::from_bytes(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn truncated_reply_token_is_rejected() {
+        let reply_token = ReplyToken::new([1u8; 32], [2u8; 16]);
+        let bytes = MessageEnvelope::WithReplyToken(reply_token, Vec::new()).to_bytes();
+
+        assert!(MessageEnvelope::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MessageEnvelope::from_bytes(&[]).is_err());
+        assert!(MessageEnvelope::from_bytes(&[42]).is_err());
+    }
+
+    #[test]
+    fn reliable_messages_cannot_be_nested() {
+        let reply_token = ReplyToken::new([1u8; 32], [2u8; 16]);
+        let envelope = MessageEnvelope::Reliable(
+            42,
+            reply_token,
+            Box::new(MessageEnvelope::Reliable(
+                43,
+                reply_token,
+                Box::new(MessageEnvelope::Plain(b"foomp".to_vec())),
+            )),
+        );
+
+        assert!(MessageEnvelope::from_bytes(&envelope.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn reply_token_is_recovered_from_b64_string() {
+        let reply_token = ReplyToken::new([1u8; 32], [2u8; 16]);
+        let encoded = reply_token.to_b64_string();
+
+        assert_eq!(reply_token, ReplyToken::from_b64_string(&encoded).unwrap());
+        assert!(ReplyToken::from_b64_string(&encoded[..10]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod recipient_provider {
+    use super::*;
+    use topology::{CocoNode, MixNode, MixProviderClient};
+
+    struct TestTopology {
+        providers: Vec<MixProviderNode>,
+    }
+
+    impl NymTopology for TestTopology {
+        fn new(_: String) -> Self {
+            unimplemented!()
+        }
+
+        fn new_from_nodes(
+            _: Vec<MixNode>,
+            providers: Vec<MixProviderNode>,
+            _: Vec<CocoNode>,
+        ) -> Self {
+            TestTopology { providers }
+        }
+
+        fn get_mix_nodes(&self) -> Vec<MixNode> {
+            Vec::new()
+        }
+
+        fn get_mix_provider_nodes(&self) -> Vec<MixProviderNode> {
+            self.providers.clone()
+        }
+
+        fn get_coco_nodes(&self) -> Vec<CocoNode> {
+            Vec::new()
+        }
+    }
+
+    fn provider(pub_key: &str, registered_clients: &[DestinationAddressBytes]) -> MixProviderNode {
+        MixProviderNode {
+            client_listener: "127.0.0.1:9000".parse().unwrap(),
+            mixnet_listener: "127.0.0.1:1789".parse().unwrap(),
+            pub_key: pub_key.to_string(),
+            registered_clients: registered_clients
+                .iter()
+                .map(|client| MixProviderClient {
+                    pub_key: base64::encode_config(client, base64::URL_SAFE),
+                })
+                .collect(),
+            last_seen: 0,
+            version: "0.3.3".to_string(),
+        }
+    }
+
+    #[test]
+    fn is_the_one_the_recipient_is_registered_with() {
+        let topology = TestTopology::new_from_nodes(
+            Vec::new(),
+            vec![
+                provider("first", &[[1u8; 32]]),
+                provider("second", &[[2u8; 32], [3u8; 32]]),
+            ],
+            Vec::new(),
+        );
+
+        assert_eq!(
+            "second",
+            recipient_provider(&[3u8; 32], &topology).unwrap().pub_key
+        );
+        assert_eq!(
+            "first",
+            recipient_provider(&[1u8; 32], &topology).unwrap().pub_key
+        );
+        assert!(recipient_provider(&[4u8; 32], &topology).is_none());
+    }
+}
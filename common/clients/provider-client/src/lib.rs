@@ -1,14 +1,25 @@
+use crypto::encryption::MixnetEncryptionPublicKey;
+use crypto::identity::{ed25519, MixnetIdentityPrivateKey, MixnetIdentityPublicKey};
 use futures::io::Error;
+use futures::lock::Mutex as FMutex;
+use futures::{SinkExt, StreamExt};
 use log::*;
-use sfw_provider_requests::requests::{ProviderRequest, PullRequest, RegisterRequest};
+use sfw_provider_requests::codec::{ProviderCodec, ProviderCodecError};
+use sfw_provider_requests::requests::{
+    AckRequest, ChallengeRequest, ProviderRequest, PullRequest, RegisterRequest, ReplyTokenRequest,
+    SubscribeRequest, MAXIMUM_ACKNOWLEDGED_IDS,
+};
 use sfw_provider_requests::responses::{
-    ProviderResponse, ProviderResponseError, PullResponse, RegisterResponse,
+    AckResponse, ChallengeResponse, ProviderResponse, ProviderResponseError, PullResponse,
+    RegisterResponse, ReplyTokenResponse, SubscribeResponse,
 };
-use sfw_provider_requests::AuthToken;
-use sphinx::route::DestinationAddressBytes;
-use std::net::{Shutdown, SocketAddr};
+use sfw_provider_requests::{AuthToken, MessageId};
+use sphinx::route::{DestinationAddressBytes, SURBIdentifier};
+use std::net::SocketAddr;
 use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio_util::codec::Framed;
 
 #[derive(Debug)]
 pub enum ProviderClientError {
@@ -19,6 +30,8 @@ pub enum ProviderClientError {
     InvalidRequestError,
     InvalidResponseError,
     InvalidResponseLengthError,
+
+    SubscriptionTimeoutError,
 }
 
 impl From<io::Error> for ProviderClientError {
@@ -40,22 +53,70 @@ impl From<ProviderResponseError> for ProviderClientError {
     }
 }
 
+impl From<ProviderCodecError> for ProviderClientError {
+    fn from(err: ProviderCodecError) -> Self {
+        use ProviderClientError::*;
+        match err {
+            ProviderCodecError::FrameTooLargeError(_) => InvalidResponseLengthError,
+            ProviderCodecError::IOError(_) => NetworkError,
+        }
+    }
+}
+
+// if we haven't received anything for that many push intervals, we assume the subscription is dead
+const MISSED_PUSHES_THRESHOLD: u32 = 3;
+
+// Dedicated connection over which the provider pushes pages of our messages.
+// Received messages still need to be acknowledged with `ProviderClient::ack_messages`.
+pub struct ProviderSubscription {
+    connection: Framed<TcpStream, ProviderCodec>,
+    push_interval: Duration,
+}
+
+impl ProviderSubscription {
+    pub fn push_interval(&self) -> Duration {
+        self.push_interval
+    }
+
+    // waits for the next page of messages; the provider sends one every push interval
+    // even if it's empty
+    pub async fn next_page(&mut self) -> Result<PullResponse, ProviderClientError> {
+        let timeout = self.push_interval * MISSED_PUSHES_THRESHOLD;
+        let page = match tokio::time::timeout(timeout, self.connection.next()).await {
+            Err(_) => return Err(ProviderClientError::SubscriptionTimeoutError),
+            Ok(None) => return Err(ProviderClientError::NetworkError),
+            Ok(Some(page)) => page?,
+        };
+
+        Ok(PullResponse::from_bytes(&page)?)
+    }
+}
+
 pub struct ProviderClient {
     provider_network_address: SocketAddr,
     our_address: DestinationAddressBytes,
+    // used to prove to the provider that we own `our_address`, which is derived from it
+    identity_key: ed25519::PrivateKey,
     auth_token: Option<AuthToken>,
+    // established lazily on the first request and reused by all subsequent ones
+    connection: FMutex<Option<Framed<TcpStream, ProviderCodec>>>,
 }
 
 impl ProviderClient {
     pub fn new(
         provider_network_address: SocketAddr,
-        our_address: DestinationAddressBytes,
+        identity_key: ed25519::PrivateKey,
         auth_token: Option<AuthToken>,
     ) -> Self {
+        let mut our_address = [0u8; 32];
+        our_address.copy_from_slice(&identity_key.public_key().to_x25519().to_bytes());
+
         ProviderClient {
             provider_network_address,
             our_address,
+            identity_key,
             auth_token,
+            connection: FMutex::new(None),
         }
     }
 
@@ -63,36 +124,151 @@ impl ProviderClient {
         self.auth_token = Some(auth_token)
     }
 
-    pub async fn send_request(&self, bytes: Vec<u8>) -> Result<Vec<u8>, ProviderClientError> {
-        let mut socket = tokio::net::TcpStream::connect(self.provider_network_address).await?;
-
+    // the provider only serves our inbox on connections on which we have proven we own our
+    // address, so every new connection starts with the challenge-response registration.
+    // returns the connection alongside the auth token the provider has issued for us
+    async fn connect(
+        &self,
+    ) -> Result<(Framed<TcpStream, ProviderCodec>, AuthToken), ProviderClientError> {
+        let socket = TcpStream::connect(self.provider_network_address).await?;
         socket.set_keepalive(Some(Duration::from_secs(2))).unwrap();
-        socket.write_all(&bytes[..]).await?;
-        if let Err(e) = socket.shutdown(Shutdown::Write) {
-            warn!("failed to close write part of the socket; err = {:?}", e)
+        let mut connection = Framed::new(socket, ProviderCodec);
+
+        let response =
+            ProviderClient::exchange(&mut connection, ChallengeRequest::new().to_bytes()).await?;
+        let challenge = ChallengeResponse::from_bytes(&response)?;
+
+        let challenge_message =
+            RegisterRequest::challenge_message(&challenge.nonce, &self.our_address);
+        let signature = self.identity_key.sign(&challenge_message);
+        let mut identity_key = [0u8; 32];
+        identity_key.copy_from_slice(&self.identity_key.public_key().to_bytes());
+        let register_request = RegisterRequest::new(self.our_address, identity_key, signature);
+        let response =
+            ProviderClient::exchange(&mut connection, register_request.to_bytes()).await?;
+        let parsed_response = RegisterResponse::from_bytes(&response)?;
+
+        Ok((connection, parsed_response.auth_token))
+    }
+
+    async fn exchange(
+        connection: &mut Framed<TcpStream, ProviderCodec>,
+        bytes: Vec<u8>,
+    ) -> Result<Vec<u8>, ProviderClientError> {
+        connection.send(bytes).await?;
+        match connection.next().await {
+            Some(response) => Ok(response?),
+            // provider closed the connection without responding
+            None => Err(ProviderClientError::NetworkError),
+        }
+    }
+
+    pub async fn send_request(&self, bytes: Vec<u8>) -> Result<Vec<u8>, ProviderClientError> {
+        // holding the lock for the entire exchange guarantees responses are matched with
+        // the requests they were sent for
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            let (new_connection, _) = self.connect().await?;
+            *connection = Some(new_connection);
         }
 
-        let mut response = Vec::new();
-        socket.read_to_end(&mut response).await?;
-        if let Err(e) = socket.shutdown(Shutdown::Read) {
-            warn!("failed to close read part of the socket; err = {:?}", e)
+        // this can't fail as we've just established the connection
+        let response = ProviderClient::exchange(connection.as_mut().unwrap(), bytes).await;
+        if let Err(ref err) = response {
+            // we can't know in what state the connection was left in so the next request
+            // is going to establish a new one
+            warn!("failed to exchange data with the provider - {:?}", err);
+            *connection = None;
         }
 
-        Ok(response)
+        response
     }
 
-    pub async fn retrieve_messages(&self) -> Result<Vec<Vec<u8>>, ProviderClientError> {
+    // retrieves single page of messages starting from the cursor (use 0 to start from the oldest
+    // one). retrieved messages are kept by the provider until they are acknowledged with
+    // `ack_messages`, otherwise they are going to be retrieved again after their lease expires
+    pub async fn retrieve_messages(
+        &self,
+        cursor: MessageId,
+    ) -> Result<PullResponse, ProviderClientError> {
         if self.auth_token.is_none() {
             return Err(ProviderClientError::EmptyAuthTokenError);
         }
 
-        let pull_request = PullRequest::new(self.our_address, self.auth_token.unwrap());
+        let pull_request = PullRequest::new(self.our_address, self.auth_token.unwrap(), cursor);
         let bytes = pull_request.to_bytes();
 
         let response = self.send_request(bytes).await?;
 
-        let parsed_response = PullResponse::from_bytes(&response)?;
-        Ok(parsed_response.messages)
+        Ok(PullResponse::from_bytes(&response)?)
+    }
+
+    // returns the number of messages that were actually removed by the provider. if there are
+    // more ids than fit into a single request, they are sent in multiple ones
+    pub async fn ack_messages(
+        &self,
+        message_ids: Vec<MessageId>,
+    ) -> Result<usize, ProviderClientError> {
+        if self.auth_token.is_none() {
+            return Err(ProviderClientError::EmptyAuthTokenError);
+        }
+
+        let mut acknowledged = 0;
+        for ids_batch in message_ids.chunks(MAXIMUM_ACKNOWLEDGED_IDS) {
+            // this can't fail as the batch is not longer than the maximum
+            let ack_request = AckRequest::new(
+                self.our_address,
+                self.auth_token.unwrap(),
+                ids_batch.to_vec(),
+            )
+            .unwrap();
+            let bytes = ack_request.to_bytes();
+
+            let response = self.send_request(bytes).await?;
+
+            let parsed_response = AckResponse::from_bytes(&response)?;
+            acknowledged += parsed_response.acknowledged as usize;
+        }
+        Ok(acknowledged)
+    }
+
+    // asks the provider to deliver the first reply sent with this reply token id to us.
+    // returns for how long the provider is going to accept such reply
+    pub async fn register_reply_token(
+        &self,
+        token_id: SURBIdentifier,
+    ) -> Result<Duration, ProviderClientError> {
+        if self.auth_token.is_none() {
+            return Err(ProviderClientError::EmptyAuthTokenError);
+        }
+
+        let reply_token_request =
+            ReplyTokenRequest::new(self.our_address, self.auth_token.unwrap(), token_id);
+        let bytes = reply_token_request.to_bytes();
+
+        let response = self.send_request(bytes).await?;
+
+        let parsed_response = ReplyTokenResponse::from_bytes(&response)?;
+        Ok(Duration::from_secs(parsed_response.validity_secs))
+    }
+
+    // unlike other requests, the subscription uses a new connection as it can no longer be used
+    // for anything else
+    pub async fn subscribe(&self) -> Result<ProviderSubscription, ProviderClientError> {
+        if self.auth_token.is_none() {
+            return Err(ProviderClientError::EmptyAuthTokenError);
+        }
+
+        let subscribe_request = SubscribeRequest::new(self.our_address, self.auth_token.unwrap());
+        let (mut connection, _) = self.connect().await?;
+        let response =
+            ProviderClient::exchange(&mut connection, subscribe_request.to_bytes()).await?;
+        let parsed_response = SubscribeResponse::from_bytes(&response)?;
+
+        Ok(ProviderSubscription {
+            connection,
+            push_interval: Duration::from_millis(parsed_response.push_interval_millis),
+        })
     }
 
     pub async fn register(&self) -> Result<AuthToken, ProviderClientError> {
@@ -100,13 +276,13 @@ impl ProviderClient {
             return Err(ProviderClientError::ClientAlreadyRegisteredError);
         }
 
-        let register_request = RegisterRequest::new(self.our_address);
-        let bytes = register_request.to_bytes();
-
-        let response = self.send_request(bytes).await?;
-        let parsed_response = RegisterResponse::from_bytes(&response)?;
+        // registration happens as part of establishing the connection which we can then
+        // reuse for all subsequent requests
+        let mut connection = self.connection.lock().await;
+        let (new_connection, auth_token) = self.connect().await?;
+        *connection = Some(new_connection);
 
-        Ok(parsed_response.auth_token)
+        Ok(auth_token)
     }
 
     pub fn is_registered(&self) -> bool {
@@ -1,4 +1,4 @@
-use crate::PemStorable;
+use crate::{KeyRecoveryError, PemStorable};
 
 pub mod x25519;
 
@@ -10,7 +10,7 @@ where
     fn new() -> Self;
     fn private_key(&self) -> &Priv;
     fn public_key(&self) -> &Pub;
-    fn from_bytes(priv_bytes: &[u8], pub_bytes: &[u8]) -> Self;
+    fn from_bytes(priv_bytes: &[u8], pub_bytes: &[u8]) -> Result<Self, KeyRecoveryError>;
 
     // TODO: encryption related methods
 }
@@ -22,7 +22,7 @@ pub trait MixnetEncryptionPublicKey:
     type PrivateKeyMaterial: MixnetEncryptionPrivateKey<PublicKeyMaterial = Self>;
 
     fn to_bytes(&self) -> Vec<u8>;
-    fn from_bytes(b: &[u8]) -> Self;
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError>;
 }
 
 pub trait MixnetEncryptionPrivateKey: Sized + PemStorable {
@@ -35,5 +35,5 @@ pub trait MixnetEncryptionPrivateKey: Sized + PemStorable {
     }
 
     fn to_bytes(&self) -> Vec<u8>;
-    fn from_bytes(b: &[u8]) -> Self;
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError>;
 }
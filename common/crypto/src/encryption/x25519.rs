@@ -1,7 +1,7 @@
 use crate::encryption::{
     MixnetEncryptionKeyPair, MixnetEncryptionPrivateKey, MixnetEncryptionPublicKey,
 };
-use crate::PemStorable;
+use crate::{key_bytes_32, KeyRecoveryError, PemStorable};
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
 
@@ -34,11 +34,11 @@ impl MixnetEncryptionKeyPair<PrivateKey, PublicKey> for KeyPair {
         &self.public_key
     }
 
-    fn from_bytes(priv_bytes: &[u8], pub_bytes: &[u8]) -> Self {
-        KeyPair {
-            private_key: PrivateKey::from_bytes(priv_bytes),
-            public_key: PublicKey::from_bytes(pub_bytes),
-        }
+    fn from_bytes(priv_bytes: &[u8], pub_bytes: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Ok(KeyPair {
+            private_key: PrivateKey::from_bytes(priv_bytes)?,
+            public_key: PublicKey::from_bytes(pub_bytes)?,
+        })
     }
 }
 
@@ -53,18 +53,25 @@ impl MixnetEncryptionPrivateKey for PrivateKey {
         self.0.to_bytes().to_vec()
     }
 
-    fn from_bytes(b: &[u8]) -> Self {
-        let mut bytes = [0; 32];
-        bytes.copy_from_slice(&b[..]);
-        let key = Scalar::from_canonical_bytes(bytes).unwrap();
-        Self(key)
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        let bytes = key_bytes_32(b)?;
+        let key = Scalar::from_canonical_bytes(bytes).ok_or(KeyRecoveryError::MalformedKeyError)?;
+        Ok(Self(key))
     }
 }
 
 impl PemStorable for PrivateKey {
-    fn pem_type(&self) -> String {
+    fn pem_type() -> String {
         String::from("X25519 PRIVATE KEY")
     }
+
+    fn to_pem_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_pem_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Self::from_bytes(b)
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -83,16 +90,22 @@ impl MixnetEncryptionPublicKey for PublicKey {
         self.0.to_bytes().to_vec()
     }
 
-    fn from_bytes(b: &[u8]) -> Self {
-        let mut bytes = [0; 32];
-        bytes.copy_from_slice(&b[..]);
-        let key = MontgomeryPoint(bytes);
-        Self(key)
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        let bytes = key_bytes_32(b)?;
+        Ok(Self(MontgomeryPoint(bytes)))
     }
 }
 
 impl PemStorable for PublicKey {
-    fn pem_type(&self) -> String {
+    fn pem_type() -> String {
         String::from("X25519 PUBLIC KEY")
     }
+
+    fn to_pem_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_pem_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Self::from_bytes(b)
+    }
 }
@@ -0,0 +1,257 @@
+use crate::encryption;
+use crate::identity::{MixnetIdentityKeyPair, MixnetIdentityPrivateKey, MixnetIdentityPublicKey};
+use crate::{key_bytes_32, KeyRecoveryError, PemStorable};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{ExpandedSecretKey, SecretKey, Signature};
+
+pub struct KeyPair {
+    pub(crate) private_key: PrivateKey,
+    pub(crate) public_key: PublicKey,
+}
+
+impl MixnetIdentityKeyPair<PrivateKey, PublicKey> for KeyPair {
+    fn new() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let keypair = ed25519_dalek::Keypair::generate(&mut rng);
+
+        KeyPair {
+            private_key: PrivateKey(keypair.secret),
+            public_key: PublicKey(keypair.public),
+        }
+    }
+
+    fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    fn from_bytes(priv_bytes: &[u8], pub_bytes: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Ok(KeyPair {
+            private_key: PrivateKey::from_bytes(priv_bytes)?,
+            public_key: PublicKey::from_bytes(pub_bytes)?,
+        })
+    }
+}
+
+impl KeyPair {
+    /// Derives x25519 keypair that can be used for sphinx packet processing.
+    pub fn to_x25519(&self) -> encryption::x25519::KeyPair {
+        encryption::x25519::KeyPair {
+            private_key: self.private_key.to_x25519(),
+            public_key: self.public_key.to_x25519(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PrivateKey(ed25519_dalek::SecretKey);
+
+impl Clone for PrivateKey {
+    fn clone(&self) -> Self {
+        // this can't fail as the bytes come from a valid key
+        PrivateKey(SecretKey::from_bytes(self.0.as_bytes()).unwrap())
+    }
+}
+
+impl<'a> From<&'a PrivateKey> for PublicKey {
+    fn from(pk: &'a PrivateKey) -> Self {
+        PublicKey(ed25519_dalek::PublicKey::from(&pk.0))
+    }
+}
+
+impl MixnetIdentityPrivateKey for PrivateKey {
+    type PublicKeyMaterial = PublicKey;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        let bytes = key_bytes_32(b)?;
+        SecretKey::from_bytes(&bytes)
+            .map(Self)
+            .map_err(|_| KeyRecoveryError::MalformedKeyError)
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let public_key = ed25519_dalek::PublicKey::from(&self.0);
+        let expanded_key = ExpandedSecretKey::from(&self.0);
+        expanded_key.sign(message, &public_key).to_bytes().to_vec()
+    }
+}
+
+impl PrivateKey {
+    /// Derives x25519 private key corresponding to `PublicKey::to_x25519`, i.e. the clamped
+    /// scalar ed25519 uses for signing.
+    pub fn to_x25519(&self) -> encryption::x25519::PrivateKey {
+        let expanded_key = ExpandedSecretKey::from(&self.0).to_bytes();
+        let mut scalar_bytes = [0u8; 32];
+        scalar_bytes.copy_from_slice(&expanded_key[..32]);
+        encryption::x25519::PrivateKey(Scalar::from_bytes_mod_order(scalar_bytes))
+    }
+}
+
+impl PemStorable for PrivateKey {
+    fn pem_type() -> String {
+        String::from("ED25519 PRIVATE KEY")
+    }
+
+    fn to_pem_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_pem_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Self::from_bytes(b)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PublicKey(ed25519_dalek::PublicKey);
+
+impl MixnetIdentityPublicKey for PublicKey {
+    type PrivateKeyMaterial = PrivateKey;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+
+    fn from_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        let bytes = key_bytes_32(b)?;
+        ed25519_dalek::PublicKey::from_bytes(&bytes)
+            .map(Self)
+            .map_err(|_| KeyRecoveryError::MalformedKeyError)
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match Signature::from_bytes(signature) {
+            Ok(signature) => self.0.verify(message, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl PublicKey {
+    /// Converts the key into its birationally equivalent montgomery form used by x25519.
+    pub fn to_x25519(&self) -> encryption::x25519::PublicKey {
+        let edwards_point = CompressedEdwardsY::from_slice(self.0.as_bytes())
+            .decompress()
+            // this can't fail as the key was already decompressed when it was created
+            .expect("ed25519 public key is not a valid curve point");
+        encryption::x25519::PublicKey(edwards_point.to_montgomery())
+    }
+
+    pub fn to_b64_string(&self) -> String {
+        base64::encode_config(&self.to_bytes(), base64::URL_SAFE)
+    }
+}
+
+impl PemStorable for PublicKey {
+    fn pem_type() -> String {
+        String::from("ED25519 PUBLIC KEY")
+    }
+
+    fn to_pem_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn from_pem_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError> {
+        Self::from_bytes(b)
+    }
+}
+
+#[cfg(test)]
+mod ed25519_identity {
+    use super::*;
+    use crate::encryption::{MixnetEncryptionPrivateKey, MixnetEncryptionPublicKey};
+
+    #[test]
+    fn signatures_are_verified_with_the_matching_key() {
+        let keypair = KeyPair::new();
+        let signature = keypair.sign(b"foomp");
+
+        assert!(keypair.verify(b"foomp", &signature));
+        assert!(!keypair.verify(b"bar", &signature));
+        assert!(!KeyPair::new().verify(b"foomp", &signature));
+        assert!(!keypair.verify(b"foomp", &signature[..32]));
+    }
+
+    #[test]
+    fn keys_can_be_recovered_from_bytes() {
+        let keypair = KeyPair::new();
+        let recovered = KeyPair::from_bytes(
+            &keypair.private_key().to_bytes(),
+            &keypair.public_key().to_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(keypair.public_key(), recovered.public_key());
+        assert_eq!(keypair.public_key(), &recovered.private_key().public_key());
+    }
+
+    #[test]
+    fn x25519_keys_derived_from_the_same_identity_match() {
+        let keypair = KeyPair::new();
+        let x25519_keypair = keypair.to_x25519();
+
+        assert_eq!(
+            x25519_keypair.public_key,
+            x25519_keypair.private_key.public_key()
+        );
+    }
+
+    // test vectors 1 and 2 from RFC 8032, section 7.1
+    #[test]
+    fn signatures_match_rfc8032_test_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+
+        for (private_key, public_key, message, signature) in vectors.iter() {
+            let private_key = PrivateKey::from_bytes(&hex::decode(private_key).unwrap()).unwrap();
+            let public_key = PublicKey::from_bytes(&hex::decode(public_key).unwrap()).unwrap();
+            let message = hex::decode(message).unwrap();
+            let signature = hex::decode(signature).unwrap();
+
+            assert_eq!(public_key, private_key.public_key());
+            assert_eq!(signature, private_key.sign(&message));
+            assert!(public_key.verify(&message, &signature));
+        }
+    }
+
+    // expected values were computed independently for RFC 8032 test vector 1
+    #[test]
+    fn x25519_conversion_matches_known_values() {
+        let private_key = PrivateKey::from_bytes(
+            &hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap(),
+        )
+        .unwrap();
+        let public_key = private_key.public_key();
+
+        assert_eq!(
+            hex::decode("7c2cac12e69be96ae9065065462385e8fcff2768d980c0a3a520f006904de90f")
+                .unwrap(),
+            private_key.to_x25519().to_bytes()
+        );
+        assert_eq!(
+            hex::decode("d85e07ec22b0ad881537c2f44d662d1a143cf830c57aca4305d85c7a90f6b62e")
+                .unwrap(),
+            public_key.to_x25519().to_bytes()
+        );
+    }
+}
@@ -4,6 +4,27 @@ pub mod identity;
 // TODO: this trait will need to be moved elsewhere, probably to some 'persistence' crate
 // but since it will need to be used by all identities, it's not really appropriate if it lived in nym-client
 
-pub trait PemStorable {
-    fn pem_type(&self) -> String;
+pub trait PemStorable: Sized {
+    // tag identifying the type of the key in its PEM encoding
+    fn pem_type() -> String;
+    fn to_pem_bytes(&self) -> Vec<u8>;
+    fn from_pem_bytes(b: &[u8]) -> Result<Self, KeyRecoveryError>;
+}
+
+#[derive(Debug)]
+pub enum KeyRecoveryError {
+    InvalidLengthError { expected: usize, received: usize },
+    MalformedKeyError,
+}
+
+pub(crate) fn key_bytes_32(b: &[u8]) -> Result<[u8; 32], KeyRecoveryError> {
+    if b.len() != 32 {
+        return Err(KeyRecoveryError::InvalidLengthError {
+            expected: 32,
+            received: b.len(),
+        });
+    }
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(b);
+    Ok(bytes)
 }
@@ -1,3 +1,4 @@
+use directory_client::presence::SignaturePolicy;
 use serde_derive::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -12,4 +13,8 @@ pub struct HealthCheck {
 
     #[serde(rename(deserialize = "test-packets-per-node"))]
     pub num_test_packets: usize,
+
+    // which presences with missing or invalid signatures are still checked
+    #[serde(rename(deserialize = "signature-policy"), default)]
+    pub signature_policy: SignaturePolicy,
 }
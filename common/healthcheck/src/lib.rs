@@ -47,7 +47,8 @@ impl HealthChecker {
             "healthcheck will be using the following directory server: {:?}",
             config.directory_server
         );
-        let directory_client_config = directory_client::Config::new(config.directory_server);
+        let directory_client_config = directory_client::Config::new(config.directory_server)
+            .with_signature_policy(config.signature_policy);
         HealthChecker {
             directory_client: directory_client::Client::new(directory_client_config),
             interval: Duration::from_secs_f64(config.interval),
@@ -1,4 +1,5 @@
-use crypto::identity::{DummyMixIdentityKeyPair, MixnetIdentityKeyPair, MixnetIdentityPublicKey};
+use crypto::encryption::MixnetEncryptionPublicKey;
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
 use itertools::Itertools;
 use log::{debug, error, trace, warn};
 use mix_client::MixClient;
@@ -17,10 +18,8 @@ pub enum PathStatus {
 
 pub(crate) struct PathChecker {
     provider_clients: HashMap<[u8; 32], Option<ProviderClient>>,
-    // currently this is an overkill as MixClient is extremely cheap to create,
-    // however, once we introduce persistent connection between client and layer one mixes,
-    // this will be extremely helpful to have
-    layer_one_clients: HashMap<[u8; 32], Option<MixClient>>,
+    // keeps persistent connections to all layer one mixes we are sending test packets to
+    mix_client: MixClient,
     paths_status: HashMap<Vec<u8>, PathStatus>,
     our_destination: Destination,
 }
@@ -28,17 +27,20 @@ pub(crate) struct PathChecker {
 impl PathChecker {
     pub(crate) async fn new(
         providers: Vec<MixProviderNode>,
-        ephemeral_keys: DummyMixIdentityKeyPair,
+        ephemeral_keys: ed25519::KeyPair,
     ) -> Self {
         let mut provider_clients = HashMap::new();
 
         let mut temporary_address = [0u8; 32];
-        let public_key_bytes = ephemeral_keys.public_key().to_bytes();
+        let public_key_bytes = ephemeral_keys.public_key().to_x25519().to_bytes();
         temporary_address.copy_from_slice(&public_key_bytes[..]);
 
         for provider in providers {
-            let mut provider_client =
-                ProviderClient::new(provider.client_listener, temporary_address, None);
+            let mut provider_client = ProviderClient::new(
+                provider.client_listener,
+                ephemeral_keys.private_key().clone(),
+                None,
+            );
             let insertion_result = match provider_client.register().await {
                 Ok(token) => {
                     debug!("registered at provider {}", provider.pub_key);
@@ -61,7 +63,7 @@ impl PathChecker {
 
         PathChecker {
             provider_clients,
-            layer_one_clients: HashMap::new(),
+            mix_client: MixClient::new(),
             our_destination: Destination::new(temporary_address, Default::default()),
             paths_status: HashMap::new(),
         }
@@ -115,31 +117,34 @@ impl PathChecker {
         self.paths_status
     }
 
-    // pull messages from given provider until there are no more 'real' messages
+    // pull messages from given provider until it tells us there are no more of them
     async fn resolve_pending_provider_checks(
         &self,
         provider_client: &ProviderClient,
     ) -> Vec<Vec<u8>> {
-        // keep getting messages until we encounter the dummy message
         let mut provider_messages = Vec::new();
+        let mut cursor = 0;
         loop {
-            match provider_client.retrieve_messages().await {
+            match provider_client.retrieve_messages(cursor).await {
                 Err(err) => {
                     error!("failed to fetch provider messages! - {:?}", err);
                     break;
                 }
-                Ok(messages) => {
-                    let mut should_stop = false;
-                    for msg in messages.into_iter() {
+                Ok(page) => {
+                    cursor = page.next_cursor;
+                    let has_more = page.has_more();
+                    let mut ack_ids = Vec::new();
+                    for msg in page.messages.into_iter() {
                         trace!("received provider response: {:?}", msg);
-                        if msg == sfw_provider_requests::DUMMY_MESSAGE_CONTENT {
-                            // finish iterating the loop as the messages might not be ordered
-                            should_stop = true;
-                        } else {
-                            provider_messages.push(msg);
+                        ack_ids.push(msg.id);
+                        provider_messages.push(msg.content);
+                    }
+                    if !ack_ids.is_empty() {
+                        if let Err(err) = provider_client.ack_messages(ack_ids).await {
+                            warn!("failed to acknowledge provider messages! - {:?}", err);
                         }
                     }
-                    if should_stop {
+                    if !has_more {
                         break;
                     }
                 }
@@ -187,29 +192,9 @@ impl PathChecker {
         }
 
         let layer_one_mix = path.first().unwrap();
-        let first_node_key = layer_one_mix.pub_key.to_bytes();
         let first_node_address =
             addressing::socket_address_from_encoded_bytes(layer_one_mix.address.to_bytes());
 
-        let first_node_client = self
-            .layer_one_clients
-            .entry(first_node_key)
-            .or_insert(Some(mix_client::MixClient::new()));
-
-        if first_node_client.is_none() {
-            debug!("we can ignore this path as layer one mix is inaccessible");
-            if self
-                .paths_status
-                .insert(path_identifier, PathStatus::Unhealthy)
-                .is_some()
-            {
-                panic!("Overwriting path checks!")
-            }
-            return;
-        }
-
-        let first_node_client = first_node_client.as_ref().unwrap();
-
         let delays: Vec<_> = path.iter().map(|_| Delay::new(0)).collect();
 
         let packet = sphinx::SphinxPacket::new(
@@ -221,7 +206,7 @@ impl PathChecker {
         .unwrap();
 
         debug!("sending test packet to {}", first_node_address);
-        match first_node_client.send(packet, first_node_address).await {
+        match self.mix_client.send(packet, first_node_address).await {
             Err(err) => {
                 warn!("failed to send packet to {} - {}", first_node_address, err);
                 if self
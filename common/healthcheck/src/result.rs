@@ -1,6 +1,6 @@
 use crate::path_check::{PathChecker, PathStatus};
 use crate::score::NodeScore;
-use crypto::identity::{DummyMixIdentityKeyPair, MixnetIdentityKeyPair};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
 use log::{debug, error, info, warn};
 use sphinx::route::NodeAddressBytes;
 use std::collections::HashMap;
@@ -121,7 +121,7 @@ impl HealthCheckResult {
                 score_map.insert(node.get_pub_key_bytes(), NodeScore::from_provider(node));
             });
 
-        let ephemeral_keys = DummyMixIdentityKeyPair::new();
+        let ephemeral_keys = ed25519::KeyPair::new();
         let providers = topology.get_mix_provider_nodes();
 
         let mut path_checker = PathChecker::new(providers, ephemeral_keys).await;
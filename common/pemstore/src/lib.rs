@@ -1,2 +1,3 @@
 pub mod pathfinder;
 pub mod pemstore;
+mod sealing;
@@ -1,89 +1,518 @@
 use crate::pathfinder::PathFinder;
+use crate::sealing::{self, SEALED_TAG_PREFIX};
+use crypto::{KeyRecoveryError, PemStorable};
 use pem::{encode, parse, Pem};
-use std::fs::File;
+use std::cell::RefCell;
+use std::fmt::{self, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
-#[allow(dead_code)]
-pub fn read_mix_encryption_keypair_from_disk(_id: String) -> crypto::encryption::x25519::KeyPair {
-    unimplemented!()
+pub use crate::sealing::SealingError;
+
+#[derive(Debug)]
+pub enum PemStoreError {
+    MissingFileError(PathBuf),
+    IOError(PathBuf, io::Error),
+    PemParsingError(PathBuf),
+    WrongKeyTypeError {
+        path: PathBuf,
+        expected: String,
+        found: String,
+    },
+    InvalidKeyError(PathBuf, KeyRecoveryError),
+    PassphraseReadError(io::Error),
+    UndefinedKeyPathError,
+    EncryptionError(PathBuf, SealingError),
+}
+
+impl fmt::Display for PemStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use PemStoreError::*;
+        match self {
+            MissingFileError(path) => write!(f, "key file {:?} does not exist", path),
+            IOError(path, err) => write!(f, "could not access key file {:?} - {}", path, err),
+            PemParsingError(path) => write!(f, "key file {:?} is not a valid PEM file", path),
+            WrongKeyTypeError {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "key file {:?} contains {:?} while {:?} was expected",
+                path, found, expected
+            ),
+            InvalidKeyError(path, KeyRecoveryError::InvalidLengthError { expected, received }) => {
+                write!(
+                    f,
+                    "key stored in {:?} has invalid length ({} bytes, expected {})",
+                    path, received, expected
+                )
+            }
+            InvalidKeyError(path, KeyRecoveryError::MalformedKeyError) => {
+                write!(f, "key stored in {:?} is malformed", path)
+            }
+            PassphraseReadError(err) => write!(f, "could not read the passphrase - {}", err),
+            UndefinedKeyPathError => write!(f, "no location has been defined for the keys"),
+            EncryptionError(path, SealingError::DecryptionError) => write!(
+                f,
+                "could not decrypt key file {:?} - is the passphrase correct?",
+                path
+            ),
+            EncryptionError(path, err) => {
+                write!(f, "could not protect key file {:?} - {:?}", path, err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PemStoreError {}
+
+pub fn read_mix_encryption_keypair_from_disk<P: PathFinder>(
+    pathfinder: P,
+) -> Result<crypto::encryption::x25519::KeyPair, PemStoreError> {
+    PemStore::new(pathfinder).read_encryption()
+}
+
+// if set, the passphrase protecting the private keys is taken from this environment variable
+// instead of asking the user for it
+pub const PASSPHRASE_ENV_VAR: &str = "NYM_KEYS_PASSPHRASE";
+
+/// Passphrase that can be obtained without asking the user: the first line of `passphrase_file`
+/// if it was given, otherwise the value of `PASSPHRASE_ENV_VAR` if it is set.
+pub fn non_interactive_passphrase(
+    passphrase_file: Option<&str>,
+) -> Result<Option<String>, PemStoreError> {
+    if let Some(passphrase_file) = passphrase_file {
+        let contents =
+            std::fs::read_to_string(passphrase_file).map_err(PemStoreError::PassphraseReadError)?;
+        return Ok(Some(
+            contents.lines().next().unwrap_or_default().to_string(),
+        ));
+    }
+    Ok(std::env::var(PASSPHRASE_ENV_VAR).ok())
+}
+
+/// Passphrase for protecting newly generated keys. Unless it can be obtained without user
+/// interaction, the user is asked for it.
+pub fn new_passphrase(passphrase_file: Option<&str>) -> Result<String, PemStoreError> {
+    match non_interactive_passphrase(passphrase_file)? {
+        Some(passphrase) => Ok(passphrase),
+        None => prompt_new_passphrase(),
+    }
+}
+
+// asks the user for the passphrase twice to make sure there was no typo in it
+pub fn prompt_new_passphrase() -> Result<String, PemStoreError> {
+    loop {
+        let passphrase = rpassword::read_password_from_tty(Some("Enter passphrase: "))
+            .map_err(PemStoreError::PassphraseReadError)?;
+        let confirmation = rpassword::read_password_from_tty(Some("Confirm passphrase: "))
+            .map_err(PemStoreError::PassphraseReadError)?;
+        if passphrase == confirmation {
+            return Ok(passphrase);
+        }
+        eprintln!("Passphrases did not match, please try again.");
+    }
 }
 
 pub struct PemStore {
-    config_dir: PathBuf,
-    private_mix_key: PathBuf,
-    public_mix_key: PathBuf,
+    private_identity_key: PathBuf,
+    public_identity_key: PathBuf,
+    private_encryption_key: Option<PathBuf>,
+    public_encryption_key: Option<PathBuf>,
+    // if set, private keys are encrypted with it when written to disk. it is also used to
+    // decrypt them when read - if it's not set, the user is going to be asked for it instead.
+    passphrase: Option<String>,
+    // passphrase the user was asked for, so that they would only have to type it in once
+    // even though the keys are stored in separate files
+    prompted_passphrase: RefCell<Option<String>>,
 }
 
 impl PemStore {
     pub fn new<P: PathFinder>(pathfinder: P) -> PemStore {
         PemStore {
-            config_dir: pathfinder.config_dir(),
-            private_mix_key: pathfinder.private_identity_key(),
-            public_mix_key: pathfinder.public_identity_key(),
+            private_identity_key: pathfinder.private_identity_key(),
+            public_identity_key: pathfinder.public_identity_key(),
+            private_encryption_key: pathfinder.private_encryption_key(),
+            public_encryption_key: pathfinder.public_encryption_key(),
+            passphrase: None,
+            prompted_passphrase: RefCell::new(None),
         }
     }
 
-    pub fn read_identity<IDPair, Priv, Pub>(&self) -> IDPair
+    pub fn with_passphrase(mut self, passphrase: String) -> Self {
+        self.passphrase = Some(passphrase);
+        self
+    }
+
+    /// Uses the passphrase that can be obtained without asking the user, if there is one.
+    pub fn with_non_interactive_passphrase(
+        self,
+        passphrase_file: Option<&str>,
+    ) -> Result<Self, PemStoreError> {
+        Ok(match non_interactive_passphrase(passphrase_file)? {
+            Some(passphrase) => self.with_passphrase(passphrase),
+            None => self,
+        })
+    }
+
+    pub fn read_identity<IDPair, Priv, Pub>(&self) -> Result<IDPair, PemStoreError>
     where
         IDPair: crypto::identity::MixnetIdentityKeyPair<Priv, Pub>,
         Priv: crypto::identity::MixnetIdentityPrivateKey,
         Pub: crypto::identity::MixnetIdentityPublicKey,
     {
-        let private_pem = self.read_pem_file(self.private_mix_key.clone());
-        let public_pem = self.read_pem_file(self.public_mix_key.clone());
+        let private_key: Priv = self.read_key(&self.private_identity_key)?;
+        let public_key: Pub = self.read_key(&self.public_identity_key)?;
 
-        let key_pair = IDPair::from_bytes(&private_pem.contents, &public_pem.contents);
+        IDPair::from_bytes(&private_key.to_pem_bytes(), &public_key.to_pem_bytes())
+            .map_err(|err| PemStoreError::InvalidKeyError(self.private_identity_key.clone(), err))
+    }
 
-        assert_eq!(key_pair.private_key().pem_type(), private_pem.tag);
-        assert_eq!(key_pair.public_key().pem_type(), public_pem.tag);
+    pub fn write_identity<IDPair, Priv, Pub>(&self, key_pair: IDPair) -> Result<(), PemStoreError>
+    where
+        IDPair: crypto::identity::MixnetIdentityKeyPair<Priv, Pub>,
+        Priv: crypto::identity::MixnetIdentityPrivateKey,
+        Pub: crypto::identity::MixnetIdentityPublicKey,
+    {
+        self.write_private_key(&self.private_identity_key, key_pair.private_key())?;
+        self.write_public_key(&self.public_identity_key, key_pair.public_key())
+    }
+
+    pub fn read_encryption<EncPair, Priv, Pub>(&self) -> Result<EncPair, PemStoreError>
+    where
+        EncPair: crypto::encryption::MixnetEncryptionKeyPair<Priv, Pub>,
+        Priv: crypto::encryption::MixnetEncryptionPrivateKey,
+        Pub: crypto::encryption::MixnetEncryptionPublicKey,
+    {
+        let (private_key_path, public_key_path) = self.encryption_key_paths()?;
+        let private_key: Priv = self.read_key(private_key_path)?;
+        let public_key: Pub = self.read_key(public_key_path)?;
+
+        EncPair::from_bytes(&private_key.to_pem_bytes(), &public_key.to_pem_bytes())
+            .map_err(|err| PemStoreError::InvalidKeyError(private_key_path.clone(), err))
+    }
+
+    pub fn write_encryption<EncPair, Priv, Pub>(
+        &self,
+        key_pair: EncPair,
+    ) -> Result<(), PemStoreError>
+    where
+        EncPair: crypto::encryption::MixnetEncryptionKeyPair<Priv, Pub>,
+        Priv: crypto::encryption::MixnetEncryptionPrivateKey,
+        Pub: crypto::encryption::MixnetEncryptionPublicKey,
+    {
+        let (private_key_path, public_key_path) = self.encryption_key_paths()?;
+        self.write_private_key(private_key_path, key_pair.private_key())?;
+        self.write_public_key(public_key_path, key_pair.public_key())
+    }
+
+    fn encryption_key_paths(&self) -> Result<(&PathBuf, &PathBuf), PemStoreError> {
+        match (&self.private_encryption_key, &self.public_encryption_key) {
+            (Some(private_key_path), Some(public_key_path)) => {
+                Ok((private_key_path, public_key_path))
+            }
+            _ => Err(PemStoreError::UndefinedKeyPathError),
+        }
+    }
+
+    /// Reads key of any type from the file, making sure it was stored under the tag of that type.
+    /// If the key was written encrypted, it is going to be decrypted first.
+    pub fn read_key<K: PemStorable>(&self, filepath: &PathBuf) -> Result<K, PemStoreError> {
+        let pem = self.read_pem_file(filepath)?;
+        let pem = self.unseal_pem(filepath, pem)?;
+
+        // make sure we are not about to interpret some other key as the one we want
+        let expected = K::pem_type();
+        if pem.tag != expected {
+            return Err(PemStoreError::WrongKeyTypeError {
+                path: filepath.clone(),
+                expected,
+                found: pem.tag,
+            });
+        }
+
+        K::from_pem_bytes(&pem.contents)
+            .map_err(|err| PemStoreError::InvalidKeyError(filepath.clone(), err))
+    }
+
+    /// Writes the key to the file, encrypting it if the store has a passphrase.
+    pub fn write_private_key<K: PemStorable>(
+        &self,
+        filepath: &PathBuf,
+        key: &K,
+    ) -> Result<(), PemStoreError> {
+        let (data, tag) = self.seal_key(filepath, key.to_pem_bytes(), K::pem_type())?;
+        self.write_pem_file(filepath, data, tag)
+    }
+
+    /// Writes the key to the file as is.
+    pub fn write_public_key<K: PemStorable>(
+        &self,
+        filepath: &PathBuf,
+        key: &K,
+    ) -> Result<(), PemStoreError> {
+        self.write_pem_file(filepath, key.to_pem_bytes(), K::pem_type())
+    }
+
+    // decrypts the pem if it was written with a passphrase, otherwise returns it unchanged
+    fn unseal_pem(&self, filepath: &PathBuf, pem: Pem) -> Result<Pem, PemStoreError> {
+        if !pem.tag.starts_with(SEALED_TAG_PREFIX) {
+            return Ok(pem);
+        }
+
+        let tag = pem.tag[SEALED_TAG_PREFIX.len()..].to_string();
+        if let Some(passphrase) = self.passphrase.as_ref() {
+            let contents = sealing::unseal(passphrase, &tag, &pem.contents)
+                .map_err(|err| PemStoreError::EncryptionError(filepath.clone(), err))?;
+            return Ok(Pem { tag, contents });
+        }
+
+        let mut prompted_passphrase = self.prompted_passphrase.borrow_mut();
+        let passphrase = match prompted_passphrase.take() {
+            Some(passphrase) => passphrase,
+            None => rpassword::read_password_from_tty(Some("Enter passphrase for the keys: "))
+                .map_err(PemStoreError::PassphraseReadError)?,
+        };
 
-        key_pair
+        let contents = sealing::unseal(&passphrase, &tag, &pem.contents)
+            .map_err(|err| PemStoreError::EncryptionError(filepath.clone(), err))?;
+        // only remembered once we know it's the right one
+        *prompted_passphrase = Some(passphrase);
+        Ok(Pem { tag, contents })
     }
 
-    fn read_pem_file(&self, filepath: PathBuf) -> Pem {
-        let mut pem_bytes = File::open(filepath).expect("Could not open stored keys from disk.");
+    fn read_pem_file(&self, filepath: &PathBuf) -> Result<Pem, PemStoreError> {
+        let mut pem_bytes = File::open(filepath).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => PemStoreError::MissingFileError(filepath.clone()),
+            _ => PemStoreError::IOError(filepath.clone(), err),
+        })?;
         let mut buf = Vec::new();
         pem_bytes
             .read_to_end(&mut buf)
-            .expect("PEM bytes reading failed.");
-        let pem = parse(&buf).expect("PEM parsing failed while reading keys");
+            .map_err(|err| PemStoreError::IOError(filepath.clone(), err))?;
 
-        pem
+        parse(&buf).map_err(|_| PemStoreError::PemParsingError(filepath.clone()))
     }
-    // This should be refactored and made more generic for when we have other kinds of
-    // KeyPairs that we want to persist (e.g. validator keypairs, or keys for
-    // signing vs encryption). However, for the moment, it does the job.
-    pub fn write_identity<IDPair, Priv, Pub>(&self, key_pair: IDPair)
-    where
-        IDPair: crypto::identity::MixnetIdentityKeyPair<Priv, Pub>,
-        Priv: crypto::identity::MixnetIdentityPrivateKey,
-        Pub: crypto::identity::MixnetIdentityPublicKey,
-    {
-        std::fs::create_dir_all(self.config_dir.clone()).unwrap();
-
-        let private_key = key_pair.private_key();
-        let public_key = key_pair.public_key();
-        self.write_pem_file(
-            self.private_mix_key.clone(),
-            private_key.to_bytes(),
-            private_key.pem_type(),
-        );
-        self.write_pem_file(
-            self.public_mix_key.clone(),
-            public_key.to_bytes(),
-            public_key.pem_type(),
-        );
+
+    // encrypts the key if we have a passphrase, otherwise returns it unchanged
+    fn seal_key(
+        &self,
+        filepath: &PathBuf,
+        data: Vec<u8>,
+        tag: String,
+    ) -> Result<(Vec<u8>, String), PemStoreError> {
+        match self.passphrase.as_ref() {
+            None => Ok((data, tag)),
+            Some(passphrase) => {
+                let sealed_data = sealing::seal(passphrase, &tag, &data)
+                    .map_err(|err| PemStoreError::EncryptionError(filepath.clone(), err))?;
+                Ok((sealed_data, format!("{}{}", SEALED_TAG_PREFIX, tag)))
+            }
+        }
     }
 
-    fn write_pem_file(&self, filepath: PathBuf, data: Vec<u8>, tag: String) {
+    // files are only readable and writable by their owner as other users on the same host
+    // have no business looking at our keys
+    fn write_pem_file(
+        &self,
+        filepath: &PathBuf,
+        data: Vec<u8>,
+        tag: String,
+    ) -> Result<(), PemStoreError> {
         let pem = Pem {
             tag,
             contents: data,
         };
         let key = encode(&pem);
 
-        let mut file = File::create(filepath).unwrap();
-        file.write_all(key.as_bytes()).unwrap();
+        if let Some(parent_dir) = filepath.parent() {
+            std::fs::create_dir_all(parent_dir)
+                .map_err(|err| PemStoreError::IOError(parent_dir.to_path_buf(), err))?;
+        }
+
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            open_options.mode(0o600);
+        }
+
+        let io_error = |err| PemStoreError::IOError(filepath.clone(), err);
+        let mut file = open_options.open(filepath).map_err(io_error)?;
+        // the mode is only applied to newly created files so make sure to also fix
+        // permissions of any file we have just overwritten
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(io_error)?;
+        }
+        file.write_all(key.as_bytes()).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod storing_keys {
+    use super::*;
+    use crypto::encryption::{x25519, MixnetEncryptionKeyPair};
+    use crypto::identity::{ed25519, MixnetIdentityKeyPair, MixnetIdentityPrivateKey};
+    use tempfile::TempDir;
+
+    struct TestPathfinder(PathBuf);
+
+    impl PathFinder for TestPathfinder {
+        fn config_dir(&self) -> PathBuf {
+            self.0.clone()
+        }
+
+        fn private_identity_key(&self) -> PathBuf {
+            self.0.join("private.pem")
+        }
+
+        fn public_identity_key(&self) -> PathBuf {
+            self.0.join("public.pem")
+        }
+
+        fn private_encryption_key(&self) -> Option<PathBuf> {
+            Some(self.0.join("private_encryption.pem"))
+        }
+
+        fn public_encryption_key(&self) -> Option<PathBuf> {
+            Some(self.0.join("public_encryption.pem"))
+        }
+    }
+
+    // the directory is removed once the returned handle is dropped
+    fn test_store() -> (TempDir, PemStore) {
+        let dir = TempDir::new().unwrap();
+        let store = PemStore::new(TestPathfinder(dir.path().to_path_buf()));
+        (dir, store)
+    }
+
+    #[test]
+    fn recovers_written_keys() {
+        let (_dir, store) = test_store();
+        let keypair = ed25519::KeyPair::new();
+        let public_key = keypair.public_key().to_b64_string();
+        store.write_identity(keypair).unwrap();
+
+        let recovered: ed25519::KeyPair = store.read_identity().unwrap();
+        assert_eq!(public_key, recovered.public_key().to_b64_string());
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let (_dir, store) = test_store();
+        match store.read_identity::<ed25519::KeyPair, _, _>() {
+            Err(PemStoreError::MissingFileError(path)) => {
+                assert_eq!(path, store.private_identity_key)
+            }
+            _ => panic!("expected missing file error"),
+        }
+    }
+
+    #[test]
+    fn reports_keys_of_wrong_type() {
+        let (_dir, store) = test_store();
+        let keypair = x25519::KeyPair::new();
+        store
+            .write_private_key(&store.private_identity_key, keypair.private_key())
+            .unwrap();
+        store
+            .write_public_key(&store.public_identity_key, keypair.public_key())
+            .unwrap();
+
+        match store.read_identity::<ed25519::KeyPair, _, _>() {
+            Err(PemStoreError::WrongKeyTypeError { path, .. }) => {
+                assert_eq!(path, store.private_identity_key)
+            }
+            _ => panic!("expected wrong key type error"),
+        }
+    }
+
+    #[test]
+    fn reports_keys_of_invalid_length() {
+        let (_dir, store) = test_store();
+        store.write_identity(ed25519::KeyPair::new()).unwrap();
+        store
+            .write_pem_file(
+                &store.public_identity_key,
+                vec![42; 31],
+                ed25519::PublicKey::pem_type(),
+            )
+            .unwrap();
+
+        match store.read_identity::<ed25519::KeyPair, _, _>() {
+            Err(PemStoreError::InvalidKeyError(path, _)) => {
+                assert_eq!(path, store.public_identity_key)
+            }
+            _ => panic!("expected invalid key error"),
+        }
+    }
+
+    #[test]
+    fn keeps_identity_and_encryption_keys_separate() {
+        let (_dir, store) = test_store();
+        let identity_keypair = ed25519::KeyPair::new();
+        let identity_public_key = identity_keypair.public_key().to_b64_string();
+        let encryption_keypair = x25519::KeyPair::new();
+        let encryption_public_key = encryption_keypair.public_key().clone();
+        store.write_identity(identity_keypair).unwrap();
+        store.write_encryption(encryption_keypair).unwrap();
+
+        let recovered_identity: ed25519::KeyPair = store.read_identity().unwrap();
+        let recovered_encryption: x25519::KeyPair = store.read_encryption().unwrap();
+        assert_eq!(
+            identity_public_key,
+            recovered_identity.public_key().to_b64_string()
+        );
+        assert_eq!(&encryption_public_key, recovered_encryption.public_key());
+    }
+
+    #[test]
+    fn reads_single_keys_by_their_type() {
+        let (dir, store) = test_store();
+        let store = store.with_passphrase("foomp".to_string());
+        let path = dir.path().join("validator").join("validator.pem");
+        let keypair = ed25519::KeyPair::new();
+        store
+            .write_private_key(&path, keypair.private_key())
+            .unwrap();
+
+        let recovered: ed25519::PrivateKey = store.read_key(&path).unwrap();
+        assert_eq!(keypair.public_key(), &recovered.public_key());
+        assert!(store.read_key::<x25519::PrivateKey>(&path).is_err());
+    }
+
+    #[test]
+    fn reads_encrypted_keys_with_remembered_passphrase() {
+        let (dir, store) = test_store();
+        let keypair = ed25519::KeyPair::new();
+        let public_key = keypair.public_key().to_b64_string();
+        store
+            .with_passphrase("foomp".to_string())
+            .write_identity(keypair)
+            .unwrap();
+
+        // as if the user has already typed it in while reading some other key
+        let store = PemStore::new(TestPathfinder(dir.path().to_path_buf()));
+        *store.prompted_passphrase.borrow_mut() = Some("foomp".to_string());
+        let recovered: ed25519::KeyPair = store.read_identity().unwrap();
+        assert_eq!(public_key, recovered.public_key().to_b64_string());
+    }
+
+    #[test]
+    fn passphrase_is_read_from_first_line_of_file() {
+        let dir = TempDir::new().unwrap();
+        let passphrase_file = dir.path().join("passphrase");
+        std::fs::write(&passphrase_file, "foomp\n").unwrap();
+
+        let passphrase = non_interactive_passphrase(passphrase_file.to_str()).unwrap();
+        assert_eq!(Some("foomp".to_string()), passphrase);
+        assert!(non_interactive_passphrase(dir.path().join("missing").to_str()).is_err());
     }
 }
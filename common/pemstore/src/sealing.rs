@@ -0,0 +1,124 @@
+// Passphrase-based encryption of private keys stored on disk.
+// The key is derived from the passphrase with Argon2id and used to encrypt the data
+// with ChaCha20-Poly1305. The PEM tag is authenticated alongside the data so that
+// the decrypted key couldn't be loaded as a different key type.
+//
+// Sealed data layout: format_version || salt || nonce || ciphertext (with the auth tag)
+
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+const FORMAT_VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: u32 = 32;
+
+// TODO: if we ever need to change those, they will have to become part of the sealed data
+const ARGON2_MEMORY_COST_KIB: u32 = 19 * 1024;
+const ARGON2_TIME_COST: u32 = 2;
+const ARGON2_LANES: u32 = 1;
+
+pub(crate) const SEALED_TAG_PREFIX: &str = "ENCRYPTED ";
+
+#[derive(Debug)]
+pub enum SealingError {
+    MalformedDataError,
+    UnsupportedFormatError(u8),
+    KeyDerivationError,
+    // either the passphrase is wrong or the data was tampered with
+    DecryptionError,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>, SealingError> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: ARGON2_MEMORY_COST_KIB,
+        time_cost: ARGON2_TIME_COST,
+        lanes: ARGON2_LANES,
+        hash_length: KEY_LENGTH,
+        ..Default::default()
+    };
+
+    argon2::hash_raw(passphrase.as_bytes(), salt, &config)
+        .map_err(|_| SealingError::KeyDerivationError)
+}
+
+pub(crate) fn seal(passphrase: &str, tag: &str, data: &[u8]) -> Result<Vec<u8>, SealingError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt)?;
+    let cipher = ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: data,
+                aad: tag.as_bytes(),
+            },
+        )
+        // this can only fail if the data is unreasonably large (i.e. way bigger than any key)
+        .map_err(|_| SealingError::MalformedDataError)?;
+
+    Ok(std::iter::once(FORMAT_VERSION)
+        .chain(salt.iter().cloned())
+        .chain(nonce.iter().cloned())
+        .chain(ciphertext.into_iter())
+        .collect())
+}
+
+pub(crate) fn unseal(
+    passphrase: &str,
+    tag: &str,
+    sealed_data: &[u8],
+) -> Result<Vec<u8>, SealingError> {
+    if sealed_data.len() < 1 + SALT_LENGTH + NONCE_LENGTH {
+        return Err(SealingError::MalformedDataError);
+    }
+    if sealed_data[0] != FORMAT_VERSION {
+        return Err(SealingError::UnsupportedFormatError(sealed_data[0]));
+    }
+
+    let salt = &sealed_data[1..1 + SALT_LENGTH];
+    let nonce = &sealed_data[1 + SALT_LENGTH..1 + SALT_LENGTH + NONCE_LENGTH];
+    let ciphertext = &sealed_data[1 + SALT_LENGTH + NONCE_LENGTH..];
+
+    let key = derive_key(passphrase, salt)?;
+    let cipher = ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key));
+    cipher
+        .decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: tag.as_bytes(),
+            },
+        )
+        .map_err(|_| SealingError::DecryptionError)
+}
+
+#[cfg(test)]
+mod sealing_private_keys {
+    use super::*;
+
+    #[test]
+    fn data_is_recovered_with_the_same_passphrase_and_tag() {
+        let sealed = seal("foomp", "FOO KEY", &[42u8; 32]).unwrap();
+        assert_ne!(&sealed[sealed.len() - 32..], &[42u8; 32][..]);
+
+        let unsealed = unseal("foomp", "FOO KEY", &sealed).unwrap();
+        assert_eq!(vec![42u8; 32], unsealed);
+    }
+
+    #[test]
+    fn data_is_not_recovered_with_different_passphrase_or_tag() {
+        let sealed = seal("foomp", "FOO KEY", &[42u8; 32]).unwrap();
+
+        assert!(unseal("bar", "FOO KEY", &sealed).is_err());
+        assert!(unseal("foomp", "BAR KEY", &sealed).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::packet::{
+    FramedSphinxPacket, Header, PacketType, CURRENT_PROTOCOL_VERSION, HEADER_SIZE,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug)]
+pub enum SphinxCodecError {
+    UnsupportedVersionError(u8),
+    UnknownPacketTypeError(u8),
+    InvalidPacketLengthError(usize),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for SphinxCodecError {
+    fn from(err: io::Error) -> Self {
+        SphinxCodecError::IOError(err)
+    }
+}
+
+// version || packet type || packet length || packet
+// Every frame contains exactly one packet; packets of unknown type or unexpected length
+// are rejected as at that point we can't trust anything else sent on the connection.
+pub struct SphinxCodec;
+
+impl Encoder for SphinxCodec {
+    type Item = FramedSphinxPacket;
+    type Error = SphinxCodecError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let packet_len = item.packet_bytes.len();
+        if packet_len != item.header.packet_type.expected_length() {
+            return Err(SphinxCodecError::InvalidPacketLengthError(packet_len));
+        }
+
+        dst.reserve(HEADER_SIZE + packet_len);
+        dst.put_u8(item.header.version);
+        dst.put_u8(item.header.packet_type as u8);
+        dst.put_u32(packet_len as u32);
+        dst.put_slice(&item.packet_bytes);
+        Ok(())
+    }
+}
+
+impl Decoder for SphinxCodec {
+    type Item = FramedSphinxPacket;
+    type Error = SphinxCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_SIZE {
+            // we don't even have the header yet
+            return Ok(None);
+        }
+
+        let version = src[0];
+        if version != CURRENT_PROTOCOL_VERSION {
+            return Err(SphinxCodecError::UnsupportedVersionError(version));
+        }
+
+        let packet_type = PacketType::try_from(src[1])
+            .map_err(|err| SphinxCodecError::UnknownPacketTypeError(err.0))?;
+
+        // this can't fail as we've already checked the buffer length
+        let packet_len = u32::from_be_bytes(src[2..HEADER_SIZE].try_into().unwrap()) as usize;
+        if packet_len != packet_type.expected_length() {
+            return Err(SphinxCodecError::InvalidPacketLengthError(packet_len));
+        }
+
+        if src.len() < HEADER_SIZE + packet_len {
+            // the full frame hasn't arrived yet; make space for the rest of it
+            src.reserve(HEADER_SIZE + packet_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_SIZE);
+        let packet_bytes = src.split_to(packet_len).to_vec();
+
+        Ok(Some(FramedSphinxPacket {
+            header: Header {
+                version,
+                packet_type,
+            },
+            packet_bytes,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod sphinx_codec {
+    use super::*;
+
+    fn dummy_framed_packet(content: u8) -> FramedSphinxPacket {
+        FramedSphinxPacket {
+            header: Header::new(PacketType::Sphinx),
+            packet_bytes: vec![content; sphinx::PACKET_SIZE],
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encoded() {
+        let mut buf = BytesMut::new();
+        SphinxCodec
+            .encode(dummy_framed_packet(42), &mut buf)
+            .unwrap();
+
+        let decoded = SphinxCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(Header::new(PacketType::Sphinx), decoded.header());
+        assert_eq!(vec![42; sphinx::PACKET_SIZE], decoded.into_packet_bytes());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_entire_frame_on_short_reads() {
+        let mut full_buf = BytesMut::new();
+        SphinxCodec
+            .encode(dummy_framed_packet(42), &mut full_buf)
+            .unwrap();
+
+        let mut partial_buf = BytesMut::new();
+        partial_buf.extend_from_slice(&full_buf[..HEADER_SIZE - 1]);
+        assert!(SphinxCodec.decode(&mut partial_buf).unwrap().is_none());
+
+        partial_buf.extend_from_slice(&full_buf[HEADER_SIZE - 1..HEADER_SIZE + 10]);
+        assert!(SphinxCodec.decode(&mut partial_buf).unwrap().is_none());
+
+        partial_buf.extend_from_slice(&full_buf[HEADER_SIZE + 10..]);
+        assert!(SphinxCodec.decode(&mut partial_buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn separates_coalesced_frames() {
+        let mut buf = BytesMut::new();
+        SphinxCodec
+            .encode(dummy_framed_packet(1), &mut buf)
+            .unwrap();
+        SphinxCodec
+            .encode(dummy_framed_packet(2), &mut buf)
+            .unwrap();
+
+        let first = SphinxCodec.decode(&mut buf).unwrap().unwrap();
+        let second = SphinxCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(vec![1; sphinx::PACKET_SIZE], first.into_packet_bytes());
+        assert_eq!(vec![2; sphinx::PACKET_SIZE], second.into_packet_bytes());
+        assert!(SphinxCodec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_frames_with_invalid_length() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(CURRENT_PROTOCOL_VERSION);
+        buf.put_u8(PacketType::Sphinx as u8);
+        buf.put_u32(42);
+
+        match SphinxCodec.decode(&mut buf) {
+            Err(SphinxCodecError::InvalidPacketLengthError(len)) => assert_eq!(42, len),
+            _ => panic!("expected invalid length error"),
+        }
+    }
+
+    #[test]
+    fn rejects_frames_with_unknown_version() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(CURRENT_PROTOCOL_VERSION + 1);
+        buf.put_u8(PacketType::Sphinx as u8);
+        buf.put_u32(sphinx::PACKET_SIZE as u32);
+
+        match SphinxCodec.decode(&mut buf) {
+            Err(SphinxCodecError::UnsupportedVersionError(_)) => (),
+            _ => panic!("expected unsupported version error"),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use sphinx::SphinxPacket;
+use std::convert::TryFrom;
+
+pub const CURRENT_PROTOCOL_VERSION: u8 = 1;
+
+// version || packet type || packet length
+pub const HEADER_SIZE: usize = 1 + 1 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    Sphinx = 1,
+}
+
+impl PacketType {
+    // length of the payload every packet of given type MUST have
+    pub fn expected_length(self) -> usize {
+        match self {
+            PacketType::Sphinx => sphinx::PACKET_SIZE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownPacketType(pub u8);
+
+impl TryFrom<u8> for PacketType {
+    type Error = UnknownPacketType;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            _ if value == (PacketType::Sphinx as u8) => Ok(PacketType::Sphinx),
+            _ => Err(UnknownPacketType(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub packet_type: PacketType,
+}
+
+impl Header {
+    pub fn new(packet_type: PacketType) -> Self {
+        Header {
+            version: CURRENT_PROTOCOL_VERSION,
+            packet_type,
+        }
+    }
+}
+
+// FramedSphinxPacket is the unit of data being sent between nodes in the mixnet.
+// Note that the packet is kept as raw bytes as it's up to the receiver to decide what to do
+// with malformed sphinx packets that were otherwise framed correctly.
+#[derive(Debug)]
+pub struct FramedSphinxPacket {
+    pub(crate) header: Header,
+    pub(crate) packet_bytes: Vec<u8>,
+}
+
+impl FramedSphinxPacket {
+    pub fn new(packet: SphinxPacket) -> Self {
+        FramedSphinxPacket {
+            header: Header::new(PacketType::Sphinx),
+            packet_bytes: packet.to_bytes(),
+        }
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    pub fn packet_bytes(&self) -> &[u8] {
+        &self.packet_bytes
+    }
+
+    pub fn into_packet_bytes(self) -> Vec<u8> {
+        self.packet_bytes
+    }
+}
@@ -2,6 +2,7 @@ use clap::{App, Arg, ArgMatches, SubCommand};
 use log::*;
 use std::process;
 
+mod config;
 mod mix_peer;
 mod node;
 
@@ -13,9 +14,40 @@ fn main() {
         .version(built_info::PKG_VERSION)
         .author("Nymtech")
         .about("Implementation of the Loopix-based Mixnode")
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Initialise the mixnode. Do this first!")
+                .arg(Arg::with_name("id")
+                    .long("id")
+                    .help("Id of the nym-mixnode we want to create config for.")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("encrypt")
+                    .long("encrypt")
+                    .help("Protect the private keys with a passphrase you will be asked for")
+                )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase to protect the private keys with, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                    .requires("encrypt")
+                )
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Starts the mixnode")
+                .arg(Arg::with_name("id")
+                    .long("id")
+                    .help("Id of the nym-mixnode we want to run.")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase protecting the private keys, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                )
                 .arg(
                     Arg::with_name("host")
                         .long("host")
@@ -48,6 +80,12 @@ fn main() {
                         .help("The port that will be reported to the directory server")
                         .takes_value(true)
                 )
+                .arg(
+                    Arg::with_name("max_bad_packets")
+                        .long("max-bad-packets")
+                        .help("Number of malformed packets after which connection to the sender gets closed")
+                        .takes_value(true)
+                )
                 .arg(
                     Arg::with_name("directory")
                         .long("directory")
@@ -70,6 +108,7 @@ pub mod built_info {
 
 fn execute(matches: ArgMatches) -> Result<(), String> {
     match matches.subcommand() {
+        ("init", Some(m)) => Ok(node::init::execute(m)),
         ("run", Some(m)) => Ok(node::runner::start(m)),
         _ => Err(usage()),
     }
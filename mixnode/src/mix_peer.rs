@@ -1,8 +1,8 @@
 use addressing;
+use mix_client::{MixClient, MixClientError};
 use sphinx::route::NodeAddressBytes;
-use std::error::Error;
+use sphinx::SphinxPacket;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct MixPeer {
@@ -19,11 +19,13 @@ impl MixPeer {
         }
     }
 
-    pub async fn send(&self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let next_hop_address = self.connection.clone();
-        let mut stream = tokio::net::TcpStream::connect(next_hop_address).await?;
-        stream.write_all(&bytes).await?;
-        Ok(())
+    // the underlying connection is shared between all packets going to this peer
+    pub async fn send(
+        &self,
+        mix_client: &MixClient,
+        packet: SphinxPacket,
+    ) -> Result<(), MixClientError> {
+        mix_client.send(packet, self.connection).await
     }
 
     pub fn to_string(&self) -> String {
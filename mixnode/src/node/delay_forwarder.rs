@@ -0,0 +1,82 @@
+use crate::node::ForwardingData;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use log::*;
+use std::time::Duration;
+use tokio::time::DelayQueue;
+
+// DelayForwarder holds all processed packets until their sphinx delays expire and only then
+// forwards them to the next hop. This way connection handlers never have to wait for the delay
+// themselves and can go straight back to reading next packets.
+pub(crate) struct DelayForwarder {
+    delay_queue: DelayQueue<ForwardingData>,
+    forwarding_rx: mpsc::UnboundedReceiver<ForwardingData>,
+}
+
+impl DelayForwarder {
+    pub(crate) fn new() -> (Self, mpsc::UnboundedSender<ForwardingData>) {
+        let (forwarding_tx, forwarding_rx) = mpsc::unbounded();
+        (
+            DelayForwarder {
+                delay_queue: DelayQueue::new(),
+                forwarding_rx,
+            },
+            forwarding_tx,
+        )
+    }
+
+    async fn forward_packet(mut forwarding_data: ForwardingData) {
+        forwarding_data
+            .sent_metrics_tx
+            .send(forwarding_data.recipient.to_string())
+            .await
+            .unwrap();
+
+        trace!("RECIPIENT: {:?}", forwarding_data.recipient);
+        match forwarding_data
+            .recipient
+            .send(&forwarding_data.mix_client, forwarding_data.packet)
+            .await
+        {
+            Ok(()) => (),
+            Err(e) => {
+                warn!(
+                    "failed to write bytes to next mix peer. err = {:?}",
+                    e.to_string()
+                );
+            }
+        }
+    }
+
+    fn insert_packet(&mut self, forwarding_data: ForwardingData) {
+        let delay_duration = Duration::from_nanos(forwarding_data.delay.get_value());
+        self.delay_queue.insert(forwarding_data, delay_duration);
+    }
+
+    pub(crate) async fn run(mut self) {
+        loop {
+            tokio::select! {
+                forwarding_data = self.forwarding_rx.next() => match forwarding_data {
+                    Some(forwarding_data) => self.insert_packet(forwarding_data),
+                    None => {
+                        // all connection handlers and the listener are gone
+                        error!("delay forwarder channel got closed - stopping forwarding");
+                        return;
+                    }
+                },
+                // empty DelayQueue returns `None` immediately, so only poll it if it's not empty
+                expired = self.delay_queue.next(), if !self.delay_queue.is_empty() => {
+                    match expired {
+                        // sending might have to wait for the connection to the next hop
+                        // to be established, which shouldn't hold up the other packets
+                        Some(Ok(expired)) => {
+                            tokio::spawn(DelayForwarder::forward_packet(expired.into_inner()));
+                        }
+                        Some(Err(e)) => error!("delay queue timer failure - {:?}", e),
+                        None => (),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::persistance::pathfinder::MixNodePathfinder;
+use clap::ArgMatches;
+use crypto::encryption::{x25519, MixnetEncryptionKeyPair};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+use pemstore::pemstore::{new_passphrase, PemStore};
+use std::process;
+
+pub fn execute(matches: &ArgMatches) {
+    println!("Initialising mixnode...");
+
+    let id = matches.value_of("id").unwrap().to_string(); // required for now
+    let pathfinder = MixNodePathfinder::new(id);
+
+    println!("Writing keypairs to {:?}...", pathfinder.config_dir);
+    // the identity key is only used for signing while the sphinx key is used for processing packets
+    let identity_keys = ed25519::KeyPair::new();
+    let sphinx_keys = x25519::KeyPair::new();
+    let mut pem_store = PemStore::new(pathfinder);
+    if matches.is_present("encrypt") {
+        match new_passphrase(matches.value_of("passphrase-file")) {
+            Ok(passphrase) => pem_store = pem_store.with_passphrase(passphrase),
+            Err(err) => {
+                eprintln!("Failed to obtain the passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(err) = pem_store
+        .write_identity(identity_keys)
+        .and_then(|_| pem_store.write_encryption(sphinx_keys))
+    {
+        eprintln!("Failed to write the mixnode keys: {}", err);
+        process::exit(1);
+    }
+
+    println!("Mixnode configuration completed.\n\n\n")
+}
@@ -1,10 +1,11 @@
+use crate::node::MixProcessingError;
 use directory_client::metrics::MixMetric;
 use directory_client::requests::metrics_mixes_post::MetricsMixPoster;
 use directory_client::DirectoryClient;
 use futures::channel::mpsc;
 use futures::lock::Mutex;
 use futures::StreamExt;
-use log::{debug, error};
+use log::{debug, error, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
@@ -15,6 +16,7 @@ const METRICS_INTERVAL: u64 = 3;
 pub struct MetricsReporter {
     received: u64,
     sent: HashMap<String, u64>,
+    bad_packets: HashMap<MixProcessingError, u64>,
 }
 
 impl MetricsReporter {
@@ -22,6 +24,7 @@ impl MetricsReporter {
         MetricsReporter {
             received: 0,
             sent: HashMap::new(),
+            bad_packets: HashMap::new(),
         }
     }
 
@@ -58,16 +61,35 @@ impl MetricsReporter {
         }
     }
 
+    async fn increment_bad_packets_metrics(
+        metrics: Arc<Mutex<MetricsReporter>>,
+        processing_error: MixProcessingError,
+    ) {
+        let mut unlocked = metrics.lock().await;
+        let error_count = unlocked.bad_packets.entry(processing_error).or_insert(0);
+        *error_count += 1;
+    }
+
+    pub(crate) async fn run_bad_packets_metrics_control(
+        metrics: Arc<Mutex<MetricsReporter>>,
+        mut rx: mpsc::Receiver<MixProcessingError>,
+    ) {
+        while let Some(processing_error) = rx.next().await {
+            MetricsReporter::increment_bad_packets_metrics(metrics.clone(), processing_error).await;
+        }
+    }
+
     async fn acquire_and_reset_metrics(
         metrics: Arc<Mutex<MetricsReporter>>,
-    ) -> (u64, HashMap<String, u64>) {
+    ) -> (u64, HashMap<String, u64>, HashMap<MixProcessingError, u64>) {
         let mut unlocked = metrics.lock().await;
         let received = unlocked.received;
 
         let sent = std::mem::replace(&mut unlocked.sent, HashMap::new());
+        let bad_packets = std::mem::replace(&mut unlocked.bad_packets, HashMap::new());
         unlocked.received = 0;
 
-        (received, sent)
+        (received, sent, bad_packets)
     }
 
     pub(crate) async fn run_metrics_sender(
@@ -79,9 +101,18 @@ impl MetricsReporter {
         let directory_client = directory_client::Client::new(cfg);
         loop {
             tokio::time::delay_for(delay_duration).await;
-            let (received, sent) =
+            let (received, sent, bad_packets) =
                 MetricsReporter::acquire_and_reset_metrics(metrics.clone()).await;
 
+            // directory server does not know anything about dropped packets (yet),
+            // so for now just make the node operator aware of them
+            if !bad_packets.is_empty() {
+                warn!(
+                    "dropped malformed packets in the last {}s: {:?}",
+                    METRICS_INTERVAL, bad_packets
+                );
+            }
+
             match directory_client.metrics_post.post(&MixMetric {
                 pub_key: pub_key_str.clone(),
                 received,
@@ -1,20 +1,26 @@
 use crate::mix_peer::MixPeer;
 use crate::node;
+use crate::node::delay_forwarder::DelayForwarder;
 use crate::node::metrics::MetricsReporter;
+use crypto::identity::ed25519;
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
 use futures::channel::mpsc;
 use futures::lock::Mutex;
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 use log::*;
+use mix_client::MixClient;
 use sphinx::header::delays::Delay as SphinxDelay;
+use sphinx::route::NodeAddressBytes;
 use sphinx::{ProcessedPacket, SphinxPacket};
+use sphinx_framing::codec::SphinxCodec;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::prelude::*;
 use tokio::runtime::Runtime;
+use tokio_util::codec::FramedRead;
 
+mod delay_forwarder;
+pub mod init;
 mod metrics;
 mod presence;
 pub mod runner;
@@ -22,7 +28,10 @@ pub mod runner;
 pub struct Config {
     announce_address: String,
     directory_server: String,
+    // only used for signing our presence
+    identity_keypair: ed25519::KeyPair,
     layer: usize,
+    max_bad_packets: usize,
     public_key: MontgomeryPoint,
     secret_key: Scalar,
     socket_address: SocketAddr,
@@ -35,7 +44,7 @@ impl Config {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MixProcessingError {
     SphinxRecoveryError,
     ReceivedFinalHopError,
@@ -55,6 +64,7 @@ struct ForwardingData {
     delay: SphinxDelay,
     recipient: MixPeer,
     sent_metrics_tx: mpsc::Sender<String>,
+    mix_client: MixClient,
 }
 
 // TODO: this will need to be changed if MixPeer will live longer than our Forwarding Data
@@ -64,12 +74,14 @@ impl ForwardingData {
         delay: SphinxDelay,
         recipient: MixPeer,
         sent_metrics_tx: mpsc::Sender<String>,
+        mix_client: MixClient,
     ) -> Self {
         ForwardingData {
             packet,
             delay,
             recipient,
             sent_metrics_tx,
+            mix_client,
         }
     }
 }
@@ -79,6 +91,10 @@ struct ProcessingData {
     secret_key: Scalar,
     received_metrics_tx: mpsc::Sender<()>,
     sent_metrics_tx: mpsc::Sender<String>,
+    bad_packets_metrics_tx: mpsc::Sender<MixProcessingError>,
+    delay_forwarding_tx: mpsc::UnboundedSender<ForwardingData>,
+    // shared by all connections so that packets to the same next hop would reuse the same connection
+    mix_client: MixClient,
 }
 
 impl ProcessingData {
@@ -86,11 +102,17 @@ impl ProcessingData {
         secret_key: Scalar,
         received_metrics_tx: mpsc::Sender<()>,
         sent_metrics_tx: mpsc::Sender<String>,
+        bad_packets_metrics_tx: mpsc::Sender<MixProcessingError>,
+        delay_forwarding_tx: mpsc::UnboundedSender<ForwardingData>,
+        mix_client: MixClient,
     ) -> Self {
         ProcessingData {
             secret_key,
             received_metrics_tx,
             sent_metrics_tx,
+            bad_packets_metrics_tx,
+            delay_forwarding_tx,
+            mix_client,
         }
     }
 
@@ -102,6 +124,19 @@ impl ProcessingData {
 struct PacketProcessor;
 
 impl PacketProcessor {
+    fn unwrap_sphinx_packet(
+        packet_data: &[u8],
+        secret_key: Scalar,
+    ) -> Result<(SphinxPacket, NodeAddressBytes, SphinxDelay), MixProcessingError> {
+        let packet = SphinxPacket::from_bytes(packet_data.to_vec())?;
+        match packet.process(secret_key) {
+            ProcessedPacket::ProcessedPacketForwardHop(packet, address, delay) => {
+                Ok((packet, address, delay))
+            }
+            _ => Err(MixProcessingError::ReceivedFinalHopError),
+        }
+    }
+
     pub async fn process_sphinx_data_packet(
         packet_data: &[u8],
         processing_data: Arc<Mutex<ProcessingData>>,
@@ -112,14 +147,16 @@ impl PacketProcessor {
 
         received_sender.send(()).await.unwrap();
 
-        let packet = SphinxPacket::from_bytes(packet_data.to_vec())?;
-        let (next_packet, next_hop_address, delay) =
-            match packet.process(processing_data.secret_key) {
-                ProcessedPacket::ProcessedPacketForwardHop(packet, address, delay) => {
-                    (packet, address, delay)
-                }
-                _ => return Err(MixProcessingError::ReceivedFinalHopError),
-            };
+        let processing_result =
+            PacketProcessor::unwrap_sphinx_packet(packet_data, processing_data.secret_key);
+        let (next_packet, next_hop_address, delay) = match processing_result {
+            Ok(unwrapped_packet) => unwrapped_packet,
+            Err(err) => {
+                let mut bad_packets_sender = processing_data.bad_packets_metrics_tx.clone();
+                bad_packets_sender.send(err).await.unwrap();
+                return Err(err);
+            }
+        };
 
         let next_mix = MixPeer::new(next_hop_address);
 
@@ -128,34 +165,10 @@ impl PacketProcessor {
             delay,
             next_mix,
             processing_data.sent_metrics_tx.clone(),
+            processing_data.mix_client.clone(),
         );
         Ok(fwd_data)
     }
-
-    async fn wait_and_forward(mut forwarding_data: ForwardingData) {
-        let delay_duration = Duration::from_nanos(forwarding_data.delay.get_value());
-        tokio::time::delay_for(delay_duration).await;
-        forwarding_data
-            .sent_metrics_tx
-            .send(forwarding_data.recipient.to_string())
-            .await
-            .unwrap();
-
-        trace!("RECIPIENT: {:?}", forwarding_data.recipient);
-        match forwarding_data
-            .recipient
-            .send(forwarding_data.packet.to_bytes())
-            .await
-        {
-            Ok(()) => (),
-            Err(e) => {
-                warn!(
-                    "failed to write bytes to next mix peer. err = {:?}",
-                    e.to_string()
-                );
-            }
-        }
-    }
 }
 
 // the MixNode will live for whole duration of this program
@@ -164,6 +177,7 @@ pub struct MixNode {
     network_address: SocketAddr,
     public_key: MontgomeryPoint,
     secret_key: Scalar,
+    max_bad_packets: usize,
     // TODO: use it later to enforce forward travel
     //    layer: usize,
 }
@@ -175,46 +189,62 @@ impl MixNode {
             network_address: config.socket_address,
             secret_key: config.secret_key,
             public_key: config.public_key,
+            max_bad_packets: config.max_bad_packets,
             //            layer: config.layer,
         }
     }
 
     async fn process_socket_connection(
-        mut socket: tokio::net::TcpStream,
+        socket: tokio::net::TcpStream,
         processing_data: Arc<Mutex<ProcessingData>>,
+        max_bad_packets: usize,
     ) {
-        // NOTE: processing_data is copied here!!
-        let mut buf = [0u8; sphinx::PACKET_SIZE];
-
-        // In a loop, read data from the socket and write the data back.
-        loop {
-            match socket.read(&mut buf).await {
-                // socket closed
-                Ok(n) if n == 0 => {
-                    trace!("Remote connection closed.");
-                    return;
-                }
-                Ok(_) => {
-                    let fwd_data = PacketProcessor::process_sphinx_data_packet(
-                        buf.as_ref(),
+        // every frame read from the socket contains exactly one sphinx packet, regardless of
+        // how the data got split or coalesced by the underlying tcp stream
+        let mut framed_socket = FramedRead::new(socket, SphinxCodec);
+        let mut bad_packets = 0;
+        let delay_forwarding_tx = processing_data.lock().await.delay_forwarding_tx.clone();
+
+        while let Some(framed_packet) = framed_socket.next().await {
+            match framed_packet {
+                Ok(framed_packet) => {
+                    match PacketProcessor::process_sphinx_data_packet(
+                        framed_packet.packet_bytes(),
                         processing_data.clone(),
                     )
                     .await
-                    .unwrap();
-                    PacketProcessor::wait_and_forward(fwd_data).await;
+                    {
+                        // the packet is going to be held in the delay queue; we don't wait for it
+                        Ok(fwd_data) => {
+                            if delay_forwarding_tx.unbounded_send(fwd_data).is_err() {
+                                error!("delay forwarder is gone - can't forward any packets");
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            // the packet is just dropped, but if the remote keeps sending us
+                            // garbage, there's no point in talking to it any longer
+                            bad_packets += 1;
+                            debug!("failed to process sphinx packet; err = {:?}", e);
+                            if bad_packets >= max_bad_packets {
+                                warn!(
+                                    "received {} bad packets on the connection - closing it",
+                                    bad_packets
+                                );
+                                return;
+                            }
+                        }
+                    }
                 }
                 Err(e) => {
-                    warn!("failed to read from socket; err = {:?}", e);
+                    // we can't recover the frame boundaries anymore so just drop the connection
+                    warn!("failed to read packet from socket; err = {:?}", e);
                     return;
                 }
-            };
-
-            // Write the some data back
-            if let Err(e) = socket.write_all(b"foomp").await {
-                warn!("failed to write reply to socket; err = {:?}", e);
-                return;
             }
         }
+
+        trace!("Remote connection closed.");
     }
 
     pub fn start(&self, config: node::Config) -> Result<(), Box<dyn std::error::Error>> {
@@ -223,10 +253,12 @@ impl MixNode {
 
         let (received_tx, received_rx) = mpsc::channel(1024);
         let (sent_tx, sent_rx) = mpsc::channel(1024);
+        let (bad_packets_tx, bad_packets_rx) = mpsc::channel(1024);
 
-        let directory_cfg = directory_client::Config {
-            base_url: self.directory_server.clone(),
-        };
+        let (delay_forwarder, delay_forwarding_tx) = DelayForwarder::new();
+        rt.spawn(delay_forwarder.run());
+
+        let directory_cfg = directory_client::Config::new(self.directory_server.clone());
         let pub_key_str =
             base64::encode_config(&self.public_key.to_bytes().to_vec(), base64::URL_SAFE);
 
@@ -244,6 +276,10 @@ impl MixNode {
             metrics.clone(),
             sent_rx,
         ));
+        rt.spawn(MetricsReporter::run_bad_packets_metrics_control(
+            metrics.clone(),
+            bad_packets_rx,
+        ));
         rt.spawn(MetricsReporter::run_metrics_sender(
             metrics,
             directory_cfg,
@@ -253,15 +289,28 @@ impl MixNode {
         // Spawn the root task
         rt.block_on(async {
             let mut listener = tokio::net::TcpListener::bind(self.network_address).await?;
-            let processing_data =
-                ProcessingData::new(self.secret_key, received_tx, sent_tx).add_arc_mutex();
+            let processing_data = ProcessingData::new(
+                self.secret_key,
+                received_tx,
+                sent_tx,
+                bad_packets_tx,
+                delay_forwarding_tx,
+                MixClient::new(),
+            )
+            .add_arc_mutex();
+            let max_bad_packets = self.max_bad_packets;
 
             loop {
                 let (socket, _) = listener.accept().await?;
 
                 let thread_processing_data = processing_data.clone();
                 tokio::spawn(async move {
-                    MixNode::process_socket_connection(socket, thread_processing_data).await;
+                    MixNode::process_socket_connection(
+                        socket,
+                        thread_processing_data,
+                        max_bad_packets,
+                    )
+                    .await;
                 });
             }
         })
@@ -1,5 +1,6 @@
 use crate::node;
-use directory_client::presence::MixNodePresence;
+use crypto::identity::MixnetIdentityKeyPair;
+use directory_client::presence::{MixNodePresence, SignedPresence};
 use directory_client::requests::presence_mixnodes_post::PresenceMixNodesPoster;
 use directory_client::DirectoryClient;
 use log::{debug, error};
@@ -12,17 +13,19 @@ pub struct Notifier {
 
 impl Notifier {
     pub fn new(node_config: &node::Config) -> Notifier {
-        let config = directory_client::Config {
-            base_url: node_config.directory_server.clone(),
-        };
+        let config = directory_client::Config::new(node_config.directory_server.clone());
         let net_client = directory_client::Client::new(config);
-        let presence = MixNodePresence {
+        let mut presence = MixNodePresence {
             host: node_config.announce_address.clone(),
             pub_key: node_config.public_key_string(),
+            identity_key: node_config.identity_keypair.public_key().to_b64_string(),
             layer: node_config.layer as u64,
             last_seen: 0,
             version: env!("CARGO_PKG_VERSION").to_string(),
+            signature: String::new(),
         };
+        // the presence never changes so it only needs to be signed once
+        presence.sign(node_config.identity_keypair.private_key());
         Notifier {
             net_client,
             presence,
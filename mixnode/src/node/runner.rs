@@ -1,8 +1,16 @@
 use crate::banner;
+use crate::config::persistance::pathfinder::MixNodePathfinder;
 use crate::node;
 use crate::node::MixNode;
 use clap::ArgMatches;
+use crypto::encryption::{x25519, MixnetEncryptionKeyPair};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+use pemstore::pemstore::PemStore;
 use std::net::ToSocketAddrs;
+use std::process;
+
+// number of malformed packets a single connection can send us before it gets closed
+const DEFAULT_MAX_BAD_PACKETS: usize = 10;
 
 fn print_binding_warning(address: &str) {
     println!("\n##### WARNING #####");
@@ -33,6 +41,25 @@ pub fn start(matches: &ArgMatches) {
     mix.start(config).unwrap();
 }
 
+// Loads both the identity and the sphinx keys written by `init` or exits with a message
+// explaining what the user should do about it.
+fn load_keys(id: &str, passphrase_file: Option<&str>) -> (ed25519::KeyPair, x25519::KeyPair) {
+    match PemStore::new(MixNodePathfinder::new(id.to_string()))
+        .with_non_interactive_passphrase(passphrase_file)
+        .and_then(|pem_store| Ok((pem_store.read_identity()?, pem_store.read_encryption()?)))
+    {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("Failed to load the mixnode keys: {}", err);
+            eprintln!(
+                "Has the mixnode been initialised? Run `nym-mixnode init --id {}` first.",
+                id
+            );
+            process::exit(1);
+        }
+    }
+}
+
 fn new_config(matches: &ArgMatches) -> node::Config {
     let host = matches.value_of("host").unwrap();
     if host == "localhost" || host == "127.0.0.1" || host == "0.0.0.0" {
@@ -69,7 +96,23 @@ fn new_config(matches: &ArgMatches) -> node::Config {
 
     let announce_address = format!("{}:{}", announce_host, announce_port);
 
-    let (secret_key, public_key) = sphinx::crypto::keygen();
+    let id = matches.value_of("id").unwrap().to_string();
+    let (identity_keys, sphinx_keys) = load_keys(&id, matches.value_of("passphrase-file"));
+    println!(
+        "Identity key: {}",
+        identity_keys.public_key().to_b64_string()
+    );
+    let secret_key = sphinx_keys.private_key().0;
+    let public_key = sphinx_keys.public_key().0;
+
+    let max_bad_packets = match matches
+        .value_of("max_bad_packets")
+        .map(|max| max.parse::<usize>())
+        .unwrap_or(Ok(DEFAULT_MAX_BAD_PACKETS))
+    {
+        Ok(n) => n,
+        Err(err) => panic!("Invalid max bad packets value provided - {:?}", err),
+    };
 
     let directory_server = matches
         .value_of("directory")
@@ -78,7 +121,9 @@ fn new_config(matches: &ArgMatches) -> node::Config {
 
     node::Config {
         directory_server,
+        identity_keypair: identity_keys,
         layer,
+        max_bad_packets,
         public_key,
         socket_address,
         announce_address,
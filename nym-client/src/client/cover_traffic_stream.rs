@@ -1,27 +1,29 @@
 use crate::client::mix_traffic::MixMessage;
-use crate::client::LOOP_COVER_AVERAGE_DELAY;
+use crate::client::topology_control::TopologyAccessor;
 use futures::channel::mpsc;
 use log::{info, trace};
 use sphinx::route::Destination;
 use std::time::Duration;
-use topology::NymTopology;
+use topology::MixProviderNode;
 
-pub(crate) async fn start_loop_cover_traffic_stream<T>(
+pub(crate) async fn start_loop_cover_traffic_stream(
     tx: mpsc::UnboundedSender<MixMessage>,
     our_info: Destination,
-    topology: T,
-) where
-    T: NymTopology,
-{
+    our_provider: MixProviderNode,
+    topology_accessor: TopologyAccessor,
+    average_delay: f64,
+) {
     info!("Starting loop cover traffic stream");
     loop {
         trace!("next cover message!");
-        let delay = mix_client::poisson::sample(LOOP_COVER_AVERAGE_DELAY);
+        let delay = mix_client::poisson::sample(average_delay);
         let delay_duration = Duration::from_secs_f64(delay);
         tokio::time::delay_for(delay_duration).await;
+        let topology = topology_accessor.get_current_topology_clone().await;
         let cover_message = mix_client::packet::loop_cover_message(
             our_info.address,
             our_info.identifier,
+            our_provider.clone(),
             &topology,
         );
 
@@ -0,0 +1,86 @@
+use mix_client::packet::ReliableMessageId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how long the final status of a reliable message is kept around for the sockets to query it
+const FINISHED_STATUS_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryStatus {
+    // waiting for the recipient to acknowledge the message, possibly after retransmissions
+    Pending,
+    Delivered,
+    // the message was not acknowledged even after all of the retransmissions
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn is_finished(self) -> bool {
+        self != DeliveryStatus::Pending
+    }
+}
+
+struct TrackedMessage {
+    status: DeliveryStatus,
+    updated_at: Instant,
+}
+
+// Delivery statuses of the reliable messages sent through the sockets. They are updated by
+// OutQueueControl as the acks arrive or the retransmissions run out.
+#[derive(Clone)]
+pub struct DeliveryTracker {
+    inner: Arc<Mutex<HashMap<ReliableMessageId, TrackedMessage>>>,
+}
+
+impl DeliveryTracker {
+    pub(crate) fn new() -> Self {
+        DeliveryTracker {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn update(&self, id: ReliableMessageId, status: DeliveryStatus) {
+        let mut messages = self.inner.lock().unwrap();
+        // finished messages are only cleaned up here as that's the only place where they
+        // could start piling up
+        messages.retain(|_, message| {
+            !message.status.is_finished()
+                || message.updated_at.elapsed() < FINISHED_STATUS_RETENTION
+        });
+        messages.insert(
+            id,
+            TrackedMessage {
+                status,
+                updated_at: Instant::now(),
+            },
+        );
+    }
+
+    // None if the message is unknown or its status has already been forgotten
+    pub fn status(&self, id: ReliableMessageId) -> Option<DeliveryStatus> {
+        self.inner
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|message| message.status)
+    }
+}
+
+#[cfg(test)]
+mod delivery_tracker {
+    use super::*;
+
+    #[test]
+    fn reports_most_recent_status() {
+        let tracker = DeliveryTracker::new();
+        assert_eq!(None, tracker.status(42));
+
+        tracker.update(42, DeliveryStatus::Pending);
+        assert_eq!(Some(DeliveryStatus::Pending), tracker.status(42));
+
+        tracker.update(42, DeliveryStatus::Delivered);
+        assert_eq!(Some(DeliveryStatus::Delivered), tracker.status(42));
+        assert_eq!(None, tracker.status(43));
+    }
+}
@@ -1,128 +1,109 @@
-use crate::built_info;
+use crate::client::delivery_status::DeliveryTracker;
 use crate::client::mix_traffic::MixTrafficController;
 use crate::client::received_buffer::ReceivedMessagesBuffer;
+use crate::client::reply_tokens::{AckTokenPool, ReplyTokenIssuer};
+use crate::client::topology_control::TopologyControl;
+use crate::config::Config;
 use crate::sockets::tcp;
 use crate::sockets::ws;
-use directory_client::presence::Topology;
+use crypto::identity::ed25519;
 use futures::channel::mpsc;
 use futures::join;
 use log::*;
+use mix_client::fragmentation;
+use mix_client::packet::{
+    MessageEnvelope, ReliableMessageId, ReplyToken, MAXIMUM_ENVELOPE_OVERHEAD,
+    REPLY_ENVELOPE_OVERHEAD,
+};
+use provider_client::ProviderClient;
+use serde::{Deserialize, Serialize};
 use sfw_provider_requests::AuthToken;
 use sphinx::route::{Destination, DestinationAddressBytes};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 use topology::NymTopology;
 
 mod cover_traffic_stream;
+pub mod delivery_status;
 mod mix_traffic;
 mod provider_poller;
 mod real_traffic_stream;
 pub mod received_buffer;
-
-// TODO: all of those constants should probably be moved to config file
-const LOOP_COVER_AVERAGE_DELAY: f64 = 0.5;
-// seconds
-const MESSAGE_SENDING_AVERAGE_DELAY: f64 = 0.5;
-//  seconds;
-const FETCH_MESSAGES_DELAY: f64 = 1.0; // seconds;
-
+pub mod reply_tokens;
+pub mod topology_control;
+
+// the longest message that can be sent, with space left for the envelope
+pub const MAXIMUM_MESSAGE_LENGTH: usize =
+    fragmentation::MAXIMUM_MESSAGE_LENGTH - MAXIMUM_ENVELOPE_OVERHEAD;
+// replies can't be fragmented as the reply token can only be used for a single packet
+pub const MAXIMUM_REPLY_LENGTH: usize =
+    fragmentation::MAXIMUM_FRAGMENT_DATA_LENGTH - REPLY_ENVELOPE_OVERHEAD;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum SocketType {
     TCP,
     WebSocket,
     None,
 }
 
+// whether we periodically ask the provider for our messages or have it push them to us
+pub enum MessageRetrievalMode {
+    Poll,
+    Push,
+}
+
 pub struct NymClient {
     // to be replaced by something else I guess
     address: DestinationAddressBytes,
+    // proves to the provider that we own the above address
+    identity_key: ed25519::PrivateKey,
 
     // to be used by "send" function or socket, etc
     pub input_tx: mpsc::UnboundedSender<InputMessage>,
 
     input_rx: mpsc::UnboundedReceiver<InputMessage>,
-    socket_listening_address: SocketAddr,
-    directory: String,
-    auth_token: Option<AuthToken>,
-    socket_type: SocketType,
+    config: Config,
+    auth_token: AuthToken,
+    retrieval_mode: MessageRetrievalMode,
 }
 
-// TODO: this will be moved into module responsible for refreshing topology
 #[derive(Debug)]
-enum TopologyError {
-    HealthCheckError,
-    NoValidPathsError,
+pub enum InputMessage {
+    // message sent directly to the address of its recipient
+    Forward(Destination, MessageEnvelope),
+    // same as above, but retransmitted until the recipient acknowledges it. its delivery
+    // status is reported to the DeliveryTracker under the given id
+    ReliableForward(ReliableMessageId, Destination, MessageEnvelope),
+    // reply sent with the reply token the recipient has attached to one of its messages
+    Reply(ReplyToken, Vec<u8>),
+    // acknowledgement of a reliable message we have received, sent with its ack token
+    Ack(ReplyToken, ReliableMessageId),
 }
 
-#[derive(Debug)]
-pub struct InputMessage(pub Destination, pub Vec<u8>);
-
 impl NymClient {
     pub fn new(
+        config: Config,
         address: DestinationAddressBytes,
-        socket_listening_address: SocketAddr,
-        directory: String,
-        auth_token: Option<AuthToken>,
-        socket_type: SocketType,
+        identity_key: ed25519::PrivateKey,
+        auth_token: AuthToken,
+        retrieval_mode: MessageRetrievalMode,
     ) -> Self {
         let (input_tx, input_rx) = mpsc::unbounded::<InputMessage>();
 
         NymClient {
             address,
+            identity_key,
             input_tx,
             input_rx,
-            socket_listening_address,
-            directory,
+            config,
             auth_token,
-            socket_type,
+            retrieval_mode,
         }
     }
 
-    // TODO: this will be moved into module responsible for refreshing topology
-    async fn get_compatible_topology(&self) -> Result<Topology, TopologyError> {
-        let score_threshold = 0.0;
-        info!("Trying to obtain valid, healthy, topology");
-
-        let full_topology = Topology::new(self.directory.clone());
-
-        // run a healthcheck to determine healthy-ish nodes:
-        // this is a temporary solution as the healthcheck will eventually be moved to validators
-        let healthcheck_config = healthcheck::config::HealthCheck {
-            directory_server: self.directory.clone(),
-            // those are literally unrelevant when running single check
-            interval: 100000.0,
-            resolution_timeout: 5.0,
-            num_test_packets: 2,
-        };
-        let healthcheck = healthcheck::HealthChecker::new(healthcheck_config);
-        let healthcheck_result = healthcheck.do_check().await;
-
-        let healthcheck_scores = match healthcheck_result {
-            Err(err) => {
-                error!("Error while performing the healtcheck: {:?}", err);
-                return Err(TopologyError::HealthCheckError);
-            }
-            Ok(scores) => scores,
-        };
-
-        let healthy_topology =
-            healthcheck_scores.filter_topology_by_score(&full_topology, score_threshold);
-
-        // for time being assume same versioning, i.e. if client is running X.Y.Z,
-        // we're expecting mixes, providers and coconodes to also be running X.Y.Z
-        let versioned_healthy_topology = healthy_topology.filter_node_versions(
-            built_info::PKG_VERSION,
-            built_info::PKG_VERSION,
-            built_info::PKG_VERSION,
-        );
-
-        // make sure you can still send a packet through the network:
-        if !versioned_healthy_topology.can_construct_path_through() {
-            return Err(TopologyError::NoValidPathsError);
-        }
-
-        Ok(versioned_healthy_topology)
-    }
-
     pub fn start(self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting nym client");
         let mut rt = Runtime::new()?;
@@ -139,44 +120,80 @@ impl NymClient {
         let (poller_input_tx, poller_input_rx) = mpsc::unbounded();
 
         // received_messages_buffer_output_tx is the transmitter for *REQUESTS* for messages contained in ReceivedMessagesBuffer - used by sockets
-        // the requests contain a oneshot channel to send a reply on
+        // the requests contain either a oneshot channel to send a reply on or a channel to push all new messages on
         // received_messages_buffer_output_rx is the received for the said requests - used by ReceivedMessagesBuffer
         let (received_messages_buffer_output_tx, received_messages_buffer_output_rx) =
             mpsc::unbounded();
 
+        // acks_tx is the transmitter of acks of our reliable messages - used by ReceivedMessagesBuffer
+        // acks_rx is the receiver for said acks - used by OutQueueControl to stop retransmitting them
+        let (acks_tx, acks_rx) = mpsc::unbounded();
+
+        // shared between OutQueueControl updating the statuses and the sockets reporting them
+        let delivery_tracker = DeliveryTracker::new();
+
         // get initial topology; already filtered by health and version
-        let initial_topology = match rt.block_on(self.get_compatible_topology()) {
-            Ok(topology) => topology,
+        let topology_controller = match rt.block_on(TopologyControl::new(
+            self.config.client.directory_server.clone(),
+            self.config.client.signature_policy,
+            Duration::from_secs_f64(self.config.traffic.topology_refresh_rate),
+        )) {
+            Ok(topology_control) => topology_control,
             Err(err) => {
                 panic!("Failed to obtain initial network topology: {:?}", err);
             }
         };
-
-        // this is temporary and assumes there exists only a single provider.
-        let provider_client_listener_address: SocketAddr = initial_topology
+        let topology_accessor = topology_controller.get_accessor();
+        let initial_topology = rt.block_on(topology_accessor.get_current_topology_clone());
+
+        // the provider was chosen (and registered with) during `init`
+        let provider_id = &self
+            .config
+            .provider
+            .as_ref()
+            .expect("The client has not been registered with any provider")
+            .id;
+        let provider = initial_topology
             .get_mix_provider_nodes()
-            .first()
-            .expect("Could not get a provider from the supplied network topology, are you using the right directory server?")
-            .client_listener;
-
-        let mut provider_poller = provider_poller::ProviderPoller::new(
+            .into_iter()
+            .find(|provider| &provider.pub_key == provider_id)
+            .unwrap_or_else(|| panic!("Our provider {} is not present in the network topology, are you using the right directory server?", provider_id));
+
+        // the same client (and hence the same connection) is used for retrieving our messages
+        // and for registering reply tokens handed out through the sockets
+        let provider_client = Arc::new(ProviderClient::new(
+            provider.client_listener,
+            self.identity_key,
+            Some(self.auth_token),
+        ));
+        let reply_token_issuer =
+            ReplyTokenIssuer::new(provider_client.clone(), provider.get_pub_key_bytes());
+
+        // ack tokens of our reliable messages are registered ahead of time, so that sending
+        // them would never have to wait for the provider
+        let ack_token_pool = AckTokenPool::new(reply_token_issuer.clone());
+        let ack_tokens = ack_token_pool.get_accessor();
+
+        let provider_poller = provider_poller::ProviderPoller::new(
             poller_input_tx,
-            provider_client_listener_address,
-            self.address,
-            self.auth_token,
+            provider_client,
+            self.retrieval_mode,
+            self.config.traffic.fetch_messages_delay,
         );
 
-        // registration
-        if let Err(err) = rt.block_on(provider_poller.perform_initial_registration()) {
-            panic!("Failed to perform initial registration: {:?}", err);
-        };
-
         // setup all of futures for the components running on the client
 
+        // future periodically refreshing the network topology so that the rest of the components
+        // would always construct routes through the most recent set of healthy nodes
+        let topology_refresher_future = rt.spawn(topology_controller.run_refresher());
+
+        // future keeping enough ack tokens registered with the provider
+        let ack_token_refiller_future = rt.spawn(ack_token_pool.run_refiller());
+
         // buffer controlling all messages fetched from provider
         // required so that other components would be able to use them (say the websocket)
         let received_messages_buffer_controllers_future = rt.spawn(
-            ReceivedMessagesBuffer::new()
+            ReceivedMessagesBuffer::new(self.input_tx.clone(), acks_tx)
                 .start_controllers(poller_input_rx, received_messages_buffer_output_rx),
         );
 
@@ -189,13 +206,20 @@ impl NymClient {
             rt.spawn(cover_traffic_stream::start_loop_cover_traffic_stream(
                 mix_tx.clone(),
                 Destination::new(self.address, Default::default()),
-                initial_topology.clone(),
+                provider.clone(),
+                topology_accessor.clone(),
+                self.config.traffic.loop_cover_average_delay,
             ));
 
         // cloning arguments required by OutQueueControl; required due to move
-        let topology_clone = initial_topology.clone();
+        let topology_accessor_clone = topology_accessor.clone();
+        let our_provider = provider.clone();
         let self_address = self.address;
         let input_rx = self.input_rx;
+        let message_sending_average_delay = self.config.traffic.message_sending_average_delay;
+        let average_packet_delay = self.config.traffic.average_packet_delay;
+        let fetch_messages_delay = self.config.traffic.fetch_messages_delay;
+        let delivery_tracker_clone = delivery_tracker.clone();
 
         // future constantly pumping traffic at some specified average rate
         // if a real message is available on 'input_rx' that might have been received from say
@@ -205,36 +229,49 @@ impl NymClient {
             real_traffic_stream::OutQueueControl::new(
                 mix_tx,
                 input_rx,
+                acks_rx,
+                ack_tokens,
+                delivery_tracker_clone,
                 Destination::new(self_address, Default::default()),
-                topology_clone,
+                our_provider,
+                topology_accessor_clone,
+                message_sending_average_delay,
+                average_packet_delay,
+                fetch_messages_delay,
             )
             .run_out_queue_control()
             .await
         });
 
-        // future constantly trying to fetch any received messages from the provider
+        // future constantly trying to fetch (or receiving pushed) messages from the provider
         // the received messages are sent to ReceivedMessagesBuffer to be available to rest of the system
-        let provider_polling_future = rt.spawn(provider_poller.start_provider_polling());
+        let provider_polling_future = rt.spawn(provider_poller.run());
 
         // a temporary workaround for starting socket listener of specified type
         // in the future the actual socket handler should start THIS client instead
-        match self.socket_type {
+        let socket_listening_address =
+            SocketAddr::from(([127, 0, 0, 1], self.config.socket.listening_port));
+        match self.config.socket.socket_type {
             SocketType::WebSocket => {
                 rt.spawn(ws::start_websocket(
-                    self.socket_listening_address,
+                    socket_listening_address,
                     self.input_tx,
                     received_messages_buffer_output_tx,
                     self.address,
-                    initial_topology,
+                    topology_accessor,
+                    reply_token_issuer,
+                    delivery_tracker,
                 ));
             }
             SocketType::TCP => {
                 rt.spawn(tcp::start_tcpsocket(
-                    self.socket_listening_address,
+                    socket_listening_address,
                     self.input_tx,
                     received_messages_buffer_output_tx,
                     self.address,
-                    initial_topology,
+                    topology_accessor,
+                    reply_token_issuer,
+                    delivery_tracker,
                 ));
             }
             SocketType::None => (),
@@ -247,6 +284,8 @@ impl NymClient {
                 loop_cover_traffic_future,
                 out_queue_control_future,
                 provider_polling_future,
+                topology_refresher_future,
+                ack_token_refiller_future,
             );
 
             assert!(
@@ -255,6 +294,8 @@ impl NymClient {
                     && future_results.2.is_ok()
                     && future_results.3.is_ok()
                     && future_results.4.is_ok()
+                    && future_results.5.is_ok()
+                    && future_results.6.is_ok()
             );
         });
 
@@ -1,86 +1,143 @@
-use crate::client::FETCH_MESSAGES_DELAY;
+use crate::client::MessageRetrievalMode;
 use futures::channel::mpsc;
 use log::{debug, error, info, trace, warn};
-use provider_client::ProviderClientError;
-use sfw_provider_requests::AuthToken;
-use sphinx::route::DestinationAddressBytes;
-use std::net::SocketAddr;
+use provider_client::{ProviderClient, ProviderClientError};
+use sfw_provider_requests::responses::PullResponse;
+use std::sync::Arc;
 use std::time::Duration;
 
 pub(crate) struct ProviderPoller {
-    provider_client: provider_client::ProviderClient,
+    provider_client: Arc<ProviderClient>,
     poller_tx: mpsc::UnboundedSender<Vec<Vec<u8>>>,
+    retrieval_mode: MessageRetrievalMode,
+    fetch_delay: Duration,
 }
 
 impl ProviderPoller {
     pub(crate) fn new(
         poller_tx: mpsc::UnboundedSender<Vec<Vec<u8>>>,
-        provider_client_listener_address: SocketAddr,
-        client_address: DestinationAddressBytes,
-        auth_token: Option<AuthToken>,
+        provider_client: Arc<ProviderClient>,
+        retrieval_mode: MessageRetrievalMode,
+        fetch_delay: f64,
     ) -> Self {
         ProviderPoller {
-            provider_client: provider_client::ProviderClient::new(
-                provider_client_listener_address,
-                client_address,
-                auth_token,
-            ),
+            provider_client,
             poller_tx,
+            retrieval_mode,
+            fetch_delay: Duration::from_secs_f64(fetch_delay),
         }
     }
 
-    // This method is only temporary until registration is moved to `client init`
-    pub(crate) async fn perform_initial_registration(&mut self) -> Result<(), ProviderClientError> {
-        debug!("performing initial provider registration");
+    // processes single page of messages and returns whether there are more of them waiting
+    async fn process_page(&self, page: PullResponse) -> bool {
+        let loop_message = mix_client::packet::LOOP_COVER_MESSAGE_PAYLOAD;
+        let has_more = page.has_more();
 
-        if !self.provider_client.is_registered() {
-            let auth_token = match self.provider_client.register().await {
-                // in this particular case we can ignore this error
-                Err(ProviderClientError::ClientAlreadyRegisteredError) => return Ok(()),
-                Err(err) => return Err(err),
-                Ok(token) => token,
-            };
+        // all received messages have to be acknowledged, including our own loop cover messages
+        let (ack_ids, good_messages): (Vec<_>, Vec<_>) = page
+            .messages
+            .into_iter()
+            .map(|message| (message.id, message.content))
+            .unzip();
+        let good_messages: Vec<_> = good_messages
+            .into_iter()
+            .filter(|message| message.as_slice() != loop_message)
+            .collect();
+        trace!("Obtained the following messages: {:?}", good_messages);
+
+        // if this one fails, there's no retrying because it means that either:
+        // - we run out of memory
+        // - the receiver channel is closed
+        // in either case there's no recovery and we can only panic
+        self.poller_tx.unbounded_send(good_messages).unwrap();
 
-            self.provider_client.update_token(auth_token)
-        } else {
-            warn!("did not perform registration - we were already registered")
+        // only acknowledge messages once they are safely in our buffer
+        if !ack_ids.is_empty() {
+            let num_acks = ack_ids.len();
+            match self.provider_client.ack_messages(ack_ids).await {
+                Err(err) => warn!("Failed to acknowledge received messages: {:?}. They are going to be received again", err),
+                Ok(acknowledged) if acknowledged != num_acks => warn!(
+                    "Provider only acknowledged {} out of {} messages",
+                    acknowledged, num_acks
+                ),
+                Ok(_) => (),
+            }
         }
 
-        Ok(())
+        has_more
     }
 
-    pub(crate) async fn start_provider_polling(self) {
-        info!("Starting provider poller");
+    // keeps retrieving pages of messages until the provider tells us there are no more
+    async fn drain_inbox(&self) -> Result<(), ProviderClientError> {
+        let mut cursor = 0;
+        loop {
+            let page = self.provider_client.retrieve_messages(cursor).await?;
+            cursor = page.next_cursor;
+            if !self.process_page(page).await {
+                return Ok(());
+            }
+            debug!("There are more messages waiting at the provider");
+        }
+    }
 
-        let loop_message = &mix_client::packet::LOOP_COVER_MESSAGE_PAYLOAD.to_vec();
-        let dummy_message = &sfw_provider_requests::DUMMY_MESSAGE_CONTENT.to_vec();
+    pub(crate) async fn run(self) {
+        match self.retrieval_mode {
+            MessageRetrievalMode::Poll => self.start_provider_polling().await,
+            MessageRetrievalMode::Push => self.start_provider_subscription().await,
+        }
+    }
 
-        let delay_duration = Duration::from_secs_f64(FETCH_MESSAGES_DELAY);
-        let extended_delay_duration = Duration::from_secs_f64(FETCH_MESSAGES_DELAY * 10.0);
+    async fn start_provider_subscription(self) {
+        info!("Starting provider subscription");
 
-        loop {
-            debug!("Polling provider...");
+        let retry_delay_duration = self.fetch_delay * 10;
 
-            let messages = match self.provider_client.retrieve_messages().await {
+        loop {
+            let mut subscription = match self.provider_client.subscribe().await {
                 Err(err) => {
-                    error!("Failed to query the provider for messages: {:?}, ... Going to wait {:?} before retrying", err, extended_delay_duration);
-                    tokio::time::delay_for(extended_delay_duration).await;
+                    error!("Failed to subscribe to the provider: {:?}, ... Going to wait {:?} before retrying", err, retry_delay_duration);
+                    tokio::time::delay_for(retry_delay_duration).await;
                     continue;
                 }
-                Ok(messages) => messages,
+                Ok(subscription) => subscription,
             };
+            debug!(
+                "Provider is going to push our messages every {:?}",
+                subscription.push_interval()
+            );
+
+            loop {
+                match subscription.next_page().await {
+                    Err(err) => {
+                        error!(
+                            "Provider subscription failed: {:?}, ... Going to resubscribe",
+                            err
+                        );
+                        break;
+                    }
+                    // we don't care whether there are more messages - they will be pushed anyway
+                    Ok(page) => {
+                        self.process_page(page).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn start_provider_polling(self) {
+        info!("Starting provider poller");
+
+        let delay_duration = self.fetch_delay;
+        let extended_delay_duration = self.fetch_delay * 10;
+
+        loop {
+            debug!("Polling provider...");
 
-            let good_messages = messages
-                .into_iter()
-                .filter(|message| message != loop_message && message != dummy_message)
-                .collect();
-            trace!("Obtained the following messages: {:?}", good_messages);
-
-            // if this one fails, there's no retrying because it means that either:
-            // - we run out of memory
-            // - the receiver channel is closed
-            // in either case there's no recovery and we can only panic
-            self.poller_tx.unbounded_send(good_messages).unwrap();
+            if let Err(err) = self.drain_inbox().await {
+                error!("Failed to query the provider for messages: {:?}, ... Going to wait {:?} before retrying", err, extended_delay_duration);
+                tokio::time::delay_for(extended_delay_duration).await;
+                continue;
+            }
 
             tokio::time::delay_for(delay_duration).await;
         }
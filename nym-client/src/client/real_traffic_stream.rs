@@ -1,34 +1,112 @@
+use crate::client::delivery_status::{DeliveryStatus, DeliveryTracker};
 use crate::client::mix_traffic::MixMessage;
-use crate::client::{InputMessage, MESSAGE_SENDING_AVERAGE_DELAY};
-use directory_client::presence::Topology;
+use crate::client::reply_tokens::AckTokenAccessor;
+use crate::client::topology_control::TopologyAccessor;
+use crate::client::InputMessage;
 use futures::channel::mpsc;
 use futures::task::{Context, Poll};
 use futures::{Future, Stream, StreamExt};
-use log::{debug, info, trace};
-use sphinx::route::Destination;
+use log::{debug, info, trace, warn};
+use mix_client::fragmentation::{self, Fragment, FragmentationError};
+use mix_client::packet::{MessageEnvelope, ReliableMessageId, ReplyToken};
+use sphinx::route::{Destination, DestinationAddressBytes, SURBIdentifier};
 use sphinx::SphinxPacket;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time;
+use topology::{MixProviderNode, NymTopology};
 
-// have a rather low value for test sake
-const AVERAGE_PACKET_DELAY: f64 = 0.1;
+// Reliable messages are retransmitted if they're not acknowledged in time. The timeout is
+// derived from the expected delays of the mixnet, but as the delays are random, we're giving
+// the ack quite a bit of extra time before declaring the message lost.
+const ACK_TIMEOUT_MULTIPLIER: f64 = 3.0;
+const ACK_TIMEOUT_EXTRA: Duration = Duration::from_secs(5);
+const MAX_TRANSMISSION_ATTEMPTS: u32 = 5;
+// used if we failed to even get a route through the network to estimate the timeout with
+const FALLBACK_ROUTE_LENGTH: usize = 5;
 
 pub(crate) struct OutQueueControl {
     delay: time::Delay,
     mix_tx: mpsc::UnboundedSender<MixMessage>,
     input_rx: mpsc::UnboundedReceiver<InputMessage>,
+    // ids of our reliable messages acknowledged by their recipients
+    acks_rx: mpsc::UnboundedReceiver<ReliableMessageId>,
+    // remaining fragments of messages that did not fit into a single packet. each of them
+    // is sent in its own slot, so that long messages wouldn't stand out in our traffic
+    pending_fragments: VecDeque<OutgoingFragment>,
+    unacknowledged: HashMap<ReliableMessageId, UnacknowledgedMessage>,
+    ack_tokens: AckTokenAccessor,
+    delivery_tracker: DeliveryTracker,
     our_info: Destination,
+    // loop cover messages go through it back to us
+    our_provider: MixProviderNode,
+    topology_accessor: TopologyAccessor,
+    // in seconds
+    average_message_sending_delay: f64,
+    average_packet_delay: f64,
+    fetch_messages_delay: f64,
+}
+
+#[derive(Clone)]
+enum FragmentRecipient {
+    Address(DestinationAddressBytes, SURBIdentifier),
+    ReplyToken(ReplyToken),
+}
+
+pub(crate) struct OutgoingFragment {
+    recipient: FragmentRecipient,
+    fragment: Fragment,
+    // set on the last fragment of a reliable message; the ack timeout starts once it's sent
+    completes: Option<ReliableMessageId>,
+}
+
+struct UnacknowledgedMessage {
+    destination: Destination,
+    envelope: MessageEnvelope,
+    attempts: u32,
+    // not set while the fragments of the current transmission are still waiting to be sent
+    ack_deadline: Option<Instant>,
+}
 
-    // due to pinning, DerefMut trait, futures, etc its way easier to
-    // just have concrete implementation here rather than generic NymTopology
-    // considering that it will be replaced with refreshing topology within few days anyway
-    topology: Topology,
+pub(crate) enum StreamMessage {
+    Cover,
+    Fragment(OutgoingFragment),
+    Real(InputMessage),
+}
+
+fn split_into_outgoing_fragments(
+    recipient: FragmentRecipient,
+    message: &[u8],
+    reliable_id: Option<ReliableMessageId>,
+) -> Result<Vec<OutgoingFragment>, FragmentationError> {
+    let fragments = fragmentation::split_into_fragments(message)?;
+    // reply token can only be used once, so the reply has to fit into a single packet
+    if let FragmentRecipient::ReplyToken(_) = recipient {
+        if fragments.len() > 1 {
+            return Err(FragmentationError::TooLongMessageError(message.len()));
+        }
+    }
+
+    let last_index = fragments.len() - 1;
+    Ok(fragments
+        .into_iter()
+        .enumerate()
+        .map(|(index, fragment)| OutgoingFragment {
+            recipient: recipient.clone(),
+            fragment,
+            completes: if index == last_index {
+                reliable_id
+            } else {
+                None
+            },
+        })
+        .collect())
 }
 
 impl Stream for OutQueueControl {
-    type Item = (SocketAddr, SphinxPacket);
+    type Item = StreamMessage;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         // it is not yet time to return a message
@@ -40,71 +118,293 @@ impl Stream for OutQueueControl {
         // Get the `now` by looking at the current `delay` deadline
         let now = self.delay.deadline();
 
-        let next_poisson_delay =
-            Duration::from_secs_f64(mix_client::poisson::sample(MESSAGE_SENDING_AVERAGE_DELAY));
+        let next_poisson_delay = Duration::from_secs_f64(mix_client::poisson::sample(
+            self.average_message_sending_delay,
+        ));
 
         // The next interval value is `next_poisson_delay` after the one that just
         // yielded.
         let next = now + next_poisson_delay;
         self.delay.reset(next);
 
+        // messages that were already partially sent go first
+        if let Some(fragment) = self.pending_fragments.pop_front() {
+            trace!("real message fragment");
+            return Poll::Ready(Some(StreamMessage::Fragment(fragment)));
+        }
+
         // decide what kind of message to send
         match Stream::poll_next(Pin::new(&mut self.input_rx), cx) {
             // in the case our real message channel stream was closed, we should also indicate we are closed
             // (and whoever is using the stream should panic)
             Poll::Ready(None) => Poll::Ready(None),
 
-            // if there's an actual message - return it
+            // if there's an actual message - return it so that it could be split into fragments
             Poll::Ready(Some(real_message)) => {
                 trace!("real message");
-                Poll::Ready(Some(mix_client::packet::encapsulate_message(
-                    real_message.0,
-                    real_message.1,
-                    &self.topology,
-                    AVERAGE_PACKET_DELAY,
-                )))
+                Poll::Ready(Some(StreamMessage::Real(real_message)))
             }
 
             // otherwise construct a dummy one
             _ => {
                 trace!("loop cover message");
-                Poll::Ready(Some(mix_client::packet::loop_cover_message(
-                    self.our_info.address,
-                    self.our_info.identifier,
-                    &self.topology,
-                )))
+                Poll::Ready(Some(StreamMessage::Cover))
             }
         }
     }
 }
 
 impl OutQueueControl {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         mix_tx: mpsc::UnboundedSender<MixMessage>,
         input_rx: mpsc::UnboundedReceiver<InputMessage>,
+        acks_rx: mpsc::UnboundedReceiver<ReliableMessageId>,
+        ack_tokens: AckTokenAccessor,
+        delivery_tracker: DeliveryTracker,
         our_info: Destination,
-        topology: Topology,
+        our_provider: MixProviderNode,
+        topology_accessor: TopologyAccessor,
+        average_message_sending_delay: f64,
+        average_packet_delay: f64,
+        fetch_messages_delay: f64,
     ) -> Self {
-        let initial_delay = time::delay_for(Duration::from_secs_f64(MESSAGE_SENDING_AVERAGE_DELAY));
+        let initial_delay = time::delay_for(Duration::from_secs_f64(average_message_sending_delay));
         OutQueueControl {
             delay: initial_delay,
             mix_tx,
             input_rx,
+            acks_rx,
+            pending_fragments: VecDeque::new(),
+            unacknowledged: HashMap::new(),
+            ack_tokens,
+            delivery_tracker,
             our_info,
+            our_provider,
+            topology_accessor,
+            average_message_sending_delay,
+            average_packet_delay,
+            fetch_messages_delay,
+        }
+    }
+
+    // Expected time between sending the last fragment of a message and receiving its ack.
+    // Both of them have to go through all the hops of the route and wait to be fetched from
+    // the provider, and the ack also has to wait for a sending slot of the recipient.
+    fn ack_timeout(&self, route_length: usize) -> Duration {
+        let expected_delay = 2.0 * route_length as f64 * self.average_packet_delay
+            + 2.0 * self.fetch_messages_delay
+            + self.average_message_sending_delay;
+        Duration::from_secs_f64(expected_delay * ACK_TIMEOUT_MULTIPLIER) + ACK_TIMEOUT_EXTRA
+    }
+
+    fn process_acks(&mut self) {
+        while let Ok(Some(id)) = self.acks_rx.try_next() {
+            // every transmission has an ack token of its own, so acks of retransmitted
+            // messages might arrive more than once
+            if self.unacknowledged.remove(&id).is_some() {
+                debug!("reliable message {} got acknowledged", id);
+                self.delivery_tracker.update(id, DeliveryStatus::Delivered);
+            }
+        }
+    }
+
+    fn retransmit_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .unacknowledged
+            .iter()
+            .filter(|(_, message)| match message.ack_deadline {
+                Some(deadline) => deadline <= now,
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            // this can't fail as we've just got the id from the map
+            let message = self.unacknowledged.remove(&id).unwrap();
+            if message.attempts >= MAX_TRANSMISSION_ATTEMPTS {
+                warn!(
+                    "reliable message {} was not acknowledged after {} attempts",
+                    id, message.attempts
+                );
+                self.delivery_tracker.update(id, DeliveryStatus::Failed);
+                continue;
+            }
+            debug!("retransmitting reliable message {}", id);
+            self.transmit_reliable(id, message);
+        }
+    }
+
+    // queues fragments of the reliable message. every fragment goes over a new random route
+    // and every transmission uses a new ack token, as the provider only accepts a single ack
+    // over each of them
+    fn transmit_reliable(&mut self, id: ReliableMessageId, mut message: UnacknowledgedMessage) {
+        message.attempts += 1;
+        message.ack_deadline = None;
+
+        let ack_token = match self.ack_tokens.take() {
+            Some(ack_token) => ack_token,
+            None => {
+                // treat it as a lost transmission so that we'd try again after the timeout
+                warn!("No ack token is available for message {}", id);
+                message.ack_deadline =
+                    Some(Instant::now() + self.ack_timeout(FALLBACK_ROUTE_LENGTH));
+                self.unacknowledged.insert(id, message);
+                return;
+            }
+        };
+
+        let envelope = MessageEnvelope::Reliable(id, ack_token, Box::new(message.envelope.clone()));
+        let recipient =
+            FragmentRecipient::Address(message.destination.address, message.destination.identifier);
+        match split_into_outgoing_fragments(recipient, &envelope.to_bytes(), Some(id)) {
+            Ok(fragments) => {
+                self.pending_fragments.extend(fragments);
+                self.unacknowledged.insert(id, message);
+            }
+            // the sockets are supposed to reject such messages
+            Err(err) => {
+                warn!("Failed to split message {} into fragments: {:?}", id, err);
+                self.delivery_tracker.update(id, DeliveryStatus::Failed);
+            }
+        }
+    }
+
+    // queues all fragments of the message and returns the first one
+    fn prepare_input_message(&mut self, input_message: InputMessage) -> Option<OutgoingFragment> {
+        let (recipient, message) = match input_message {
+            InputMessage::Forward(destination, envelope) => (
+                FragmentRecipient::Address(destination.address, destination.identifier),
+                envelope.to_bytes(),
+            ),
+            InputMessage::ReliableForward(id, destination, envelope) => {
+                let message = UnacknowledgedMessage {
+                    destination,
+                    envelope,
+                    attempts: 0,
+                    ack_deadline: None,
+                };
+                self.transmit_reliable(id, message);
+                return self.pending_fragments.pop_front();
+            }
+            InputMessage::Reply(reply_token, message) => (
+                FragmentRecipient::ReplyToken(reply_token),
+                MessageEnvelope::Reply(reply_token.id, message).to_bytes(),
+            ),
+            InputMessage::Ack(ack_token, id) => (
+                FragmentRecipient::ReplyToken(ack_token),
+                MessageEnvelope::Ack(id).to_bytes(),
+            ),
+        };
+
+        match split_into_outgoing_fragments(recipient, &message, None) {
+            Ok(fragments) => {
+                trace!("real message split into {} fragments", fragments.len());
+                self.pending_fragments.extend(fragments);
+                self.pending_fragments.pop_front()
+            }
+            // the sockets are supposed to reject such messages, but if it somehow got
+            // here, we still need to send something in this slot
+            Err(err) => {
+                warn!("Failed to split message into fragments: {:?}", err);
+                None
+            }
+        }
+    }
+
+    fn loop_cover_message<T: NymTopology>(&self, topology: &T) -> (SocketAddr, SphinxPacket) {
+        mix_client::packet::loop_cover_message(
+            self.our_info.address,
+            self.our_info.identifier,
+            self.our_provider.clone(),
             topology,
+        )
+    }
+
+    fn encapsulate_fragment<T: NymTopology>(
+        &mut self,
+        outgoing: OutgoingFragment,
+        topology: &T,
+    ) -> (SocketAddr, SphinxPacket) {
+        if let Some(id) = outgoing.completes {
+            let route_length = topology
+                .mix_route()
+                .map(|route| route.len() + 1)
+                .unwrap_or(FALLBACK_ROUTE_LENGTH);
+            let ack_deadline = Instant::now() + self.ack_timeout(route_length);
+            if let Some(message) = self.unacknowledged.get_mut(&id) {
+                message.ack_deadline = Some(ack_deadline);
+            }
+        }
+
+        match outgoing.recipient {
+            FragmentRecipient::Address(address, surb_id) => {
+                match mix_client::packet::recipient_provider(&address, topology) {
+                    Some(provider) => mix_client::packet::encapsulate_message(
+                        Destination::new(address, surb_id),
+                        provider,
+                        outgoing.fragment.to_bytes(),
+                        topology,
+                        self.average_packet_delay,
+                    ),
+                    // we still send something so that the traffic pattern wouldn't change
+                    None => {
+                        warn!("The recipient is not registered with any known provider. Sending cover message instead");
+                        self.loop_cover_message(topology)
+                    }
+                }
+            }
+            FragmentRecipient::ReplyToken(reply_token) => {
+                match mix_client::packet::encapsulate_reply(
+                    &reply_token,
+                    outgoing.fragment.to_bytes(),
+                    topology,
+                    self.average_packet_delay,
+                ) {
+                    Ok(packet) => packet,
+                    // we still send something so that the traffic pattern wouldn't change
+                    Err(err) => {
+                        warn!(
+                            "Failed to create reply: {:?}. Sending cover message instead",
+                            err
+                        );
+                        self.loop_cover_message(topology)
+                    }
+                }
+            }
         }
     }
 
     pub(crate) async fn run_out_queue_control(mut self) {
         info!("starting out queue controller");
         while let Some(next_message) = self.next().await {
+            // retransmissions are only queued here, so they are going to be sent in the
+            // following slots
+            self.process_acks();
+            self.retransmit_expired();
+
+            let next_fragment = match next_message {
+                StreamMessage::Cover => None,
+                StreamMessage::Fragment(fragment) => Some(fragment),
+                StreamMessage::Real(input_message) => self.prepare_input_message(input_message),
+            };
+
+            // the topology is obtained for every single message so that we would always use
+            // the most recent one
+            let topology = self.topology_accessor.get_current_topology_clone().await;
+            let next_packet = match next_fragment {
+                Some(fragment) => self.encapsulate_fragment(fragment, &topology),
+                None => self.loop_cover_message(&topology),
+            };
             debug!("created new message");
             // if this one fails, there's no retrying because it means that either:
             // - we run out of memory
             // - the receiver channel is closed
             // in either case there's no recovery and we can only panic
             self.mix_tx
-                .unbounded_send(MixMessage::new(next_message.0, next_message.1))
+                .unbounded_send(MixMessage::new(next_packet.0, next_packet.1))
                 .unwrap();
         }
     }
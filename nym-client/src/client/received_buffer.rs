@@ -1,26 +1,64 @@
+use crate::client::InputMessage;
 use futures::channel::{mpsc, oneshot};
 use futures::lock::Mutex as FMutex;
 use futures::StreamExt;
-use log::{error, info, trace};
+use log::{debug, error, info, trace, warn};
+use mix_client::fragmentation::{Fragment, MessageReassembler};
+use mix_client::packet::{MessageEnvelope, ReliableMessageId};
+use sphinx::route::SURBIdentifier;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub type BufferResponse = oneshot::Sender<Vec<Vec<u8>>>;
+// how long we wait for the next fragment of partially received message before giving up on it
+const FRAGMENT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+// how many partially received messages we keep at once, so that never completed ones (say sent
+// by someone misbehaving) could not exhaust our memory
+const MAXIMUM_INCOMPLETE_MESSAGES: usize = 100;
+// how long we remember ids of received reliable messages to recognise their retransmissions
+const RELIABLE_MESSAGE_ID_RETENTION: Duration = Duration::from_secs(60 * 60);
+// how many of those ids we remember at most, so that someone sending us loads of reliable
+// messages could not exhaust our memory. the oldest ones are forgotten first
+const MAXIMUM_RELIABLE_MESSAGE_IDS: usize = 10_000;
+// every retransmission comes with a new ack token. anyone retransmitting more often than that
+// does not get acked anymore
+const MAXIMUM_ACK_TOKENS_PER_MESSAGE: usize = 10;
+
+pub type BufferResponse = oneshot::Sender<Vec<MessageEnvelope>>;
+pub type MessagesSubscriber = mpsc::UnboundedSender<Vec<MessageEnvelope>>;
+
+pub enum BufferRequest {
+    // all stored messages are removed from the buffer and sent back
+    Fetch(BufferResponse),
+    // new messages are pushed to the subscriber as they arrive (starting with the already stored
+    // ones) for as long as it keeps its receiver open. they are only stored while there are no
+    // subscribers
+    Subscribe(MessagesSubscriber),
+    // messages that were pushed to a subscriber that got disconnected before delivering them.
+    // they are only returned on fetch as other subscribers might have already received them
+    Restore(Vec<MessageEnvelope>),
+}
 
 pub(crate) struct ReceivedMessagesBuffer {
     inner: Arc<FMutex<Inner>>,
 }
 
 impl ReceivedMessagesBuffer {
-    pub(crate) fn new() -> Self {
+    // acks of received reliable messages are sent with `input_tx` while acks of our own
+    // reliable messages are passed to OutQueueControl with `acks_tx`
+    pub(crate) fn new(
+        input_tx: mpsc::UnboundedSender<InputMessage>,
+        acks_tx: mpsc::UnboundedSender<ReliableMessageId>,
+    ) -> Self {
         ReceivedMessagesBuffer {
-            inner: Arc::new(FMutex::new(Inner::new())),
+            inner: Arc::new(FMutex::new(Inner::new(input_tx, acks_tx))),
         }
     }
 
     pub(crate) async fn start_controllers(
         self,
         poller_rx: mpsc::UnboundedReceiver<Vec<Vec<u8>>>, // to receive new messages
-        query_receiver: mpsc::UnboundedReceiver<BufferResponse>, // to receive requests to acquire all stored messages
+        query_receiver: mpsc::UnboundedReceiver<BufferRequest>, // to receive requests to acquire (or subscribe to) stored messages
     ) {
         let input_controller_future = tokio::spawn(Self::run_poller_input_controller(
             self.inner.clone(),
@@ -41,49 +79,335 @@ impl ReceivedMessagesBuffer {
     ) {
         info!("Started Received Messages Buffer Input Controller");
 
-        while let Some(new_messages) = poller_rx.next().await {
-            Inner::add_new_messages(&*buf, new_messages).await;
+        while let Some(new_fragments) = poller_rx.next().await {
+            Inner::add_new_fragments(&*buf, new_fragments).await;
         }
     }
 
     pub(crate) async fn run_query_output_controller(
         buf: Arc<FMutex<Inner>>,
-        mut query_receiver: mpsc::UnboundedReceiver<BufferResponse>,
+        mut query_receiver: mpsc::UnboundedReceiver<BufferRequest>,
     ) {
         info!("Started Received Messages Buffer Output Controller");
 
         while let Some(request) = query_receiver.next().await {
-            let messages = Inner::acquire_and_empty(&*buf).await;
-            if let Err(failed_messages) = request.send(messages) {
-                error!(
-                    "Failed to send the messages to the requester. Adding them back to the buffer"
-                );
-                Inner::add_new_messages(&*buf, failed_messages).await;
+            match request {
+                BufferRequest::Fetch(response) => {
+                    let messages = Inner::acquire_and_empty(&*buf).await;
+                    if let Err(failed_messages) = response.send(messages) {
+                        error!(
+                            "Failed to send the messages to the requester. Adding them back to the buffer"
+                        );
+                        Inner::add_new_messages(&*buf, failed_messages).await;
+                    }
+                }
+                BufferRequest::Subscribe(subscriber) => {
+                    Inner::add_subscriber(&*buf, subscriber).await
+                }
+                BufferRequest::Restore(messages) => {
+                    debug!("Restoring {} undelivered messages", messages.len());
+                    Inner::restore_messages(&*buf, messages).await;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ReliableArrival {
+    New,
+    // the sender has not got our ack yet and sent the message again with a new ack token
+    Retransmission,
+    // the same transmission delivered again, say because our provider did not get our ack.
+    // its ack token has already been used, so it must not be acked again
+    AlreadyAcknowledged,
+}
+
+// Ids of the reliable messages we have received, kept in the order of their arrival, together
+// with the ack tokens we have acknowledged them with.
+struct ReceivedReliableIds {
+    ids: HashMap<ReliableMessageId, HashSet<SURBIdentifier>>,
+    arrivals: VecDeque<(ReliableMessageId, Instant)>,
+    max_ids: usize,
+}
+
+impl ReceivedReliableIds {
+    fn new(max_ids: usize) -> Self {
+        ReceivedReliableIds {
+            ids: HashMap::new(),
+            arrivals: VecDeque::new(),
+            max_ids,
+        }
+    }
+
+    // the caller is expected to ack the message unless it has already been acknowledged
+    // with this token
+    fn insert(&mut self, id: ReliableMessageId, ack_token_id: SURBIdentifier) -> ReliableArrival {
+        if let Some(ack_tokens) = self.ids.get_mut(&id) {
+            if ack_tokens.contains(&ack_token_id)
+                || ack_tokens.len() >= MAXIMUM_ACK_TOKENS_PER_MESSAGE
+            {
+                return ReliableArrival::AlreadyAcknowledged;
+            }
+            ack_tokens.insert(ack_token_id);
+            return ReliableArrival::Retransmission;
+        }
+
+        self.ids.insert(id, std::iter::once(ack_token_id).collect());
+        self.arrivals.push_back((id, Instant::now()));
+        if self.arrivals.len() > self.max_ids {
+            self.remove_oldest();
+        }
+        ReliableArrival::New
+    }
+
+    fn remove_expired(&mut self, retention: Duration) {
+        while let Some((_, received_at)) = self.arrivals.front() {
+            if received_at.elapsed() < retention {
+                break;
             }
+            self.remove_oldest();
+        }
+    }
+
+    fn remove_oldest(&mut self) {
+        if let Some((id, _)) = self.arrivals.pop_front() {
+            self.ids.remove(&id);
         }
     }
 }
 
 pub(crate) struct Inner {
-    messages: Vec<Vec<u8>>,
+    messages: Vec<MessageEnvelope>,
+    // undelivered messages given back by subscribers, never pushed again
+    restored: Vec<MessageEnvelope>,
+    subscribers: Vec<MessagesSubscriber>,
+    // only fully reassembled messages are made available to the rest of the system
+    reassembler: MessageReassembler,
+    received_reliable: ReceivedReliableIds,
+    input_tx: mpsc::UnboundedSender<InputMessage>,
+    acks_tx: mpsc::UnboundedSender<ReliableMessageId>,
 }
 
 impl Inner {
-    fn new() -> Self {
+    fn new(
+        input_tx: mpsc::UnboundedSender<InputMessage>,
+        acks_tx: mpsc::UnboundedSender<ReliableMessageId>,
+    ) -> Self {
         Inner {
             messages: Vec::new(),
+            restored: Vec::new(),
+            subscribers: Vec::new(),
+            reassembler: MessageReassembler::new(
+                FRAGMENT_REASSEMBLY_TIMEOUT,
+                MAXIMUM_INCOMPLETE_MESSAGES,
+            ),
+            received_reliable: ReceivedReliableIds::new(MAXIMUM_RELIABLE_MESSAGE_IDS),
+            input_tx,
+            acks_tx,
+        }
+    }
+
+    // acks and reliability wrappers are handled here, so that only the actual messages
+    // are made available to the rest of the system
+    fn handle_envelope(&mut self, envelope: MessageEnvelope) {
+        match envelope {
+            MessageEnvelope::Ack(id) => {
+                if self.acks_tx.unbounded_send(id).is_err() {
+                    error!("Failed to pass ack of message {} on", id);
+                }
+            }
+            MessageEnvelope::Reliable(id, ack_token, inner) => {
+                let arrival = self.received_reliable.insert(id, ack_token.id);
+                // retransmissions are acked as well as our previous ack might have been lost
+                if arrival != ReliableArrival::AlreadyAcknowledged
+                    && self
+                        .input_tx
+                        .unbounded_send(InputMessage::Ack(ack_token, id))
+                        .is_err()
+                {
+                    error!("Failed to send ack of message {}", id);
+                }
+                if arrival == ReliableArrival::New {
+                    self.messages.push(*inner);
+                } else {
+                    debug!("Received duplicate of reliable message {}", id);
+                }
+            }
+            envelope => self.messages.push(envelope),
+        }
+    }
+
+    // hands all stored messages over to the subscribers, unless none of them is still connected
+    fn push_to_subscribers(&mut self) {
+        self.subscribers
+            .retain(|subscriber| !subscriber.is_closed());
+        if self.messages.is_empty() || self.subscribers.is_empty() {
+            return;
+        }
+
+        let messages = std::mem::replace(&mut self.messages, Vec::new());
+        let mut delivered = false;
+        for subscriber in self.subscribers.iter() {
+            delivered |= subscriber.unbounded_send(messages.clone()).is_ok();
+        }
+        if !delivered {
+            self.messages = messages;
         }
     }
 
-    async fn add_new_messages(buf: &FMutex<Self>, msgs: Vec<Vec<u8>>) {
+    async fn add_subscriber(buf: &FMutex<Self>, subscriber: MessagesSubscriber) {
+        trace!("Adding new subscriber to the buffer");
+        let mut unlocked = buf.lock().await;
+        unlocked.subscribers.push(subscriber);
+        unlocked.push_to_subscribers();
+    }
+
+    async fn add_new_fragments(buf: &FMutex<Self>, fragments: Vec<Vec<u8>>) {
+        trace!("Adding {} new fragments to the buffer", fragments.len());
+        let mut unlocked = buf.lock().await;
+
+        let expired = unlocked.reassembler.remove_expired();
+        if expired > 0 {
+            warn!(
+                "{} partially received messages timed out waiting for the rest of their fragments",
+                expired
+            );
+        }
+        unlocked
+            .received_reliable
+            .remove_expired(RELIABLE_MESSAGE_ID_RETENTION);
+
+        for raw_fragment in fragments {
+            let fragment = match Fragment::from_bytes(&raw_fragment) {
+                Ok(fragment) => fragment,
+                Err(err) => {
+                    warn!(
+                        "Received malformed fragment: {:?}. It's going to be dropped",
+                        err
+                    );
+                    continue;
+                }
+            };
+            let message = match unlocked.reassembler.insert_fragment(fragment) {
+                Some(message) => message,
+                None => continue,
+            };
+            match MessageEnvelope::from_bytes(&message) {
+                Ok(envelope) => unlocked.handle_envelope(envelope),
+                Err(err) => warn!(
+                    "Received malformed message: {:?}. It's going to be dropped",
+                    err
+                ),
+            }
+        }
+        debug!(
+            "{} messages are still waiting for the rest of their fragments",
+            unlocked.reassembler.incomplete_count()
+        );
+        unlocked.push_to_subscribers();
+    }
+
+    async fn add_new_messages(buf: &FMutex<Self>, msgs: Vec<MessageEnvelope>) {
         trace!("Adding new messages to the buffer! {:?}", msgs);
         let mut unlocked = buf.lock().await;
         unlocked.messages.extend(msgs);
+        unlocked.push_to_subscribers();
+    }
+
+    async fn restore_messages(buf: &FMutex<Self>, msgs: Vec<MessageEnvelope>) {
+        trace!("Restoring undelivered messages! {:?}", msgs);
+        let mut unlocked = buf.lock().await;
+        unlocked.restored.extend(msgs);
     }
 
-    async fn acquire_and_empty(buf: &FMutex<Self>) -> Vec<Vec<u8>> {
+    async fn acquire_and_empty(buf: &FMutex<Self>) -> Vec<MessageEnvelope> {
         trace!("Emptying the buffer and returning all messages");
         let mut unlocked = buf.lock().await;
-        std::mem::replace(&mut unlocked.messages, Vec::new())
+        let new_messages = std::mem::replace(&mut unlocked.messages, Vec::new());
+        let mut messages = std::mem::replace(&mut unlocked.restored, Vec::new());
+        messages.extend(new_messages);
+        messages
+    }
+}
+
+#[cfg(test)]
+mod received_messages_buffer {
+    use super::*;
+
+    fn new_buffer() -> FMutex<Inner> {
+        let (input_tx, _) = mpsc::unbounded();
+        let (acks_tx, _) = mpsc::unbounded();
+        FMutex::new(Inner::new(input_tx, acks_tx))
+    }
+
+    #[tokio::test]
+    async fn stored_messages_are_pushed_to_new_subscribers() {
+        let buf = new_buffer();
+        let message = MessageEnvelope::Plain(b"foomp".to_vec());
+        Inner::add_new_messages(&buf, vec![message.clone()]).await;
+
+        let (subscriber, mut pushed) = mpsc::unbounded();
+        Inner::add_subscriber(&buf, subscriber).await;
+        assert_eq!(vec![message], pushed.try_next().unwrap().unwrap());
+        assert!(Inner::acquire_and_empty(&buf).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn restored_messages_are_only_fetched() {
+        let buf = new_buffer();
+        let (subscriber, mut pushed) = mpsc::unbounded();
+        Inner::add_subscriber(&buf, subscriber).await;
+
+        let restored = MessageEnvelope::Plain(b"foomp".to_vec());
+        let received = MessageEnvelope::Plain(b"bar".to_vec());
+        Inner::restore_messages(&buf, vec![restored.clone()]).await;
+        Inner::add_new_messages(&buf, vec![received.clone()]).await;
+
+        assert_eq!(vec![received], pushed.try_next().unwrap().unwrap());
+        assert!(pushed.try_next().is_err());
+        assert_eq!(vec![restored], Inner::acquire_and_empty(&buf).await);
+    }
+
+    #[test]
+    fn oldest_reliable_message_ids_are_forgotten_first() {
+        let mut received = ReceivedReliableIds::new(2);
+        assert_eq!(ReliableArrival::New, received.insert(1, [1u8; 16]));
+        assert_eq!(ReliableArrival::New, received.insert(2, [2u8; 16]));
+        assert_ne!(ReliableArrival::New, received.insert(1, [3u8; 16]));
+
+        assert_eq!(ReliableArrival::New, received.insert(3, [4u8; 16]));
+        assert_ne!(ReliableArrival::New, received.insert(3, [5u8; 16]));
+        assert_ne!(ReliableArrival::New, received.insert(2, [6u8; 16]));
+        assert_eq!(ReliableArrival::New, received.insert(1, [7u8; 16]));
+    }
+
+    #[test]
+    fn expired_reliable_message_ids_are_forgotten() {
+        let mut received = ReceivedReliableIds::new(2);
+        assert_eq!(ReliableArrival::New, received.insert(1, [1u8; 16]));
+        received.remove_expired(Duration::from_secs(60));
+        assert_ne!(ReliableArrival::New, received.insert(1, [2u8; 16]));
+
+        received.remove_expired(Duration::from_secs(0));
+        assert_eq!(ReliableArrival::New, received.insert(1, [3u8; 16]));
+    }
+
+    #[test]
+    fn spent_ack_tokens_are_not_used_again() {
+        let mut received = ReceivedReliableIds::new(10);
+        assert_eq!(ReliableArrival::New, received.insert(1, [1u8; 16]));
+        assert_eq!(
+            ReliableArrival::AlreadyAcknowledged,
+            received.insert(1, [1u8; 16])
+        );
+        assert_eq!(
+            ReliableArrival::Retransmission,
+            received.insert(1, [2u8; 16])
+        );
+        assert_eq!(
+            ReliableArrival::AlreadyAcknowledged,
+            received.insert(1, [2u8; 16])
+        );
     }
 }
@@ -0,0 +1,183 @@
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{debug, warn};
+use mix_client::packet::ReplyToken;
+use provider_client::{ProviderClient, ProviderClientError};
+use sphinx::route::SURBIdentifier;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// how many ack tokens are kept registered with the provider ahead of time
+const ACK_TOKEN_POOL_SIZE: usize = 10;
+// pooled tokens are thrown away this long before the provider forgets them, so that the acks
+// sent with them would still have plenty of time to arrive
+const ACK_TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(15 * 60);
+// the pool is also refilled whenever a token is taken out of it
+const ACK_TOKEN_POOL_REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Creates reply tokens pointing at our provider. Each of them gets registered with the provider
+// before it's handed out, so that the provider would know to deliver the reply to us.
+#[derive(Clone)]
+pub struct ReplyTokenIssuer {
+    provider_client: Arc<ProviderClient>,
+    provider_key: [u8; 32],
+}
+
+impl ReplyTokenIssuer {
+    pub(crate) fn new(provider_client: Arc<ProviderClient>, provider_key: [u8; 32]) -> Self {
+        ReplyTokenIssuer {
+            provider_client,
+            provider_key,
+        }
+    }
+
+    pub async fn issue(&self) -> Result<ReplyToken, ProviderClientError> {
+        self.issue_with_validity()
+            .await
+            .map(|(reply_token, _)| reply_token)
+    }
+
+    // also returns for how long the provider is going to accept the reply
+    async fn issue_with_validity(&self) -> Result<(ReplyToken, Duration), ProviderClientError> {
+        let id: SURBIdentifier = rand::random();
+        let validity = self.provider_client.register_reply_token(id).await?;
+        Ok((ReplyToken::new(self.provider_key, id), validity))
+    }
+}
+
+struct PooledAckToken {
+    ack_token: ReplyToken,
+    usable_until: Instant,
+}
+
+// Hands out the ack tokens of the pool without waiting for the provider.
+pub(crate) struct AckTokenAccessor {
+    tokens: Arc<Mutex<VecDeque<PooledAckToken>>>,
+    refill_tx: mpsc::Sender<()>,
+}
+
+impl AckTokenAccessor {
+    // None if the pool has run dry, say because the provider could not be reached
+    pub(crate) fn take(&mut self) -> Option<ReplyToken> {
+        let now = Instant::now();
+        let ack_token = {
+            let mut tokens = self.tokens.lock().unwrap();
+            loop {
+                match tokens.pop_front() {
+                    Some(pooled) if pooled.usable_until > now => break Some(pooled.ack_token),
+                    Some(_) => continue,
+                    None => break None,
+                }
+            }
+        };
+        // if the channel is full, the refill is already pending
+        let _ = self.refill_tx.try_send(());
+        ack_token
+    }
+}
+
+// Ack tokens registered ahead of time, so that sending a reliable message would not have to
+// wait for a round trip to the provider. Every transmission gets a token of its own as the
+// provider forgets each of them once the first ack arrives over it.
+pub(crate) struct AckTokenPool {
+    issuer: ReplyTokenIssuer,
+    tokens: Arc<Mutex<VecDeque<PooledAckToken>>>,
+    refill_tx: mpsc::Sender<()>,
+    refill_rx: mpsc::Receiver<()>,
+}
+
+impl AckTokenPool {
+    pub(crate) fn new(issuer: ReplyTokenIssuer) -> Self {
+        let (refill_tx, refill_rx) = mpsc::channel(1);
+        AckTokenPool {
+            issuer,
+            tokens: Arc::new(Mutex::new(VecDeque::new())),
+            refill_tx,
+            refill_rx,
+        }
+    }
+
+    pub(crate) fn get_accessor(&self) -> AckTokenAccessor {
+        AckTokenAccessor {
+            tokens: self.tokens.clone(),
+            refill_tx: self.refill_tx.clone(),
+        }
+    }
+
+    fn missing_tokens(&self) -> usize {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|pooled| pooled.usable_until > now);
+        ACK_TOKEN_POOL_SIZE.saturating_sub(tokens.len())
+    }
+
+    async fn refill(&self) {
+        let missing = self.missing_tokens();
+        for _ in 0..missing {
+            let (ack_token, validity) = match self.issuer.issue_with_validity().await {
+                Ok(issued) => issued,
+                Err(err) => {
+                    warn!("Failed to register ack token: {:?}", err);
+                    return;
+                }
+            };
+            let usable_for = match validity.checked_sub(ACK_TOKEN_EXPIRY_MARGIN) {
+                Some(usable_for) => usable_for,
+                None => {
+                    warn!(
+                        "Our provider only accepts replies for {:?}, which is too short for the ack tokens",
+                        validity
+                    );
+                    return;
+                }
+            };
+            self.tokens.lock().unwrap().push_back(PooledAckToken {
+                ack_token,
+                usable_until: Instant::now() + usable_for,
+            });
+        }
+        if missing > 0 {
+            debug!("Registered {} new ack tokens", missing);
+        }
+    }
+
+    pub(crate) async fn run_refiller(mut self) {
+        loop {
+            self.refill().await;
+            // whatever happens first - a token gets taken or the interval passes
+            let _ = time::timeout(ACK_TOKEN_POOL_REFILL_INTERVAL, self.refill_rx.next()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod ack_token_accessor {
+    use super::*;
+
+    #[test]
+    fn unusable_ack_tokens_are_not_handed_out() {
+        let (refill_tx, mut refill_rx) = mpsc::channel(1);
+        let now = Instant::now();
+        let tokens = vec![
+            PooledAckToken {
+                ack_token: ReplyToken::new([1u8; 32], [1u8; 16]),
+                usable_until: now,
+            },
+            PooledAckToken {
+                ack_token: ReplyToken::new([1u8; 32], [2u8; 16]),
+                usable_until: now + Duration::from_secs(60),
+            },
+        ];
+        let mut accessor = AckTokenAccessor {
+            tokens: Arc::new(Mutex::new(tokens.into_iter().collect())),
+            refill_tx,
+        };
+
+        assert_eq!([2u8; 16], accessor.take().unwrap().id);
+        assert!(accessor.take().is_none());
+        // and the refiller got woken up
+        assert!(refill_rx.try_next().unwrap().is_some());
+    }
+}
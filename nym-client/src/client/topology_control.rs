@@ -0,0 +1,155 @@
+use crate::built_info;
+use directory_client::presence::{SignaturePolicy, Topology};
+use directory_client::requests::presence_topology_get::PresenceTopologyGetRequester;
+use directory_client::DirectoryClient;
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock as FRwLock;
+use topology::NymTopology;
+
+#[derive(Debug)]
+pub(crate) enum TopologyError {
+    DirectoryError,
+    HealthCheckError,
+    NoValidPathsError,
+}
+
+// due to pinning, DerefMut trait, futures, etc its way easier to
+// just have concrete implementation here rather than generic NymTopology
+// (also `NymTopology::new` panics on failure, which is not something we want to happen during refresh)
+#[derive(Clone)]
+pub(crate) struct TopologyAccessor {
+    inner: Arc<FRwLock<Topology>>,
+}
+
+impl TopologyAccessor {
+    pub(crate) async fn get_current_topology_clone(&self) -> Topology {
+        self.inner.read().await.clone()
+    }
+}
+
+#[cfg(test)]
+impl TopologyAccessor {
+    pub(crate) fn new(topology: Topology) -> Self {
+        TopologyAccessor {
+            inner: Arc::new(FRwLock::new(topology)),
+        }
+    }
+}
+
+pub(crate) struct TopologyControl {
+    directory_server: String,
+    signature_policy: SignaturePolicy,
+    inner: Arc<FRwLock<Topology>>,
+    refresh_rate: Duration,
+}
+
+impl TopologyControl {
+    pub(crate) async fn new(
+        directory_server: String,
+        signature_policy: SignaturePolicy,
+        refresh_rate: Duration,
+    ) -> Result<Self, TopologyError> {
+        // get initial topology; already filtered by health and version
+        let initial_topology =
+            Self::get_compatible_topology(&directory_server, signature_policy).await?;
+
+        Ok(TopologyControl {
+            directory_server,
+            signature_policy,
+            inner: Arc::new(FRwLock::new(initial_topology)),
+            refresh_rate,
+        })
+    }
+
+    pub(crate) fn get_accessor(&self) -> TopologyAccessor {
+        TopologyAccessor {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub(crate) async fn get_compatible_topology(
+        directory_server: &str,
+        signature_policy: SignaturePolicy,
+    ) -> Result<Topology, TopologyError> {
+        let score_threshold = 0.0;
+        info!("Trying to obtain valid, healthy, topology");
+
+        let directory_config = directory_client::Config::new(directory_server.to_string())
+            .with_signature_policy(signature_policy);
+        let full_topology = match directory_client::Client::new(directory_config)
+            .presence_topology
+            .get()
+        {
+            Err(err) => {
+                error!("Failed to retrieve network topology: {:?}", err);
+                return Err(TopologyError::DirectoryError);
+            }
+            Ok(topology) => topology,
+        };
+
+        // run a healthcheck to determine healthy-ish nodes:
+        // this is a temporary solution as the healthcheck will eventually be moved to validators
+        let healthcheck_config = healthcheck::config::HealthCheck {
+            directory_server: directory_server.to_string(),
+            // those are literally unrelevant when running single check
+            interval: 100000.0,
+            resolution_timeout: 5.0,
+            num_test_packets: 2,
+            signature_policy,
+        };
+        let healthcheck = healthcheck::HealthChecker::new(healthcheck_config);
+        let healthcheck_result = healthcheck.do_check().await;
+
+        let healthcheck_scores = match healthcheck_result {
+            Err(err) => {
+                error!("Error while performing the healtcheck: {:?}", err);
+                return Err(TopologyError::HealthCheckError);
+            }
+            Ok(scores) => scores,
+        };
+
+        let healthy_topology =
+            healthcheck_scores.filter_topology_by_score(&full_topology, score_threshold);
+
+        // for time being assume same versioning, i.e. if client is running X.Y.Z,
+        // we're expecting mixes, providers and coconodes to also be running X.Y.Z
+        let versioned_healthy_topology = healthy_topology.filter_node_versions(
+            built_info::PKG_VERSION,
+            built_info::PKG_VERSION,
+            built_info::PKG_VERSION,
+        );
+
+        // make sure you can still send a packet through the network:
+        if !versioned_healthy_topology.can_construct_path_through() {
+            return Err(TopologyError::NoValidPathsError);
+        }
+
+        Ok(versioned_healthy_topology)
+    }
+
+    async fn update_global_topology(&self) {
+        match Self::get_compatible_topology(&self.directory_server, self.signature_policy).await {
+            Ok(new_topology) => {
+                let mut unlocked = self.inner.write().await;
+                *unlocked = new_topology;
+                debug!("updated the network topology");
+            }
+            // keep using the last known good topology, it's still better than nothing
+            Err(err) => warn!(
+                "Failed to refresh the network topology: {:?}. Going to keep using the old one",
+                err
+            ),
+        }
+    }
+
+    pub(crate) async fn run_refresher(self) {
+        info!("Starting topology refresher");
+        loop {
+            tokio::time::delay_for(self.refresh_rate).await;
+            trace!("Refreshing the topology");
+            self.update_global_topology().await;
+        }
+    }
+}
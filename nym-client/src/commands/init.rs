@@ -1,18 +1,121 @@
+use crate::client::topology_control::TopologyControl;
 use crate::config::persistance::pathfinder::ClientPathfinder;
+use crate::config::{self, Config};
 use clap::ArgMatches;
-use crypto::identity::MixnetIdentityKeyPair;
-use pemstore::pemstore::PemStore;
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+use directory_client::presence::SignaturePolicy;
+use pemstore::pemstore::{new_passphrase, PemStore};
+use rand::seq::IteratorRandom;
+use sfw_provider_requests::AuthToken;
+use std::process;
+use tokio::runtime::Runtime;
+use topology::{MixProviderNode, NymTopology};
 
 pub fn execute(matches: &ArgMatches) {
     println!("Initialising client...");
 
     let id = matches.value_of("id").unwrap().to_string(); // required for now
-    let pathfinder = ClientPathfinder::new(id);
+    let pathfinder = ClientPathfinder::new(id.clone());
+    let config_file = pathfinder.config_file.clone();
+
+    let mut config = Config::new(id);
+    if let Some(directory) = matches.value_of("directory") {
+        config.client.directory_server = directory.to_string();
+    }
 
     println!("Writing keypairs to {:?}...", pathfinder.config_dir);
-    let mix_keys = crypto::identity::DummyMixIdentityKeyPair::new();
-    let pem_store = PemStore::new(pathfinder);
-    pem_store.write_identity(mix_keys);
+    let identity_keys = ed25519::KeyPair::new();
+    // our address is the sphinx public key, which is derived from the identity so that the
+    // provider can check we own it
+    let sphinx_keys = identity_keys.to_x25519();
+    let identity_key = identity_keys.private_key().clone();
+    let mut pem_store = PemStore::new(pathfinder);
+    if matches.is_present("encrypt") {
+        match new_passphrase(matches.value_of("passphrase-file")) {
+            Ok(passphrase) => pem_store = pem_store.with_passphrase(passphrase),
+            Err(err) => {
+                eprintln!("Failed to obtain the passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(err) = pem_store
+        .write_identity(identity_keys)
+        .and_then(|_| pem_store.write_encryption(sphinx_keys))
+    {
+        eprintln!("Failed to write the client keys: {}", err);
+        process::exit(1);
+    }
+
+    let mut rt = Runtime::new().unwrap();
+    let provider = rt.block_on(choose_provider(
+        &config.client.directory_server,
+        config.client.signature_policy,
+        matches.value_of("provider"),
+    ));
+    println!("Registering with provider {}...", provider.pub_key);
+    let auth_token = rt.block_on(register_with_provider(&provider, identity_key));
+    config.provider = Some(config::Provider {
+        id: provider.pub_key,
+        auth_token: base64::encode_config(&auth_token, base64::URL_SAFE),
+    });
+
+    println!("Writing config to {:?}...", config_file);
+    if let Err(err) = config.save_to_file(&config_file) {
+        eprintln!("Failed to write the client config: {:?}", err);
+        process::exit(1);
+    }
 
     println!("Client configuration completed.\n\n\n")
 }
+
+// picks the provider with the given id or, if none was specified, a random one
+async fn choose_provider(
+    directory_server: &str,
+    signature_policy: SignaturePolicy,
+    provider_id: Option<&str>,
+) -> MixProviderNode {
+    let topology =
+        match TopologyControl::get_compatible_topology(directory_server, signature_policy).await {
+            Ok(topology) => topology,
+            Err(err) => {
+                eprintln!("Failed to obtain network topology: {:?}", err);
+                process::exit(1);
+            }
+        };
+
+    let mut providers = topology.get_mix_provider_nodes().into_iter();
+    let provider = match provider_id {
+        Some(provider_id) => providers.find(|provider| provider.pub_key == provider_id),
+        None => providers.choose(&mut rand::thread_rng()),
+    };
+
+    match provider {
+        Some(provider) => provider,
+        None => {
+            match provider_id {
+                Some(provider_id) => eprintln!(
+                    "Provider {} is not part of the current network topology (or is not healthy). Choose a different one or leave out `--provider` to have one chosen at random.",
+                    provider_id
+                ),
+                None => eprintln!("There are no available providers in the current network topology."),
+            }
+            process::exit(1);
+        }
+    }
+}
+
+async fn register_with_provider(
+    provider: &MixProviderNode,
+    identity_key: ed25519::PrivateKey,
+) -> AuthToken {
+    let provider_client =
+        provider_client::ProviderClient::new(provider.client_listener, identity_key, None);
+    match provider_client.register().await {
+        Ok(auth_token) => auth_token,
+        Err(err) => {
+            eprintln!("Failed to register with the provider: {:?}", err);
+            process::exit(1);
+        }
+    }
+}
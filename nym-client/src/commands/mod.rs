@@ -1,3 +1,124 @@
+use crate::client::SocketType;
+use crate::config::persistance::pathfinder::ClientPathfinder;
+use crate::config::{Config, ConfigError};
+use clap::ArgMatches;
+use crypto::encryption::{x25519, MixnetEncryptionKeyPair};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+use pemstore::pemstore::{PemStore, PemStoreError};
+use sfw_provider_requests::AuthToken;
+use std::io;
+use std::process;
+
 pub mod init;
 pub mod tcpsocket;
 pub mod websocket;
+
+// Loads the client keys or exits with a message explaining what the user should do about it.
+// The sphinx keys are derived from the identity during `init`, so they have to match.
+fn load_keys(id: &str, passphrase_file: Option<&str>) -> (ed25519::KeyPair, x25519::KeyPair) {
+    let pem_store = PemStore::new(ClientPathfinder::new(id.to_string()))
+        .with_non_interactive_passphrase(passphrase_file);
+    let (identity_keys, sphinx_keys): (ed25519::KeyPair, x25519::KeyPair) = match pem_store
+        .and_then(|pem_store| Ok((pem_store.read_identity()?, pem_store.read_encryption()?)))
+    {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("Failed to load keys of client '{}': {}", id, err);
+            match err {
+                PemStoreError::MissingFileError(_) => eprintln!(
+                    "Has the client been initialised? Run `nym-client init --id {}` first.",
+                    id
+                ),
+                PemStoreError::EncryptionError(..) | PemStoreError::PassphraseReadError(_) => {
+                    eprintln!("The keys are protected with a passphrase - make sure to provide the one given during `nym-client init`.")
+                }
+                PemStoreError::IOError(..) => {
+                    eprintln!("Make sure the current user is allowed to read the client's config directory.")
+                }
+                _ => eprintln!(
+                    "The stored keys appear to be corrupted. Run `nym-client init --id {}` to generate new ones.",
+                    id
+                ),
+            }
+            process::exit(1);
+        }
+    };
+
+    if &identity_keys.public_key().to_x25519() != sphinx_keys.public_key() {
+        eprintln!(
+            "The sphinx keys of client '{}' do not match its identity. Run `nym-client init --id {}` to generate new ones.",
+            id, id
+        );
+        process::exit(1);
+    }
+
+    (identity_keys, sphinx_keys)
+}
+
+// Loads the config written by `init` with any values given on the command line taking precedence.
+fn load_config(id: &str, matches: &ArgMatches, socket_type: SocketType) -> Config {
+    let config_file = ClientPathfinder::new(id.to_string()).config_file;
+    let mut config = match Config::load_from_file(&config_file) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load config file {:?}: {:?}", config_file, err);
+            match err {
+                ConfigError::IOError(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    eprintln!(
+                        "Has the client been initialised? Run `nym-client init --id {}` first.",
+                        id
+                    )
+                }
+                ConfigError::UnsupportedVersionError(_) => eprintln!(
+                    "The config was created by a newer version of nym-client than this one ({}).",
+                    crate::built_info::PKG_VERSION
+                ),
+                _ => eprintln!(
+                    "Fix the file or run `nym-client init --id {}` to create a new one.",
+                    id
+                ),
+            }
+            process::exit(1);
+        }
+    };
+
+    if let Some(directory) = matches.value_of("directory") {
+        config.client.directory_server = directory.to_string();
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.socket.listening_port = match port.parse::<u16>() {
+            Ok(n) => n,
+            Err(err) => panic!("Invalid port value provided - {:?}", err),
+        };
+    }
+    config.socket.socket_type = socket_type;
+
+    if config.provider.is_none() {
+        eprintln!(
+            "The client has not been registered with any provider. Run `nym-client init --id {}` first.",
+            id
+        );
+        process::exit(1);
+    }
+
+    config
+}
+
+// Recovers the token we were given by our provider during `init`.
+fn load_auth_token(config: &Config) -> AuthToken {
+    let encoded_token = &config.provider.as_ref().unwrap().auth_token;
+    match base64::decode_config(encoded_token, base64::URL_SAFE) {
+        Ok(bytes) if bytes.len() == std::mem::size_of::<AuthToken>() => {
+            let mut auth_token = [0u8; 32];
+            auth_token.copy_from_slice(&bytes);
+            auth_token
+        }
+        _ => {
+            eprintln!(
+                "The auth token stored in the config is malformed. Run `nym-client init --id {}` to register again.",
+                config.client.id
+            );
+            process::exit(1);
+        }
+    }
+}
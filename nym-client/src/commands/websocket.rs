@@ -1,48 +1,45 @@
-use crate::client::{NymClient, SocketType};
-use crate::config::persistance::pathfinder::ClientPathfinder;
+use crate::client::{MessageRetrievalMode, NymClient, SocketType};
 use clap::ArgMatches;
-use crypto::identity::{DummyMixIdentityKeyPair, MixnetIdentityKeyPair, MixnetIdentityPublicKey};
-use pemstore::pemstore::PemStore;
-use std::net::ToSocketAddrs;
+use crypto::encryption::{MixnetEncryptionKeyPair, MixnetEncryptionPublicKey};
+use crypto::identity::MixnetIdentityKeyPair;
 
 pub fn execute(matches: &ArgMatches) {
     let id = matches.value_of("id").unwrap().to_string();
-    let port = match matches.value_of("port").unwrap_or("9001").parse::<u16>() {
-        Ok(n) => n,
-        Err(err) => panic!("Invalid port value provided - {:?}", err),
-    };
-
-    let directory_server = matches
-        .value_of("directory")
-        .unwrap_or("https://directory.nymtech.net")
-        .to_string();
+    let config = super::load_config(&id, matches, SocketType::WebSocket);
 
-    println!("Starting websocket on port: {:?}", port);
+    println!(
+        "Starting websocket on port: {:?}",
+        config.socket.listening_port
+    );
     println!("Listening for messages...");
 
-    let socket_address = ("127.0.0.1", port)
-        .to_socket_addrs()
-        .expect("Failed to combine host and port")
-        .next()
-        .expect("Failed to extract the socket address from the iterator");
-
-    // TODO: currently we know we are reading the 'DummyMixIdentityKeyPair', but how to properly assert the type?
-    let keypair: DummyMixIdentityKeyPair = PemStore::new(ClientPathfinder::new(id)).read_identity();
+    let (identity_keys, sphinx_keys) = super::load_keys(&id, matches.value_of("passphrase-file"));
 
-    // TODO: reading auth_token from disk (if exists);
-
-    println!("Public key: {}", keypair.public_key.to_b64_string());
+    println!(
+        "Identity key: {}",
+        identity_keys.public_key().to_b64_string()
+    );
 
+    // our address is the key the sphinx packets destined for us are encrypted with
     let mut temporary_address = [0u8; 32];
-    let public_key_bytes = keypair.public_key().to_bytes();
+    let public_key_bytes = sphinx_keys.public_key().to_bytes();
     temporary_address.copy_from_slice(&public_key_bytes[..]);
-    let auth_token = None;
+    println!(
+        "Address: {}",
+        base64::encode_config(&temporary_address, base64::URL_SAFE)
+    );
+    let auth_token = super::load_auth_token(&config);
+    let retrieval_mode = if matches.is_present("push") {
+        MessageRetrievalMode::Push
+    } else {
+        MessageRetrievalMode::Poll
+    };
     let client = NymClient::new(
+        config,
         temporary_address,
-        socket_address,
-        directory_server,
+        identity_keys.private_key().clone(),
         auth_token,
-        SocketType::WebSocket,
+        retrieval_mode,
     );
 
     client.start().unwrap();
@@ -1 +1,245 @@
+use crate::client::SocketType;
+use directory_client::presence::SignaturePolicy;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
 pub mod persistance;
+
+// needs to be incremented whenever the file format changes in a way requiring existing
+// configs to be migrated
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+const DEFAULT_DIRECTORY_SERVER: &str = "https://directory.nymtech.net";
+const DEFAULT_LISTENING_PORT: u16 = 9001;
+
+// all of the delays are in seconds
+const DEFAULT_LOOP_COVER_AVERAGE_DELAY: f64 = 0.5;
+const DEFAULT_MESSAGE_SENDING_AVERAGE_DELAY: f64 = 0.5;
+// have a rather low value for test sake
+const DEFAULT_AVERAGE_PACKET_DELAY: f64 = 0.1;
+const DEFAULT_FETCH_MESSAGES_DELAY: f64 = 1.0;
+const DEFAULT_TOPOLOGY_REFRESH_RATE: f64 = 10.0;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(io::Error),
+    ParsingError(toml::de::Error),
+    SerializationError(toml::ser::Error),
+    MissingVersionError,
+    UnsupportedVersionError(u32),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::IOError(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::ParsingError(err)
+    }
+}
+
+impl From<toml::ser::Error> for ConfigError {
+    fn from(err: toml::ser::Error) -> Self {
+        ConfigError::SerializationError(err)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub version: u32,
+    pub client: Client,
+    // chosen and registered with during `init`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<Provider>,
+    pub socket: Socket,
+    pub traffic: Traffic,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Client {
+    pub id: String,
+    pub directory_server: String,
+    // what to do with nodes whose presences are not (correctly) signed
+    #[serde(default)]
+    pub signature_policy: SignaturePolicy,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Provider {
+    // public key of the provider as it appears in the network topology
+    pub id: String,
+    // base64 encoded token the provider has issued us when we registered
+    pub auth_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Socket {
+    pub socket_type: SocketType,
+    pub listening_port: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Traffic {
+    pub loop_cover_average_delay: f64,
+    pub message_sending_average_delay: f64,
+    pub average_packet_delay: f64,
+    pub fetch_messages_delay: f64,
+    pub topology_refresh_rate: f64,
+}
+
+impl Config {
+    pub fn new(id: String) -> Self {
+        Config {
+            version: CURRENT_CONFIG_VERSION,
+            client: Client {
+                id,
+                directory_server: DEFAULT_DIRECTORY_SERVER.to_string(),
+                signature_policy: Default::default(),
+            },
+            provider: None,
+            socket: Socket {
+                socket_type: SocketType::WebSocket,
+                listening_port: DEFAULT_LISTENING_PORT,
+            },
+            traffic: Traffic {
+                loop_cover_average_delay: DEFAULT_LOOP_COVER_AVERAGE_DELAY,
+                message_sending_average_delay: DEFAULT_MESSAGE_SENDING_AVERAGE_DELAY,
+                average_packet_delay: DEFAULT_AVERAGE_PACKET_DELAY,
+                fetch_messages_delay: DEFAULT_FETCH_MESSAGES_DELAY,
+                topology_refresh_rate: DEFAULT_TOPOLOGY_REFRESH_RATE,
+            },
+        }
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self, ConfigError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    // the file contains our auth token so, like the keys, it's only accessible by its owner
+    pub fn save_to_file(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(parent_dir) = path.parent() {
+            fs::create_dir_all(parent_dir)?;
+        }
+        let content = toml::to_string_pretty(self)?;
+
+        let mut open_options = OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            open_options.mode(0o600);
+        }
+        let mut file = open_options.open(path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(file.write_all(content.as_bytes())?)
+    }
+
+    fn parse(content: &str) -> Result<Self, ConfigError> {
+        // the version is checked before anything else as the rest of the file might
+        // not have the structure we expect
+        let raw_config: toml::Value = toml::from_str(content)?;
+        let version = raw_config
+            .get("version")
+            .and_then(|version| version.as_integer())
+            .ok_or(ConfigError::MissingVersionError)?;
+        let version = version as u32;
+        if version > CURRENT_CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersionError(version));
+        }
+
+        Ok(Self::migrate(raw_config, version)?.try_into()?)
+    }
+
+    // brings config written by an older client up to date, one version at a time
+    fn migrate(mut raw_config: toml::Value, version: u32) -> Result<toml::Value, ConfigError> {
+        match version {
+            CURRENT_CONFIG_VERSION => Ok(raw_config),
+            // version 2 only added the optional provider section
+            1 => {
+                if let Some(table) = raw_config.as_table_mut() {
+                    table.insert("version".to_string(), toml::Value::Integer(2));
+                }
+                Self::migrate(raw_config, 2)
+            }
+            _ => Err(ConfigError::UnsupportedVersionError(version)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod client_config {
+    use super::*;
+
+    #[test]
+    fn is_recovered_after_serialization() {
+        let mut config = Config::new("foomp".to_string());
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(config, Config::parse(&serialized).unwrap());
+
+        config.provider = Some(Provider {
+            id: "bar".to_string(),
+            auth_token: "baz".to_string(),
+        });
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(config, Config::parse(&serialized).unwrap());
+    }
+
+    #[test]
+    fn is_rejected_if_written_by_newer_version() {
+        let mut config = Config::new("foomp".to_string());
+        config.version = CURRENT_CONFIG_VERSION + 1;
+        let serialized = toml::to_string_pretty(&config).unwrap();
+
+        match Config::parse(&serialized) {
+            Err(ConfigError::UnsupportedVersionError(version)) => {
+                assert_eq!(CURRENT_CONFIG_VERSION + 1, version)
+            }
+            _ => panic!("expected unsupported version error"),
+        }
+    }
+
+    #[test]
+    fn is_migrated_from_version_without_provider() {
+        let mut config = Config::new("foomp".to_string());
+        config.version = 1;
+        let serialized = toml::to_string_pretty(&config).unwrap();
+
+        let migrated = Config::parse(&serialized).unwrap();
+        assert_eq!(CURRENT_CONFIG_VERSION, migrated.version);
+        assert_eq!(None, migrated.provider);
+    }
+
+    #[test]
+    fn uses_default_signature_policy_if_not_set() {
+        let config = Config::new("foomp".to_string());
+        let serialized = toml::to_string_pretty(&config)
+            .unwrap()
+            .replace("signature-policy = \"strict\"\n", "");
+        assert!(!serialized.contains("signature-policy"));
+
+        let recovered = Config::parse(&serialized).unwrap();
+        assert_eq!(
+            SignaturePolicy::default(),
+            recovered.client.signature_policy
+        );
+    }
+
+    #[test]
+    fn is_rejected_without_version() {
+        assert!(Config::parse("[client]\nid = \"foomp\"").is_err());
+    }
+}
@@ -3,20 +3,29 @@ use std::path::PathBuf;
 
 pub struct ClientPathfinder {
     pub config_dir: PathBuf,
+    pub config_file: PathBuf,
     pub private_mix_key: PathBuf,
     pub public_mix_key: PathBuf,
+    pub private_encryption_key: PathBuf,
+    pub public_encryption_key: PathBuf,
 }
 
 impl ClientPathfinder {
     pub fn new(id: String) -> Self {
         let os_config_dir = dirs::config_dir().unwrap(); // grabs the OS default config dir
         let config_dir = os_config_dir.join("nym").join("clients").join(id);
+        let config_file = config_dir.join("config.toml");
         let private_mix_key = config_dir.join("private.pem");
         let public_mix_key = config_dir.join("public.pem");
+        let private_encryption_key = config_dir.join("private_sphinx.pem");
+        let public_encryption_key = config_dir.join("public_sphinx.pem");
         ClientPathfinder {
             config_dir,
+            config_file,
             private_mix_key,
             public_mix_key,
+            private_encryption_key,
+            public_encryption_key,
         }
     }
 }
@@ -33,4 +42,12 @@ impl PathFinder for ClientPathfinder {
     fn public_identity_key(&self) -> PathBuf {
         self.public_mix_key.clone()
     }
+
+    fn private_encryption_key(&self) -> Option<PathBuf> {
+        Some(self.private_encryption_key.clone())
+    }
+
+    fn public_encryption_key(&self) -> Option<PathBuf> {
+        Some(self.public_encryption_key.clone())
+    }
 }
@@ -28,6 +28,21 @@ fn main() {
                     .help("Id of the provider we have preference to connect to. If left empty, a random provider will be chosen.")
                     .takes_value(true)
                 )
+                .arg(Arg::with_name("directory")
+                    .long("directory")
+                    .help("Address of the directory server the client is getting topology from")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("encrypt")
+                    .long("encrypt")
+                    .help("Protect the private keys with a passphrase you will be asked for")
+                )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase to protect the private keys with, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                    .requires("encrypt")
+                )
         )
         .subcommand(
             SubCommand::with_name("tcpsocket")
@@ -36,14 +51,13 @@ fn main() {
                     Arg::with_name("port")
                         .short("p")
                         .long("port")
-                        .help("Port for TCP socket to listen on")
-                        .takes_value(true)
-                        .required(true),
+                        .help("Port for TCP socket to listen on, overriding the one from the config")
+                        .takes_value(true),
                 )
                 .arg(
                     Arg::with_name("directory")
                         .long("directory")
-                        .help("Address of the directory server the client is getting topology from")
+                        .help("Address of the directory server the client is getting topology from, overriding the one from the config")
                         .takes_value(true),
                 )
                 .arg(Arg::with_name("id")
@@ -52,6 +66,15 @@ fn main() {
                     .takes_value(true)
                     .required(true)
                 )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase protecting the private keys, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("push")
+                    .long("push")
+                    .help("Have the provider push received messages rather than periodically polling for them")
+                )
         )
         .subcommand(
             SubCommand::with_name("websocket")
@@ -60,13 +83,13 @@ fn main() {
                     Arg::with_name("port")
                         .short("p")
                         .long("port")
-                        .help("Port for websocket to listen on")
+                        .help("Port for websocket to listen on, overriding the one from the config")
                         .takes_value(true)
                 )
                 .arg(
                     Arg::with_name("directory")
                         .long("directory")
-                        .help("Address of the directory server the client is getting topology from")
+                        .help("Address of the directory server the client is getting topology from, overriding the one from the config")
                         .takes_value(true),
                 )
                 .arg(Arg::with_name("id")
@@ -75,6 +98,15 @@ fn main() {
                     .takes_value(true)
                     .required(true)
                 )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase protecting the private keys, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("push")
+                    .long("push")
+                    .help("Have the provider push received messages rather than periodically polling for them")
+                )
         )
         .get_matches();
 
@@ -1,30 +1,53 @@
-use crate::client::received_buffer::BufferResponse;
-use crate::client::InputMessage;
+use crate::client::delivery_status::{DeliveryStatus, DeliveryTracker};
+use crate::client::received_buffer::BufferRequest;
+use crate::client::reply_tokens::ReplyTokenIssuer;
+use crate::client::topology_control::TopologyAccessor;
+use crate::client::{InputMessage, MAXIMUM_MESSAGE_LENGTH, MAXIMUM_REPLY_LENGTH};
+use bytes::{Bytes, BytesMut};
 use directory_client::presence::Topology;
 use futures::channel::{mpsc, oneshot};
 use futures::future::FutureExt;
 use futures::io::Error;
-use futures::SinkExt;
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use log::*;
-use sphinx::route::{Destination, DestinationAddressBytes};
-use std::borrow::Borrow;
-use std::convert::TryFrom;
+use mix_client::packet::{MessageEnvelope, ReliableMessageId, ReplyToken, REPLY_TOKEN_LENGTH};
+use sphinx::route::{Destination, DestinationAddressBytes, SURBIdentifier};
+use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::prelude::*;
+use tokio::net::TcpStream;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
 const SEND_REQUEST_PREFIX: u8 = 1;
 const FETCH_REQUEST_PREFIX: u8 = 2;
 const GET_CLIENTS_REQUEST_PREFIX: u8 = 3;
 const OWN_DETAILS_REQUEST_PREFIX: u8 = 4;
+const SEND_WITH_REPLY_TOKEN_REQUEST_PREFIX: u8 = 5;
+const REPLY_REQUEST_PREFIX: u8 = 6;
+const SEND_RELIABLE_REQUEST_PREFIX: u8 = 7;
+const SEND_RELIABLE_WITH_REPLY_TOKEN_REQUEST_PREFIX: u8 = 8;
+const DELIVERY_STATUS_REQUEST_PREFIX: u8 = 9;
+const SUBSCRIBE_REQUEST_PREFIX: u8 = 10;
+
+// send requests are the longest ones: prefix || recipient address || message
+const MAXIMUM_REQUEST_LENGTH: usize = 1 + 32 + MAXIMUM_MESSAGE_LENGTH;
+
+// single byte response to delivery status requests
+const UNKNOWN_MESSAGE_STATUS: u8 = 0;
+const PENDING_MESSAGE_STATUS: u8 = 1;
+const DELIVERED_MESSAGE_STATUS: u8 = 2;
+const FAILED_MESSAGE_STATUS: u8 = 3;
 
 #[derive(Debug)]
 pub enum TCPSocketError {
     FailedToStartSocketError,
     UnknownSocketError,
     IncompleteDataError,
+    TooLongMessageError,
     UnknownRequestError,
+    // subscription is only possible on TCP connections, as it turns them into a stream of
+    // received messages
+    UnsupportedRequestError,
 }
 
 impl From<io::Error> for TCPSocketError {
@@ -47,8 +70,17 @@ enum ClientRequest {
     Send {
         message: Vec<u8>,
         recipient_address: DestinationAddressBytes,
+        with_reply_token: bool,
+        reliable: bool,
+    },
+    Reply {
+        message: Vec<u8>,
+        reply_token: ReplyToken,
     },
     Fetch,
+    DeliveryStatus {
+        message_id: ReliableMessageId,
+    },
     GetClients,
     OwnDetails,
 }
@@ -63,7 +95,13 @@ impl TryFrom<&[u8]> for ClientRequest {
         }
 
         match data[0] {
-            SEND_REQUEST_PREFIX => parse_send_request(data),
+            SEND_REQUEST_PREFIX => parse_send_request(data, false, false),
+            SEND_WITH_REPLY_TOKEN_REQUEST_PREFIX => parse_send_request(data, true, false),
+            SEND_RELIABLE_REQUEST_PREFIX => parse_send_request(data, false, true),
+            SEND_RELIABLE_WITH_REPLY_TOKEN_REQUEST_PREFIX => parse_send_request(data, true, true),
+            REPLY_REQUEST_PREFIX => parse_reply_request(data),
+            DELIVERY_STATUS_REQUEST_PREFIX => parse_delivery_status_request(data),
+            SUBSCRIBE_REQUEST_PREFIX => Err(UnsupportedRequestError),
             FETCH_REQUEST_PREFIX => Ok(ClientRequest::Fetch),
             GET_CLIENTS_REQUEST_PREFIX => Ok(ClientRequest::GetClients),
             OWN_DETAILS_REQUEST_PREFIX => Ok(ClientRequest::OwnDetails),
@@ -72,7 +110,11 @@ impl TryFrom<&[u8]> for ClientRequest {
     }
 }
 
-fn parse_send_request(data: &[u8]) -> Result<ClientRequest, TCPSocketError> {
+fn parse_send_request(
+    data: &[u8],
+    with_reply_token: bool,
+    reliable: bool,
+) -> Result<ClientRequest, TCPSocketError> {
     if data.len() < 1 + 32 + 1 {
         // make sure it has the prefix, destination and at least single byte of data
         return Err(TCPSocketError::IncompleteDataError);
@@ -82,30 +124,124 @@ fn parse_send_request(data: &[u8]) -> Result<ClientRequest, TCPSocketError> {
     recipient_address.copy_from_slice(&data[1..33]);
 
     let message = data[33..].to_vec();
+    if message.len() > MAXIMUM_MESSAGE_LENGTH {
+        return Err(TCPSocketError::TooLongMessageError);
+    }
 
     Ok(ClientRequest::Send {
         message,
         recipient_address,
+        with_reply_token,
+        reliable,
+    })
+}
+
+fn parse_reply_request(data: &[u8]) -> Result<ClientRequest, TCPSocketError> {
+    if data.len() < 1 + REPLY_TOKEN_LENGTH + 1 {
+        // make sure it has the prefix, reply token and at least single byte of data
+        return Err(TCPSocketError::IncompleteDataError);
+    }
+
+    // this can't fail as the token has a constant length which we have just checked
+    let reply_token = ReplyToken::from_bytes(&data[1..1 + REPLY_TOKEN_LENGTH]).unwrap();
+    let message = data[1 + REPLY_TOKEN_LENGTH..].to_vec();
+    if message.len() > MAXIMUM_REPLY_LENGTH {
+        return Err(TCPSocketError::TooLongMessageError);
+    }
+
+    Ok(ClientRequest::Reply {
+        message,
+        reply_token,
     })
 }
 
+fn parse_delivery_status_request(data: &[u8]) -> Result<ClientRequest, TCPSocketError> {
+    if data.len() != 1 + 8 {
+        return Err(TCPSocketError::IncompleteDataError);
+    }
+
+    // this can't fail as we've just checked the length
+    let message_id = ReliableMessageId::from_be_bytes(data[1..].try_into().unwrap());
+    Ok(ClientRequest::DeliveryStatus { message_id })
+}
+
 impl ClientRequest {
+    // with reply token the recipient is going to be able to reply exactly once, while reliable
+    // messages are retransmitted until the recipient acknowledges them
     async fn handle_send(
         msg: Vec<u8>,
         recipient_address: DestinationAddressBytes,
         mut input_tx: mpsc::UnboundedSender<InputMessage>,
+        reply_token_issuer: Option<&ReplyTokenIssuer>,
+        delivery_tracker: Option<&DeliveryTracker>,
     ) -> ServerResponse {
         trace!("sending to: {:?}, msg: {:?}", recipient_address, msg);
-        let dummy_surb = [0; 16];
-        let input_msg = InputMessage(Destination::new(recipient_address, dummy_surb), msg);
+        let (envelope, reply_token_id) = match reply_token_issuer {
+            None => (MessageEnvelope::Plain(msg), None),
+            Some(reply_token_issuer) => match reply_token_issuer.issue().await {
+                Ok(reply_token) => (
+                    MessageEnvelope::WithReplyToken(reply_token, msg),
+                    Some(reply_token.id),
+                ),
+                Err(err) => {
+                    let e = format!("Failed to create reply token: {:?}", err);
+                    error!("{}", e);
+                    return ServerResponse::Error { message: e };
+                }
+            },
+        };
+
+        let destination = Destination::new(recipient_address, Default::default());
+        let (input_msg, message_id) = match delivery_tracker {
+            None => (InputMessage::Forward(destination, envelope), None),
+            Some(delivery_tracker) => {
+                let message_id: ReliableMessageId = rand::random();
+                delivery_tracker.update(message_id, DeliveryStatus::Pending);
+                (
+                    InputMessage::ReliableForward(message_id, destination, envelope),
+                    Some(message_id),
+                )
+            }
+        };
         input_tx.send(input_msg).await.unwrap();
-        ServerResponse::Send
+        ServerResponse::Send {
+            message_id,
+            reply_token_id,
+        }
     }
 
-    async fn handle_fetch(mut msg_query: mpsc::UnboundedSender<BufferResponse>) -> ServerResponse {
+    async fn handle_reply(
+        msg: Vec<u8>,
+        reply_token: ReplyToken,
+        mut input_tx: mpsc::UnboundedSender<InputMessage>,
+    ) -> ServerResponse {
+        trace!("replying to: {:?}, msg: {:?}", reply_token.id, msg);
+        let input_msg = InputMessage::Reply(reply_token, msg);
+        if input_tx.send(input_msg).await.is_err() {
+            let e = "Nym-client TCP socket failed to queue the reply".to_string();
+            error!("{}", e);
+            return ServerResponse::Error { message: e };
+        }
+        ServerResponse::Reply
+    }
+
+    async fn handle_delivery_status(
+        message_id: ReliableMessageId,
+        delivery_tracker: &DeliveryTracker,
+    ) -> ServerResponse {
+        let status = match delivery_tracker.status(message_id) {
+            None => UNKNOWN_MESSAGE_STATUS,
+            Some(DeliveryStatus::Pending) => PENDING_MESSAGE_STATUS,
+            Some(DeliveryStatus::Delivered) => DELIVERED_MESSAGE_STATUS,
+            Some(DeliveryStatus::Failed) => FAILED_MESSAGE_STATUS,
+        };
+        ServerResponse::DeliveryStatus { status }
+    }
+
+    async fn handle_fetch(mut msg_query: mpsc::UnboundedSender<BufferRequest>) -> ServerResponse {
         trace!("handle_fetch called");
         let (res_tx, res_rx) = oneshot::channel();
-        if msg_query.send(res_tx).await.is_err() {
+        if msg_query.send(BufferRequest::Fetch(res_tx)).await.is_err() {
             let e = "Nym-client TCP socket failed to receive messages".to_string();
             error!("{}", e);
             return ServerResponse::Error { message: e };
@@ -142,18 +278,47 @@ impl ClientRequest {
 }
 
 enum ServerResponse {
-    Send,
-    Fetch { messages: Vec<Vec<u8>> },
-    GetClients { clients: Vec<Vec<u8>> },
-    OwnDetails { address: Vec<u8> },
-    Error { message: String },
+    Send {
+        message_id: Option<ReliableMessageId>,
+        reply_token_id: Option<SURBIdentifier>,
+    },
+    Reply,
+    Fetch {
+        messages: Vec<MessageEnvelope>,
+    },
+    DeliveryStatus {
+        status: u8,
+    },
+    GetClients {
+        clients: Vec<Vec<u8>>,
+    },
+    OwnDetails {
+        address: Vec<u8>,
+    },
+    Error {
+        message: String,
+    },
 }
 
 impl Into<Vec<u8>> for ServerResponse {
     fn into(self) -> Vec<u8> {
         match self {
-            ServerResponse::Send => b"ok".to_vec(),
+            ServerResponse::Send {
+                message_id: None,
+                reply_token_id: None,
+            } => b"ok".to_vec(),
+            // [message_id] || [reply_token_id], depending on what kind of send it was
+            ServerResponse::Send {
+                message_id,
+                reply_token_id,
+            } => message_id
+                .iter()
+                .flat_map(|id| id.to_be_bytes().to_vec().into_iter())
+                .chain(reply_token_id.iter().flat_map(|id| id.to_vec().into_iter()))
+                .collect(),
+            ServerResponse::Reply => b"ok".to_vec(),
             ServerResponse::Fetch { messages } => encode_fetched_messages(messages),
+            ServerResponse::DeliveryStatus { status } => vec![status],
             ServerResponse::GetClients { clients } => encode_list_of_clients(clients),
             ServerResponse::OwnDetails { address } => address,
             ServerResponse::Error { message } => message.as_bytes().to_vec(),
@@ -162,8 +327,12 @@ impl Into<Vec<u8>> for ServerResponse {
 }
 
 // num_msgs || len1 || len2 || ... || msg1 || msg2 || ...
-fn encode_fetched_messages(messages: Vec<Vec<u8>>) -> Vec<u8> {
-    // for reciprocal of this look into sfw-provider-requests::responses::PullResponse::from_bytes()
+// where each msg is an encoded `MessageEnvelope`, i.e. its first byte says whether it's a plain
+// message, a message with attached reply token or a reply to one of our reply tokens
+fn encode_fetched_messages(messages: Vec<MessageEnvelope>) -> Vec<u8> {
+    // this is similar to sfw-provider-requests::responses::PullResponse::to_bytes(), but without
+    // the message ids
+    let messages: Vec<_> = messages.iter().map(|msg| msg.to_bytes()).collect();
 
     let num_msgs = messages.len() as u16;
     let msgs_lens: Vec<u16> = messages.iter().map(|msg| msg.len() as u16).collect();
@@ -205,13 +374,48 @@ async fn handle_connection(
         ClientRequest::Send {
             message,
             recipient_address,
+            with_reply_token,
+            reliable,
         } => {
-            ClientRequest::handle_send(message, recipient_address, request_handling_data.msg_input)
-                .await
+            let reply_token_issuer = if with_reply_token {
+                Some(&request_handling_data.reply_token_issuer)
+            } else {
+                None
+            };
+            let delivery_tracker = if reliable {
+                Some(&request_handling_data.delivery_tracker)
+            } else {
+                None
+            };
+            ClientRequest::handle_send(
+                message,
+                recipient_address,
+                request_handling_data.msg_input,
+                reply_token_issuer,
+                delivery_tracker,
+            )
+            .await
+        }
+        ClientRequest::Reply {
+            message,
+            reply_token,
+        } => {
+            ClientRequest::handle_reply(message, reply_token, request_handling_data.msg_input).await
         }
         ClientRequest::Fetch => ClientRequest::handle_fetch(request_handling_data.msg_query).await,
+        ClientRequest::DeliveryStatus { message_id } => {
+            ClientRequest::handle_delivery_status(
+                message_id,
+                &request_handling_data.delivery_tracker,
+            )
+            .await
+        }
         ClientRequest::GetClients => {
-            ClientRequest::handle_get_clients(request_handling_data.topology.borrow()).await
+            let topology = request_handling_data
+                .topology_accessor
+                .get_current_topology_clone()
+                .await;
+            ClientRequest::handle_get_clients(&topology).await
         }
         ClientRequest::OwnDetails => {
             ClientRequest::handle_own_details(request_handling_data.self_address).await
@@ -221,59 +425,159 @@ async fn handle_connection(
     Ok(response)
 }
 
-struct RequestHandlingData {
-    msg_input: mpsc::UnboundedSender<InputMessage>,
-    msg_query: mpsc::UnboundedSender<BufferResponse>,
-    self_address: DestinationAddressBytes,
-    topology: Arc<Topology>,
+pub(crate) struct RequestHandlingData {
+    pub(crate) msg_input: mpsc::UnboundedSender<InputMessage>,
+    pub(crate) msg_query: mpsc::UnboundedSender<BufferRequest>,
+    pub(crate) self_address: DestinationAddressBytes,
+    pub(crate) topology_accessor: TopologyAccessor,
+    pub(crate) reply_token_issuer: ReplyTokenIssuer,
+    pub(crate) delivery_tracker: DeliveryTracker,
+}
+
+// the same encoding of requests and responses is used for binary websocket messages
+pub(crate) async fn handle_binary_request(
+    data: &[u8],
+    request_handling_data: RequestHandlingData,
+) -> Vec<u8> {
+    let response = match handle_connection(data, request_handling_data).await {
+        Ok(res) => res,
+        Err(e) => ServerResponse::new_error(format!("{:?}", e)),
+    };
+    response.into()
+}
+
+// Once subscribed, the connection is only used for pushing received messages to the client as they
+// arrive, each batch encoded the same way as the response to fetch request.
+async fn push_received_messages<R, W>(
+    mut requests: R,
+    mut responses: W,
+    mut msg_query: mpsc::UnboundedSender<BufferRequest>,
+) where
+    R: Stream<Item = Result<BytesMut, io::Error>> + Unpin,
+    W: Sink<Bytes, Error = io::Error> + Unpin,
+{
+    let (subscriber_tx, mut subscriber_rx) = mpsc::unbounded();
+    if msg_query
+        .send(BufferRequest::Subscribe(subscriber_tx))
+        .await
+        .is_err()
+    {
+        error!("Nym-client TCP socket failed to subscribe to received messages");
+        return;
+    }
+
+    if let Err(e) = responses.send(b"ok".to_vec().into()).await {
+        warn!("failed to write reply to socket; err = {:?}", e);
+        return;
+    }
+
+    // we do not expect any more requests, but we need to keep reading to know as soon as
+    // possible if the connection got closed
+    let mut undelivered = Vec::new();
+    loop {
+        tokio::select! {
+            messages = subscriber_rx.next() => match messages {
+                // the buffer is gone, so there's nothing more to push
+                None => return,
+                Some(messages) => {
+                    let response: Vec<u8> = ServerResponse::Fetch {
+                        messages: messages.clone(),
+                    }
+                    .into();
+                    if let Err(e) = responses.send(response.into()).await {
+                        warn!("failed to push messages to socket; err = {:?}", e);
+                        undelivered.extend(messages);
+                        break;
+                    }
+                }
+            },
+            request = requests.next() => {
+                match request {
+                    None => trace!("Remote connection closed."),
+                    Some(Ok(_)) => warn!("subscribed client sent unexpected data - closing the connection"),
+                    Some(Err(e)) => warn!("failed to read from socket; err = {:?}", e),
+                }
+                break;
+            }
+        }
+    }
+
+    // whatever was already handed to us, but not sent, goes back to the buffer
+    subscriber_rx.close();
+    while let Ok(Some(messages)) = subscriber_rx.try_next() {
+        undelivered.extend(messages);
+    }
+    if !undelivered.is_empty()
+        && msg_query
+            .send(BufferRequest::Restore(undelivered))
+            .await
+            .is_err()
+    {
+        error!("Failed to restore undelivered messages");
+    }
 }
 
 async fn accept_connection(
-    mut socket: tokio::net::TcpStream,
+    socket: TcpStream,
     msg_input: mpsc::UnboundedSender<InputMessage>,
-    msg_query: mpsc::UnboundedSender<BufferResponse>,
+    msg_query: mpsc::UnboundedSender<BufferRequest>,
     self_address: DestinationAddressBytes,
-    topology: Topology,
+    topology_accessor: TopologyAccessor,
+    reply_token_issuer: ReplyTokenIssuer,
+    delivery_tracker: DeliveryTracker,
 ) {
     let address = socket
         .peer_addr()
         .expect("connected streams should have a peer address");
     debug!("Peer address: {}", address);
 
-    let topology = Arc::new(topology);
+    // every request and response is preceded by its length, so that they could be split into
+    // (or arrive in) any number of segments
+    let (reader, writer) = tokio::io::split(socket);
+    let mut requests = FramedRead::new(
+        reader,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(MAXIMUM_REQUEST_LENGTH)
+            .new_codec(),
+    );
+    // there's no limit on how many messages can be fetched at once
+    let mut responses = FramedWrite::new(
+        writer,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(std::u32::MAX as usize)
+            .new_codec(),
+    );
 
-    let mut buf = [0u8; 2048];
-
-    // In a loop, read data from the socket and write the data back.
     loop {
         // TODO: shutdowns?
 
-        let response = match socket.read(&mut buf).await {
+        let response = match requests.next().await {
             // socket closed
-            Ok(n) if n == 0 => {
+            None => {
                 trace!("Remote connection closed.");
                 return;
             }
-            Ok(n) => {
+            Some(Ok(request)) if request[..] == [SUBSCRIBE_REQUEST_PREFIX] => {
+                return push_received_messages(requests, responses, msg_query).await;
+            }
+            Some(Ok(request)) => {
                 let request_handling_data = RequestHandlingData {
-                    topology: topology.clone(),
+                    topology_accessor: topology_accessor.clone(),
                     msg_input: msg_input.clone(),
                     msg_query: msg_query.clone(),
                     self_address: self_address.clone(),
+                    reply_token_issuer: reply_token_issuer.clone(),
+                    delivery_tracker: delivery_tracker.clone(),
                 };
-                match handle_connection(&buf[..n], request_handling_data).await {
-                    Ok(res) => res,
-                    Err(e) => ServerResponse::new_error(format!("{:?}", e)),
-                }
+                handle_binary_request(&request, request_handling_data).await
             }
-            Err(e) => {
+            Some(Err(e)) => {
                 warn!("failed to read from socket; err = {:?}", e);
                 return;
             }
         };
 
-        let response_vec: Vec<u8> = response.into();
-        if let Err(e) = socket.write_all(&response_vec).await {
+        if let Err(e) = responses.send(response.into()).await {
             warn!("failed to write reply to socket; err = {:?}", e);
             return;
         }
@@ -283,9 +587,11 @@ async fn accept_connection(
 pub async fn start_tcpsocket(
     address: SocketAddr,
     message_tx: mpsc::UnboundedSender<InputMessage>,
-    received_messages_query_tx: mpsc::UnboundedSender<BufferResponse>,
+    received_messages_query_tx: mpsc::UnboundedSender<BufferRequest>,
     self_address: DestinationAddressBytes,
-    topology: Topology,
+    topology_accessor: TopologyAccessor,
+    reply_token_issuer: ReplyTokenIssuer,
+    delivery_tracker: DeliveryTracker,
 ) -> Result<(), TCPSocketError> {
     let mut listener = tokio::net::TcpListener::bind(address).await?;
 
@@ -297,10 +603,248 @@ pub async fn start_tcpsocket(
             message_tx.clone(),
             received_messages_query_tx.clone(),
             self_address,
-            topology.clone(),
+            topology_accessor.clone(),
+            reply_token_issuer.clone(),
+            delivery_tracker.clone(),
         ));
     }
 
     error!("The tcpsocket went kaput...");
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tcp_socket {
+    use super::*;
+    use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+    use mix_client::fragmentation::{self, MessageReassembler};
+    use provider_client::ProviderClient;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Framed;
+
+    // none of the requests used in the tests reaches the provider or the network topology
+    pub(crate) fn request_handling_data(
+        msg_input: mpsc::UnboundedSender<InputMessage>,
+        msg_query: mpsc::UnboundedSender<BufferRequest>,
+    ) -> RequestHandlingData {
+        let provider_client = ProviderClient::new(
+            "127.0.0.1:1".parse().unwrap(),
+            ed25519::KeyPair::new().private_key().clone(),
+            None,
+        );
+        RequestHandlingData {
+            msg_input,
+            msg_query,
+            self_address: [42u8; 32],
+            topology_accessor: TopologyAccessor::new(Topology {
+                coco_nodes: Vec::new(),
+                mix_nodes: Vec::new(),
+                mix_provider_nodes: Vec::new(),
+            }),
+            reply_token_issuer: ReplyTokenIssuer::new(Arc::new(provider_client), [1u8; 32]),
+            delivery_tracker: DeliveryTracker::new(),
+        }
+    }
+
+    struct TestConnection {
+        socket: Framed<TcpStream, LengthDelimitedCodec>,
+        input_rx: mpsc::UnboundedReceiver<InputMessage>,
+        query_rx: mpsc::UnboundedReceiver<BufferRequest>,
+    }
+
+    async fn connect() -> TestConnection {
+        let (input_tx, input_rx) = mpsc::unbounded();
+        let (query_tx, query_rx) = mpsc::unbounded();
+        let data = request_handling_data(input_tx, query_tx);
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept_connection(
+                socket,
+                data.msg_input,
+                data.msg_query,
+                data.self_address,
+                data.topology_accessor,
+                data.reply_token_issuer,
+                data.delivery_tracker,
+            )
+            .await
+        });
+
+        let socket = TcpStream::connect(address).await.unwrap();
+        TestConnection {
+            socket: Framed::new(socket, LengthDelimitedCodec::new()),
+            input_rx,
+            query_rx,
+        }
+    }
+
+    #[tokio::test]
+    async fn long_message_arriving_in_multiple_segments_is_sent_in_full() {
+        let mut connection = connect().await;
+        let message: Vec<_> = (0..fragmentation::MAXIMUM_FRAGMENT_DATA_LENGTH * 3 + 42)
+            .map(|i| i as u8)
+            .collect();
+        let request: Vec<_> = std::iter::once(SEND_REQUEST_PREFIX)
+            .chain([7u8; 32].iter().cloned())
+            .chain(message.iter().cloned())
+            .collect();
+        let frame: Vec<_> = (request.len() as u32)
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(request.into_iter())
+            .collect();
+
+        // each write is likely to be received separately
+        for segment in frame.chunks(1000) {
+            connection
+                .socket
+                .get_mut()
+                .write_all(segment)
+                .await
+                .unwrap();
+            connection.socket.get_mut().flush().await.unwrap();
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        let response = connection.socket.next().await.unwrap().unwrap();
+        assert_eq!(b"ok", &response[..]);
+
+        let envelope = match connection.input_rx.next().await.unwrap() {
+            InputMessage::Forward(destination, envelope) => {
+                assert_eq!([7u8; 32], destination.address);
+                envelope
+            }
+            _ => panic!("expected the message to be forwarded"),
+        };
+
+        // and it should get to the recipient over multiple packets
+        let fragments = fragmentation::split_into_fragments(&envelope.to_bytes()).unwrap();
+        assert!(fragments.len() > 1);
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(60), 10);
+        let reassembled = fragments
+            .into_iter()
+            .filter_map(|fragment| reassembler.insert_fragment(fragment))
+            .next()
+            .unwrap();
+        assert_eq!(
+            MessageEnvelope::Plain(message),
+            MessageEnvelope::from_bytes(&reassembled).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn multiple_requests_can_be_sent_over_the_same_connection() {
+        let mut connection = connect().await;
+        let received = vec![MessageEnvelope::Plain(vec![42u8; 5000])];
+        let buffered = received.clone();
+        let mut query_rx = connection.query_rx;
+        tokio::spawn(async move {
+            while let Some(request) = query_rx.next().await {
+                if let BufferRequest::Fetch(response) = request {
+                    response.send(buffered.clone()).unwrap();
+                }
+            }
+        });
+
+        for _ in 0..2 {
+            connection
+                .socket
+                .send(vec![OWN_DETAILS_REQUEST_PREFIX].into())
+                .await
+                .unwrap();
+            let response = connection.socket.next().await.unwrap().unwrap();
+            assert_eq!([42u8; 32], &response[..]);
+
+            connection
+                .socket
+                .send(vec![FETCH_REQUEST_PREFIX].into())
+                .await
+                .unwrap();
+            let response = connection.socket.next().await.unwrap().unwrap();
+            assert_eq!(encode_fetched_messages(received.clone()), &response[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribed_client_receives_pushed_messages() {
+        let mut connection = connect().await;
+        connection
+            .socket
+            .send(vec![SUBSCRIBE_REQUEST_PREFIX].into())
+            .await
+            .unwrap();
+        let subscriber = match connection.query_rx.next().await.unwrap() {
+            BufferRequest::Subscribe(subscriber) => subscriber,
+            _ => panic!("expected subscription request"),
+        };
+        let response = connection.socket.next().await.unwrap().unwrap();
+        assert_eq!(b"ok", &response[..]);
+
+        for i in 0..2 {
+            let messages = vec![
+                MessageEnvelope::Plain(vec![i; 3000]),
+                MessageEnvelope::Plain(vec![i; 42]),
+            ];
+            subscriber.unbounded_send(messages.clone()).unwrap();
+            let pushed = connection.socket.next().await.unwrap().unwrap();
+            assert_eq!(encode_fetched_messages(messages), &pushed[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_that_could_not_be_pushed_are_restored() {
+        let (query_tx, mut query_rx) = mpsc::unbounded();
+        let (responses_tx, mut responses_rx) = mpsc::unbounded::<Bytes>();
+        let responses = responses_tx.sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+        tokio::spawn(push_received_messages(
+            futures::stream::pending(),
+            responses,
+            query_tx,
+        ));
+
+        let subscriber = match query_rx.next().await.unwrap() {
+            BufferRequest::Subscribe(subscriber) => subscriber,
+            _ => panic!("expected subscription request"),
+        };
+        assert_eq!(b"ok", &responses_rx.next().await.unwrap()[..]);
+
+        // the client is gone before we manage to push anything to it
+        drop(responses_rx);
+        let messages = vec![MessageEnvelope::Plain(b"foomp".to_vec())];
+        subscriber.unbounded_send(messages.clone()).unwrap();
+        match query_rx.next().await.unwrap() {
+            BufferRequest::Restore(restored) => assert_eq!(messages, restored),
+            _ => panic!("expected undelivered messages to be restored"),
+        }
+        assert!(subscriber.is_closed());
+    }
+
+    #[tokio::test]
+    async fn reply_that_could_not_be_queued_is_reported_as_failed() {
+        let (input_tx, input_rx) = mpsc::unbounded();
+        let (query_tx, _query_rx) = mpsc::unbounded();
+        let reply_token = ReplyToken::new([1u8; 32], [2u8; 16]);
+        let request: Vec<_> = std::iter::once(REPLY_REQUEST_PREFIX)
+            .chain(reply_token.to_bytes().into_iter())
+            .chain(b"foomp".iter().cloned())
+            .collect();
+
+        let queued = handle_binary_request(
+            &request,
+            request_handling_data(input_tx.clone(), query_tx.clone()),
+        )
+        .await;
+        assert_eq!(b"ok", &queued[..]);
+
+        // nothing is going to send the reply anymore
+        drop(input_rx);
+        let failed =
+            handle_binary_request(&request, request_handling_data(input_tx, query_tx)).await;
+        assert_ne!(b"ok", &failed[..]);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::client::received_buffer::BufferResponse;
-use crate::client::InputMessage;
+use crate::client::delivery_status::{DeliveryStatus, DeliveryTracker};
+use crate::client::received_buffer::{BufferRequest, MessagesSubscriber};
+use crate::client::reply_tokens::ReplyTokenIssuer;
+use crate::client::topology_control::TopologyAccessor;
+use crate::client::{InputMessage, MAXIMUM_MESSAGE_LENGTH, MAXIMUM_REPLY_LENGTH};
+use crate::sockets::tcp::{self, RequestHandlingData};
 use directory_client::presence::Topology;
 use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures::channel::{mpsc, oneshot};
@@ -7,6 +11,7 @@ use futures::future::FutureExt;
 use futures::io::Error;
 use futures::{SinkExt, StreamExt};
 use log::{debug, error, info, trace, warn};
+use mix_client::packet::{MessageEnvelope, ReliableMessageId, ReplyToken};
 use serde::{Deserialize, Serialize};
 use sphinx::route::{Destination, DestinationAddressBytes};
 use std::convert::TryFrom;
@@ -18,15 +23,26 @@ use tungstenite::protocol::{CloseFrame, Message};
 struct Connection {
     address: SocketAddr,
     msg_input: mpsc::UnboundedSender<InputMessage>,
-    msg_query: mpsc::UnboundedSender<BufferResponse>,
+    msg_query: mpsc::UnboundedSender<BufferRequest>,
+    // received messages pushed by the buffer once the client subscribes to them
+    pushed_tx: MessagesSubscriber,
+    pushed_rx: UnboundedReceiver<Vec<MessageEnvelope>>,
+    subscribed: bool,
     rx: UnboundedReceiver<Message>,
     self_address: DestinationAddressBytes,
-    topology: Topology,
+    topology_accessor: TopologyAccessor,
+    reply_token_issuer: ReplyTokenIssuer,
+    delivery_tracker: DeliveryTracker,
     tx: UnboundedSender<Message>,
 }
 
+enum ConnectionEvent {
+    Request(Message),
+    Received(Vec<MessageEnvelope>),
+}
+
 impl Connection {
-    async fn handle_text_message(&self, msg: String) -> ServerResponse {
+    async fn handle_text_message(&mut self, msg: String) -> ServerResponse {
         debug!("Handling text message request");
         trace!("Content: {:?}", msg.clone());
 
@@ -44,26 +60,71 @@ impl Connection {
             ClientRequest::Send {
                 message,
                 recipient_address,
+                with_reply_token,
+                reliable,
             } => {
-                ClientRequest::handle_send(message, recipient_address, self.msg_input.clone()).await
+                let reply_token_issuer = if with_reply_token {
+                    Some(&self.reply_token_issuer)
+                } else {
+                    None
+                };
+                let delivery_tracker = if reliable {
+                    Some(&self.delivery_tracker)
+                } else {
+                    None
+                };
+                ClientRequest::handle_send(
+                    message,
+                    recipient_address,
+                    reply_token_issuer,
+                    delivery_tracker,
+                    self.msg_input.clone(),
+                )
+                .await
             }
+            ClientRequest::Reply {
+                message,
+                reply_token,
+            } => ClientRequest::handle_reply(message, reply_token, self.msg_input.clone()).await,
             ClientRequest::Fetch => ClientRequest::handle_fetch(self.msg_query.clone()).await,
+            ClientRequest::Subscribe => {
+                // subscribing again would make the buffer push everything twice
+                if self.subscribed {
+                    return ServerResponse::Subscribe;
+                }
+                let response =
+                    ClientRequest::handle_subscribe(self.msg_query.clone(), self.pushed_tx.clone())
+                        .await;
+                if let ServerResponse::Subscribe = response {
+                    self.subscribed = true;
+                }
+                response
+            }
+            ClientRequest::DeliveryStatus { message_id } => {
+                ClientRequest::handle_delivery_status(message_id, &self.delivery_tracker).await
+            }
             ClientRequest::GetClients => {
-                ClientRequest::handle_get_clients(self.topology.clone()).await
+                let topology = self.topology_accessor.get_current_topology_clone().await;
+                ClientRequest::handle_get_clients(topology).await
             }
             ClientRequest::OwnDetails => ClientRequest::handle_own_details(self.self_address).await,
         }
     }
 
-    // Currently our websocket cannot handle binary data, so just close the connection
-    // with unsupported close code.
-    async fn handle_binary_message(&self, _msg: Vec<u8>) -> Message {
+    // Binary requests and responses are encoded exactly the same way as for the TCP socket.
+    // Unlike the JSON ones, they can carry arbitrary message content.
+    async fn handle_binary_message(&self, msg: Vec<u8>) -> Message {
         debug!("Handling binary message request");
 
-        Message::Close(Some(CloseFrame {
-            code: CloseCode::Unsupported,
-            reason: "binary messages aren't yet supported".into(),
-        }))
+        let request_handling_data = RequestHandlingData {
+            msg_input: self.msg_input.clone(),
+            msg_query: self.msg_query.clone(),
+            self_address: self.self_address,
+            topology_accessor: self.topology_accessor.clone(),
+            reply_token_issuer: self.reply_token_issuer.clone(),
+            delivery_tracker: self.delivery_tracker.clone(),
+        };
+        Message::Binary(tcp::handle_binary_request(&msg, request_handling_data).await)
     }
 
     // As per RFC6455 5.5.2. and 5.5.3.:
@@ -118,15 +179,63 @@ impl Connection {
         Message::Close(close_frame)
     }
 
+    async fn handle_request(&mut self, msg: Message) -> Message {
+        trace!("Received a message from {}: {}", self.address, msg);
+        match msg {
+            Message::Text(text_message) => self.handle_text_message(text_message).await.into(),
+            Message::Binary(binary_message) => self.handle_binary_message(binary_message).await,
+            Message::Ping(ping_message) => self.handle_ping_message(ping_message).await,
+            Message::Pong(pong_message) => self.handle_pong_message(pong_message).await,
+            Message::Close(close_frame) => self.handle_close_message(close_frame).await,
+        }
+    }
+
+    // messages that were pushed to us by the buffer, but never made it to the client,
+    // are given back so that they could be fetched later
+    async fn restore_undelivered(&mut self, mut undelivered: Vec<MessageEnvelope>) {
+        self.pushed_rx.close();
+        while let Ok(Some(messages)) = self.pushed_rx.try_next() {
+            undelivered.extend(messages);
+        }
+        if !undelivered.is_empty()
+            && self
+                .msg_query
+                .send(BufferRequest::Restore(undelivered))
+                .await
+                .is_err()
+        {
+            error!("Failed to restore undelivered messages");
+        }
+    }
+
     async fn handle(mut self) {
-        while let Some(msg) = self.rx.next().await {
-            trace!("Received a message from {}: {}", self.address, msg);
-            let response_message = match msg {
-                Message::Text(text_message) => self.handle_text_message(text_message).await.into(),
-                Message::Binary(binary_message) => self.handle_binary_message(binary_message).await,
-                Message::Ping(ping_message) => self.handle_ping_message(ping_message).await,
-                Message::Pong(pong_message) => self.handle_pong_message(pong_message).await,
-                Message::Close(close_frame) => self.handle_close_message(close_frame).await,
+        let mut undelivered = Vec::new();
+        loop {
+            // requests are handled one at a time, but received messages can be pushed in between
+            let event = tokio::select! {
+                msg = self.rx.next() => match msg {
+                    Some(msg) => ConnectionEvent::Request(msg),
+                    None => break,
+                },
+                messages = self.pushed_rx.next() => match messages {
+                    Some(messages) => ConnectionEvent::Received(messages),
+                    // can't happen as we're holding the sender ourselves
+                    None => break,
+                },
+            };
+
+            let (response_message, pushed_messages) = match event {
+                ConnectionEvent::Request(msg) => (self.handle_request(msg).await, None),
+                ConnectionEvent::Received(messages) => {
+                    let response = ServerResponse::Received {
+                        messages: messages
+                            .iter()
+                            .cloned()
+                            .map(ReceivedMessage::from)
+                            .collect(),
+                    };
+                    (response.into(), Some(messages))
+                }
             };
 
             if let Err(err) = self.tx.unbounded_send(response_message) {
@@ -135,9 +244,12 @@ impl Connection {
                      Shutting off the connection handler",
                     err
                 );
-                return;
+                undelivered.extend(pushed_messages.into_iter().flatten());
+                break;
             }
         }
+
+        self.restore_undelivered(undelivered).await;
     }
 }
 
@@ -168,8 +280,23 @@ enum ClientRequest {
     Send {
         message: String,
         recipient_address: String,
+        // whether the recipient should be able to reply to the message
+        #[serde(default)]
+        with_reply_token: bool,
+        // whether the message should be retransmitted until the recipient acknowledges it
+        #[serde(default)]
+        reliable: bool,
+    },
+    Reply {
+        message: String,
+        reply_token: String,
     },
     Fetch,
+    // from now on, received messages are pushed to the client as they arrive
+    Subscribe,
+    DeliveryStatus {
+        message_id: String,
+    },
     GetClients,
     OwnDetails,
 }
@@ -182,30 +309,33 @@ impl TryFrom<String> for ClientRequest {
     }
 }
 
+fn check_message_length(
+    message_bytes: &[u8],
+    maximum_message_length: usize,
+) -> Result<(), ServerResponse> {
+    if message_bytes.len() > maximum_message_length {
+        return Err(ServerResponse::Error {
+            message: format!(
+                "too long message. Sent {} bytes while the maximum is {}",
+                message_bytes.len(),
+                maximum_message_length
+            ),
+        });
+    }
+    Ok(())
+}
+
 impl ClientRequest {
     async fn handle_send(
         msg: String,
         recipient_address: String,
+        reply_token_issuer: Option<&ReplyTokenIssuer>,
+        delivery_tracker: Option<&DeliveryTracker>,
         mut input_tx: mpsc::UnboundedSender<InputMessage>,
     ) -> ServerResponse {
         let message_bytes = msg.into_bytes();
-        // TODO: wait until 0.4.0 release to replace those constants with newly exposed
-        // sphinx::constants::MAXIMUM_PLAINTEXT_LENGTH
-        // we can't do it now for compatibility reasons as most recent sphinx revision
-        // has breaking changes due to packet format changes
-        let maximum_plaintext_length = sphinx::constants::PAYLOAD_SIZE
-            - sphinx::constants::SECURITY_PARAMETER
-            - sphinx::constants::DESTINATION_ADDRESS_LENGTH
-            - 1;
-        if message_bytes.len() > maximum_plaintext_length {
-            return ServerResponse::Error {
-                message: format!(
-                    "too long message. Sent {} bytes while the maximum is {}",
-                    message_bytes.len(),
-                    maximum_plaintext_length
-                )
-                .to_string(),
-            };
+        if let Err(error_response) = check_message_length(&message_bytes, MAXIMUM_MESSAGE_LENGTH) {
+            return error_response;
         }
 
         let address_vec = match base64::decode_config(&recipient_address, base64::URL_SAFE) {
@@ -226,17 +356,98 @@ impl ClientRequest {
         let mut address = [0; 32];
         address.copy_from_slice(&address_vec);
 
-        let dummy_surb = [0; 16];
+        // the token is only registered once we know the request is valid
+        let (envelope, reply_token_id) = match reply_token_issuer {
+            None => (MessageEnvelope::Plain(message_bytes), None),
+            Some(reply_token_issuer) => match reply_token_issuer.issue().await {
+                Ok(reply_token) => (
+                    MessageEnvelope::WithReplyToken(reply_token, message_bytes),
+                    Some(base64::encode_config(&reply_token.id, base64::URL_SAFE)),
+                ),
+                Err(err) => {
+                    warn!("Failed to create reply token: {:?}", err);
+                    return ServerResponse::Error {
+                        message: format!("failed to create reply token: {:?}", err),
+                    };
+                }
+            },
+        };
 
-        let input_msg = InputMessage(Destination::new(address, dummy_surb), message_bytes);
+        let destination = Destination::new(address, Default::default());
+        let (input_msg, message_id) = match delivery_tracker {
+            None => (InputMessage::Forward(destination, envelope), None),
+            Some(delivery_tracker) => {
+                let message_id: ReliableMessageId = rand::random();
+                delivery_tracker.update(message_id, DeliveryStatus::Pending);
+                (
+                    InputMessage::ReliableForward(message_id, destination, envelope),
+                    Some(message_id.to_string()),
+                )
+            }
+        };
         input_tx.send(input_msg).await.unwrap();
 
-        ServerResponse::Send
+        ServerResponse::Send {
+            reply_token_id,
+            message_id,
+        }
+    }
+
+    async fn handle_delivery_status(
+        message_id: String,
+        delivery_tracker: &DeliveryTracker,
+    ) -> ServerResponse {
+        let id: ReliableMessageId = match message_id.parse() {
+            Ok(id) => id,
+            Err(err) => {
+                return ServerResponse::Error {
+                    message: format!("invalid message id: {}", err),
+                }
+            }
+        };
+
+        let status = match delivery_tracker.status(id) {
+            None => MessageStatus::Unknown,
+            Some(DeliveryStatus::Pending) => MessageStatus::Pending,
+            Some(DeliveryStatus::Delivered) => MessageStatus::Delivered,
+            Some(DeliveryStatus::Failed) => MessageStatus::Failed,
+        };
+        ServerResponse::DeliveryStatus { message_id, status }
+    }
+
+    async fn handle_reply(
+        msg: String,
+        reply_token: String,
+        mut input_tx: mpsc::UnboundedSender<InputMessage>,
+    ) -> ServerResponse {
+        let message_bytes = msg.into_bytes();
+        if let Err(error_response) = check_message_length(&message_bytes, MAXIMUM_REPLY_LENGTH) {
+            return error_response;
+        }
+
+        let reply_token = match ReplyToken::from_b64_string(&reply_token) {
+            Err(err) => {
+                return ServerResponse::Error {
+                    message: format!("invalid reply token: {:?}", err),
+                }
+            }
+            Ok(reply_token) => reply_token,
+        };
+
+        let input_msg = InputMessage::Reply(reply_token, message_bytes);
+        if input_tx.send(input_msg).await.is_err() {
+            warn!("Failed to handle_reply. input_tx.send() is an error.");
+            return ServerResponse::Error {
+                message: "Server failed to queue the reply".to_string(),
+            };
+        }
+
+        ServerResponse::Reply
     }
 
-    async fn handle_fetch(mut msg_query: mpsc::UnboundedSender<BufferResponse>) -> ServerResponse {
+    async fn handle_fetch(mut msg_query: mpsc::UnboundedSender<BufferRequest>) -> ServerResponse {
         let (res_tx, res_rx) = oneshot::channel();
-        if msg_query.send(res_tx).await.is_err() {
+        if msg_query.send(BufferRequest::Fetch(res_tx)).await.is_err() {
             warn!("Failed to handle_fetch. msg_query.send() is an error.");
             return ServerResponse::Error {
                 message: "Server failed to receive messages".to_string(),
@@ -254,21 +465,30 @@ impl ClientRequest {
         let messages = messages
             .unwrap()
             .into_iter()
-            .map(|message| {
-                match std::str::from_utf8(&message) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("Invalid UTF-8 sequence in response message: {}", e);
-                        ""
-                    }
-                }
-                .to_owned()
-            })
+            .map(ReceivedMessage::from)
             .collect();
 
         ServerResponse::Fetch { messages }
     }
 
+    async fn handle_subscribe(
+        mut msg_query: mpsc::UnboundedSender<BufferRequest>,
+        subscriber: MessagesSubscriber,
+    ) -> ServerResponse {
+        if msg_query
+            .send(BufferRequest::Subscribe(subscriber))
+            .await
+            .is_err()
+        {
+            warn!("Failed to handle_subscribe. msg_query.send() is an error.");
+            return ServerResponse::Error {
+                message: "Server failed to subscribe to received messages".to_string(),
+            };
+        }
+
+        ServerResponse::Subscribe
+    }
+
     async fn handle_get_clients(topology: Topology) -> ServerResponse {
         let clients = topology
             .mix_provider_nodes
@@ -287,14 +507,99 @@ impl ClientRequest {
     }
 }
 
+// Message as presented to the websocket client. If it has an attached reply token, the client
+// can use it (once) in `Reply` request. If it is a reply, `reply_to` is the id of the reply token
+// it was sent with, as returned in our `Send` response. Content that is not valid UTF-8 is
+// base64 encoded, which is indicated by `base64` being set.
+#[derive(Serialize, Deserialize, Debug)]
+struct ReceivedMessage {
+    message: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    base64: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<String>,
+}
+
+impl From<MessageEnvelope> for ReceivedMessage {
+    fn from(envelope: MessageEnvelope) -> Self {
+        let (message, reply_token, reply_to) = match envelope {
+            // reliability wrappers and acks are handled by the buffer and never get here
+            MessageEnvelope::Reliable(_, _, inner) => return Self::from(*inner),
+            MessageEnvelope::Ack(_) => (Vec::new(), None, None),
+            MessageEnvelope::Plain(message) => (message, None, None),
+            MessageEnvelope::WithReplyToken(reply_token, message) => {
+                (message, Some(reply_token.to_b64_string()), None)
+            }
+            MessageEnvelope::Reply(token_id, message) => (
+                message,
+                None,
+                Some(base64::encode_config(&token_id, base64::URL_SAFE)),
+            ),
+        };
+
+        let (message, base64) = match String::from_utf8(message) {
+            Ok(message) => (message, false),
+            Err(e) => (base64::encode_config(e.as_bytes(), base64::URL_SAFE), true),
+        };
+
+        ReceivedMessage {
+            message,
+            base64,
+            reply_token,
+            reply_to,
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+// `Unknown` is reported for ids we have never seen or have already forgotten about
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+enum MessageStatus {
+    Pending,
+    Delivered,
+    Failed,
+    Unknown,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "camelCase")]
 enum ServerResponse {
-    Send,
-    Fetch { messages: Vec<String> },
-    GetClients { clients: Vec<String> },
-    OwnDetails { address: String },
-    Error { message: String },
+    Send {
+        // only present if the message was sent with a reply token
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_token_id: Option<String>,
+        // only present for reliable messages, to be used in `DeliveryStatus` requests
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<String>,
+    },
+    Reply,
+    Fetch {
+        messages: Vec<ReceivedMessage>,
+    },
+    Subscribe,
+    // pushed to subscribed clients, not a response to any particular request
+    Received {
+        messages: Vec<ReceivedMessage>,
+    },
+    DeliveryStatus {
+        message_id: String,
+        status: MessageStatus,
+    },
+    GetClients {
+        clients: Vec<String>,
+    },
+    OwnDetails {
+        address: String,
+    },
+    Error {
+        message: String,
+    },
 }
 
 impl Into<Message> for ServerResponse {
@@ -309,9 +614,11 @@ impl Into<Message> for ServerResponse {
 async fn accept_connection(
     stream: tokio::net::TcpStream,
     msg_input: mpsc::UnboundedSender<InputMessage>,
-    msg_query: mpsc::UnboundedSender<BufferResponse>,
+    msg_query: mpsc::UnboundedSender<BufferRequest>,
     self_address: DestinationAddressBytes,
-    topology: Topology,
+    topology_accessor: TopologyAccessor,
+    reply_token_issuer: ReplyTokenIssuer,
+    delivery_tracker: DeliveryTracker,
 ) {
     warn!("accept_connection");
     let address = stream
@@ -319,7 +626,7 @@ async fn accept_connection(
         .expect("connected streams should have a peer address");
     debug!("Peer address: {}", address);
 
-    let mut ws_stream = tokio_tungstenite::accept_async(stream)
+    let ws_stream = tokio_tungstenite::accept_async(stream)
         .await
         .expect("Error during the websocket handshake occurred");
 
@@ -328,59 +635,77 @@ async fn accept_connection(
     // data to us.
     let (msg_tx, msg_rx) = futures::channel::mpsc::unbounded();
     let (response_tx, mut response_rx) = futures::channel::mpsc::unbounded();
+    let (pushed_tx, pushed_rx) = futures::channel::mpsc::unbounded();
     let conn = Connection {
         address,
         rx: msg_rx,
         tx: response_tx,
-        topology,
+        topology_accessor,
         msg_input,
         msg_query,
+        pushed_tx,
+        pushed_rx,
+        subscribed: false,
         self_address,
+        reply_token_issuer,
+        delivery_tracker,
     };
     tokio::spawn(conn.handle());
 
-    while let Some(message) = ws_stream.next().await {
-        let message = match message {
-            Ok(msg) => msg,
-            Err(err) => {
-                error!("failed to obtain message from websocket stream! stopping connection handler: {}", err);
-                return;
-            }
-        };
+    // responses are not necessarily preceded by requests anymore, as received messages can be
+    // pushed to subscribed clients at any time
+    let (mut ws_sink, mut ws_stream) = ws_stream.split();
+    let mut close_received = false;
+    loop {
+        tokio::select! {
+            message = ws_stream.next() => {
+                let message = match message {
+                    None => return,
+                    Some(Ok(msg)) => msg,
+                    Some(Err(err)) => {
+                        error!("failed to obtain message from websocket stream! stopping connection handler: {}", err);
+                        return;
+                    }
+                };
 
-        let mut should_close = false;
-        if message.is_close() {
-            should_close = true;
-        }
+                if message.is_close() {
+                    close_received = true;
+                }
 
-        if let Err(err) = msg_tx.unbounded_send(message) {
-            error!(
-                "Failed to forward request. Closing the socket connection: {}",
-                err
-            );
-            return;
-        }
-        // if the received message is a close message it means we will reply with a close
-        // or it is a reply to our close, either way as per RFC6455 5.5.1 we should close the
-        // underlying TCP connection:
-        // After both sending and receiving a Close message, an endpoint
-        // considers the WebSocket connection closed and MUST close the
-        // underlying TCP connection. The server MUST close the underlying TCP
-        // connection immediately;
-
-        if let Some(resp) = response_rx.next().await {
-            if let Err(err) = ws_stream.send(resp).await {
-                warn!(
-                    "Failed to send message over websocket: {}. Assuming the connection is dead.",
-                    err
-                );
-                return;
+                if let Err(err) = msg_tx.unbounded_send(message) {
+                    error!(
+                        "Failed to forward request. Closing the socket connection: {}",
+                        err
+                    );
+                    return;
+                }
             }
-        }
+            response = response_rx.next() => {
+                let response = match response {
+                    None => return,
+                    Some(response) => response,
+                };
+                let is_close = response.is_close();
+
+                if let Err(err) = ws_sink.send(response).await {
+                    warn!(
+                        "Failed to send message over websocket: {}. Assuming the connection is dead.",
+                        err
+                    );
+                    return;
+                }
 
-        if should_close {
-            info!("Closing the websocket connection");
-            return;
+                // if we received a close message and replied with a close, as per RFC6455 5.5.1
+                // we should close the underlying TCP connection:
+                // After both sending and receiving a Close message, an endpoint
+                // considers the WebSocket connection closed and MUST close the
+                // underlying TCP connection. The server MUST close the underlying TCP
+                // connection immediately;
+                if close_received && is_close {
+                    info!("Closing the websocket connection");
+                    return;
+                }
+            }
         }
     }
 }
@@ -388,9 +713,11 @@ async fn accept_connection(
 pub async fn start_websocket(
     address: SocketAddr,
     message_tx: mpsc::UnboundedSender<InputMessage>,
-    received_messages_query_tx: mpsc::UnboundedSender<BufferResponse>,
+    received_messages_query_tx: mpsc::UnboundedSender<BufferRequest>,
     self_address: DestinationAddressBytes,
-    topology: Topology,
+    topology_accessor: TopologyAccessor,
+    reply_token_issuer: ReplyTokenIssuer,
+    delivery_tracker: DeliveryTracker,
 ) -> Result<(), WebSocketError> {
     let mut listener = tokio::net::TcpListener::bind(address).await?;
 
@@ -402,10 +729,200 @@ pub async fn start_websocket(
             message_tx.clone(),
             received_messages_query_tx.clone(),
             self_address,
-            topology.clone(),
+            topology_accessor.clone(),
+            reply_token_issuer.clone(),
+            delivery_tracker.clone(),
         ));
     }
 
     error!("The websocket went kaput...");
     Ok(())
 }
+
+#[cfg(test)]
+mod websocket_connection {
+    use super::*;
+    use crate::sockets::tcp::tcp_socket::request_handling_data;
+
+    fn to_json(envelope: MessageEnvelope) -> String {
+        serde_json::to_string(&ReceivedMessage::from(envelope)).unwrap()
+    }
+
+    struct TestConnection {
+        requests: UnboundedSender<Message>,
+        responses: UnboundedReceiver<Message>,
+        query_rx: UnboundedReceiver<BufferRequest>,
+    }
+
+    fn new_connection(
+        msg_input: mpsc::UnboundedSender<InputMessage>,
+        msg_query: mpsc::UnboundedSender<BufferRequest>,
+    ) -> (
+        Connection,
+        UnboundedSender<Message>,
+        UnboundedReceiver<Message>,
+    ) {
+        let data = request_handling_data(msg_input, msg_query);
+        let (requests, rx) = mpsc::unbounded();
+        let (tx, responses) = mpsc::unbounded();
+        let (pushed_tx, pushed_rx) = mpsc::unbounded();
+        let connection = Connection {
+            address: "127.0.0.1:1977".parse().unwrap(),
+            msg_input: data.msg_input,
+            msg_query: data.msg_query,
+            pushed_tx,
+            pushed_rx,
+            subscribed: false,
+            rx,
+            self_address: data.self_address,
+            topology_accessor: data.topology_accessor,
+            reply_token_issuer: data.reply_token_issuer,
+            delivery_tracker: data.delivery_tracker,
+            tx,
+        };
+        (connection, requests, responses)
+    }
+
+    fn start_connection() -> TestConnection {
+        let (input_tx, _) = mpsc::unbounded();
+        let (query_tx, query_rx) = mpsc::unbounded();
+        let (connection, requests, responses) = new_connection(input_tx, query_tx);
+        tokio::spawn(connection.handle());
+
+        TestConnection {
+            requests,
+            responses,
+            query_rx,
+        }
+    }
+
+    async fn subscribe(connection: &mut TestConnection) -> MessagesSubscriber {
+        let subscribe_request = Message::Text(r#"{"type":"subscribe"}"#.to_string());
+        connection
+            .requests
+            .unbounded_send(subscribe_request.clone())
+            .unwrap();
+        let subscriber = match connection.query_rx.next().await.unwrap() {
+            BufferRequest::Subscribe(subscriber) => subscriber,
+            _ => panic!("expected subscription request"),
+        };
+        // the response looks exactly like the request
+        assert_eq!(
+            subscribe_request,
+            connection.responses.next().await.unwrap()
+        );
+        subscriber
+    }
+
+    #[tokio::test]
+    async fn subscribed_client_receives_pushed_messages() {
+        let mut connection = start_connection();
+        let subscriber = subscribe(&mut connection).await;
+
+        subscriber
+            .unbounded_send(vec![MessageEnvelope::Plain(b"foomp".to_vec())])
+            .unwrap();
+        assert_eq!(
+            Message::Text(r#"{"type":"received","messages":[{"message":"foomp"}]}"#.to_string()),
+            connection.responses.next().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribing_again_does_not_create_another_subscription() {
+        let mut connection = start_connection();
+        let _subscriber = subscribe(&mut connection).await;
+
+        let subscribe_request = Message::Text(r#"{"type":"subscribe"}"#.to_string());
+        connection
+            .requests
+            .unbounded_send(subscribe_request.clone())
+            .unwrap();
+        assert_eq!(
+            subscribe_request,
+            connection.responses.next().await.unwrap()
+        );
+        assert!(connection.query_rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_that_could_not_be_pushed_are_restored() {
+        let mut connection = start_connection();
+        let subscriber = subscribe(&mut connection).await;
+
+        // the client is gone before we manage to push anything to it
+        drop(connection.responses);
+        let messages = vec![MessageEnvelope::Plain(b"foomp".to_vec())];
+        subscriber.unbounded_send(messages.clone()).unwrap();
+        match connection.query_rx.next().await.unwrap() {
+            BufferRequest::Restore(restored) => assert_eq!(messages, restored),
+            _ => panic!("expected undelivered messages to be restored"),
+        }
+        assert!(subscriber.is_closed());
+    }
+
+    #[test]
+    fn received_messages_that_are_not_valid_utf8_are_base64_encoded() {
+        assert_eq!(
+            r#"{"message":"foomp"}"#,
+            to_json(MessageEnvelope::Plain(b"foomp".to_vec()))
+        );
+        assert_eq!(
+            r#"{"message":"_wA=","base64":true}"#,
+            to_json(MessageEnvelope::Plain(vec![0xff, 0x00]))
+        );
+        assert_eq!(
+            r#"{"message":"_wA=","base64":true,"reply_to":"AQEBAQEBAQEBAQEBAQEBAQ=="}"#,
+            to_json(MessageEnvelope::Reply([1u8; 16], vec![0xff, 0x00]))
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_requests_are_handled_exactly_like_tcp_requests() {
+        let (input_tx, _input_rx) = mpsc::unbounded();
+        let (query_tx, mut query_rx) = mpsc::unbounded();
+        // binary content is fetched intact
+        let received = vec![
+            MessageEnvelope::Plain(vec![0xff, 0x00, 0xfe]),
+            MessageEnvelope::Reply([1u8; 16], b"foomp".to_vec()),
+        ];
+        tokio::spawn(async move {
+            while let Some(request) = query_rx.next().await {
+                if let BufferRequest::Fetch(response) = request {
+                    response.send(received.clone()).unwrap();
+                }
+            }
+        });
+        let (connection, _, _) = new_connection(input_tx.clone(), query_tx.clone());
+
+        let send_request: Vec<_> = std::iter::once(1)
+            .chain([7u8; 32].iter().cloned())
+            .chain(vec![0xff; 100].into_iter())
+            .collect();
+        let requests = vec![
+            // send
+            send_request,
+            // fetch
+            vec![2],
+            // own details
+            vec![4],
+            // delivery status of unknown message
+            vec![9, 1, 2, 3, 4, 5, 6, 7, 8],
+            // incomplete send
+            vec![1, 2, 3],
+            // unknown request
+            vec![42],
+        ];
+        for request in requests {
+            let tcp_response = tcp::handle_binary_request(
+                &request,
+                request_handling_data(input_tx.clone(), query_tx.clone()),
+            )
+            .await;
+            assert_eq!(
+                Message::Binary(tcp_response),
+                connection.handle_binary_message(request).await
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryInto;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+const LENGTH_PREFIX_SIZE: usize = 4;
+// none of the requests or responses should ever get anywhere near this size
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ProviderCodecError {
+    FrameTooLargeError(usize),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for ProviderCodecError {
+    fn from(err: io::Error) -> Self {
+        ProviderCodecError::IOError(err)
+    }
+}
+
+// big endian u32 length || serialized request or response
+// Framing allows the same connection to be reused for any number of requests, each of which
+// is followed by exactly one response.
+pub struct ProviderCodec;
+
+impl Encoder for ProviderCodec {
+    type Item = Vec<u8>;
+    type Error = ProviderCodecError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.len() > MAX_FRAME_LENGTH {
+            return Err(ProviderCodecError::FrameTooLargeError(item.len()));
+        }
+
+        dst.reserve(LENGTH_PREFIX_SIZE + item.len());
+        dst.put_u32(item.len() as u32);
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for ProviderCodec {
+    type Item = Vec<u8>;
+    type Error = ProviderCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LENGTH_PREFIX_SIZE {
+            // we don't even have the length yet
+            return Ok(None);
+        }
+
+        // this can't fail as we've already checked the buffer length
+        let frame_len = u32::from_be_bytes(src[..LENGTH_PREFIX_SIZE].try_into().unwrap()) as usize;
+        if frame_len > MAX_FRAME_LENGTH {
+            return Err(ProviderCodecError::FrameTooLargeError(frame_len));
+        }
+
+        if src.len() < LENGTH_PREFIX_SIZE + frame_len {
+            // the full frame hasn't arrived yet; make space for the rest of it
+            src.reserve(LENGTH_PREFIX_SIZE + frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_PREFIX_SIZE);
+        Ok(Some(src.split_to(frame_len).to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod provider_codec {
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encoded() {
+        let mut buf = BytesMut::new();
+        ProviderCodec.encode(vec![1, 2, 3], &mut buf).unwrap();
+        ProviderCodec.encode(vec![], &mut buf).unwrap();
+
+        assert_eq!(
+            vec![1, 2, 3],
+            ProviderCodec.decode(&mut buf).unwrap().unwrap()
+        );
+        assert!(ProviderCodec.decode(&mut buf).unwrap().unwrap().is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_entire_frame_on_short_reads() {
+        let mut full_buf = BytesMut::new();
+        ProviderCodec.encode(vec![42; 100], &mut full_buf).unwrap();
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&full_buf[..2]);
+        assert!(ProviderCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&full_buf[2..50]);
+        assert!(ProviderCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&full_buf[50..]);
+        assert_eq!(
+            vec![42; 100],
+            ProviderCodec.decode(&mut buf).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_frames_over_the_maximum_length() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME_LENGTH as u32 + 1);
+        match ProviderCodec.decode(&mut buf) {
+            Err(ProviderCodecError::FrameTooLargeError(len)) => {
+                assert_eq!(MAX_FRAME_LENGTH + 1, len)
+            }
+            _ => panic!("expected frame too large error"),
+        }
+    }
+}
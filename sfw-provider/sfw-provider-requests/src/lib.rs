@@ -1,7 +1,26 @@
+pub mod codec;
 pub mod requests;
 pub mod responses;
 
+// used to pad pull responses to constant number of messages
 pub const DUMMY_MESSAGE_CONTENT: &[u8] =
     b"[DUMMY MESSAGE] Wanting something does not give you the right to have it.";
 
+// every message in a pull response takes the same amount of space, which is enough for any
+// sphinx payload
+pub const MESSAGE_SLOT_LENGTH: usize = sphinx::constants::PAYLOAD_SIZE;
+
 pub type AuthToken = [u8; 32];
+
+// Sphinx destination used by replies sent with reply tokens. Such messages are not delivered
+// to this address but to whoever has registered the reply token identified by the packet's
+// reply token id, which means the replying client never learns who it's actually replying to.
+pub const REPLY_DESTINATION_ADDRESS: sphinx::route::DestinationAddressBytes = [0u8; 32];
+
+// random value issued by the provider that the client has to sign with its identity key
+// in order to prove it actually owns the address it is trying to register
+pub type ChallengeNonce = [u8; 32];
+
+// identifier assigned by the provider to each stored message so that the client could later
+// acknowledge it has received it
+pub type MessageId = u64;
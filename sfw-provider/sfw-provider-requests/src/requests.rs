@@ -1,14 +1,31 @@
-use crate::AuthToken;
-use sphinx::route::DestinationAddressBytes;
+use crate::{AuthToken, ChallengeNonce, MessageId};
+use crypto::identity::SIGNATURE_LENGTH;
+use sphinx::route::{DestinationAddressBytes, SURBIdentifier};
+use std::convert::TryInto;
 
 const PULL_REQUEST_MESSAGE_PREFIX: [u8; 2] = [1, 0];
 const REGISTER_MESSAGE_PREFIX: [u8; 2] = [0, 1];
+const ACK_REQUEST_MESSAGE_PREFIX: [u8; 2] = [1, 1];
+const SUBSCRIBE_REQUEST_MESSAGE_PREFIX: [u8; 2] = [2, 0];
+const CHALLENGE_REQUEST_MESSAGE_PREFIX: [u8; 2] = [0, 2];
+const REPLY_TOKEN_REQUEST_MESSAGE_PREFIX: [u8; 2] = [2, 1];
+
+// the number of ids in ack request is encoded as u16
+pub const MAXIMUM_ACKNOWLEDGED_IDS: usize = std::u16::MAX as usize;
+
+// prepended to the signed registration challenge so that the signature could not be
+// reused in any other context
+const REGISTRATION_CHALLENGE_CONTEXT: &[u8] = b"NYM_PROVIDER_REGISTRATION";
 
 // TODO: how to do it more nicely, considering all sfw-provider-requests implement same trait that is exercised here?
 #[derive(Debug)]
 pub enum ProviderRequests {
     PullMessages(PullRequest),
     Register(RegisterRequest),
+    AckMessages(AckRequest),
+    Subscribe(SubscribeRequest),
+    Challenge(ChallengeRequest),
+    RegisterReplyToken(ReplyTokenRequest),
 }
 
 impl ProviderRequests {
@@ -17,6 +34,10 @@ impl ProviderRequests {
         match self {
             PullMessages(pr) => pr.to_bytes(),
             Register(pr) => pr.to_bytes(),
+            AckMessages(ar) => ar.to_bytes(),
+            Subscribe(sr) => sr.to_bytes(),
+            Challenge(cr) => cr.to_bytes(),
+            RegisterReplyToken(rr) => rr.to_bytes(),
         }
     }
 
@@ -30,6 +51,12 @@ impl ProviderRequests {
         match received_prefix {
             PULL_REQUEST_MESSAGE_PREFIX => Ok(PullMessages(PullRequest::from_bytes(bytes)?)),
             REGISTER_MESSAGE_PREFIX => Ok(Register(RegisterRequest::from_bytes(bytes)?)),
+            ACK_REQUEST_MESSAGE_PREFIX => Ok(AckMessages(AckRequest::from_bytes(bytes)?)),
+            SUBSCRIBE_REQUEST_MESSAGE_PREFIX => Ok(Subscribe(SubscribeRequest::from_bytes(bytes)?)),
+            CHALLENGE_REQUEST_MESSAGE_PREFIX => Ok(Challenge(ChallengeRequest::from_bytes(bytes)?)),
+            REPLY_TOKEN_REQUEST_MESSAGE_PREFIX => {
+                Ok(RegisterReplyToken(ReplyTokenRequest::from_bytes(bytes)?))
+            }
             _ => Err(ProviderRequestError::UnmarshalErrorIncorrectPrefix),
         }
     }
@@ -51,21 +78,28 @@ where
     fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError>;
 }
 
+// Pull, ack, subscribe and reply token requests are only accepted on connections that have registered
+// (i.e. proven ownership of) the same destination address.
 #[derive(Debug)]
 pub struct PullRequest {
-    // TODO: public keys, signatures, tokens, etc. basically some kind of authentication bs
     pub auth_token: AuthToken,
     pub destination_address: sphinx::route::DestinationAddressBytes,
+    // only messages with ids not lower than the cursor are going to be retrieved.
+    // use 0 to start from the oldest message or `next_cursor` of the previous response
+    // to get the following page
+    pub cursor: MessageId,
 }
 
 impl PullRequest {
     pub fn new(
         destination_address: sphinx::route::DestinationAddressBytes,
         auth_token: AuthToken,
+        cursor: MessageId,
     ) -> Self {
         PullRequest {
             auth_token,
             destination_address,
+            cursor,
         }
     }
 }
@@ -81,11 +115,12 @@ impl ProviderRequest for PullRequest {
             .into_iter()
             .chain(self.destination_address.iter().cloned())
             .chain(self.auth_token.iter().cloned())
+            .chain(self.cursor.to_be_bytes().to_vec().into_iter())
             .collect()
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
-        if bytes.len() != 2 + 32 + 32 {
+        if bytes.len() != 2 + 32 + 32 + 8 {
             return Err(ProviderRequestError::UnmarshalError);
         }
 
@@ -99,26 +134,54 @@ impl ProviderRequest for PullRequest {
         destination_address.copy_from_slice(&bytes[2..34]);
 
         let mut auth_token = [0u8; 32];
-        auth_token.copy_from_slice(&bytes[34..]);
+        auth_token.copy_from_slice(&bytes[34..66]);
+
+        // this can't fail as we've checked the length at the beginning
+        let cursor = MessageId::from_be_bytes(bytes[66..].try_into().unwrap());
 
         Ok(PullRequest {
             auth_token,
             destination_address,
+            cursor,
         })
     }
 }
 
+// Registration requires a prior `ChallengeRequest` on the same connection. The client
+// proves it owns the destination address by signing the received nonce with its ed25519
+// identity key. The address has to be the x25519 form of that identity key.
 #[derive(Debug)]
 pub struct RegisterRequest {
     pub destination_address: DestinationAddressBytes,
+    pub identity_key: [u8; 32],
+    pub signature: Vec<u8>,
 }
 
 impl RegisterRequest {
-    pub fn new(destination_address: DestinationAddressBytes) -> Self {
+    pub fn new(
+        destination_address: DestinationAddressBytes,
+        identity_key: [u8; 32],
+        signature: Vec<u8>,
+    ) -> Self {
         RegisterRequest {
             destination_address,
+            identity_key,
+            signature,
         }
     }
+
+    // message that is expected to be signed by the client in order to register
+    pub fn challenge_message(
+        nonce: &ChallengeNonce,
+        destination_address: &DestinationAddressBytes,
+    ) -> Vec<u8> {
+        REGISTRATION_CHALLENGE_CONTEXT
+            .iter()
+            .chain(nonce.iter())
+            .chain(destination_address.iter())
+            .cloned()
+            .collect()
+    }
 }
 
 impl ProviderRequest for RegisterRequest {
@@ -131,11 +194,13 @@ impl ProviderRequest for RegisterRequest {
             .to_vec()
             .into_iter()
             .chain(self.destination_address.iter().cloned())
+            .chain(self.identity_key.iter().cloned())
+            .chain(self.signature.iter().cloned())
             .collect()
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
-        if bytes.len() != 2 + 32 {
+        if bytes.len() != 2 + 32 + 32 + SIGNATURE_LENGTH {
             return Err(ProviderRequestError::UnmarshalError);
         }
 
@@ -146,10 +211,260 @@ impl ProviderRequest for RegisterRequest {
         }
 
         let mut destination_address = [0u8; 32];
-        destination_address.copy_from_slice(&bytes[2..]);
+        destination_address.copy_from_slice(&bytes[2..34]);
+        let mut identity_key = [0u8; 32];
+        identity_key.copy_from_slice(&bytes[34..66]);
 
         Ok(RegisterRequest {
             destination_address,
+            identity_key,
+            signature: bytes[66..].to_vec(),
+        })
+    }
+}
+
+// Asks the provider for a fresh nonce to sign in the subsequent `RegisterRequest`.
+// The nonce is only valid for the connection it was issued on and is discarded after
+// the first registration attempt.
+#[derive(Debug, Default)]
+pub struct ChallengeRequest;
+
+impl ChallengeRequest {
+    pub fn new() -> Self {
+        ChallengeRequest
+    }
+}
+
+impl ProviderRequest for ChallengeRequest {
+    fn get_prefix() -> [u8; 2] {
+        CHALLENGE_REQUEST_MESSAGE_PREFIX
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        Self::get_prefix().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
+        if bytes.len() != 2 {
+            return Err(ProviderRequestError::UnmarshalError);
+        }
+
+        let mut received_prefix = [0u8; 2];
+        received_prefix.copy_from_slice(&bytes[..2]);
+        if received_prefix != Self::get_prefix() {
+            return Err(ProviderRequestError::UnmarshalErrorIncorrectPrefix);
+        }
+
+        Ok(ChallengeRequest)
+    }
+}
+
+#[derive(Debug)]
+pub struct AckRequest {
+    pub auth_token: AuthToken,
+    pub destination_address: DestinationAddressBytes,
+    pub message_ids: Vec<MessageId>,
+}
+
+impl AckRequest {
+    // more than `MAXIMUM_ACKNOWLEDGED_IDS` have to be split into multiple requests
+    pub fn new(
+        destination_address: DestinationAddressBytes,
+        auth_token: AuthToken,
+        message_ids: Vec<MessageId>,
+    ) -> Result<Self, ProviderRequestError> {
+        if message_ids.len() > MAXIMUM_ACKNOWLEDGED_IDS {
+            return Err(ProviderRequestError::MarshalError);
+        }
+
+        Ok(AckRequest {
+            auth_token,
+            destination_address,
+            message_ids,
+        })
+    }
+}
+
+impl ProviderRequest for AckRequest {
+    fn get_prefix() -> [u8; 2] {
+        ACK_REQUEST_MESSAGE_PREFIX
+    }
+
+    // prefix || address || token || num_ids || id1 || id2 || ...
+    fn to_bytes(&self) -> Vec<u8> {
+        let num_ids = self.message_ids.len() as u16;
+
+        Self::get_prefix()
+            .to_vec()
+            .into_iter()
+            .chain(self.destination_address.iter().cloned())
+            .chain(self.auth_token.iter().cloned())
+            .chain(num_ids.to_be_bytes().to_vec().into_iter())
+            .chain(
+                self.message_ids
+                    .iter()
+                    .flat_map(|id| id.to_be_bytes().to_vec().into_iter()),
+            )
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
+        // can we read number of ids?
+        if bytes.len() < 2 + 32 + 32 + 2 {
+            return Err(ProviderRequestError::UnmarshalError);
+        }
+
+        let mut received_prefix = [0u8; 2];
+        received_prefix.copy_from_slice(&bytes[..2]);
+        if received_prefix != Self::get_prefix() {
+            return Err(ProviderRequestError::UnmarshalErrorIncorrectPrefix);
+        }
+
+        let mut destination_address = [0u8; 32];
+        destination_address.copy_from_slice(&bytes[2..34]);
+
+        let mut auth_token = [0u8; 32];
+        auth_token.copy_from_slice(&bytes[34..66]);
+
+        let num_ids = u16::from_be_bytes([bytes[66], bytes[67]]) as usize;
+        let id_bytes = &bytes[68..];
+        let id_size = std::mem::size_of::<MessageId>();
+        if id_bytes.len() != num_ids * id_size {
+            return Err(ProviderRequestError::UnmarshalError);
+        }
+
+        let message_ids = id_bytes
+            .chunks(id_size)
+            // this can't fail as we've just checked the length
+            .map(|id| MessageId::from_be_bytes(id.try_into().unwrap()))
+            .collect();
+
+        Ok(AckRequest {
+            auth_token,
+            destination_address,
+            message_ids,
+        })
+    }
+}
+
+// After successful subscription, the connection is no longer used for requests - instead
+// the provider keeps pushing pages of messages (as `PullResponse`s) over it at a fixed interval.
+#[derive(Debug)]
+pub struct SubscribeRequest {
+    pub auth_token: AuthToken,
+    pub destination_address: DestinationAddressBytes,
+}
+
+impl SubscribeRequest {
+    pub fn new(destination_address: DestinationAddressBytes, auth_token: AuthToken) -> Self {
+        SubscribeRequest {
+            auth_token,
+            destination_address,
+        }
+    }
+}
+
+impl ProviderRequest for SubscribeRequest {
+    fn get_prefix() -> [u8; 2] {
+        SUBSCRIBE_REQUEST_MESSAGE_PREFIX
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        Self::get_prefix()
+            .to_vec()
+            .into_iter()
+            .chain(self.destination_address.iter().cloned())
+            .chain(self.auth_token.iter().cloned())
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
+        if bytes.len() != 2 + 32 + 32 {
+            return Err(ProviderRequestError::UnmarshalError);
+        }
+
+        let mut received_prefix = [0u8; 2];
+        received_prefix.copy_from_slice(&bytes[..2]);
+        if received_prefix != Self::get_prefix() {
+            return Err(ProviderRequestError::UnmarshalErrorIncorrectPrefix);
+        }
+
+        let mut destination_address = [0u8; 32];
+        destination_address.copy_from_slice(&bytes[2..34]);
+
+        let mut auth_token = [0u8; 32];
+        auth_token.copy_from_slice(&bytes[34..]);
+
+        Ok(SubscribeRequest {
+            auth_token,
+            destination_address,
+        })
+    }
+}
+
+// Registers a single-use reply token. The first message the provider receives for
+// `REPLY_DESTINATION_ADDRESS` with the given token id is going to be delivered to the inbox
+// of `destination_address`, after which the reply token is forgotten.
+#[derive(Debug)]
+pub struct ReplyTokenRequest {
+    pub auth_token: AuthToken,
+    pub destination_address: DestinationAddressBytes,
+    pub token_id: SURBIdentifier,
+}
+
+impl ReplyTokenRequest {
+    pub fn new(
+        destination_address: DestinationAddressBytes,
+        auth_token: AuthToken,
+        token_id: SURBIdentifier,
+    ) -> Self {
+        ReplyTokenRequest {
+            auth_token,
+            destination_address,
+            token_id,
+        }
+    }
+}
+
+impl ProviderRequest for ReplyTokenRequest {
+    fn get_prefix() -> [u8; 2] {
+        REPLY_TOKEN_REQUEST_MESSAGE_PREFIX
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        Self::get_prefix()
+            .to_vec()
+            .into_iter()
+            .chain(self.destination_address.iter().cloned())
+            .chain(self.auth_token.iter().cloned())
+            .chain(self.token_id.iter().cloned())
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderRequestError> {
+        if bytes.len() != 2 + 32 + 32 + 16 {
+            return Err(ProviderRequestError::UnmarshalError);
+        }
+
+        let mut received_prefix = [0u8; 2];
+        received_prefix.copy_from_slice(&bytes[..2]);
+        if received_prefix != Self::get_prefix() {
+            return Err(ProviderRequestError::UnmarshalErrorIncorrectPrefix);
+        }
+
+        let mut destination_address = [0u8; 32];
+        destination_address.copy_from_slice(&bytes[2..34]);
+
+        let mut auth_token = [0u8; 32];
+        auth_token.copy_from_slice(&bytes[34..66]);
+
+        let mut token_id = [0u8; 16];
+        token_id.copy_from_slice(&bytes[66..]);
+
+        Ok(ReplyTokenRequest {
+            auth_token,
+            destination_address,
+            token_id,
         })
     }
 }
@@ -165,12 +480,13 @@ mod creating_pull_request {
             0, 1, 2,
         ];
         let auth_token = [1u8; 32];
-        let pull_request = PullRequest::new(address, auth_token);
+        let pull_request = PullRequest::new(address, auth_token, 42);
         let bytes = pull_request.to_bytes();
 
         let recovered = PullRequest::from_bytes(&bytes).unwrap();
         assert_eq!(address, recovered.destination_address);
         assert_eq!(auth_token, recovered.auth_token);
+        assert_eq!(42, recovered.cursor);
     }
 
     #[test]
@@ -180,7 +496,7 @@ mod creating_pull_request {
             0, 1, 2,
         ];
         let auth_token = [1u8; 32];
-        let pull_request = PullRequest::new(address, auth_token);
+        let pull_request = PullRequest::new(address, auth_token, 42);
         let bytes = pull_request.to_bytes();
 
         let recovered = ProviderRequests::from_bytes(&bytes).unwrap();
@@ -188,6 +504,7 @@ mod creating_pull_request {
             ProviderRequests::PullMessages(req) => {
                 assert_eq!(address, req.destination_address);
                 assert_eq!(auth_token, req.auth_token);
+                assert_eq!(42, req.cursor);
             }
             _ => panic!("expected to recover pull request!"),
         }
@@ -204,11 +521,15 @@ mod creating_register_request {
             1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
             0, 1, 2,
         ];
-        let register_request = RegisterRequest::new(address);
+        let identity_key = [3u8; 32];
+        let signature = vec![42u8; SIGNATURE_LENGTH];
+        let register_request = RegisterRequest::new(address, identity_key, signature.clone());
         let bytes = register_request.to_bytes();
 
         let recovered = RegisterRequest::from_bytes(&bytes).unwrap();
         assert_eq!(address, recovered.destination_address);
+        assert_eq!(identity_key, recovered.identity_key);
+        assert_eq!(signature, recovered.signature);
     }
 
     #[test]
@@ -217,15 +538,182 @@ mod creating_register_request {
             1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
             0, 1, 2,
         ];
-        let register_request = RegisterRequest::new(address);
+        let identity_key = [3u8; 32];
+        let signature = vec![42u8; SIGNATURE_LENGTH];
+        let register_request = RegisterRequest::new(address, identity_key, signature.clone());
         let bytes = register_request.to_bytes();
 
         let recovered = ProviderRequests::from_bytes(&bytes).unwrap();
         match recovered {
             ProviderRequests::Register(req) => {
                 assert_eq!(address, req.destination_address);
+                assert_eq!(identity_key, req.identity_key);
+                assert_eq!(signature, req.signature);
             }
-            _ => panic!("expected to recover pull request!"),
+            _ => panic!("expected to recover register request!"),
+        }
+    }
+
+    #[test]
+    fn it_is_not_possible_to_recover_it_without_full_signature() {
+        let register_request =
+            RegisterRequest::new([1u8; 32], [3u8; 32], vec![42u8; SIGNATURE_LENGTH - 1]);
+        let bytes = register_request.to_bytes();
+
+        assert!(RegisterRequest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn challenge_message_depends_on_both_nonce_and_address() {
+        let base = RegisterRequest::challenge_message(&[1u8; 32], &[2u8; 32]);
+        assert_ne!(
+            base,
+            RegisterRequest::challenge_message(&[3u8; 32], &[2u8; 32])
+        );
+        assert_ne!(
+            base,
+            RegisterRequest::challenge_message(&[1u8; 32], &[3u8; 32])
+        );
+    }
+}
+
+#[cfg(test)]
+mod creating_challenge_request {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes_with_enum_wrapper() {
+        let bytes = ChallengeRequest::new().to_bytes();
+
+        match ProviderRequests::from_bytes(&bytes).unwrap() {
+            ProviderRequests::Challenge(_) => (),
+            _ => panic!("expected to recover challenge request!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod creating_ack_request {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes() {
+        let address = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+            0, 1, 2,
+        ];
+        let auth_token = [1u8; 32];
+        let message_ids = vec![1, 42, std::u64::MAX];
+        let ack_request = AckRequest::new(address, auth_token, message_ids.clone()).unwrap();
+        let bytes = ack_request.to_bytes();
+
+        let recovered = AckRequest::from_bytes(&bytes).unwrap();
+        assert_eq!(address, recovered.destination_address);
+        assert_eq!(auth_token, recovered.auth_token);
+        assert_eq!(message_ids, recovered.message_ids);
+    }
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes_with_enum_wrapper() {
+        let address = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+            0, 1, 2,
+        ];
+        let auth_token = [1u8; 32];
+        let ack_request = AckRequest::new(address, auth_token, vec![]).unwrap();
+        let bytes = ack_request.to_bytes();
+
+        let recovered = ProviderRequests::from_bytes(&bytes).unwrap();
+        match recovered {
+            ProviderRequests::AckMessages(req) => {
+                assert_eq!(address, req.destination_address);
+                assert_eq!(auth_token, req.auth_token);
+                assert!(req.message_ids.is_empty());
+            }
+            _ => panic!("expected to recover ack request!"),
         }
     }
+
+    #[test]
+    fn it_is_not_possible_to_recover_it_if_ids_are_truncated() {
+        let ack_request = AckRequest::new([1u8; 32], [1u8; 32], vec![1, 2]).unwrap();
+        let bytes = ack_request.to_bytes();
+
+        assert!(AckRequest::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn it_is_not_possible_to_create_it_with_more_ids_than_fit_into_it() {
+        let message_ids: Vec<_> = (0..MAXIMUM_ACKNOWLEDGED_IDS as u64 + 1).collect();
+        assert!(AckRequest::new([1u8; 32], [1u8; 32], message_ids.clone()).is_err());
+
+        let message_ids = message_ids[1..].to_vec();
+        let bytes = AckRequest::new([1u8; 32], [1u8; 32], message_ids.clone())
+            .unwrap()
+            .to_bytes();
+        assert_eq!(
+            message_ids,
+            AckRequest::from_bytes(&bytes).unwrap().message_ids
+        );
+    }
+}
+
+#[cfg(test)]
+mod creating_subscribe_request {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes_with_enum_wrapper() {
+        let address = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+            0, 1, 2,
+        ];
+        let auth_token = [1u8; 32];
+        let subscribe_request = SubscribeRequest::new(address, auth_token);
+        let bytes = subscribe_request.to_bytes();
+
+        let recovered = ProviderRequests::from_bytes(&bytes).unwrap();
+        match recovered {
+            ProviderRequests::Subscribe(req) => {
+                assert_eq!(address, req.destination_address);
+                assert_eq!(auth_token, req.auth_token);
+            }
+            _ => panic!("expected to recover subscribe request!"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod creating_reply_token_request {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes_with_enum_wrapper() {
+        let address = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
+            0, 1, 2,
+        ];
+        let auth_token = [1u8; 32];
+        let token_id = [42u8; 16];
+        let reply_token_request = ReplyTokenRequest::new(address, auth_token, token_id);
+        let bytes = reply_token_request.to_bytes();
+
+        let recovered = ProviderRequests::from_bytes(&bytes).unwrap();
+        match recovered {
+            ProviderRequests::RegisterReplyToken(req) => {
+                assert_eq!(address, req.destination_address);
+                assert_eq!(auth_token, req.auth_token);
+                assert_eq!(token_id, req.token_id);
+            }
+            _ => panic!("expected to recover reply token request!"),
+        }
+    }
+
+    #[test]
+    fn it_is_not_possible_to_recover_it_without_full_token_id() {
+        let reply_token_request = ReplyTokenRequest::new([1u8; 32], [1u8; 32], [42u8; 16]);
+        let bytes = reply_token_request.to_bytes();
+
+        assert!(ReplyTokenRequest::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
 }
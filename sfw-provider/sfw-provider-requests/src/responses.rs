@@ -1,5 +1,5 @@
-use crate::AuthToken;
-use std::convert::TryInto;
+use crate::{AuthToken, ChallengeNonce, MessageId, DUMMY_MESSAGE_CONTENT, MESSAGE_SLOT_LENGTH};
+use std::convert::{TryFrom, TryInto};
 
 #[derive(Debug)]
 pub enum ProviderResponseError {
@@ -16,9 +16,29 @@ where
     fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError>;
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulledMessage {
+    pub id: MessageId,
+    pub content: Vec<u8>,
+}
+
+impl PulledMessage {
+    pub fn new(id: MessageId, content: Vec<u8>) -> Self {
+        PulledMessage { id, content }
+    }
+}
+
+// Single page of client messages. On the wire the page is always padded with dummy messages
+// up to `page_size` and every message is padded to the same length so that it would not reveal
+// how many messages the client actually received.
 #[derive(Debug)]
 pub struct PullResponse {
-    pub messages: Vec<Vec<u8>>,
+    pub messages: Vec<PulledMessage>,
+    // number of messages still waiting at the provider starting from `next_cursor`
+    pub remaining: u32,
+    // cursor to use in the subsequent pull request to retrieve the following page
+    pub next_cursor: MessageId,
+    pub page_size: u16,
 }
 
 #[derive(Debug)]
@@ -26,9 +46,50 @@ pub struct RegisterResponse {
     pub auth_token: AuthToken,
 }
 
+#[derive(Debug)]
+pub struct ChallengeResponse {
+    pub nonce: ChallengeNonce,
+}
+
+#[derive(Debug)]
+pub struct AckResponse {
+    // number of messages that were pending delivery and got removed from the provider
+    pub acknowledged: u16,
+}
+
+#[derive(Debug)]
+pub struct SubscribeResponse {
+    // interval at which the provider is going to push pages of messages
+    pub push_interval_millis: u64,
+}
+
+#[derive(Debug)]
+pub struct ReplyTokenResponse {
+    // for how long the provider is going to keep the reply token before discarding it
+    pub validity_secs: u64,
+}
+
 impl PullResponse {
-    pub fn new(messages: Vec<Vec<u8>>) -> Self {
-        PullResponse { messages }
+    pub fn new(
+        messages: Vec<PulledMessage>,
+        remaining: u32,
+        next_cursor: MessageId,
+        page_size: u16,
+    ) -> Self {
+        PullResponse {
+            messages,
+            remaining,
+            next_cursor,
+            page_size,
+        }
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.remaining > 0
+    }
+
+    fn padding_message() -> PulledMessage {
+        PulledMessage::new(0, DUMMY_MESSAGE_CONTENT.to_vec())
     }
 }
 
@@ -38,6 +99,32 @@ impl RegisterResponse {
     }
 }
 
+impl ChallengeResponse {
+    pub fn new(nonce: ChallengeNonce) -> Self {
+        ChallengeResponse { nonce }
+    }
+}
+
+impl SubscribeResponse {
+    pub fn new(push_interval_millis: u64) -> Self {
+        SubscribeResponse {
+            push_interval_millis,
+        }
+    }
+}
+
+impl ReplyTokenResponse {
+    pub fn new(validity_secs: u64) -> Self {
+        ReplyTokenResponse { validity_secs }
+    }
+}
+
+impl AckResponse {
+    pub fn new(acknowledged: u16) -> Self {
+        AckResponse { acknowledged }
+    }
+}
+
 // TODO: This should go into some kind of utils module/crate
 fn read_be_u16(input: &mut &[u8]) -> u16 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
@@ -45,65 +132,117 @@ fn read_be_u16(input: &mut &[u8]) -> u16 {
     u16::from_be_bytes(int_bytes.try_into().unwrap())
 }
 
-// TODO: currently this allows for maximum 64kB payload - if we go over that in sphinx,
-// we need to update this code.
+fn read_be_u64(input: &mut &[u8]) -> u64 {
+    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u64>());
+    *input = rest;
+    u64::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
+fn read_be_u32(input: &mut &[u8]) -> u32 {
+    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
+    *input = rest;
+    u32::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
+// marks whether an entry of the pull response is an actual message or just padding
+const MESSAGE_ENTRY_FLAG: u8 = 1;
+const PADDING_ENTRY_FLAG: u8 = 0;
+
+// length of the given message or slot as written on the wire. Stored messages are sphinx
+// payloads, so they always fit in it.
+fn wire_length(len: usize) -> u16 {
+    u16::try_from(len).expect("pulled message is longer than 64kB")
+}
+
 impl ProviderResponse for PullResponse {
-    // num_msgs || len1 || len2 || ... || msg1 || msg2 || ...
+    // page_size || slot_length || remaining || next_cursor || entry1 || ... || entryN
+    // where N = page_size and each entry is flag || id || len || msg || zero padding up to
+    // slot_length. The flag tells whether the entry is a real message or padding, so the
+    // content of a message never decides whether it's going to be delivered.
     fn to_bytes(&self) -> Vec<u8> {
-        let num_msgs = self.messages.len() as u16;
-        let msgs_lens: Vec<u16> = self.messages.iter().map(|msg| msg.len() as u16).collect();
+        let num_msgs = wire_length(self.messages.len());
+        let page_size = std::cmp::max(self.page_size, num_msgs);
+        let padding: Vec<_> = (num_msgs..page_size)
+            .map(|_| PullResponse::padding_message())
+            .collect();
+        let entries: Vec<_> = self
+            .messages
+            .iter()
+            .map(|msg| (MESSAGE_ENTRY_FLAG, msg))
+            .chain(padding.iter().map(|msg| (PADDING_ENTRY_FLAG, msg)))
+            .collect();
+        // this can only be exceeded if someone stores messages longer than sphinx payload
+        let slot_length = wire_length(
+            entries
+                .iter()
+                .map(|(_, msg)| msg.content.len())
+                .fold(MESSAGE_SLOT_LENGTH, std::cmp::max),
+        );
 
-        num_msgs
+        page_size
             .to_be_bytes()
-            .to_vec()
-            .into_iter()
-            .chain(
-                msgs_lens
-                    .into_iter()
-                    .flat_map(|len| len.to_be_bytes().to_vec().into_iter()),
-            )
-            .chain(self.messages.iter().flat_map(|msg| msg.clone().into_iter()))
+            .iter()
+            .chain(slot_length.to_be_bytes().iter())
+            .chain(self.remaining.to_be_bytes().iter())
+            .chain(self.next_cursor.to_be_bytes().iter())
+            .cloned()
+            .chain(entries.iter().flat_map(|(flag, msg)| {
+                std::iter::once(*flag)
+                    .chain(msg.id.to_be_bytes().to_vec().into_iter())
+                    .chain(
+                        wire_length(msg.content.len())
+                            .to_be_bytes()
+                            .to_vec()
+                            .into_iter(),
+                    )
+                    .chain(msg.content.clone().into_iter())
+                    .chain(std::iter::repeat(0).take(slot_length as usize - msg.content.len()))
+            }))
             .collect()
     }
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError> {
-        // can we read number of messages?
-        if bytes.len() < 2 {
+        // can we read the page header?
+        if bytes.len() < 2 + 2 + 4 + 8 {
             return Err(ProviderResponseError::UnmarshalErrorInvalidLength);
         }
 
         let mut bytes_copy = bytes.clone();
-        let num_msgs = read_be_u16(&mut bytes_copy);
+        let page_size = read_be_u16(&mut bytes_copy);
+        let slot_length = read_be_u16(&mut bytes_copy) as usize;
+        let remaining = read_be_u32(&mut bytes_copy);
+        let next_cursor = read_be_u64(&mut bytes_copy);
 
-        // can we read all lengths of messages?
-        if bytes_copy.len() < (num_msgs * 2) as usize {
+        // can we read all the entries?
+        let entry_len =
+            1 + std::mem::size_of::<MessageId>() + std::mem::size_of::<u16>() + slot_length;
+        if bytes_copy.len() != page_size as usize * entry_len {
             return Err(ProviderResponseError::UnmarshalErrorInvalidLength);
         }
 
-        let msgs_lens: Vec<_> = (0..num_msgs)
-            .map(|_| read_be_u16(&mut bytes_copy))
-            .collect();
-
-        let required_remaining_len = msgs_lens
-            .iter()
-            .fold(0usize, |acc, &len| acc + (len as usize));
-
-        // can we read messages themselves?
-        if bytes_copy.len() != required_remaining_len {
-            return Err(ProviderResponseError::UnmarshalErrorInvalidLength);
+        let mut msgs = Vec::new();
+        for entry in bytes_copy.chunks(entry_len) {
+            let flag = entry[0];
+            let mut entry = &entry[1..];
+            let id = read_be_u64(&mut entry);
+            let len = read_be_u16(&mut entry) as usize;
+            if len > slot_length {
+                return Err(ProviderResponseError::UnmarshalError);
+            }
+            match flag {
+                MESSAGE_ENTRY_FLAG => msgs.push(PulledMessage::new(id, entry[..len].to_vec())),
+                // padding is not included in the recovered messages
+                PADDING_ENTRY_FLAG => (),
+                _ => return Err(ProviderResponseError::UnmarshalError),
+            }
         }
 
-        let msgs = msgs_lens
-            .iter()
-            .scan(0usize, |i, &len| {
-                let j = *i + (len as usize);
-                let msg = bytes_copy[*i..j].to_vec();
-                *i = j;
-                Some(msg)
-            })
-            .collect();
-
-        Ok(PullResponse { messages: msgs })
+        Ok(PullResponse {
+            messages: msgs,
+            remaining,
+            next_cursor,
+            page_size,
+        })
     }
 }
 
@@ -124,6 +263,70 @@ impl ProviderResponse for RegisterResponse {
     }
 }
 
+impl ProviderResponse for ChallengeResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.nonce.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError> {
+        match bytes.len() {
+            32 => {
+                let mut nonce = [0u8; 32];
+                nonce.copy_from_slice(bytes);
+                Ok(ChallengeResponse { nonce })
+            }
+            _ => Err(ProviderResponseError::UnmarshalErrorInvalidLength),
+        }
+    }
+}
+
+impl ProviderResponse for AckResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.acknowledged.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError> {
+        match bytes.len() {
+            2 => Ok(AckResponse {
+                acknowledged: u16::from_be_bytes([bytes[0], bytes[1]]),
+            }),
+            _ => Err(ProviderResponseError::UnmarshalErrorInvalidLength),
+        }
+    }
+}
+
+impl ProviderResponse for SubscribeResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.push_interval_millis.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError> {
+        match bytes.len() {
+            8 => Ok(SubscribeResponse {
+                // this can't fail as we've just checked the length
+                push_interval_millis: u64::from_be_bytes(bytes.try_into().unwrap()),
+            }),
+            _ => Err(ProviderResponseError::UnmarshalErrorInvalidLength),
+        }
+    }
+}
+
+impl ProviderResponse for ReplyTokenResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.validity_secs.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProviderResponseError> {
+        match bytes.len() {
+            8 => Ok(ReplyTokenResponse {
+                // this can't fail as we've just checked the length
+                validity_secs: u64::from_be_bytes(bytes.try_into().unwrap()),
+            }),
+            _ => Err(ProviderResponseError::UnmarshalErrorInvalidLength),
+        }
+    }
+}
+
 #[cfg(test)]
 mod creating_pull_response {
     use super::*;
@@ -141,11 +344,108 @@ mod creating_pull_response {
         ];
         let msg4 = vec![1, 2, 3, 4, 5, 6, 7];
 
-        let msgs = vec![msg1, msg2, msg3, msg4];
-        let pull_response = PullResponse::new(msgs.clone());
+        let msgs = vec![
+            PulledMessage::new(1, msg1),
+            PulledMessage::new(2, msg2),
+            PulledMessage::new(42, msg3),
+            PulledMessage::new(std::u64::MAX, msg4),
+        ];
+        let pull_response = PullResponse::new(msgs.clone(), 3, 43, 4);
+        let bytes = pull_response.to_bytes();
+
+        let recovered = PullResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(msgs, recovered.messages);
+        assert_eq!(3, recovered.remaining);
+        assert_eq!(43, recovered.next_cursor);
+        assert!(recovered.has_more());
+    }
+
+    #[test]
+    fn padding_is_not_included_in_recovered_messages() {
+        let msgs = vec![PulledMessage::new(1, vec![1, 2, 3])];
+        let pull_response = PullResponse::new(msgs.clone(), 0, 2, 5);
+        let bytes = pull_response.to_bytes();
+
+        let recovered = PullResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(msgs, recovered.messages);
+        assert_eq!(5, recovered.page_size);
+        assert!(!recovered.has_more());
+    }
+
+    #[test]
+    fn pages_of_the_same_size_have_the_same_length() {
+        let full_page = PullResponse::new(
+            (0..5)
+                .map(|i| PulledMessage::new(i, vec![42; i as usize * 100]))
+                .collect(),
+            0,
+            5,
+            5,
+        );
+        let partial_page = PullResponse::new(vec![PulledMessage::new(1, vec![42; 10])], 0, 2, 5);
+        let empty_page = PullResponse::new(vec![], 0, 0, 5);
+
+        let expected_length = 2 + 2 + 4 + 8 + 5 * (1 + 8 + 2 + MESSAGE_SLOT_LENGTH);
+        assert_eq!(expected_length, full_page.to_bytes().len());
+        assert_eq!(expected_length, partial_page.to_bytes().len());
+        assert_eq!(expected_length, empty_page.to_bytes().len());
+    }
+
+    #[test]
+    fn messages_longer_than_the_slot_are_recovered() {
+        let msgs = vec![
+            PulledMessage::new(1, vec![42; MESSAGE_SLOT_LENGTH + 1]),
+            PulledMessage::new(2, vec![1, 2, 3]),
+        ];
+        let pull_response = PullResponse::new(msgs.clone(), 0, 3, 5);
+        let bytes = pull_response.to_bytes();
+
+        let recovered = PullResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(msgs, recovered.messages);
+    }
+
+    #[test]
+    fn messages_with_the_same_content_as_padding_are_recovered() {
+        let msgs = vec![
+            PulledMessage::new(1, DUMMY_MESSAGE_CONTENT.to_vec()),
+            PulledMessage::new(2, vec![1, 2, 3]),
+        ];
+        let pull_response = PullResponse::new(msgs.clone(), 0, 3, 5);
         let bytes = pull_response.to_bytes();
 
         let recovered = PullResponse::from_bytes(&bytes).unwrap();
         assert_eq!(msgs, recovered.messages);
     }
+
+    #[test]
+    fn entries_with_unknown_flag_are_rejected() {
+        let msgs = vec![PulledMessage::new(1, vec![1, 2, 3])];
+        let mut bytes = PullResponse::new(msgs, 0, 2, 5).to_bytes();
+        // flag of the first entry, right after the page header
+        bytes[2 + 2 + 4 + 8] = 42;
+
+        assert!(PullResponse::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_page_is_rejected() {
+        let msgs = vec![PulledMessage::new(1, vec![1, 2, 3])];
+        let bytes = PullResponse::new(msgs, 0, 2, 5).to_bytes();
+
+        assert!(PullResponse::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod creating_ack_response {
+    use super::*;
+
+    #[test]
+    fn it_is_possible_to_recover_it_from_bytes() {
+        let ack_response = AckResponse::new(42);
+        let bytes = ack_response.to_bytes();
+
+        let recovered = AckResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(42, recovered.acknowledged);
+    }
 }
@@ -0,0 +1,50 @@
+use pemstore::pathfinder::PathFinder;
+use std::path::PathBuf;
+
+pub struct ProviderPathfinder {
+    pub config_dir: PathBuf,
+    pub private_mix_key: PathBuf,
+    pub public_mix_key: PathBuf,
+    pub private_encryption_key: PathBuf,
+    pub public_encryption_key: PathBuf,
+}
+
+impl ProviderPathfinder {
+    pub fn new(id: String) -> Self {
+        let os_config_dir = dirs::config_dir().unwrap(); // grabs the OS default config dir
+        let config_dir = os_config_dir.join("nym").join("sfw-providers").join(id);
+        let private_mix_key = config_dir.join("private.pem");
+        let public_mix_key = config_dir.join("public.pem");
+        let private_encryption_key = config_dir.join("private_sphinx.pem");
+        let public_encryption_key = config_dir.join("public_sphinx.pem");
+        ProviderPathfinder {
+            config_dir,
+            private_mix_key,
+            public_mix_key,
+            private_encryption_key,
+            public_encryption_key,
+        }
+    }
+}
+
+impl PathFinder for ProviderPathfinder {
+    fn config_dir(&self) -> PathBuf {
+        self.config_dir.clone()
+    }
+
+    fn private_identity_key(&self) -> PathBuf {
+        self.private_mix_key.clone()
+    }
+
+    fn public_identity_key(&self) -> PathBuf {
+        self.public_mix_key.clone()
+    }
+
+    fn private_encryption_key(&self) -> Option<PathBuf> {
+        Some(self.private_encryption_key.clone())
+    }
+
+    fn public_encryption_key(&self) -> Option<PathBuf> {
+        Some(self.public_encryption_key.clone())
+    }
+}
@@ -1,13 +1,21 @@
-use crate::provider::ServiceProvider;
+use crate::config::persistance::pathfinder::ProviderPathfinder;
+use crate::provider::{ProviderError, ServiceProvider};
 use clap::{App, Arg, ArgMatches, SubCommand};
-use crypto::identity::MixnetIdentityKeyPair;
+use crypto::encryption::{x25519, MixnetEncryptionKeyPair};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
 use log::error;
+use pemstore::pemstore::{new_passphrase, PemStore};
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 use std::process;
 
+mod config;
 pub mod provider;
 
+// TODO: if we ever create config file, this should go there
+const DEFAULT_MAX_STORED_MESSAGES: usize = 1000;
+const DEFAULT_MAX_BAD_PACKETS: usize = 10;
+
 fn main() {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
@@ -16,9 +24,40 @@ fn main() {
         .version(built_info::PKG_VERSION)
         .author("Nymtech")
         .about("Implementation of the Loopix-based Service Provider")
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Initialise the service provider. Do this first!")
+                .arg(Arg::with_name("id")
+                    .long("id")
+                    .help("Id of the nym-sfw-provider we want to create config for.")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("encrypt")
+                    .long("encrypt")
+                    .help("Protect the private keys with a passphrase you will be asked for")
+                )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase to protect the private keys with, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                    .requires("encrypt")
+                )
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Starts the service provider")
+                .arg(Arg::with_name("id")
+                    .long("id")
+                    .help("Id of the nym-sfw-provider we want to run.")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("passphrase-file")
+                    .long("passphrase-file")
+                    .help("File with the passphrase protecting the private keys, rather than asking for it (it can be also given in NYM_KEYS_PASSPHRASE environment variable)")
+                    .takes_value(true)
+                )
                 .arg(
                     Arg::with_name("mixHost")
                         .long("mixHost")
@@ -52,6 +91,25 @@ fn main() {
                         .help("Directory storing all packets for the clients")
                         .takes_value(true)
                 )
+                .arg(
+                    Arg::with_name("storageBackend")
+                        .long("storageBackend")
+                        .help("Backend used for storing packets for the clients - either 'sled' (default) or 'fs'")
+                        .takes_value(true)
+                        .possible_values(&["sled", "fs"])
+                )
+                .arg(
+                    Arg::with_name("maxStoredMessages")
+                        .long("maxStoredMessages")
+                        .help("Maximum number of messages stored for a single client")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("maxBadPackets")
+                        .long("maxBadPackets")
+                        .help("Number of malformed packets after which connection to the sending mixnode gets closed")
+                        .takes_value(true)
+                )
                 .arg(
                     Arg::with_name("registeredLedger")
                         .short("r")
@@ -83,14 +141,83 @@ fn print_binding_warning(address: &str) {
     println!("\n##### WARNING #####\n");
 }
 
+fn init(matches: &ArgMatches) {
+    println!("Initialising service provider...");
+
+    let id = matches.value_of("id").unwrap().to_string(); // required for now
+    let pathfinder = ProviderPathfinder::new(id);
+
+    println!("Writing keypairs to {:?}...", pathfinder.config_dir);
+    // the identity key is only used for signing while the sphinx key is used for processing packets
+    let identity_keys = ed25519::KeyPair::new();
+    let sphinx_keys = x25519::KeyPair::new();
+    let mut pem_store = PemStore::new(pathfinder);
+    if matches.is_present("encrypt") {
+        match new_passphrase(matches.value_of("passphrase-file")) {
+            Ok(passphrase) => pem_store = pem_store.with_passphrase(passphrase),
+            Err(err) => {
+                eprintln!("Failed to obtain the passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(err) = pem_store
+        .write_identity(identity_keys)
+        .and_then(|_| pem_store.write_encryption(sphinx_keys))
+    {
+        eprintln!("Failed to write the provider keys: {}", err);
+        process::exit(1);
+    }
+
+    println!("Service provider configuration completed.\n\n\n")
+}
+
 fn run(matches: &ArgMatches) {
     println!("{}", banner());
     let config = new_config(matches);
-    let provider = ServiceProvider::new(config);
+    let ledger_dir = config.ledger_dir.clone();
+    let store_dir = config.store_dir.clone();
+    let provider = match ServiceProvider::new(config) {
+        Ok(provider) => provider,
+        Err(err) => {
+            eprintln!("Failed to start the service provider: {:?}", err);
+            match err {
+                ProviderError::ClientLedgerLoadingError(_) => eprintln!(
+                    "Make sure the ledger of registered clients in {:?} is readable and not corrupted, or choose a different one with --registeredLedger.",
+                    ledger_dir
+                ),
+                ProviderError::StorageOpeningError(_) => eprintln!(
+                    "Make sure the message store in {:?} is accessible and not used by another provider, or choose a different one with --storeDir.",
+                    store_dir
+                ),
+                _ => (),
+            }
+            process::exit(1);
+        }
+    };
 
     provider.start().unwrap()
 }
 
+// Loads both the identity and the sphinx keys written by `init` or exits with a message
+// explaining what the user should do about it.
+fn load_keys(id: &str, passphrase_file: Option<&str>) -> (ed25519::KeyPair, x25519::KeyPair) {
+    match PemStore::new(ProviderPathfinder::new(id.to_string()))
+        .with_non_interactive_passphrase(passphrase_file)
+        .and_then(|pem_store| Ok((pem_store.read_identity()?, pem_store.read_encryption()?)))
+    {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("Failed to load the provider keys: {}", err);
+            eprintln!(
+                "Has the provider been initialised? Run `nym-sfw-provider init --id {}` first.",
+                id
+            );
+            process::exit(1);
+        }
+    }
+}
+
 fn new_config(matches: &ArgMatches) -> provider::Config {
     let directory_server = matches
         .value_of("directory")
@@ -121,7 +248,12 @@ fn new_config(matches: &ArgMatches) -> provider::Config {
         print_binding_warning(client_host);
     }
 
-    let key_pair = crypto::identity::DummyMixIdentityKeyPair::new(); // TODO: persist this so keypairs don't change every restart
+    let id = matches.value_of("id").unwrap().to_string();
+    let (identity_keys, sphinx_keys) = load_keys(&id, matches.value_of("passphrase-file"));
+    println!(
+        "Identity key: {}",
+        identity_keys.public_key().to_b64_string()
+    );
     let store_dir = PathBuf::from(
         matches
             .value_of("storeDir")
@@ -133,6 +265,27 @@ fn new_config(matches: &ArgMatches) -> provider::Config {
             .unwrap_or("/tmp/nym-provider/registered_clients"),
     );
 
+    let storage_backend = match matches.value_of("storageBackend").unwrap_or("sled") {
+        "fs" => provider::StorageBackend::Filesystem,
+        _ => provider::StorageBackend::Sled,
+    };
+    let max_stored_messages = match matches
+        .value_of("maxStoredMessages")
+        .map(|max| max.parse::<usize>())
+        .unwrap_or(Ok(DEFAULT_MAX_STORED_MESSAGES))
+    {
+        Ok(n) => n,
+        Err(err) => panic!("Invalid max stored messages value provided - {:?}", err),
+    };
+    let max_bad_packets = match matches
+        .value_of("maxBadPackets")
+        .map(|max| max.parse::<usize>())
+        .unwrap_or(Ok(DEFAULT_MAX_BAD_PACKETS))
+    {
+        Ok(n) => n,
+        Err(err) => panic!("Invalid max bad packets value provided - {:?}", err),
+    };
+
     println!("store_dir is: {:?}", store_dir);
     println!(
         "registered_client_ledger_dir is: {:?}",
@@ -157,10 +310,15 @@ fn new_config(matches: &ArgMatches) -> provider::Config {
     provider::Config {
         mix_socket_address,
         directory_server,
-        public_key: key_pair.public_key,
+        identity_keypair: identity_keys,
+        ledger_dir: registered_client_ledger_dir,
+        public_key: sphinx_keys.public_key().clone(),
         client_socket_address,
-        secret_key: key_pair.private_key,
+        secret_key: *sphinx_keys.private_key(),
+        storage_backend,
         store_dir: PathBuf::from(store_dir),
+        max_stored_messages,
+        max_bad_packets,
     }
 }
 
@@ -171,6 +329,7 @@ pub mod built_info {
 
 fn execute(matches: ArgMatches) -> Result<(), String> {
     match matches.subcommand() {
+        ("init", Some(m)) => Ok(init(m)),
         ("run", Some(m)) => Ok(run(m)),
         _ => Err(usage()),
     }
@@ -1,17 +1,24 @@
+use crate::provider::reply_tokens::{ReplyTokenError, ReplyTokenRegistry};
 use crate::provider::storage::{ClientStorage, StoreError};
-use crate::provider::ClientLedger;
-use crypto::identity::{DummyMixIdentityPrivateKey, MixnetIdentityPrivateKey};
+use crate::provider::subscriptions::SubscriptionRegistry;
+use crate::provider::{ClientLedger, MESSAGE_RETRIEVAL_LIMIT, PUSH_INTERVAL};
+use crypto::encryption::{x25519, MixnetEncryptionPrivateKey, MixnetEncryptionPublicKey};
+use crypto::identity::{ed25519, MixnetIdentityPublicKey};
 use futures::lock::Mutex as FMutex;
 use hmac::{Hmac, Mac};
 use log::*;
 use sfw_provider_requests::requests::{
-    ProviderRequestError, ProviderRequests, PullRequest, RegisterRequest,
+    AckRequest, ProviderRequestError, ProviderRequests, PullRequest, RegisterRequest,
+    ReplyTokenRequest, SubscribeRequest,
 };
-use sfw_provider_requests::responses::{ProviderResponse, PullResponse, RegisterResponse};
-use sfw_provider_requests::AuthToken;
+use sfw_provider_requests::responses::{
+    AckResponse, ChallengeResponse, ProviderResponse, PullResponse, PulledMessage,
+    RegisterResponse, ReplyTokenResponse, SubscribeResponse,
+};
+use sfw_provider_requests::{AuthToken, ChallengeNonce, MessageId};
 use sha2::Sha256;
+use sphinx::route::DestinationAddressBytes;
 use std::io;
-use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 type HmacSha256 = Hmac<Sha256>;
@@ -22,7 +29,12 @@ pub enum ClientProcessingError {
     StoreError,
     InvalidRequest,
     WrongToken,
+    LedgerError,
     IOError,
+    ChallengeNotIssuedError,
+    InvalidSignatureError,
+    UnauthenticatedSessionError,
+    ReplyTokenRegistrationError,
 }
 
 impl From<ProviderRequestError> for ClientProcessingError {
@@ -42,6 +54,14 @@ impl From<StoreError> for ClientProcessingError {
     }
 }
 
+impl From<ReplyTokenError> for ClientProcessingError {
+    fn from(_: ReplyTokenError) -> Self {
+        use ClientProcessingError::*;
+
+        ReplyTokenRegistrationError
+    }
+}
+
 impl From<io::Error> for ClientProcessingError {
     fn from(_: io::Error) -> Self {
         use ClientProcessingError::*;
@@ -50,23 +70,28 @@ impl From<io::Error> for ClientProcessingError {
     }
 }
 
-#[derive(Debug)]
 pub(crate) struct ClientProcessingData {
-    store_dir: PathBuf,
+    storage: Arc<dyn ClientStorage>,
     registered_clients_ledger: Arc<FMutex<ClientLedger>>,
-    secret_key: DummyMixIdentityPrivateKey,
+    secret_key: x25519::PrivateKey,
+    pub(crate) subscriptions: SubscriptionRegistry,
+    reply_tokens: ReplyTokenRegistry,
 }
 
 impl ClientProcessingData {
     pub(crate) fn new(
-        store_dir: PathBuf,
+        storage: Arc<dyn ClientStorage>,
         registered_clients_ledger: Arc<FMutex<ClientLedger>>,
-        secret_key: DummyMixIdentityPrivateKey,
+        secret_key: x25519::PrivateKey,
+        subscriptions: SubscriptionRegistry,
+        reply_tokens: ReplyTokenRegistry,
     ) -> Self {
         ClientProcessingData {
-            store_dir,
+            storage,
             registered_clients_ledger,
             secret_key,
+            subscriptions,
+            reply_tokens,
         }
     }
 
@@ -75,59 +100,314 @@ impl ClientProcessingData {
     }
 }
 
+// Messages included in the response. They are only removed from the storage once the client
+// acknowledges them, but if the response couldn't even be sent, there's no point in waiting
+// for their lease to expire.
+pub(crate) struct PendingDelivery {
+    client_address: DestinationAddressBytes,
+    message_ids: Vec<MessageId>,
+}
+
+pub(crate) struct ClientResponse {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) pending_delivery: Option<PendingDelivery>,
+    // set if, after the response is sent, the connection should be used for pushing
+    // messages of this client
+    pub(crate) subscription: Option<DestinationAddressBytes>,
+}
+
+impl ClientResponse {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        ClientResponse {
+            bytes,
+            pending_delivery: None,
+            subscription: None,
+        }
+    }
+}
+
+// State of a single client connection. A connection becomes bound to a destination address
+// once the client proves it owns it during registration and from then on it can only be used
+// to access the inbox of that address.
+pub(crate) struct ClientSession {
+    challenge_nonce: Option<ChallengeNonce>,
+    authenticated_address: Option<DestinationAddressBytes>,
+}
+
+impl ClientSession {
+    pub(crate) fn new() -> Self {
+        ClientSession {
+            challenge_nonce: None,
+            authenticated_address: None,
+        }
+    }
+}
+
 pub(crate) struct ClientRequestProcessor;
 
 impl ClientRequestProcessor {
     pub(crate) async fn process_client_request(
         data: &[u8],
+        session: &mut ClientSession,
         processing_data: Arc<ClientProcessingData>,
-    ) -> Result<Vec<u8>, ClientProcessingError> {
+    ) -> Result<ClientResponse, ClientProcessingError> {
         let client_request = ProviderRequests::from_bytes(&data)?;
         trace!("Received the following request: {:?}", client_request);
         match client_request {
-            ProviderRequests::Register(req) => Ok(ClientRequestProcessor::register_new_client(
-                req,
-                processing_data,
-            )
-            .await?
-            .to_bytes()),
-            ProviderRequests::PullMessages(req) => Ok(
-                ClientRequestProcessor::process_pull_messages_request(req, processing_data)
+            ProviderRequests::Challenge(_) => Ok(ClientResponse::new(
+                ClientRequestProcessor::issue_challenge(session).to_bytes(),
+            )),
+            ProviderRequests::Register(req) => Ok(ClientResponse::new(
+                ClientRequestProcessor::register_new_client(req, session, processing_data)
+                    .await?
+                    .to_bytes(),
+            )),
+            ProviderRequests::PullMessages(req) => {
+                ClientRequestProcessor::process_pull_messages_request(req, session, processing_data)
+                    .await
+            }
+            ProviderRequests::AckMessages(req) => Ok(ClientResponse::new(
+                ClientRequestProcessor::process_ack_request(req, session, processing_data)
+                    .await?
+                    .to_bytes(),
+            )),
+            ProviderRequests::Subscribe(req) => {
+                ClientRequestProcessor::process_subscribe_request(req, session, processing_data)
+                    .await
+            }
+            ProviderRequests::RegisterReplyToken(req) => Ok(ClientResponse::new(
+                ClientRequestProcessor::process_reply_token_request(req, session, processing_data)
                     .await?
                     .to_bytes(),
-            ),
+            )),
         }
     }
 
-    async fn process_pull_messages_request(
-        req: PullRequest,
-        processing_data: Arc<ClientProcessingData>,
-    ) -> Result<PullResponse, ClientProcessingError> {
+    // requests operating on an inbox are only allowed on a session that has proven ownership
+    // of its address and have to carry the token that was issued for that particular address
+    async fn authorize(
+        destination_address: DestinationAddressBytes,
+        auth_token: AuthToken,
+        session: &ClientSession,
+        processing_data: &ClientProcessingData,
+    ) -> Result<(), ClientProcessingError> {
+        if session.authenticated_address != Some(destination_address) {
+            return Err(ClientProcessingError::UnauthenticatedSessionError);
+        }
+
+        let expected_token = ClientRequestProcessor::generate_new_auth_token(
+            destination_address.to_vec(),
+            processing_data.secret_key,
+        );
+        if auth_token != expected_token {
+            return Err(ClientProcessingError::WrongToken);
+        }
+
         // TODO: this lock is completely unnecessary as we're only reading the data.
         // Wait for https://github.com/nymtech/nym-sfw-provider/issues/19 to resolve.
         let unlocked_ledger = processing_data.registered_clients_ledger.lock().await;
-
-        if unlocked_ledger.has_token(req.auth_token) {
-            // drop the mutex so that we could do IO without blocking others wanting to get the lock
-            drop(unlocked_ledger);
-            let retrieved_messages = ClientStorage::retrieve_client_files(
-                req.destination_address,
-                processing_data.store_dir.as_path(),
-            )?;
-            Ok(PullResponse::new(retrieved_messages))
+        if unlocked_ledger.has_token(auth_token) {
+            Ok(())
         } else {
             Err(ClientProcessingError::WrongToken)
         }
     }
 
+    // retrieves the next page of client messages starting from the cursor and marks them as pending
+    pub(crate) fn retrieve_page(
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+        processing_data: &ClientProcessingData,
+    ) -> Result<(PullResponse, PendingDelivery), ClientProcessingError> {
+        let storage = &processing_data.storage;
+        let retrieved_messages =
+            storage.fetch_and_mark_pending(client_address, cursor, MESSAGE_RETRIEVAL_LIMIT)?;
+
+        let next_cursor = retrieved_messages
+            .last()
+            .map(|msg| msg.id.saturating_add(1))
+            .unwrap_or(cursor);
+        let remaining = storage.count_stored(client_address, next_cursor)?;
+
+        let message_ids = retrieved_messages.iter().map(|msg| msg.id).collect();
+        let messages = retrieved_messages
+            .into_iter()
+            .map(|msg| PulledMessage::new(msg.id, msg.content))
+            .collect();
+        let page = PullResponse::new(
+            messages,
+            remaining.min(std::u32::MAX as usize) as u32,
+            next_cursor,
+            MESSAGE_RETRIEVAL_LIMIT as u16,
+        );
+
+        Ok((
+            page,
+            PendingDelivery {
+                client_address,
+                message_ids,
+            },
+        ))
+    }
+
+    // page containing only padding; it's indistinguishable from a page with messages on the wire
+    pub(crate) fn empty_page() -> PullResponse {
+        PullResponse::new(Vec::new(), 0, 0, MESSAGE_RETRIEVAL_LIMIT as u16)
+    }
+
+    // Puts messages of a response that failed to be sent back into the inbox for the next pull.
+    pub(crate) fn release_undelivered(
+        pending_delivery: PendingDelivery,
+        processing_data: &ClientProcessingData,
+    ) {
+        if let Err(e) = processing_data.storage.release_pending(
+            pending_delivery.client_address,
+            &pending_delivery.message_ids,
+        ) {
+            error!(
+                "failed to release undelivered messages {:?}; err = {:?}",
+                pending_delivery.message_ids, e
+            );
+        }
+    }
+
+    async fn process_pull_messages_request(
+        req: PullRequest,
+        session: &ClientSession,
+        processing_data: Arc<ClientProcessingData>,
+    ) -> Result<ClientResponse, ClientProcessingError> {
+        ClientRequestProcessor::authorize(
+            req.destination_address,
+            req.auth_token,
+            session,
+            &processing_data,
+        )
+        .await?;
+
+        let (page, pending_delivery) = ClientRequestProcessor::retrieve_page(
+            req.destination_address,
+            req.cursor,
+            &processing_data,
+        )?;
+
+        Ok(ClientResponse {
+            bytes: page.to_bytes(),
+            pending_delivery: Some(pending_delivery),
+            subscription: None,
+        })
+    }
+
+    async fn process_ack_request(
+        req: AckRequest,
+        session: &ClientSession,
+        processing_data: Arc<ClientProcessingData>,
+    ) -> Result<AckResponse, ClientProcessingError> {
+        ClientRequestProcessor::authorize(
+            req.destination_address,
+            req.auth_token,
+            session,
+            &processing_data,
+        )
+        .await?;
+
+        let acknowledged = processing_data
+            .storage
+            .acknowledge(req.destination_address, &req.message_ids)?;
+        trace!(
+            "{} out of {} messages were acknowledged",
+            acknowledged,
+            req.message_ids.len()
+        );
+
+        Ok(AckResponse::new(acknowledged as u16))
+    }
+
+    async fn process_subscribe_request(
+        req: SubscribeRequest,
+        session: &ClientSession,
+        processing_data: Arc<ClientProcessingData>,
+    ) -> Result<ClientResponse, ClientProcessingError> {
+        ClientRequestProcessor::authorize(
+            req.destination_address,
+            req.auth_token,
+            session,
+            &processing_data,
+        )
+        .await?;
+
+        debug!(
+            "{:?} subscribed for pushed messages",
+            req.destination_address
+        );
+        let push_interval_millis = PUSH_INTERVAL.as_millis() as u64;
+        Ok(ClientResponse {
+            bytes: SubscribeResponse::new(push_interval_millis).to_bytes(),
+            pending_delivery: None,
+            subscription: Some(req.destination_address),
+        })
+    }
+
+    async fn process_reply_token_request(
+        req: ReplyTokenRequest,
+        session: &ClientSession,
+        processing_data: Arc<ClientProcessingData>,
+    ) -> Result<ReplyTokenResponse, ClientProcessingError> {
+        ClientRequestProcessor::authorize(
+            req.destination_address,
+            req.auth_token,
+            session,
+            &processing_data,
+        )
+        .await?;
+
+        let reply_tokens = &processing_data.reply_tokens;
+        reply_tokens.register(req.destination_address, req.token_id)?;
+        trace!(
+            "{:?} registered reply token {:?}",
+            req.destination_address,
+            req.token_id
+        );
+
+        Ok(ReplyTokenResponse::new(reply_tokens.validity().as_secs()))
+    }
+
+    // every challenge replaces the previous one, so only the most recently issued nonce
+    // can be used for registration
+    fn issue_challenge(session: &mut ClientSession) -> ChallengeResponse {
+        let nonce: ChallengeNonce = rand::random();
+        session.challenge_nonce = Some(nonce);
+        ChallengeResponse::new(nonce)
+    }
+
+    // Registration is also used by already registered clients to authenticate new sessions -
+    // they are going to receive the same token as before.
     async fn register_new_client(
         req: RegisterRequest,
+        session: &mut ClientSession,
         processing_data: Arc<ClientProcessingData>,
     ) -> Result<RegisterResponse, ClientProcessingError> {
         debug!(
             "Processing register new client request: {:?}",
             req.destination_address
         );
+
+        // the nonce can only be used once, regardless of whether the attempt succeeds
+        let nonce = session
+            .challenge_nonce
+            .take()
+            .ok_or(ClientProcessingError::ChallengeNotIssuedError)?;
+        let challenge_message =
+            RegisterRequest::challenge_message(&nonce, &req.destination_address);
+        let client_identity = ed25519::PublicKey::from_bytes(&req.identity_key)
+            .map_err(|_| ClientProcessingError::InvalidSignatureError)?;
+        if !client_identity.verify(&challenge_message, &req.signature) {
+            return Err(ClientProcessingError::InvalidSignatureError);
+        }
+        // otherwise anyone could sign for someone else's address with their own identity
+        if client_identity.to_x25519().to_bytes() != req.destination_address {
+            return Err(ClientProcessingError::InvalidSignatureError);
+        }
+
         let mut unlocked_ledger = processing_data.registered_clients_ledger.lock().await;
 
         let auth_token = ClientRequestProcessor::generate_new_auth_token(
@@ -135,25 +415,20 @@ impl ClientRequestProcessor {
             processing_data.secret_key,
         );
         if !unlocked_ledger.has_token(auth_token) {
-            unlocked_ledger.insert_token(auth_token, req.destination_address);
-            ClientRequestProcessor::create_storage_dir(
-                req.destination_address,
-                processing_data.store_dir.as_path(),
-            )?;
+            if let Err(e) = unlocked_ledger.insert_token(auth_token, req.destination_address) {
+                error!("failed to persist new client registration - {:?}", e);
+                return Err(ClientProcessingError::LedgerError);
+            }
+            processing_data
+                .storage
+                .create_inbox(req.destination_address)?;
         }
-        Ok(RegisterResponse::new(auth_token))
-    }
 
-    fn create_storage_dir(
-        client_address: sphinx::route::DestinationAddressBytes,
-        store_dir: &Path,
-    ) -> io::Result<()> {
-        let client_dir_name = hex::encode(client_address);
-        let full_store_dir = store_dir.join(client_dir_name);
-        std::fs::create_dir_all(full_store_dir)
+        session.authenticated_address = Some(req.destination_address);
+        Ok(RegisterResponse::new(auth_token))
     }
 
-    fn generate_new_auth_token(data: Vec<u8>, key: DummyMixIdentityPrivateKey) -> AuthToken {
+    fn generate_new_auth_token(data: Vec<u8>, key: x25519::PrivateKey) -> AuthToken {
         let mut auth_token_raw =
             HmacSha256::new_varkey(&key.to_bytes()).expect("HMAC can take key of any size");
         auth_token_raw.input(&data);
@@ -227,19 +502,6 @@ mod register_new_client {
     //    }
 }
 
-#[cfg(test)]
-mod create_storage_dir {
-    use super::*;
-    use sphinx::route::DestinationAddressBytes;
-
-    #[test]
-    fn it_creates_a_correct_storage_directory() {
-        let client_address: DestinationAddressBytes = [1u8; 32];
-        let store_dir = Path::new("/tmp/");
-        ClientRequestProcessor::create_storage_dir(client_address, store_dir).unwrap();
-    }
-}
-
 #[cfg(test)]
 mod generating_new_auth_token {
     use super::*;
@@ -248,7 +510,7 @@ mod generating_new_auth_token {
     fn for_the_same_input_generates_the_same_auth_token() {
         let data1 = vec![1u8; 55];
         let data2 = vec![1u8; 55];
-        let key = DummyMixIdentityPrivateKey::from_bytes(&[1u8; 32]);
+        let key = x25519::PrivateKey::from_bytes(&[1u8; 32]).unwrap();
         let token1 = ClientRequestProcessor::generate_new_auth_token(data1, key);
         let token2 = ClientRequestProcessor::generate_new_auth_token(data2, key);
         assert_eq!(token1, token2);
@@ -258,14 +520,14 @@ mod generating_new_auth_token {
     fn for_different_inputs_generates_different_auth_tokens() {
         let data1 = vec![1u8; 55];
         let data2 = vec![2u8; 55];
-        let key = DummyMixIdentityPrivateKey::from_bytes(&[1u8; 32]);
+        let key = x25519::PrivateKey::from_bytes(&[1u8; 32]).unwrap();
         let token1 = ClientRequestProcessor::generate_new_auth_token(data1, key);
         let token2 = ClientRequestProcessor::generate_new_auth_token(data2, key);
         assert_ne!(token1, token2);
 
         let data1 = vec![1u8; 50];
         let data2 = vec![2u8; 55];
-        let key = DummyMixIdentityPrivateKey::from_bytes(&[1u8; 32]);
+        let key = x25519::PrivateKey::from_bytes(&[1u8; 32]).unwrap();
         let token1 = ClientRequestProcessor::generate_new_auth_token(data1, key);
         let token2 = ClientRequestProcessor::generate_new_auth_token(data2, key);
         assert_ne!(token1, token2);
@@ -0,0 +1,209 @@
+use directory_client::presence::MixProviderClient;
+use futures::lock::Mutex as FMutex;
+use serde::{Deserialize, Serialize};
+use sfw_provider_requests::AuthToken;
+use sphinx::route::DestinationAddressBytes;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const LEDGER_FILENAME: &str = "registered_clients.json";
+const TEMPORARY_LEDGER_FILENAME: &str = "registered_clients.json.tmp";
+
+#[derive(Debug)]
+pub enum ClientLedgerError {
+    MalformedLedgerError,
+    IOError(io::Error),
+}
+
+impl From<io::Error> for ClientLedgerError {
+    fn from(err: io::Error) -> Self {
+        ClientLedgerError::IOError(err)
+    }
+}
+
+impl From<serde_json::Error> for ClientLedgerError {
+    fn from(_: serde_json::Error) -> Self {
+        ClientLedgerError::MalformedLedgerError
+    }
+}
+
+// on-disk representation of a single ledger entry
+#[derive(Serialize, Deserialize)]
+struct LedgerEntry {
+    auth_token: String,
+    client_address: String,
+}
+
+impl LedgerEntry {
+    fn new(auth_token: &AuthToken, client_address: &DestinationAddressBytes) -> Self {
+        LedgerEntry {
+            auth_token: base64::encode_config(auth_token, base64::URL_SAFE),
+            client_address: base64::encode_config(client_address, base64::URL_SAFE),
+        }
+    }
+
+    fn try_into_raw(self) -> Result<(AuthToken, DestinationAddressBytes), ClientLedgerError> {
+        let auth_token_bytes = base64::decode_config(&self.auth_token, base64::URL_SAFE)
+            .map_err(|_| ClientLedgerError::MalformedLedgerError)?;
+        let client_address_bytes = base64::decode_config(&self.client_address, base64::URL_SAFE)
+            .map_err(|_| ClientLedgerError::MalformedLedgerError)?;
+
+        if auth_token_bytes.len() != 32 || client_address_bytes.len() != 32 {
+            return Err(ClientLedgerError::MalformedLedgerError);
+        }
+
+        let mut auth_token = [0u8; 32];
+        auth_token.copy_from_slice(&auth_token_bytes);
+        let mut client_address = [0u8; 32];
+        client_address.copy_from_slice(&client_address_bytes);
+
+        Ok((auth_token, client_address))
+    }
+}
+
+// ClientLedger keeps track of all registered clients. Every change is immediately written
+// to the ledger directory so that auth tokens remain valid across provider restarts.
+#[derive(Debug)]
+pub struct ClientLedger {
+    clients: HashMap<AuthToken, DestinationAddressBytes>,
+    ledger_dir: PathBuf,
+}
+
+impl ClientLedger {
+    pub(crate) fn new(ledger_dir: PathBuf) -> Self {
+        ClientLedger {
+            clients: HashMap::new(),
+            ledger_dir,
+        }
+    }
+
+    pub(crate) fn add_arc_futures_mutex(self) -> Arc<FMutex<Self>> {
+        Arc::new(FMutex::new(self))
+    }
+
+    pub(crate) fn has_token(&self, auth_token: AuthToken) -> bool {
+        self.clients.contains_key(&auth_token)
+    }
+
+    // note that the token is only kept if it was successfully persisted
+    pub(crate) fn insert_token(
+        &mut self,
+        auth_token: AuthToken,
+        client_address: DestinationAddressBytes,
+    ) -> Result<Option<DestinationAddressBytes>, ClientLedgerError> {
+        let old_address = self.clients.insert(auth_token, client_address);
+        if let Err(err) = self.save() {
+            match old_address {
+                Some(old_address) => self.clients.insert(auth_token, old_address),
+                None => self.clients.remove(&auth_token),
+            };
+            return Err(err);
+        }
+        Ok(old_address)
+    }
+
+    pub(crate) fn current_clients(&self) -> Vec<MixProviderClient> {
+        self.clients
+            .iter()
+            .map(|(_, v)| base64::encode_config(v, base64::URL_SAFE))
+            .map(|pub_key| MixProviderClient { pub_key })
+            .collect()
+    }
+
+    // if the ledger does not exist yet, an empty one is created
+    pub(crate) fn load(ledger_dir: PathBuf) -> Result<Self, ClientLedgerError> {
+        let ledger_file = ledger_dir.join(LEDGER_FILENAME);
+        let ledger_content = match fs::read(&ledger_file) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(ClientLedger::new(ledger_dir))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let entries: Vec<LedgerEntry> = serde_json::from_slice(&ledger_content)?;
+        let clients = entries
+            .into_iter()
+            .map(|entry| entry.try_into_raw())
+            .collect::<Result<_, _>>()?;
+
+        Ok(ClientLedger {
+            clients,
+            ledger_dir,
+        })
+    }
+
+    // To make sure we never end up with partially written ledger if we crash mid-write,
+    // the content is first written and synced to a temporary file which then atomically
+    // replaces the old ledger.
+    fn save(&self) -> Result<(), ClientLedgerError> {
+        fs::create_dir_all(&self.ledger_dir)?;
+
+        let entries: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(auth_token, client_address)| LedgerEntry::new(auth_token, client_address))
+            .collect();
+        let ledger_content = serde_json::to_vec(&entries)?;
+
+        let temporary_file = self.ledger_dir.join(TEMPORARY_LEDGER_FILENAME);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary_file)?;
+        file.write_all(&ledger_content)?;
+        file.sync_all()?;
+
+        fs::rename(&temporary_file, self.ledger_dir.join(LEDGER_FILENAME))?;
+        ClientLedger::sync_dir(&self.ledger_dir)
+    }
+
+    // required for the rename itself to be durable
+    fn sync_dir(dir: &Path) -> Result<(), ClientLedgerError> {
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod client_ledger {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn loading_nonexistent_ledger_creates_empty_one() {
+        let dir = TempDir::new().unwrap();
+        let ledger = ClientLedger::load(dir.path().join("ledger")).unwrap();
+        assert!(ledger.clients.is_empty());
+    }
+
+    #[test]
+    fn reloaded_ledger_contains_all_inserted_tokens() {
+        let dir = TempDir::new().unwrap();
+        let mut ledger = ClientLedger::load(dir.path().to_path_buf()).unwrap();
+        ledger.insert_token([1u8; 32], [2u8; 32]).unwrap();
+        ledger.insert_token([3u8; 32], [4u8; 32]).unwrap();
+
+        let reloaded_ledger = ClientLedger::load(dir.path().to_path_buf()).unwrap();
+        assert_eq!(2, reloaded_ledger.clients.len());
+        assert!(reloaded_ledger.has_token([1u8; 32]));
+        assert!(reloaded_ledger.has_token([3u8; 32]));
+        assert_eq!(Some(&[4u8; 32]), reloaded_ledger.clients.get(&[3u8; 32]));
+    }
+
+    #[test]
+    fn loading_malformed_ledger_fails() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(LEDGER_FILENAME), b"definitely not json").unwrap();
+
+        match ClientLedger::load(dir.path().to_path_buf()) {
+            Err(ClientLedgerError::MalformedLedgerError) => (),
+            _ => panic!("expected malformed ledger error"),
+        }
+    }
+}
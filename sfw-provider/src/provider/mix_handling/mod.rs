@@ -1,7 +1,9 @@
-use crate::provider::storage::StoreData;
-use crypto::identity::DummyMixIdentityPrivateKey;
+use crate::provider::reply_tokens::ReplyTokenRegistry;
+use crate::provider::storage::{ClientStorage, StoreData};
+use crate::provider::subscriptions::SubscriptionRegistry;
+use crypto::encryption::x25519;
+use log::*;
 use sphinx::{ProcessedPacket, SphinxPacket};
-use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 // TODO: this will probably need to be moved elsewhere I imagine
@@ -12,6 +14,7 @@ pub enum MixProcessingError {
     ReceivedForwardHopError,
     InvalidPayload,
     NonMatchingRecipient,
+    UnknownReplyTokenError,
     FileIOFailure,
 }
 
@@ -33,17 +36,26 @@ impl From<std::io::Error> for MixProcessingError {
 }
 
 // ProcessingData defines all data required to correctly unwrap sphinx packets
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct MixProcessingData {
-    secret_key: DummyMixIdentityPrivateKey,
-    pub(crate) store_dir: PathBuf,
+    secret_key: x25519::PrivateKey,
+    pub(crate) storage: Arc<dyn ClientStorage>,
+    pub(crate) subscriptions: SubscriptionRegistry,
+    reply_tokens: ReplyTokenRegistry,
 }
 
 impl MixProcessingData {
-    pub(crate) fn new(secret_key: DummyMixIdentityPrivateKey, store_dir: PathBuf) -> Self {
+    pub(crate) fn new(
+        secret_key: x25519::PrivateKey,
+        storage: Arc<dyn ClientStorage>,
+        subscriptions: SubscriptionRegistry,
+        reply_tokens: ReplyTokenRegistry,
+    ) -> Self {
         MixProcessingData {
             secret_key,
-            store_dir,
+            storage,
+            subscriptions,
+            reply_tokens,
         }
     }
 
@@ -62,7 +74,7 @@ impl MixPacketProcessor {
         let packet = SphinxPacket::from_bytes(packet_data.to_vec())?;
         let read_processing_data = processing_data.read().unwrap();
         let (client_address, client_surb_id, payload) =
-            match packet.process(read_processing_data.secret_key.as_scalar()) {
+            match packet.process(read_processing_data.secret_key.0) {
                 ProcessedPacket::ProcessedPacketFinalHop(client_address, surb_id, payload) => {
                     (client_address, surb_id, payload)
                 }
@@ -78,6 +90,27 @@ impl MixPacketProcessor {
             return Err(MixProcessingError::NonMatchingRecipient);
         }
 
-        Ok(StoreData::new(client_address, client_surb_id, message))
+        let store_data = StoreData::new(client_address, client_surb_id, message);
+        if store_data.is_reply() {
+            return MixPacketProcessor::resolve_reply(
+                store_data,
+                &read_processing_data.reply_tokens,
+            );
+        }
+        Ok(store_data)
+    }
+
+    // replies are stored for whoever has registered the reply token they were sent with
+    fn resolve_reply(
+        store_data: StoreData,
+        reply_tokens: &ReplyTokenRegistry,
+    ) -> Result<StoreData, MixProcessingError> {
+        match reply_tokens.take(&store_data.client_surb_id()) {
+            Some(owner) => Ok(store_data.redirect(owner)),
+            None => {
+                debug!("received reply with unknown or already used reply token");
+                Err(MixProcessingError::UnknownReplyTokenError)
+            }
+        }
     }
 }
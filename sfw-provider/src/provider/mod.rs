@@ -1,37 +1,68 @@
-use crate::provider::client_handling::{ClientProcessingData, ClientRequestProcessor};
+use crate::provider::client_handling::{
+    ClientProcessingData, ClientRequestProcessor, ClientResponse, ClientSession,
+};
+use crate::provider::client_ledger::{ClientLedger, ClientLedgerError};
 use crate::provider::mix_handling::{MixPacketProcessor, MixProcessingData};
-use crate::provider::storage::ClientStorage;
-use crypto::identity::{DummyMixIdentityPrivateKey, DummyMixIdentityPublicKey};
-use directory_client::presence::MixProviderClient;
+use crate::provider::reply_tokens::ReplyTokenRegistry;
+use crate::provider::storage::{ClientStorage, FilesystemStorage, SledStorage, StoreError};
+use crate::provider::subscriptions::SubscriptionRegistry;
+use crypto::encryption::x25519;
+use crypto::identity::ed25519;
+use futures::channel::mpsc;
 use futures::io::Error;
 use futures::lock::Mutex as FMutex;
+use futures::{SinkExt, StreamExt};
 use log::*;
-use sfw_provider_requests::AuthToken;
+use sfw_provider_requests::codec::ProviderCodec;
 use sphinx::route::DestinationAddressBytes;
-use std::collections::HashMap;
-use std::net::{Shutdown, SocketAddr};
+use sphinx_framing::codec::SphinxCodec;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::Duration;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
+use tokio_util::codec::{Framed, FramedRead};
 
 mod client_handling;
+mod client_ledger;
 mod mix_handling;
 pub mod presence;
+mod reply_tokens;
 mod storage;
+mod subscriptions;
 
 // TODO: if we ever create config file, this should go there
-const STORED_MESSAGE_FILENAME_LENGTH: usize = 16;
 const MESSAGE_RETRIEVAL_LIMIT: usize = 5;
+// how long the client has to acknowledge pulled messages before they are handed out again
+const MESSAGE_LEASE_DURATION: Duration = Duration::from_secs(60);
+const LEASE_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// how often pages of messages are pushed to subscribed clients
+const PUSH_INTERVAL: Duration = Duration::from_millis(500);
+// for how long a registered reply token can be used before it's discarded
+const REPLY_TOKEN_VALIDITY: Duration = Duration::from_secs(60 * 60);
+const MAX_REPLY_TOKENS_PER_CLIENT: usize = 100;
+
+pub enum StorageBackend {
+    Filesystem,
+    Sled,
+}
 
 pub struct Config {
     pub client_socket_address: SocketAddr,
     pub directory_server: String,
+    // only used for signing our presence
+    pub identity_keypair: ed25519::KeyPair,
+    pub ledger_dir: PathBuf,
     pub mix_socket_address: SocketAddr,
-    pub public_key: DummyMixIdentityPublicKey,
-    pub secret_key: DummyMixIdentityPrivateKey,
+    pub public_key: x25519::PublicKey,
+    pub secret_key: x25519::PrivateKey,
+    pub storage_backend: StorageBackend,
     pub store_dir: PathBuf,
+    pub max_stored_messages: usize,
+    // after how many packets that could not be processed the connection to the mixnode is closed
+    pub max_bad_packets: usize,
 }
 
 #[derive(Debug)]
@@ -41,6 +72,10 @@ pub enum ProviderError {
     TcpListenerUnexpectedEof,
 
     TcpListenerUnknownError,
+
+    // the ledger of registered clients exists, but could not be read
+    ClientLedgerLoadingError(ClientLedgerError),
+    StorageOpeningError(StoreError),
 }
 
 impl From<io::Error> for ProviderError {
@@ -60,180 +95,253 @@ impl From<io::Error> for ProviderError {
     }
 }
 
-#[derive(Debug)]
-pub struct ClientLedger(HashMap<AuthToken, DestinationAddressBytes>);
-
-impl ClientLedger {
-    fn new() -> Self {
-        ClientLedger(HashMap::new())
-    }
-
-    fn add_arc_futures_mutex(self) -> Arc<FMutex<Self>> {
-        Arc::new(FMutex::new(self))
-    }
-
-    fn has_token(&self, auth_token: AuthToken) -> bool {
-        return self.0.contains_key(&auth_token);
-    }
-
-    fn insert_token(
-        &mut self,
-        auth_token: AuthToken,
-        client_address: DestinationAddressBytes,
-    ) -> Option<DestinationAddressBytes> {
-        self.0.insert(auth_token, client_address)
-    }
-
-    fn current_clients(&self) -> Vec<MixProviderClient> {
-        self.0
-            .iter()
-            .map(|(_, v)| base64::encode_config(v, base64::URL_SAFE))
-            .map(|pub_key| MixProviderClient { pub_key })
-            .collect()
-    }
-
-    #[allow(dead_code)]
-    fn load(_file: PathBuf) -> Self {
-        unimplemented!()
-    }
-}
-
 pub struct ServiceProvider {
     directory_server: String,
+    identity_keypair: ed25519::KeyPair,
     mix_network_address: SocketAddr,
     client_network_address: SocketAddr,
-    public_key: DummyMixIdentityPublicKey,
-    secret_key: DummyMixIdentityPrivateKey,
-    store_dir: PathBuf,
+    public_key: x25519::PublicKey,
+    secret_key: x25519::PrivateKey,
+    storage: Arc<dyn ClientStorage>,
     registered_clients_ledger: ClientLedger,
+    max_bad_packets: usize,
 }
 
 impl ServiceProvider {
-    pub fn new(config: Config) -> Self {
-        ServiceProvider {
+    pub fn new(config: Config) -> Result<Self, ProviderError> {
+        Ok(ServiceProvider {
+            identity_keypair: config.identity_keypair,
             mix_network_address: config.mix_socket_address,
             client_network_address: config.client_socket_address,
             secret_key: config.secret_key,
             public_key: config.public_key,
-            store_dir: PathBuf::from(config.store_dir.clone()),
-            // TODO: load initial ledger from file
-            registered_clients_ledger: ClientLedger::new(),
+            storage: ServiceProvider::open_storage(
+                config.storage_backend,
+                config.store_dir,
+                config.max_stored_messages,
+            )?,
+            registered_clients_ledger: ClientLedger::load(config.ledger_dir)
+                .map_err(ProviderError::ClientLedgerLoadingError)?,
             directory_server: config.directory_server.clone(),
-        }
+            max_bad_packets: config.max_bad_packets,
+        })
+    }
+
+    fn open_storage(
+        backend: StorageBackend,
+        store_dir: PathBuf,
+        max_stored_messages: usize,
+    ) -> Result<Arc<dyn ClientStorage>, ProviderError> {
+        Ok(match backend {
+            StorageBackend::Filesystem => {
+                Arc::new(FilesystemStorage::new(store_dir, max_stored_messages))
+            }
+            StorageBackend::Sled => Arc::new(
+                SledStorage::open(store_dir, max_stored_messages)
+                    .map_err(ProviderError::StorageOpeningError)?,
+            ),
+        })
     }
 
     async fn process_mixnet_socket_connection(
-        mut socket: tokio::net::TcpStream,
+        socket: tokio::net::TcpStream,
         processing_data: Arc<RwLock<MixProcessingData>>,
+        max_bad_packets: usize,
     ) {
-        let mut buf = [0u8; sphinx::PACKET_SIZE];
-
-        // In a loop, read data from the socket and write the data back.
-        loop {
-            match socket.read(&mut buf).await {
-                // socket closed
-                Ok(n) if n == 0 => {
-                    trace!("Remote connection closed.");
+        // every frame read from the socket contains exactly one sphinx packet, regardless of
+        // how the data got split or coalesced by the underlying tcp stream
+        let mut framed_socket = FramedRead::new(socket, SphinxCodec);
+        let mut bad_packets = 0;
+
+        while let Some(framed_packet) = framed_socket.next().await {
+            let framed_packet = match framed_packet {
+                Ok(framed_packet) => framed_packet,
+                Err(e) => {
+                    // we can't recover the frame boundaries anymore so just drop the connection
+                    warn!("failed to read packet from socket; err = {:?}", e);
                     return;
                 }
-                Ok(_) => {
-                    let store_data = match MixPacketProcessor::process_sphinx_data_packet(
-                        buf.as_ref(),
-                        processing_data.as_ref(),
-                    ) {
-                        Ok(sd) => sd,
-                        Err(e) => {
-                            warn!("failed to process sphinx packet; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    ClientStorage::store_processed_data(
-                        store_data,
-                        processing_data.read().unwrap().store_dir.as_path(),
-                    )
-                    .unwrap_or_else(|e| {
-                        error!("failed to store processed sphinx message; err = {:?}", e);
+            };
+
+            let store_data = match MixPacketProcessor::process_sphinx_data_packet(
+                framed_packet.packet_bytes(),
+                processing_data.as_ref(),
+            ) {
+                Ok(sd) => sd,
+                Err(e) => {
+                    // the connection is shared by all packets sent by the mixnode, so the packet
+                    // is just dropped, unless the mixnode keeps sending us garbage
+                    bad_packets += 1;
+                    debug!("failed to process sphinx packet; err = {:?}", e);
+                    if bad_packets >= max_bad_packets {
+                        warn!(
+                            "received {} bad packets on the connection - closing it",
+                            bad_packets
+                        );
                         return;
-                    });
+                    }
+                    continue;
                 }
+            };
+            let (storage, subscriptions) = {
+                let data = processing_data.read().unwrap();
+                (data.storage.clone(), data.subscriptions.clone())
+            };
+            let client_address = store_data.client_address();
+            match storage.store_message(store_data) {
+                Ok(_) => subscriptions.notify(&client_address),
+                Err(e) => error!("failed to store processed sphinx message; err = {:?}", e),
+            }
+        }
+
+        trace!("Remote connection closed.");
+    }
+
+    // TODO: FIGURE OUT HOW TO SET READ_DEADLINES IN TOKIO
+    async fn process_client_socket_connection(
+        socket: tokio::net::TcpStream,
+        processing_data: Arc<ClientProcessingData>,
+    ) {
+        // the client can send any number of requests over the same connection, each of them
+        // is answered before the next one is read
+        let mut framed_socket = Framed::new(socket, ProviderCodec);
+        let mut session = ClientSession::new();
+
+        while let Some(request) = framed_socket.next().await {
+            let request = match request {
+                Ok(request) => request,
                 Err(e) => {
-                    warn!("failed to read from socket; err = {:?}", e);
+                    // we can't recover the frame boundaries anymore so just drop the connection
+                    warn!("failed to read request from socket; err = {:?}", e);
                     return;
                 }
             };
 
-            // Write the some data back
-            if let Err(e) = socket.write_all(b"foomp").await {
+            let response = match ClientRequestProcessor::process_client_request(
+                &request,
+                &mut session,
+                processing_data.clone(),
+            )
+            .await
+            {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("failed to process client request; err = {:?}", e);
+                    ClientResponse::new(b"bad foomp".to_vec())
+                }
+            };
+
+            if let Err(e) = framed_socket.send(response.bytes).await {
                 warn!("failed to write reply to socket; err = {:?}", e);
+                if let Some(pending_delivery) = response.pending_delivery {
+                    ClientRequestProcessor::release_undelivered(pending_delivery, &processing_data);
+                }
+                return;
+            }
+
+            if let Some(client_address) = response.subscription {
+                ServiceProvider::push_client_messages(
+                    framed_socket,
+                    client_address,
+                    processing_data,
+                )
+                .await;
                 return;
             }
         }
+
+        trace!("Remote connection closed.");
     }
 
-    async fn send_response(mut socket: tokio::net::TcpStream, data: &[u8]) {
-        if let Err(e) = socket.write_all(data).await {
-            warn!("failed to write reply to socket; err = {:?}", e)
-        }
-        if let Err(e) = socket.shutdown(Shutdown::Write) {
-            warn!("failed to close write part of the socket; err = {:?}", e)
+    // returns whether any new messages were stored since the last check or None if the
+    // subscription got closed
+    fn drain_notifications(notifications: &mut mpsc::UnboundedReceiver<()>) -> Option<bool> {
+        let mut has_new = false;
+        loop {
+            match notifications.try_next() {
+                Ok(Some(_)) => has_new = true,
+                Ok(None) => return None,
+                // no more notifications for now
+                Err(_) => return Some(has_new),
+            }
         }
     }
 
-    // TODO: FIGURE OUT HOW TO SET READ_DEADLINES IN TOKIO
-    async fn process_client_socket_connection(
-        mut socket: tokio::net::TcpStream,
+    // Pushes a page of messages to the subscribed client every `PUSH_INTERVAL` until either side
+    // closes the connection. Pages are always padded to the same size and are sent even if there
+    // is nothing new, so that the traffic would not reveal when the client received anything.
+    async fn push_client_messages(
+        framed_socket: Framed<tokio::net::TcpStream, ProviderCodec>,
+        client_address: DestinationAddressBytes,
         processing_data: Arc<ClientProcessingData>,
     ) {
-        let mut buf = [0; 1024];
-
-        // TODO: restore the for loop once we go back to persistent tcp socket connection
-        let response = match socket.read(&mut buf).await {
-            // socket closed
-            Ok(n) if n == 0 => {
-                trace!("Remote connection closed.");
-                Err(())
-            }
-            Ok(n) => {
-                match ClientRequestProcessor::process_client_request(
-                    buf[..n].as_ref(),
-                    processing_data,
-                )
-                .await
-                {
-                    Err(e) => {
-                        warn!("failed to process client request; err = {:?}", e);
-                        Err(())
+        let subscriptions = processing_data.subscriptions.clone();
+        let (subscription_id, mut notifications) = subscriptions.subscribe(client_address);
+        let (mut sink, mut stream) = framed_socket.split();
+        let mut push_interval = tokio::time::interval(PUSH_INTERVAL);
+        // there might have been messages stored before the client subscribed
+        let mut has_messages = true;
+
+        loop {
+            let should_push = tokio::select! {
+                _ = push_interval.tick() => true,
+                received = stream.next() => {
+                    match received {
+                        None => trace!("Remote connection closed."),
+                        Some(_) => warn!("subscribed client sent unexpected data - closing the connection"),
                     }
-                    Ok(res) => Ok(res),
+                    false
                 }
+            };
+            if !should_push {
+                break;
             }
-            Err(e) => {
-                warn!("failed to read from socket; err = {:?}", e);
-                Err(())
+
+            match ServiceProvider::drain_notifications(&mut notifications) {
+                Some(has_new) => has_messages |= has_new,
+                None => {
+                    debug!("subscription was replaced by a newer one");
+                    break;
+                }
             }
-        };
 
-        if let Err(e) = socket.shutdown(Shutdown::Read) {
-            warn!("failed to close read part of the socket; err = {:?}", e)
-        }
+            let (page, pending_delivery) = if has_messages {
+                match ClientRequestProcessor::retrieve_page(client_address, 0, &processing_data) {
+                    Ok((page, pending_delivery)) => (page, Some(pending_delivery)),
+                    Err(e) => {
+                        error!("failed to retrieve messages to push; err = {:?}", e);
+                        break;
+                    }
+                }
+            } else {
+                (ClientRequestProcessor::empty_page(), None)
+            };
+            has_messages = page.has_more();
 
-        match response {
-            Ok(res) => {
-                ServiceProvider::send_response(socket, &res).await;
-            }
-            _ => {
-                ServiceProvider::send_response(socket, b"bad foomp").await;
+            if let Err(e) = sink.send(page.to_bytes()).await {
+                warn!("failed to push messages to the client; err = {:?}", e);
+                if let Some(pending_delivery) = pending_delivery {
+                    ClientRequestProcessor::release_undelivered(pending_delivery, &processing_data);
+                }
+                break;
             }
         }
+
+        subscriptions.unsubscribe(&client_address, subscription_id);
     }
 
     async fn start_mixnet_listening(
         address: SocketAddr,
-        secret_key: DummyMixIdentityPrivateKey,
-        store_dir: PathBuf,
+        secret_key: x25519::PrivateKey,
+        storage: Arc<dyn ClientStorage>,
+        subscriptions: SubscriptionRegistry,
+        reply_tokens: ReplyTokenRegistry,
+        max_bad_packets: usize,
     ) -> Result<(), ProviderError> {
         let mut listener = tokio::net::TcpListener::bind(address).await?;
-        let processing_data = MixProcessingData::new(secret_key, store_dir).add_arc_rwlock();
+        let processing_data =
+            MixProcessingData::new(secret_key, storage, subscriptions, reply_tokens)
+                .add_arc_rwlock();
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -241,21 +349,33 @@ impl ServiceProvider {
             // (if I understand it all correctly)
             let thread_processing_data = processing_data.clone();
             tokio::spawn(async move {
-                ServiceProvider::process_mixnet_socket_connection(socket, thread_processing_data)
-                    .await
+                ServiceProvider::process_mixnet_socket_connection(
+                    socket,
+                    thread_processing_data,
+                    max_bad_packets,
+                )
+                .await
             });
         }
     }
 
     async fn start_client_listening(
         address: SocketAddr,
-        store_dir: PathBuf,
+        storage: Arc<dyn ClientStorage>,
         client_ledger: Arc<FMutex<ClientLedger>>,
-        secret_key: DummyMixIdentityPrivateKey,
+        secret_key: x25519::PrivateKey,
+        subscriptions: SubscriptionRegistry,
+        reply_tokens: ReplyTokenRegistry,
     ) -> Result<(), ProviderError> {
         let mut listener = tokio::net::TcpListener::bind(address).await?;
-        let processing_data =
-            ClientProcessingData::new(store_dir, client_ledger, secret_key).add_arc();
+        let processing_data = ClientProcessingData::new(
+            storage,
+            client_ledger,
+            secret_key,
+            subscriptions,
+            reply_tokens,
+        )
+        .add_arc();
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -269,6 +389,31 @@ impl ServiceProvider {
         }
     }
 
+    // messages that were not acknowledged in time are put back into the inboxes so that
+    // they'd be included in the subsequent pulls or pushes
+    async fn release_expired_leases(
+        storage: Arc<dyn ClientStorage>,
+        subscriptions: SubscriptionRegistry,
+    ) {
+        loop {
+            tokio::time::delay_for(LEASE_EXPIRY_CHECK_INTERVAL).await;
+            match storage.release_expired_leases(MESSAGE_LEASE_DURATION) {
+                Ok(clients) => {
+                    if !clients.is_empty() {
+                        debug!(
+                            "unacknowledged messages of {} clients were released",
+                            clients.len()
+                        );
+                    }
+                    for client_address in clients.iter() {
+                        subscriptions.notify(client_address);
+                    }
+                }
+                Err(e) => error!("failed to release expired message leases; err = {:?}", e),
+            }
+        }
+    }
+
     // Note: this now consumes the provider
     pub fn start(self) -> Result<(), Box<dyn std::error::Error>> {
         // Create the runtime, probably later move it to Provider struct itself?
@@ -284,20 +429,36 @@ impl ServiceProvider {
             self.client_network_address.clone(),
             self.mix_network_address.clone(),
             self.public_key,
+            self.identity_keypair,
             thread_shareable_ledger.clone(),
         );
 
+        // shared by both listeners so that storing a message could wake up its subscribed client
+        let subscriptions = SubscriptionRegistry::new();
+        // reply tokens are registered by clients and used by replies arriving from the mixnet
+        let reply_tokens =
+            ReplyTokenRegistry::new(REPLY_TOKEN_VALIDITY, MAX_REPLY_TOKENS_PER_CLIENT);
+
         let presence_future = rt.spawn(presence_notifier.run());
+        rt.spawn(ServiceProvider::release_expired_leases(
+            self.storage.clone(),
+            subscriptions.clone(),
+        ));
         let mix_future = rt.spawn(ServiceProvider::start_mixnet_listening(
             self.mix_network_address,
             self.secret_key.clone(),
-            self.store_dir.clone(),
+            self.storage.clone(),
+            subscriptions.clone(),
+            reply_tokens.clone(),
+            self.max_bad_packets,
         ));
         let client_future = rt.spawn(ServiceProvider::start_client_listening(
             self.client_network_address,
-            self.store_dir.clone(),
+            self.storage,
             thread_shareable_ledger,
             self.secret_key,
+            subscriptions,
+            reply_tokens,
         ));
         // Spawn the root task
         rt.block_on(async {
@@ -311,3 +472,80 @@ impl ServiceProvider {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod mixnet_connection {
+    use super::*;
+    use crate::provider::storage::FilesystemStorage;
+    use crypto::encryption::MixnetEncryptionKeyPair;
+    use sfw_provider_requests::REPLY_DESTINATION_ADDRESS;
+    use sphinx::header::delays::Delay;
+    use sphinx::route::SURBIdentifier;
+    use sphinx::route::{Destination, Node as SphinxNode, NodeAddressBytes};
+    use sphinx::SphinxPacket;
+    use sphinx_framing::packet::FramedSphinxPacket;
+    use tempfile::TempDir;
+    use tokio_util::codec::FramedWrite;
+
+    fn packet_for(
+        provider_key: &x25519::PublicKey,
+        recipient: DestinationAddressBytes,
+        surb_id: SURBIdentifier,
+    ) -> SphinxPacket {
+        let provider = SphinxNode::new(NodeAddressBytes::from_bytes([0u8; 32]), provider_key.0);
+        SphinxPacket::new(
+            b"foomp".to_vec(),
+            &[provider],
+            &Destination::new(recipient, surb_id),
+            &[Delay::new(0)],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn bad_packet_does_not_close_the_connection() {
+        let provider_keys = x25519::KeyPair::new();
+        let recipient = [1u8; 32];
+
+        let dir = TempDir::new().unwrap();
+        let storage = Arc::new(FilesystemStorage::new(dir.path().to_path_buf(), 10));
+        storage.create_inbox(recipient).unwrap();
+        let processing_data = MixProcessingData::new(
+            *provider_keys.private_key(),
+            storage.clone(),
+            SubscriptionRegistry::new(),
+            ReplyTokenRegistry::new(Duration::from_secs(60), 10),
+        )
+        .add_arc_rwlock();
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut mixnode = FramedWrite::new(
+            tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap(),
+            SphinxCodec,
+        );
+        let (socket, _) = listener.accept().await.unwrap();
+
+        // the first packet is a reply sent with reply token nobody has registered
+        let bad_packet = packet_for(
+            provider_keys.public_key(),
+            REPLY_DESTINATION_ADDRESS,
+            [2u8; 16],
+        );
+        let valid_packet = packet_for(provider_keys.public_key(), recipient, [0u8; 16]);
+        mixnode
+            .send(FramedSphinxPacket::new(bad_packet))
+            .await
+            .unwrap();
+        mixnode
+            .send(FramedSphinxPacket::new(valid_packet))
+            .await
+            .unwrap();
+        drop(mixnode);
+
+        // returns once the mixnode closes the connection
+        ServiceProvider::process_mixnet_socket_connection(socket, processing_data, 10).await;
+        assert_eq!(1, storage.count_stored(recipient, 0).unwrap());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::provider::ClientLedger;
-use crypto::identity::DummyMixIdentityPublicKey;
-use directory_client::presence::MixProviderPresence;
+use crypto::encryption::{x25519, MixnetEncryptionPublicKey};
+use crypto::identity::{ed25519, MixnetIdentityKeyPair};
+use directory_client::presence::{MixProviderPresence, SignedPresence};
 use directory_client::requests::presence_providers_post::PresenceMixProviderPoster;
 use directory_client::DirectoryClient;
 use futures::lock::Mutex as FMutex;
@@ -15,6 +16,7 @@ pub struct Notifier {
     client_listener: String,
     mixnet_listener: String,
     pub_key: String,
+    identity_keypair: ed25519::KeyPair,
 }
 
 impl Notifier {
@@ -22,19 +24,19 @@ impl Notifier {
         directory_server_address: String,
         client_listener: SocketAddr,
         mixnet_listener: SocketAddr,
-        pub_key: DummyMixIdentityPublicKey,
+        pub_key: x25519::PublicKey,
+        identity_keypair: ed25519::KeyPair,
         client_ledger: Arc<FMutex<ClientLedger>>,
     ) -> Notifier {
-        let directory_config = directory_client::Config {
-            base_url: directory_server_address,
-        };
+        let directory_config = directory_client::Config::new(directory_server_address);
         let net_client = directory_client::Client::new(directory_config);
 
         Notifier {
             net_client,
             client_listener: client_listener.to_string(),
             mixnet_listener: mixnet_listener.to_string(),
-            pub_key: pub_key.to_b64_string(),
+            pub_key: base64::encode_config(&pub_key.to_bytes(), base64::URL_SAFE),
+            identity_keypair,
             client_ledger,
         }
     }
@@ -42,14 +44,19 @@ impl Notifier {
     async fn make_presence(&self) -> MixProviderPresence {
         let unlocked_ledger = self.client_ledger.lock().await;
 
-        MixProviderPresence {
+        let mut presence = MixProviderPresence {
             client_listener: self.client_listener.clone(),
             mixnet_listener: self.mixnet_listener.clone(),
             pub_key: self.pub_key.clone(),
+            identity_key: self.identity_keypair.public_key().to_b64_string(),
             registered_clients: unlocked_ledger.current_clients(),
             last_seen: 0,
             version: env!("CARGO_PKG_VERSION").to_string(),
-        }
+            signature: String::new(),
+        };
+        // registered clients keep changing so the presence has to be signed every time
+        presence.sign(self.identity_keypair.private_key());
+        presence
     }
 
     pub fn notify(&self, presence: MixProviderPresence) {
@@ -0,0 +1,133 @@
+use sphinx::route::{DestinationAddressBytes, SURBIdentifier};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ReplyTokenError {
+    // the identifier is already used by a reply token of another client
+    DuplicateIdentifierError,
+    TooManyReplyTokensError,
+}
+
+struct RegisteredToken {
+    owner: DestinationAddressBytes,
+    registered_at: Instant,
+}
+
+// Single-use reply tokens registered by our clients. A reply addressed to one of them gets
+// delivered to its owner, which allows the replying party to reach the client without knowing
+// its address. The tokens are only kept in memory, so they do not survive provider restarts.
+#[derive(Clone)]
+pub(crate) struct ReplyTokenRegistry {
+    inner: Arc<Mutex<HashMap<SURBIdentifier, RegisteredToken>>>,
+    validity: Duration,
+    max_per_client: usize,
+}
+
+impl ReplyTokenRegistry {
+    pub(crate) fn new(validity: Duration, max_per_client: usize) -> Self {
+        ReplyTokenRegistry {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            validity,
+            max_per_client,
+        }
+    }
+
+    pub(crate) fn validity(&self) -> Duration {
+        self.validity
+    }
+
+    // registering the same identifier again by its owner just refreshes it
+    pub(crate) fn register(
+        &self,
+        owner: DestinationAddressBytes,
+        id: SURBIdentifier,
+    ) -> Result<(), ReplyTokenError> {
+        let mut blocks = self.inner.lock().unwrap();
+        // expired blocks are only cleaned up here as that's the only place where they could
+        // start piling up
+        let validity = self.validity;
+        blocks.retain(|_, block| block.registered_at.elapsed() < validity);
+
+        match blocks.get(&id) {
+            Some(block) if block.owner != owner => {
+                return Err(ReplyTokenError::DuplicateIdentifierError)
+            }
+            Some(_) => (),
+            None => {
+                let owned = blocks.values().filter(|block| block.owner == owner).count();
+                if owned >= self.max_per_client {
+                    return Err(ReplyTokenError::TooManyReplyTokensError);
+                }
+            }
+        }
+
+        blocks.insert(
+            id,
+            RegisteredToken {
+                owner,
+                registered_at: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    // removes the reply token and returns its owner unless it has already expired
+    pub(crate) fn take(&self, id: &SURBIdentifier) -> Option<DestinationAddressBytes> {
+        let block = self.inner.lock().unwrap().remove(id)?;
+        if block.registered_at.elapsed() < self.validity {
+            Some(block.owner)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod reply_token_registry {
+    use super::*;
+
+    #[test]
+    fn reply_token_can_only_be_used_once() {
+        let registry = ReplyTokenRegistry::new(Duration::from_secs(60), 10);
+        registry.register([1u8; 32], [2u8; 16]).unwrap();
+
+        assert_eq!(Some([1u8; 32]), registry.take(&[2u8; 16]));
+        assert_eq!(None, registry.take(&[2u8; 16]));
+    }
+
+    #[test]
+    fn identifier_cannot_be_taken_over_by_another_client() {
+        let registry = ReplyTokenRegistry::new(Duration::from_secs(60), 10);
+        registry.register([1u8; 32], [2u8; 16]).unwrap();
+
+        assert_eq!(
+            Err(ReplyTokenError::DuplicateIdentifierError),
+            registry.register([3u8; 32], [2u8; 16])
+        );
+        assert!(registry.register([1u8; 32], [2u8; 16]).is_ok());
+        assert_eq!(Some([1u8; 32]), registry.take(&[2u8; 16]));
+    }
+
+    #[test]
+    fn number_of_reply_tokens_per_client_is_limited() {
+        let registry = ReplyTokenRegistry::new(Duration::from_secs(60), 2);
+        registry.register([1u8; 32], [1u8; 16]).unwrap();
+        registry.register([1u8; 32], [2u8; 16]).unwrap();
+
+        assert_eq!(
+            Err(ReplyTokenError::TooManyReplyTokensError),
+            registry.register([1u8; 32], [3u8; 16])
+        );
+        assert!(registry.register([2u8; 32], [3u8; 16]).is_ok());
+    }
+
+    #[test]
+    fn expired_reply_tokens_are_not_used() {
+        let registry = ReplyTokenRegistry::new(Duration::from_secs(0), 10);
+        registry.register([1u8; 32], [2u8; 16]).unwrap();
+
+        assert_eq!(None, registry.take(&[2u8; 16]));
+    }
+}
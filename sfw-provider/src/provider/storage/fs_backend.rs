@@ -0,0 +1,517 @@
+use crate::provider::storage::{
+    current_timestamp, is_lease_expired, ClientStorage, StoreData, StoreError, StoredMessage,
+};
+use log::*;
+use sfw_provider_requests::MessageId;
+use sphinx::route::DestinationAddressBytes;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PENDING_DIR_NAME: &str = "pending";
+// files that are still being written to are prefixed with it so they're never handed out
+const PARTIAL_FILE_PREFIX: &str = ".partial-";
+
+// FilesystemStorage keeps every message in a separate file inside client's inbox directory:
+// <store_dir>/<hex client address>/<hex message id>
+// Messages handed out to the client are moved (renamed) into the `pending` subdirectory
+// as <hex message id>.<lease timestamp>.
+// Since rename is atomic, a message can only ever be moved by a single concurrent fetch.
+// Note that unlike the database backend, the quota can be slightly exceeded if multiple
+// messages for the same client are being stored at the same time.
+pub struct FilesystemStorage {
+    store_dir: PathBuf,
+    max_messages_per_client: usize,
+}
+
+impl FilesystemStorage {
+    pub fn new(store_dir: PathBuf, max_messages_per_client: usize) -> Self {
+        FilesystemStorage {
+            store_dir,
+            max_messages_per_client,
+        }
+    }
+
+    fn inbox_dir(&self, client_address: &DestinationAddressBytes) -> PathBuf {
+        self.store_dir.join(hex::encode(client_address))
+    }
+
+    // the inverse of `inbox_dir`
+    fn inbox_owner(inbox_dir: &Path) -> Result<DestinationAddressBytes, StoreError> {
+        let owner = inbox_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| hex::decode(name).ok())
+            .ok_or(StoreError::MalformedStoreDataError)?;
+        if owner.len() != 32 {
+            return Err(StoreError::MalformedStoreDataError);
+        }
+        let mut client_address = [0u8; 32];
+        client_address.copy_from_slice(&owner);
+        Ok(client_address)
+    }
+
+    fn pending_dir(&self, client_address: &DestinationAddressBytes) -> PathBuf {
+        self.inbox_dir(client_address).join(PENDING_DIR_NAME)
+    }
+
+    fn message_file_name(id: MessageId) -> String {
+        format!("{:016x}", id)
+    }
+
+    fn pending_file_name(id: MessageId, leased_at: u64) -> String {
+        format!("{:016x}.{}", id, leased_at)
+    }
+
+    fn parse_pending_file_name(file_name: &str) -> Option<(MessageId, u64)> {
+        let mut split_name = file_name.splitn(2, '.');
+        let id = MessageId::from_str_radix(split_name.next()?, 16).ok()?;
+        let leased_at = split_name.next()?.parse().ok()?;
+        Some((id, leased_at))
+    }
+
+    // ids are based on the current time so that messages could be retrieved in (roughly)
+    // the same order they were received in
+    fn new_message_id() -> MessageId {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_nanos() as MessageId
+    }
+
+    fn message_ids_in_dir(dir: &Path) -> Result<Vec<MessageId>, StoreError> {
+        let mut ids = Vec::new();
+        let dir_entries = match fs::read_dir(dir) {
+            Ok(dir_entries) => dir_entries,
+            // inboxes created by older versions do not have the pending directory
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in dir_entries {
+            let entry = entry?;
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.starts_with(PARTIAL_FILE_PREFIX) {
+                continue;
+            }
+            match MessageId::from_str_radix(&file_name, 16) {
+                Ok(id) => ids.push(id),
+                Err(_) => error!(
+                    "potentially corrupted client inbox! - found unexpected file - {:?}",
+                    entry.path()
+                ),
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    // returns id, lease timestamp and path of every pending message in the directory
+    fn pending_messages_in_dir(
+        pending_dir: &Path,
+    ) -> Result<Vec<(MessageId, u64, PathBuf)>, StoreError> {
+        let mut pending = Vec::new();
+        let dir_entries = match fs::read_dir(pending_dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(pending),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in dir_entries {
+            let entry = entry?;
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+            match FilesystemStorage::parse_pending_file_name(&entry.file_name().to_string_lossy()) {
+                Some((id, leased_at)) => pending.push((id, leased_at, entry.path())),
+                None => error!(
+                    "potentially corrupted client inbox! - found unexpected pending file - {:?}",
+                    entry.path()
+                ),
+            }
+        }
+        Ok(pending)
+    }
+
+    fn count_messages(
+        &self,
+        client_address: &DestinationAddressBytes,
+    ) -> Result<usize, StoreError> {
+        let stored = FilesystemStorage::message_ids_in_dir(&self.inbox_dir(client_address))?;
+        let pending =
+            FilesystemStorage::pending_messages_in_dir(&self.pending_dir(client_address))?;
+        Ok(stored.len() + pending.len())
+    }
+
+    // moves file between directories, returns false if the source file no longer exists
+    // (for example because it was concurrently moved by somebody else)
+    fn try_move(from: PathBuf, to: PathBuf) -> Result<bool, StoreError> {
+        match fs::rename(from, to) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl ClientStorage for FilesystemStorage {
+    fn create_inbox(&self, client_address: DestinationAddressBytes) -> Result<(), StoreError> {
+        fs::create_dir_all(self.pending_dir(&client_address))?;
+        Ok(())
+    }
+
+    fn store_message(&self, store_data: StoreData) -> Result<(), StoreError> {
+        let inbox_dir = self.inbox_dir(&store_data.client_address);
+        if !inbox_dir.exists() {
+            return Err(StoreError::ClientDoesntExistError);
+        }
+        if self.count_messages(&store_data.client_address)? >= self.max_messages_per_client {
+            return Err(StoreError::QuotaExceededError);
+        }
+
+        let pending_ids: HashSet<_> = FilesystemStorage::pending_messages_in_dir(
+            &self.pending_dir(&store_data.client_address),
+        )?
+        .into_iter()
+        .map(|(id, _, _)| id)
+        .collect();
+
+        let mut id = FilesystemStorage::new_message_id();
+        // the message is fully written under temporary name so that it wouldn't be fetched
+        // before it's complete
+        let (mut file, partial_path) = loop {
+            let file_name = FilesystemStorage::message_file_name(id);
+            if inbox_dir.join(&file_name).exists() || pending_ids.contains(&id) {
+                id += 1;
+                continue;
+            }
+            let partial_path = inbox_dir.join(format!("{}{}", PARTIAL_FILE_PREFIX, file_name));
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&partial_path)
+            {
+                Ok(file) => break (file, partial_path),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => id += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        debug!(
+            "going to store: {:?} in file: {:?}",
+            store_data.message, partial_path
+        );
+        file.write_all(store_data.message.as_ref())?;
+        file.sync_all()?;
+        fs::rename(
+            partial_path,
+            inbox_dir.join(FilesystemStorage::message_file_name(id)),
+        )?;
+
+        Ok(())
+    }
+
+    fn fetch_and_mark_pending(
+        &self,
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let inbox_dir = self.inbox_dir(&client_address);
+        let pending_dir = self.pending_dir(&client_address);
+
+        trace!("going to lookup: {:?}!", inbox_dir);
+        if !inbox_dir.exists() {
+            return Err(StoreError::ClientDoesntExistError);
+        }
+
+        // otherwise failed renames would look as if messages were fetched concurrently
+        fs::create_dir_all(&pending_dir)?;
+
+        let mut messages = Vec::new();
+        for id in FilesystemStorage::message_ids_in_dir(&inbox_dir)?
+            .into_iter()
+            .filter(|id| *id >= cursor)
+        {
+            if messages.len() == limit {
+                break;
+            }
+            let file_name = FilesystemStorage::message_file_name(id);
+            let pending_path = pending_dir.join(FilesystemStorage::pending_file_name(
+                id,
+                current_timestamp(),
+            ));
+            if FilesystemStorage::try_move(inbox_dir.join(&file_name), pending_path.clone())? {
+                let content = fs::read(pending_path)?;
+                messages.push(StoredMessage { id, content });
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn count_stored(
+        &self,
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+    ) -> Result<usize, StoreError> {
+        let inbox_dir = self.inbox_dir(&client_address);
+        if !inbox_dir.exists() {
+            return Err(StoreError::ClientDoesntExistError);
+        }
+
+        Ok(FilesystemStorage::message_ids_in_dir(&inbox_dir)?
+            .into_iter()
+            .filter(|id| *id >= cursor)
+            .count())
+    }
+
+    fn acknowledge(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<usize, StoreError> {
+        let mut removed = 0;
+        for (id, _, pending_path) in
+            FilesystemStorage::pending_messages_in_dir(&self.pending_dir(&client_address))?
+        {
+            if !message_ids.contains(&id) {
+                continue;
+            }
+            trace!("Here {:?} will be deleted!", pending_path);
+            match fs::remove_file(pending_path) {
+                Ok(_) => removed += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn release_pending(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<(), StoreError> {
+        let inbox_dir = self.inbox_dir(&client_address);
+        for (id, _, pending_path) in
+            FilesystemStorage::pending_messages_in_dir(&self.pending_dir(&client_address))?
+        {
+            if message_ids.contains(&id) {
+                let file_name = FilesystemStorage::message_file_name(id);
+                FilesystemStorage::try_move(pending_path, inbox_dir.join(&file_name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn release_expired_leases(
+        &self,
+        lease_duration: Duration,
+    ) -> Result<Vec<DestinationAddressBytes>, StoreError> {
+        let inbox_dirs = match fs::read_dir(&self.store_dir) {
+            Ok(inbox_dirs) => inbox_dirs,
+            // nobody has registered yet
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut clients = Vec::new();
+        for inbox_dir in inbox_dirs {
+            let inbox_dir = inbox_dir?;
+            if !inbox_dir.metadata()?.is_dir() {
+                continue;
+            }
+            let inbox_dir = inbox_dir.path();
+            let mut released_any = false;
+            for (id, leased_at, pending_path) in
+                FilesystemStorage::pending_messages_in_dir(&inbox_dir.join(PENDING_DIR_NAME))?
+            {
+                if !is_lease_expired(leased_at, lease_duration) {
+                    continue;
+                }
+                let file_name = FilesystemStorage::message_file_name(id);
+                released_any |=
+                    FilesystemStorage::try_move(pending_path, inbox_dir.join(&file_name))?;
+            }
+            if released_any {
+                clients.push(FilesystemStorage::inbox_owner(&inbox_dir)?);
+            }
+        }
+        Ok(clients)
+    }
+}
+
+#[cfg(test)]
+mod filesystem_storage {
+    use super::*;
+    use tempfile::TempDir;
+
+    // the directory is removed once the returned TempDir is dropped
+    fn temporary_storage(max_messages_per_client: usize) -> (TempDir, FilesystemStorage) {
+        let dir = TempDir::new().unwrap();
+        let storage = FilesystemStorage::new(dir.path().to_path_buf(), max_messages_per_client);
+        (dir, storage)
+    }
+
+    fn store(storage: &FilesystemStorage, client_address: DestinationAddressBytes, content: u8) {
+        storage
+            .store_message(StoreData::new(client_address, [0u8; 16], vec![content]))
+            .unwrap();
+    }
+
+    #[test]
+    fn storing_for_unknown_client_fails() {
+        let (_dir, storage) = temporary_storage(10);
+        match storage.store_message(StoreData::new([1u8; 32], [0u8; 16], vec![1])) {
+            Err(StoreError::ClientDoesntExistError) => (),
+            _ => panic!("expected client doesn't exist error"),
+        }
+    }
+
+    #[test]
+    fn fetched_messages_are_not_handed_out_again() {
+        let (_dir, storage) = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        for i in 0..3 {
+            store(&storage, client_address, i);
+        }
+
+        let first_batch = storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap();
+        let second_batch = storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap();
+        assert_eq!(2, first_batch.len());
+        assert_eq!(1, second_batch.len());
+        assert!(storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap()
+            .is_empty());
+
+        // messages are retrieved in the order they were stored
+        assert_eq!(vec![0], first_batch[0].content);
+        assert_eq!(vec![1], first_batch[1].content);
+        assert_eq!(vec![2], second_batch[0].content);
+    }
+
+    #[test]
+    fn only_messages_from_the_cursor_onwards_are_fetched_and_counted() {
+        let (_dir, storage) = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        for i in 0..4 {
+            store(&storage, client_address, i);
+        }
+        assert_eq!(4, storage.count_stored(client_address, 0).unwrap());
+
+        let first_page = storage
+            .fetch_and_mark_pending(client_address, 0, 1)
+            .unwrap();
+        let cursor = first_page[0].id + 1;
+        assert_eq!(3, storage.count_stored(client_address, cursor).unwrap());
+
+        let second_page = storage
+            .fetch_and_mark_pending(client_address, cursor, 2)
+            .unwrap();
+        assert_eq!(vec![1], second_page[0].content);
+        assert_eq!(vec![2], second_page[1].content);
+
+        let cursor = second_page[1].id + 1;
+        assert_eq!(1, storage.count_stored(client_address, cursor).unwrap());
+        // released messages are behind the cursor
+        storage
+            .release_pending(client_address, &[first_page[0].id])
+            .unwrap();
+        assert_eq!(1, storage.count_stored(client_address, cursor).unwrap());
+        assert_eq!(2, storage.count_stored(client_address, 0).unwrap());
+    }
+
+    #[test]
+    fn released_messages_can_be_fetched_again() {
+        let (_dir, storage) = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 42);
+
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        storage
+            .release_pending(client_address, &[fetched[0].id])
+            .unwrap();
+
+        let refetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert_eq!(fetched, refetched);
+    }
+
+    #[test]
+    fn acknowledged_messages_free_up_the_quota() {
+        let (_dir, storage) = temporary_storage(2);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 1);
+        store(&storage, client_address, 2);
+
+        match storage.store_message(StoreData::new(client_address, [0u8; 16], vec![3])) {
+            Err(StoreError::QuotaExceededError) => (),
+            _ => panic!("expected quota exceeded error"),
+        }
+
+        // pending messages still count towards the quota
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 1)
+            .unwrap();
+        assert!(storage
+            .store_message(StoreData::new(client_address, [0u8; 16], vec![3]))
+            .is_err());
+
+        assert_eq!(
+            1,
+            storage
+                .acknowledge(client_address, &[fetched[0].id])
+                .unwrap()
+        );
+        store(&storage, client_address, 3);
+    }
+
+    #[test]
+    fn messages_with_expired_leases_can_be_fetched_again() {
+        let (_dir, storage) = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 42);
+
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert!(storage
+            .release_expired_leases(Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            vec![client_address],
+            storage
+                .release_expired_leases(Duration::from_secs(0))
+                .unwrap()
+        );
+        let refetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert_eq!(fetched, refetched);
+    }
+}
@@ -1,29 +1,41 @@
-use crate::provider::{MESSAGE_RETRIEVAL_LIMIT, STORED_MESSAGE_FILENAME_LENGTH};
-use log::*;
-use rand::Rng;
-use sfw_provider_requests::DUMMY_MESSAGE_CONTENT;
+use sfw_provider_requests::{MessageId, REPLY_DESTINATION_ADDRESS};
 use sphinx::route::{DestinationAddressBytes, SURBIdentifier};
-use std::fs::File;
 use std::io;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub mod fs_backend;
+pub mod sled_backend;
+
+pub use fs_backend::FilesystemStorage;
+pub use sled_backend::SledStorage;
+
+#[derive(Debug)]
 pub enum StoreError {
     ClientDoesntExistError,
+    QuotaExceededError,
+    MalformedStoreDataError,
+    DatabaseError,
     FileIOFailure,
 }
 
-impl From<std::io::Error> for StoreError {
-    fn from(_: std::io::Error) -> Self {
+impl From<io::Error> for StoreError {
+    fn from(_: io::Error) -> Self {
         use StoreError::*;
 
         FileIOFailure
     }
 }
 
+impl From<sled::Error> for StoreError {
+    fn from(_: sled::Error) -> Self {
+        use StoreError::*;
+
+        DatabaseError
+    }
+}
+
 pub struct StoreData {
     client_address: DestinationAddressBytes,
-    #[allow(dead_code)]
     client_surb_id: SURBIdentifier,
     message: Vec<u8>,
 }
@@ -40,84 +52,95 @@ impl StoreData {
             message,
         }
     }
-}
 
-// TODO: replace with database
-pub struct ClientStorage(());
+    pub(crate) fn client_address(&self) -> DestinationAddressBytes {
+        self.client_address
+    }
 
-// TODO: change it to some generic implementation to inject fs
-impl ClientStorage {
-    pub(crate) fn generate_random_file_name() -> String {
-        rand::thread_rng()
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .take(STORED_MESSAGE_FILENAME_LENGTH)
-            .collect::<String>()
+    pub(crate) fn client_surb_id(&self) -> SURBIdentifier {
+        self.client_surb_id
     }
 
-    fn dummy_message() -> Vec<u8> {
-        // TODO: should it be padded to constant length?
-        DUMMY_MESSAGE_CONTENT.to_vec()
+    // whether the message was sent using a reply token rather than addressed to a client
+    pub(crate) fn is_reply(&self) -> bool {
+        self.client_address == REPLY_DESTINATION_ADDRESS
     }
 
-    pub fn store_processed_data(store_data: StoreData, store_dir: &Path) -> io::Result<()> {
-        let client_dir_name = hex::encode(store_data.client_address);
-        let full_store_dir = store_dir.join(client_dir_name);
-        let full_store_path = full_store_dir.join(ClientStorage::generate_random_file_name());
-        debug!(
-            "going to store: {:?} in file: {:?}",
-            store_data.message, full_store_path
-        );
+    // the message is going to be stored in the inbox of the new recipient instead
+    pub(crate) fn redirect(self, client_address: DestinationAddressBytes) -> Self {
+        StoreData {
+            client_address,
+            ..self
+        }
+    }
+}
 
-        // TODO: what to do with surbIDs??
+// lease timestamps are stored with a second precision which is more than enough for our needs
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the unix epoch")
+        .as_secs()
+}
 
-        // we can use normal io here, no need for tokio as it's all happening in one thread per connection
-        let mut file = File::create(full_store_path)?;
-        file.write_all(store_data.message.as_ref())?;
+fn is_lease_expired(leased_at: u64, lease_duration: Duration) -> bool {
+    leased_at.saturating_add(lease_duration.as_secs()) <= current_timestamp()
+}
 
-        Ok(())
-    }
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: MessageId,
+    pub content: Vec<u8>,
+}
 
-    pub fn retrieve_client_files(
+// Every message goes through the following states:
+// stored -> pending (handed out to the client) -> acknowledged (removed)
+// If the client never confirms it received the message, it is released back into the inbox,
+// either explicitly or once its lease expires.
+pub trait ClientStorage: Send + Sync {
+    // creates an (empty) inbox for the client; it is not an error if one already exists
+    fn create_inbox(&self, client_address: DestinationAddressBytes) -> Result<(), StoreError>;
+
+    // stores the message unless the client has already reached its quota
+    fn store_message(&self, store_data: StoreData) -> Result<(), StoreError>;
+
+    // atomically moves up to `limit` stored messages with ids not lower than `cursor` into the
+    // pending state and returns them ordered by their ids. the same message is never handed out
+    // to two concurrent callers. the time of the move is recorded as the start of the message lease.
+    fn fetch_and_mark_pending(
+        &self,
         client_address: DestinationAddressBytes,
-        store_dir: &Path,
-    ) -> Result<Vec<Vec<u8>>, StoreError> {
-        let client_dir_name = hex::encode(client_address);
-        let full_store_dir = store_dir.join(client_dir_name);
-
-        trace!("going to lookup: {:?}!", full_store_dir);
-        if !full_store_dir.exists() {
-            return Err(StoreError::ClientDoesntExistError);
-        }
+        cursor: MessageId,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError>;
 
-        let msgs: Vec<_> = std::fs::read_dir(full_store_dir)?
-            .map(|entry| entry.unwrap())
-            .filter(|entry| {
-                let is_file = entry.metadata().unwrap().is_file();
-                if !is_file {
-                    error!(
-                        "potentially corrupted client inbox! - found a non-file - {:?}",
-                        entry.path()
-                    );
-                }
-                is_file
-            })
-            .map(|entry| {
-                let content = std::fs::read(entry.path()).unwrap();
-                ClientStorage::delete_file(entry.path()).unwrap();
-                content
-            }) // TODO: THIS MAP IS UNSAFE (BOTH FOR READING AND DELETING)!! - in the case where there are multiple requests from the same client being processed in parallel
-            .chain(std::iter::repeat(ClientStorage::dummy_message()))
-            .take(MESSAGE_RETRIEVAL_LIMIT)
-            .collect();
-
-        Ok(msgs)
-    }
+    // returns number of stored (i.e. not pending) messages with ids not lower than `cursor`
+    fn count_stored(
+        &self,
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+    ) -> Result<usize, StoreError>;
 
-    // TODO: THIS NEEDS A LOCKING MECHANISM!!! (or a db layer on top - basically 'ClientStorage' on steroids)
-    // TODO 2: This should only be called AFTER we sent the reply. Because if client's connection failed after sending request
-    // the messages would be deleted but he wouldn't have received them
-    fn delete_file(path: PathBuf) -> io::Result<()> {
-        trace!("Here {:?} will be deleted!", path);
-        std::fs::remove_file(path) // another argument for db layer -> remove_file is NOT guaranteed to immediately get rid of the file
-    }
+    // permanently removes pending messages and returns how many of them were removed.
+    // ids that are not pending are ignored.
+    fn acknowledge(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<usize, StoreError>;
+
+    // moves pending messages back to the inbox so they could be fetched again.
+    // ids that are not pending are ignored.
+    fn release_pending(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<(), StoreError>;
+
+    // moves back to the inbox messages of all clients that have been pending for longer than
+    // `lease_duration` and returns (once) every client that got any of its messages released.
+    fn release_expired_leases(
+        &self,
+        lease_duration: Duration,
+    ) -> Result<Vec<DestinationAddressBytes>, StoreError>;
 }
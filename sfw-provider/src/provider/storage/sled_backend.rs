@@ -0,0 +1,520 @@
+use crate::provider::storage::{
+    current_timestamp, is_lease_expired, ClientStorage, StoreData, StoreError, StoredMessage,
+};
+use log::*;
+use sfw_provider_requests::MessageId;
+use sled::{ConflictableTransactionResult, TransactionError, Transactional};
+use sphinx::route::DestinationAddressBytes;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const STORED_MESSAGES_TREE: &str = "stored_messages";
+// values in this tree are big endian lease timestamp || message content
+const PENDING_MESSAGES_TREE: &str = "pending_messages";
+// number of stored AND pending messages of each client; existence of an entry means
+// the client has an inbox
+const INBOX_SIZES_TREE: &str = "inbox_sizes";
+
+// message keys are client address || big endian message id so that all messages of given
+// client are next to each other and ordered by the time they were received
+type MessageKey = [u8; 40];
+
+// note: sled only supports custom abort errors for single tree transactions, so in multi-tree
+// ones, all validation happens before any writes and the error is returned as the result instead
+type TransactionOutcome<T> = ConflictableTransactionResult<Result<T, StoreError>>;
+
+impl From<TransactionError> for StoreError {
+    fn from(_: TransactionError) -> Self {
+        use StoreError::*;
+
+        DatabaseError
+    }
+}
+
+pub struct SledStorage {
+    db: sled::Db,
+    stored_messages: sled::Tree,
+    pending_messages: sled::Tree,
+    inbox_sizes: sled::Tree,
+    max_messages_per_client: u64,
+}
+
+impl SledStorage {
+    pub fn open(db_path: PathBuf, max_messages_per_client: usize) -> Result<Self, StoreError> {
+        SledStorage::new(
+            sled::Config::new().path(db_path).open()?,
+            max_messages_per_client,
+        )
+    }
+
+    fn new(db: sled::Db, max_messages_per_client: usize) -> Result<Self, StoreError> {
+        Ok(SledStorage {
+            stored_messages: db.open_tree(STORED_MESSAGES_TREE)?,
+            pending_messages: db.open_tree(PENDING_MESSAGES_TREE)?,
+            inbox_sizes: db.open_tree(INBOX_SIZES_TREE)?,
+            db,
+            max_messages_per_client: max_messages_per_client as u64,
+        })
+    }
+
+    fn message_key(client_address: &DestinationAddressBytes, id: MessageId) -> MessageKey {
+        let mut key = [0u8; 40];
+        key[..32].copy_from_slice(client_address);
+        key[32..].copy_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn message_owner(key: &[u8]) -> Result<DestinationAddressBytes, StoreError> {
+        if key.len() != 40 {
+            return Err(StoreError::MalformedStoreDataError);
+        }
+        let mut client_address = [0u8; 32];
+        client_address.copy_from_slice(&key[..32]);
+        Ok(client_address)
+    }
+
+    fn message_id(key: &[u8]) -> Result<MessageId, StoreError> {
+        if key.len() != 40 {
+            return Err(StoreError::MalformedStoreDataError);
+        }
+        // this can't fail as we've just checked the length
+        Ok(MessageId::from_be_bytes(key[32..].try_into().unwrap()))
+    }
+
+    fn pending_value(leased_at: u64, content: &[u8]) -> Vec<u8> {
+        leased_at
+            .to_be_bytes()
+            .iter()
+            .chain(content.iter())
+            .cloned()
+            .collect()
+    }
+
+    // splits pending value into the lease timestamp and the message content
+    fn split_pending_value(pending_value: &[u8]) -> Result<(u64, &[u8]), StoreError> {
+        if pending_value.len() < 8 {
+            return Err(StoreError::MalformedStoreDataError);
+        }
+        let (raw_timestamp, content) = pending_value.split_at(8);
+        // this can't fail as we've just checked the length
+        Ok((
+            u64::from_be_bytes(raw_timestamp.try_into().unwrap()),
+            content,
+        ))
+    }
+
+    fn decode_size(raw_size: &[u8]) -> Result<u64, StoreError> {
+        raw_size
+            .try_into()
+            .map(u64::from_be_bytes)
+            .map_err(|_| StoreError::MalformedStoreDataError)
+    }
+
+    fn has_inbox(&self, client_address: &DestinationAddressBytes) -> Result<bool, StoreError> {
+        Ok(self.inbox_sizes.get(&client_address[..])?.is_some())
+    }
+
+    // iterates over stored messages of the client starting with the one with the cursor id
+    fn stored_messages_from(
+        &self,
+        client_address: &DestinationAddressBytes,
+        cursor: MessageId,
+    ) -> sled::Iter {
+        let first_key = SledStorage::message_key(client_address, cursor);
+        let last_key = SledStorage::message_key(client_address, MessageId::max_value());
+        self.stored_messages.range(first_key..=last_key)
+    }
+}
+
+impl ClientStorage for SledStorage {
+    fn create_inbox(&self, client_address: DestinationAddressBytes) -> Result<(), StoreError> {
+        self.inbox_sizes
+            .transaction(|inbox_sizes| -> ConflictableTransactionResult<()> {
+                if inbox_sizes.get(&client_address[..])?.is_none() {
+                    inbox_sizes.insert(&client_address[..], &0u64.to_be_bytes()[..])?;
+                }
+                Ok(())
+            })?;
+        Ok(())
+    }
+
+    fn store_message(&self, store_data: StoreData) -> Result<(), StoreError> {
+        let client_address = store_data.client_address;
+        let key = SledStorage::message_key(&client_address, self.db.generate_id()?);
+        debug!(
+            "going to store: {:?} for {:?}",
+            store_data.message, client_address
+        );
+
+        (&self.stored_messages, &self.inbox_sizes).transaction(
+            |(stored_messages, inbox_sizes)| -> TransactionOutcome<()> {
+                let inbox_size = match inbox_sizes.get(&client_address[..])? {
+                    None => return Ok(Err(StoreError::ClientDoesntExistError)),
+                    Some(raw_size) => match SledStorage::decode_size(&raw_size) {
+                        Ok(size) => size,
+                        Err(err) => return Ok(Err(err)),
+                    },
+                };
+                if inbox_size >= self.max_messages_per_client {
+                    return Ok(Err(StoreError::QuotaExceededError));
+                }
+
+                stored_messages.insert(&key[..], store_data.message.as_slice())?;
+                inbox_sizes.insert(&client_address[..], &(inbox_size + 1).to_be_bytes()[..])?;
+                Ok(Ok(()))
+            },
+        )??;
+
+        // make sure we'd not lose the message if we crashed right now
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn fetch_and_mark_pending(
+        &self,
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        if !self.has_inbox(&client_address)? {
+            return Err(StoreError::ClientDoesntExistError);
+        }
+
+        let mut messages = Vec::new();
+        for entry in self.stored_messages_from(&client_address, cursor) {
+            if messages.len() == limit {
+                break;
+            }
+            let (key, _) = entry?;
+
+            // each message is moved in its own transaction; if it was concurrently moved by
+            // somebody else, it's no longer there and we just skip it
+            let moved_content = (&self.stored_messages, &self.pending_messages).transaction(
+                |(stored_messages, pending_messages)| -> ConflictableTransactionResult<_> {
+                    match stored_messages.remove(&key[..])? {
+                        None => Ok(None),
+                        Some(content) => {
+                            pending_messages.insert(
+                                &key[..],
+                                SledStorage::pending_value(current_timestamp(), &content),
+                            )?;
+                            Ok(Some(content))
+                        }
+                    }
+                },
+            )?;
+
+            if let Some(content) = moved_content {
+                messages.push(StoredMessage {
+                    id: SledStorage::message_id(&key)?,
+                    content: content.to_vec(),
+                })
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn count_stored(
+        &self,
+        client_address: DestinationAddressBytes,
+        cursor: MessageId,
+    ) -> Result<usize, StoreError> {
+        if !self.has_inbox(&client_address)? {
+            return Err(StoreError::ClientDoesntExistError);
+        }
+
+        let mut count = 0;
+        for entry in self.stored_messages_from(&client_address, cursor) {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn acknowledge(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<usize, StoreError> {
+        let removed = (&self.pending_messages, &self.inbox_sizes).transaction(
+            |(pending_messages, inbox_sizes)| -> TransactionOutcome<u64> {
+                let inbox_size = match inbox_sizes.get(&client_address[..])? {
+                    None => return Ok(Err(StoreError::ClientDoesntExistError)),
+                    Some(raw_size) => match SledStorage::decode_size(&raw_size) {
+                        Ok(size) => size,
+                        Err(err) => return Ok(Err(err)),
+                    },
+                };
+
+                let mut removed = 0;
+                for id in message_ids {
+                    let key = SledStorage::message_key(&client_address, *id);
+                    if pending_messages.remove(&key[..])?.is_some() {
+                        removed += 1;
+                    }
+                }
+
+                let new_size = inbox_size.saturating_sub(removed);
+                inbox_sizes.insert(&client_address[..], &new_size.to_be_bytes()[..])?;
+                Ok(Ok(removed))
+            },
+        )??;
+        Ok(removed as usize)
+    }
+
+    fn release_pending(
+        &self,
+        client_address: DestinationAddressBytes,
+        message_ids: &[MessageId],
+    ) -> Result<(), StoreError> {
+        (&self.pending_messages, &self.stored_messages).transaction(
+            |(pending_messages, stored_messages)| -> TransactionOutcome<()> {
+                let mut released = Vec::new();
+                for id in message_ids {
+                    let key = SledStorage::message_key(&client_address, *id);
+                    if let Some(pending_value) = pending_messages.get(&key[..])? {
+                        if let Err(err) = SledStorage::split_pending_value(&pending_value) {
+                            return Ok(Err(err));
+                        }
+                        released.push((key, pending_value));
+                    }
+                }
+
+                for (key, pending_value) in released {
+                    // we have already validated all the values
+                    let (_, content) = SledStorage::split_pending_value(&pending_value).unwrap();
+                    pending_messages.remove(&key[..])?;
+                    stored_messages.insert(&key[..], content)?;
+                }
+                Ok(Ok(()))
+            },
+        )??;
+        Ok(())
+    }
+
+    fn release_expired_leases(
+        &self,
+        lease_duration: Duration,
+    ) -> Result<Vec<DestinationAddressBytes>, StoreError> {
+        let mut clients = Vec::new();
+        for entry in self.pending_messages.iter() {
+            let (key, pending_value) = entry?;
+            let (leased_at, _) = SledStorage::split_pending_value(&pending_value)?;
+            if !is_lease_expired(leased_at, lease_duration) {
+                continue;
+            }
+
+            // the message might have been acknowledged or released in the meantime so we need
+            // to check it again inside the transaction
+            let was_released = (&self.pending_messages, &self.stored_messages).transaction(
+                |(pending_messages, stored_messages)| -> TransactionOutcome<bool> {
+                    let pending_value = match pending_messages.get(&key)? {
+                        None => return Ok(Ok(false)),
+                        Some(pending_value) => pending_value,
+                    };
+                    let (leased_at, content) =
+                        match SledStorage::split_pending_value(&pending_value) {
+                            Ok(split_value) => split_value,
+                            Err(err) => return Ok(Err(err)),
+                        };
+                    if !is_lease_expired(leased_at, lease_duration) {
+                        return Ok(Ok(false));
+                    }
+
+                    pending_messages.remove(&key)?;
+                    stored_messages.insert(&key, content)?;
+                    Ok(Ok(true))
+                },
+            )??;
+
+            // keys of the same client are next to each other
+            let client_address = SledStorage::message_owner(&key)?;
+            if was_released && clients.last() != Some(&client_address) {
+                clients.push(client_address);
+            }
+        }
+
+        Ok(clients)
+    }
+}
+
+#[cfg(test)]
+mod sled_storage {
+    use super::*;
+
+    fn temporary_storage(max_messages_per_client: usize) -> SledStorage {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStorage::new(db, max_messages_per_client).unwrap()
+    }
+
+    fn store(storage: &SledStorage, client_address: DestinationAddressBytes, content: u8) {
+        storage
+            .store_message(StoreData::new(client_address, [0u8; 16], vec![content]))
+            .unwrap();
+    }
+
+    #[test]
+    fn storing_for_unknown_client_fails() {
+        let storage = temporary_storage(10);
+        match storage.store_message(StoreData::new([1u8; 32], [0u8; 16], vec![1])) {
+            Err(StoreError::ClientDoesntExistError) => (),
+            _ => panic!("expected client doesn't exist error"),
+        }
+    }
+
+    #[test]
+    fn fetched_messages_are_not_handed_out_again() {
+        let storage = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        for i in 0..3 {
+            store(&storage, client_address, i);
+        }
+
+        let first_batch = storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap();
+        let second_batch = storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap();
+        assert_eq!(2, first_batch.len());
+        assert_eq!(1, second_batch.len());
+        assert!(storage
+            .fetch_and_mark_pending(client_address, 0, 2)
+            .unwrap()
+            .is_empty());
+
+        // messages are retrieved in the order they were stored
+        assert_eq!(vec![0], first_batch[0].content);
+        assert_eq!(vec![1], first_batch[1].content);
+        assert_eq!(vec![2], second_batch[0].content);
+    }
+
+    #[test]
+    fn messages_of_other_clients_are_not_fetched() {
+        let storage = temporary_storage(10);
+        storage.create_inbox([1u8; 32]).unwrap();
+        storage.create_inbox([2u8; 32]).unwrap();
+        store(&storage, [1u8; 32], 1);
+        store(&storage, [2u8; 32], 2);
+
+        let fetched = storage.fetch_and_mark_pending([1u8; 32], 0, 5).unwrap();
+        assert_eq!(1, fetched.len());
+        assert_eq!(vec![1], fetched[0].content);
+    }
+
+    #[test]
+    fn only_messages_from_the_cursor_onwards_are_fetched_and_counted() {
+        let storage = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        for i in 0..4 {
+            store(&storage, client_address, i);
+        }
+        assert_eq!(4, storage.count_stored(client_address, 0).unwrap());
+
+        let first_page = storage
+            .fetch_and_mark_pending(client_address, 0, 1)
+            .unwrap();
+        let cursor = first_page[0].id + 1;
+        assert_eq!(3, storage.count_stored(client_address, cursor).unwrap());
+
+        let second_page = storage
+            .fetch_and_mark_pending(client_address, cursor, 2)
+            .unwrap();
+        assert_eq!(vec![1], second_page[0].content);
+        assert_eq!(vec![2], second_page[1].content);
+
+        let cursor = second_page[1].id + 1;
+        assert_eq!(1, storage.count_stored(client_address, cursor).unwrap());
+        // released messages are behind the cursor
+        storage
+            .release_pending(client_address, &[first_page[0].id])
+            .unwrap();
+        assert_eq!(1, storage.count_stored(client_address, cursor).unwrap());
+        assert_eq!(2, storage.count_stored(client_address, 0).unwrap());
+    }
+
+    #[test]
+    fn released_messages_can_be_fetched_again() {
+        let storage = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 42);
+
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        storage
+            .release_pending(client_address, &[fetched[0].id])
+            .unwrap();
+
+        let refetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert_eq!(fetched, refetched);
+    }
+
+    #[test]
+    fn acknowledged_messages_free_up_the_quota() {
+        let storage = temporary_storage(2);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 1);
+        store(&storage, client_address, 2);
+
+        match storage.store_message(StoreData::new(client_address, [0u8; 16], vec![3])) {
+            Err(StoreError::QuotaExceededError) => (),
+            _ => panic!("expected quota exceeded error"),
+        }
+
+        // pending messages still count towards the quota
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 1)
+            .unwrap();
+        assert!(storage
+            .store_message(StoreData::new(client_address, [0u8; 16], vec![3]))
+            .is_err());
+
+        assert_eq!(
+            1,
+            storage
+                .acknowledge(client_address, &[fetched[0].id])
+                .unwrap()
+        );
+        store(&storage, client_address, 3);
+    }
+
+    #[test]
+    fn messages_with_expired_leases_can_be_fetched_again() {
+        let storage = temporary_storage(10);
+        let client_address = [1u8; 32];
+        storage.create_inbox(client_address).unwrap();
+        store(&storage, client_address, 42);
+
+        let fetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert!(storage
+            .release_expired_leases(Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            vec![client_address],
+            storage
+                .release_expired_leases(Duration::from_secs(0))
+                .unwrap()
+        );
+        let refetched = storage
+            .fetch_and_mark_pending(client_address, 0, 5)
+            .unwrap();
+        assert_eq!(fetched, refetched);
+    }
+}
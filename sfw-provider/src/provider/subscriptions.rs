@@ -0,0 +1,99 @@
+use futures::channel::mpsc;
+use sphinx::route::DestinationAddressBytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type SubscriptionId = u64;
+
+struct Subscription {
+    id: SubscriptionId,
+    notifier: mpsc::UnboundedSender<()>,
+}
+
+#[derive(Default)]
+struct ActiveSubscriptions {
+    next_id: SubscriptionId,
+    subscriptions: HashMap<DestinationAddressBytes, Subscription>,
+}
+
+// Clients that want their messages pushed to them rather than polling for them.
+// Every time a message for a subscribed client gets stored, its subscription is notified
+// so that the message could be included in the next push.
+#[derive(Clone)]
+pub(crate) struct SubscriptionRegistry {
+    inner: Arc<Mutex<ActiveSubscriptions>>,
+}
+
+impl SubscriptionRegistry {
+    pub(crate) fn new() -> Self {
+        SubscriptionRegistry {
+            inner: Arc::new(Mutex::new(ActiveSubscriptions::default())),
+        }
+    }
+
+    // each client can only have a single active subscription - subscribing again closes
+    // the notification channel of the previous one
+    pub(crate) fn subscribe(
+        &self,
+        client_address: DestinationAddressBytes,
+    ) -> (SubscriptionId, mpsc::UnboundedReceiver<()>) {
+        let (notifier, notifications) = mpsc::unbounded();
+        let mut active = self.inner.lock().unwrap();
+        let id = active.next_id;
+        active.next_id += 1;
+        active
+            .subscriptions
+            .insert(client_address, Subscription { id, notifier });
+        (id, notifications)
+    }
+
+    // removes the subscription unless it was already replaced by a newer one
+    pub(crate) fn unsubscribe(&self, client_address: &DestinationAddressBytes, id: SubscriptionId) {
+        let mut active = self.inner.lock().unwrap();
+        let is_current = match active.subscriptions.get(client_address) {
+            Some(subscription) => subscription.id == id,
+            None => false,
+        };
+        if is_current {
+            active.subscriptions.remove(client_address);
+        }
+    }
+
+    pub(crate) fn notify(&self, client_address: &DestinationAddressBytes) {
+        if let Some(subscription) = self.inner.lock().unwrap().subscriptions.get(client_address) {
+            // if it failed, the subscription is just about to be removed so we don't care
+            let _ = subscription.notifier.unbounded_send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod subscription_registry {
+    use super::*;
+
+    #[test]
+    fn only_notifies_subscribed_clients() {
+        let registry = SubscriptionRegistry::new();
+        let (_, mut notifications) = registry.subscribe([1u8; 32]);
+
+        registry.notify(&[2u8; 32]);
+        assert!(notifications.try_next().is_err());
+
+        registry.notify(&[1u8; 32]);
+        assert_eq!(Some(()), notifications.try_next().unwrap());
+    }
+
+    #[test]
+    fn subscribing_again_closes_the_previous_subscription() {
+        let registry = SubscriptionRegistry::new();
+        let (old_id, mut old_notifications) = registry.subscribe([1u8; 32]);
+        let (_, mut new_notifications) = registry.subscribe([1u8; 32]);
+
+        // closing the old subscription must not affect the new one
+        registry.unsubscribe(&[1u8; 32], old_id);
+        registry.notify(&[1u8; 32]);
+
+        assert_eq!(None, old_notifications.try_next().unwrap());
+        assert_eq!(Some(()), new_notifications.try_next().unwrap());
+    }
+}